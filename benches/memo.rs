@@ -0,0 +1,82 @@
+//! End-to-end benchmarks of the memo binary
+//!
+//! memo is a binary crate, so these run the built executable against a
+//! scratch cache, which also counts process startup. For per-operation
+//! numbers without the process overhead, use `memo --self-bench`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use std::process::{Command, Stdio};
+use tempfile::TempDir;
+
+fn memo(cache: &TempDir) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_memo"));
+    cmd.env("XDG_CACHE_HOME", cache.path())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    cmd
+}
+
+fn run(mut cmd: Command) {
+    let status = cmd.status().expect("failed to run memo");
+    assert!(status.success());
+}
+
+fn bench_hit(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hit");
+    for (name, bytes) in [("small", 256), ("large", 16 << 20)] {
+        let cache = TempDir::new().unwrap();
+        let script = format!("head -c {bytes} /dev/zero");
+        let replay = || {
+            let mut cmd = memo(&cache);
+            cmd.args(["sh", "-c", &script]);
+            cmd
+        };
+        run(replay());
+
+        group.bench_function(name, |b| b.iter(|| run(replay())));
+    }
+    group.finish();
+}
+
+fn bench_buffer_size(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hit_buffer_size");
+    let cache = TempDir::new().unwrap();
+    let script = format!("head -c {} /dev/zero", 64 << 20);
+    run({
+        let mut cmd = memo(&cache);
+        cmd.args(["sh", "-c", &script]);
+        cmd
+    });
+
+    for size in ["4096", "65536", "1048576"] {
+        group.bench_function(size, |b| {
+            b.iter(|| {
+                let mut cmd = memo(&cache);
+                cmd.args(["--buffer-size", size, "sh", "-c", &script]);
+                run(cmd)
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_miss(c: &mut Criterion) {
+    let cache = TempDir::new().unwrap();
+    let mut i = 0u64;
+
+    c.bench_function("miss", |b| {
+        b.iter_batched(
+            || {
+                i += 1;
+                let mut cmd = memo(&cache);
+                cmd.args(["echo", &i.to_string()]);
+                cmd
+            },
+            run,
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_hit, bench_buffer_size, bench_miss);
+criterion_main!(benches);
@@ -0,0 +1,177 @@
+//! Built-in benchmark of the cache operations (`--self-bench`)
+//!
+//! Runs a synthetic workload against a scratch cache directory: many entries
+//! with small output, and a few with large output. Each entry goes through the
+//! same lookup, commit, and replay functions a real run uses (without running
+//! a command), and the time per operation and throughput are reported.
+
+use crate::cache::{
+    commit_cache_dir, create_temp_cache_dir, ensure_cache_dir, memo_complete, stream_stderr,
+    stream_stdout,
+};
+use crate::digest::{cache_key, DigestOptions};
+use crate::error::Result;
+use crate::memo::Memo;
+use chrono::Utc;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+
+/// A set of entries with the same output size
+struct Workload {
+    name: &'static str,
+    entries: usize,
+    output_bytes: usize,
+}
+
+/// Workloads for a full run
+const FULL: [Workload; 2] = [
+    Workload {
+        name: "small",
+        entries: 1000,
+        output_bytes: 256,
+    },
+    Workload {
+        name: "large",
+        entries: 4,
+        output_bytes: 16 << 20,
+    },
+];
+
+/// Workloads for `--quick`
+const QUICK: [Workload; 2] = [
+    Workload {
+        name: "small",
+        entries: 50,
+        output_bytes: 256,
+    },
+    Workload {
+        name: "large",
+        entries: 2,
+        output_bytes: 1 << 20,
+    },
+];
+
+/// Run the benchmark and print one line per workload and operation
+///
+/// The scratch cache is created in the system temp directory and removed
+/// afterwards.
+pub fn run(quick: bool) -> Result<()> {
+    let cache_dir = std::env::temp_dir().join(format!("memo-self-bench.{}", process::id()));
+    ensure_cache_dir(&cache_dir)?;
+
+    let result = run_in(&cache_dir, if quick { &QUICK } else { &FULL });
+    let _ = fs::remove_dir_all(&cache_dir);
+    result
+}
+
+fn run_in(cache_dir: &Path, workloads: &[Workload]) -> Result<()> {
+    println!(
+        "{:<9} {:<8} {:>6} {:>9} {:>9}",
+        "workload", "op", "ops", "us/op", "MB/s"
+    );
+
+    for workload in workloads {
+        let output = vec![b'x'; workload.output_bytes];
+        let digests = (0..workload.entries)
+            .map(|i| {
+                let args = vec![
+                    "bench".to_string(),
+                    workload.name.to_string(),
+                    i.to_string(),
+                ];
+                cache_key(&args, "/bench", &DigestOptions::default())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Lookups of missing entries, as on a first run
+        let elapsed = time(|| {
+            for digest in &digests {
+                memo_complete(cache_dir, digest);
+            }
+            Ok(())
+        })?;
+        report(workload, "miss", elapsed, 0);
+
+        let elapsed = time(|| {
+            for digest in &digests {
+                commit(cache_dir, digest, &output)?;
+            }
+            Ok(())
+        })?;
+        report(workload, "commit", elapsed, workload.output_bytes);
+
+        let elapsed = time(|| {
+            for digest in &digests {
+                memo_complete(cache_dir, digest);
+            }
+            Ok(())
+        })?;
+        report(workload, "hit", elapsed, 0);
+
+        let elapsed = time(|| {
+            for digest in &digests {
+                stream_stdout(cache_dir, digest, io::sink())?;
+                stream_stderr(cache_dir, digest, io::sink())?;
+            }
+            Ok(())
+        })?;
+        report(workload, "replay", elapsed, workload.output_bytes);
+    }
+
+    Ok(())
+}
+
+/// Write and commit one entry with `output` as its stdout
+fn commit(cache_dir: &Path, digest: &str, output: &[u8]) -> Result<()> {
+    let mut temp_dir = create_temp_cache_dir(cache_dir, digest)?;
+    let (json_path, out_path, err_path) = temp_dir.get_paths();
+
+    let memo = Memo {
+        cmd: vec!["bench".to_string()],
+        cwd: "/bench".to_string(),
+        exit_code: 0,
+        signal: None,
+        timestamp: Utc::now().to_rfc3339(),
+        digest: digest.to_string(),
+        rusage: None,
+        stdout_was_piped: true,
+        stderr_was_piped: true,
+        encryption: None,
+        duration_ms: 0,
+        started_at: None,
+        finished_at: None,
+        memo_version: "self-bench".to_string(),
+    };
+    fs::write(json_path, serde_json::to_vec_pretty(&memo)?)?;
+    fs::write(out_path, output)?;
+    fs::write(err_path, b"")?;
+
+    commit_cache_dir(&mut temp_dir, cache_dir, digest)?;
+    Ok(())
+}
+
+fn time(f: impl FnOnce() -> Result<()>) -> Result<Duration> {
+    let start = Instant::now();
+    f()?;
+    Ok(start.elapsed())
+}
+
+/// Print a result line; `bytes_per_op` of 0 leaves the throughput blank
+fn report(workload: &Workload, op: &str, elapsed: Duration, bytes_per_op: usize) {
+    let ops = workload.entries;
+    let us_per_op = elapsed.as_secs_f64() * 1e6 / ops as f64;
+    let throughput = if bytes_per_op == 0 {
+        String::new()
+    } else {
+        let mb = (ops * bytes_per_op) as f64 / (1 << 20) as f64;
+        format!("{:.1}", mb / elapsed.as_secs_f64())
+    };
+    let line = format!(
+        "{:<9} {:<8} {:>6} {:>9.1} {:>9}",
+        workload.name, op, ops, us_per_op, throughput
+    );
+    println!("{}", line.trim_end());
+}
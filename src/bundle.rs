@@ -0,0 +1,198 @@
+//! Whole-cache backups as one compressed tarball (`--bundle`, `--unbundle`)
+//!
+//! A bundle is a zstd-compressed tar archive holding each complete entry's
+//! files as `<digest>/<file>`, in digest order. Paths don't include shard
+//! directories, so a bundle can be restored into a cache with any
+//! `--shard-depth`.
+//!
+//! Restoring merges into the existing cache: each entry is unpacked into a
+//! temp directory and committed like a fresh miss, and entries that are
+//! already cached are left alone.
+
+use crate::cache::{
+    commit_cache_dir, create_temp_cache_dir, iter_memos, memo_complete, memo_complete_in_dir,
+    TempCacheDir,
+};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path};
+
+/// zstd's default compression level, a good trade of speed for size
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Summary of an [`unbundle`] run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnbundleReport {
+    /// Entries added to the cache
+    pub restored: usize,
+    /// Entries skipped because the cache already had them
+    pub skipped: usize,
+}
+
+/// Archive every complete cache entry into a bundle at `out`
+///
+/// Returns the number of entries archived. Incomplete entries and entries
+/// whose `meta.json` can't be read are left out.
+pub fn bundle(cache_dir: &Path, out: &Path) -> io::Result<usize> {
+    let encoder = zstd::Encoder::new(File::create(out)?, COMPRESSION_LEVEL)?;
+    let mut archive = tar::Builder::new(encoder);
+    let mut count = 0;
+
+    for (digest, dir, memo) in iter_memos(cache_dir)? {
+        if memo.is_err() || !memo_complete_in_dir(&dir) {
+            continue;
+        }
+
+        let mut files: Vec<_> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+            .map(|entry| entry.file_name())
+            .collect();
+        files.sort();
+
+        for name in files {
+            archive.append_path_with_name(dir.join(&name), Path::new(&digest).join(&name))?;
+        }
+        count += 1;
+    }
+
+    archive.into_inner()?.finish()?;
+    Ok(count)
+}
+
+/// Restore the entries in the bundle at `input` into the cache
+///
+/// Fails on archive paths that aren't `<digest>/<file>`, so a bundle can't
+/// write outside the cache.
+pub fn unbundle(cache_dir: &Path, input: &Path) -> io::Result<UnbundleReport> {
+    let mut archive = tar::Archive::new(zstd::Decoder::new(File::open(input)?)?);
+    let mut report = UnbundleReport::default();
+
+    // The entry being unpacked, or `None` for one that is already cached
+    let mut current: Option<(String, Option<TempCacheDir>)> = None;
+
+    for file in archive.entries()? {
+        let mut file = file?;
+        let (digest, name) = split_entry_path(&file.path()?)?;
+
+        if current.as_ref().is_none_or(|(d, _)| *d != digest) {
+            if let Some((done, temp_dir)) = current.take() {
+                finish_entry(cache_dir, &done, temp_dir, &mut report)?;
+            }
+            let temp_dir = if memo_complete(cache_dir, &digest) {
+                None
+            } else {
+                Some(create_temp_cache_dir(cache_dir, &digest)?)
+            };
+            current = Some((digest, temp_dir));
+        }
+
+        if let Some((_, Some(temp_dir))) = &current {
+            io::copy(&mut file, &mut File::create(temp_dir.path.join(name))?)?;
+        }
+    }
+
+    if let Some((done, temp_dir)) = current {
+        finish_entry(cache_dir, &done, temp_dir, &mut report)?;
+    }
+    Ok(report)
+}
+
+/// Commit one unpacked entry and count it
+fn finish_entry(
+    cache_dir: &Path,
+    digest: &str,
+    temp_dir: Option<TempCacheDir>,
+    report: &mut UnbundleReport,
+) -> io::Result<()> {
+    let committed = match temp_dir {
+        Some(mut temp_dir) => commit_cache_dir(&mut temp_dir, cache_dir, digest)?,
+        None => false,
+    };
+    if committed {
+        report.restored += 1;
+    } else {
+        report.skipped += 1;
+    }
+    Ok(())
+}
+
+/// Split an archive path into its digest and file name
+fn split_entry_path(path: &Path) -> io::Result<(String, String)> {
+    let parts: Vec<_> = path
+        .components()
+        .map(|component| match component {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect();
+
+    match parts.as_slice() {
+        [Some(digest), Some(name)] if digest.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            Ok((digest.to_string(), name.to_string()))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected path in bundle: {}", path.display()),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::write_test_entry;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_roundtrip_merges_into_existing_cache() {
+        let temp = TempDir::new().unwrap();
+        let source = temp.path().join("source");
+        let target = temp.path().join("target");
+        let out = temp.path().join("cache.tar.zst");
+        write_test_entry(&source, "aaaa", b"one\n", b"");
+        write_test_entry(&source, "bbbb", b"two\n", b"");
+        write_test_entry(&target, "bbbb", b"kept\n", b"");
+
+        assert_eq!(bundle(&source, &out).unwrap(), 2);
+        assert_eq!(
+            unbundle(&target, &out).unwrap(),
+            UnbundleReport {
+                restored: 1,
+                skipped: 1,
+            }
+        );
+
+        assert_eq!(
+            fs::read_to_string(target.join("aaaa/stdout")).unwrap(),
+            "one\n"
+        );
+        assert_eq!(
+            fs::read_to_string(target.join("bbbb/stdout")).unwrap(),
+            "kept\n"
+        );
+    }
+
+    #[test]
+    fn test_bundle_skips_incomplete_entries() {
+        let temp = TempDir::new().unwrap();
+        let cache_dir = temp.path().join("memo");
+        write_test_entry(&cache_dir, "aaaa", b"one\n", b"");
+        write_test_entry(&cache_dir, "bbbb", b"two\n", b"");
+        fs::remove_file(cache_dir.join("bbbb/stderr")).unwrap();
+
+        assert_eq!(bundle(&cache_dir, &temp.path().join("out")).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_entry_paths_must_stay_in_the_cache() {
+        assert_eq!(
+            split_entry_path(Path::new("abc123/stdout")).unwrap(),
+            ("abc123".to_string(), "stdout".to_string())
+        );
+        assert!(split_entry_path(Path::new("../abc/stdout")).is_err());
+        assert!(split_entry_path(Path::new("/abc/stdout")).is_err());
+        assert!(split_entry_path(Path::new("abc/sub/stdout")).is_err());
+        assert!(split_entry_path(Path::new("abc.tmp.1/stdout")).is_err());
+    }
+}
@@ -13,6 +13,15 @@
 //! - `<digest>/meta.json` - Metadata (command, exit code, timestamp, digest)
 //! - `<digest>/stdout` - Raw stdout bytes
 //! - `<digest>/stderr` - Raw stderr bytes
+//! - `<digest>/timing` - Optional stdout write timing (`--replay-timing`)
+//!
+//! With a shard depth of N, entries are nested under N levels of two-character
+//! digest prefixes (e.g. `ab/cd/<digest>/` for depth 2). All entry paths are
+//! built by [`digest_dir`].
+//!
+//! With `--prefix <name>`, the whole cache lives in `<name>/` below the usual
+//! cache directory. The prefix directory holds a marker file so operations on
+//! the enclosing cache skip it.
 //!
 //! # Concurrency Strategy
 //!
@@ -21,16 +30,30 @@
 //! 2. After completion, atomically renames temp dir to `<digest>/`
 //! 3. First rename wins; losers detect the existing directory and clean up
 //! 4. Orphaned temp directories are cleaned up on startup
-
-use crate::constants::CACHE_DIR_PERMISSIONS;
+//!
+//! With `--single-flight`, a process that misses first takes a `<digest>.lock`
+//! directory (atomic `mkdir`) so concurrent misses wait for its entry instead
+//! of all running the command.
+
+use crate::constants::{
+    CACHE_DIR_PERMISSIONS, CACHE_DIR_VAR, DEFAULT_BUFFER_SIZE, INCOMPLETE_ENTRY_GRACE,
+    MIN_DIGEST_PREFIX_LEN, TEMP_DIR_MAX_AGE,
+};
+use crate::crypto::EntryKeys;
+use crate::diag::diag;
+use crate::digest::untagged;
 use crate::error::{MemoError, Result};
 use crate::memo::Memo;
+use crate::trace::trace;
 use chrono::Utc;
 use std::fs::{self, File};
-use std::io::{self, copy};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::process;
-use std::time::{Duration, SystemTime};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
@@ -53,9 +76,70 @@ pub fn is_memo_disabled() -> bool {
         .unwrap_or(false)
 }
 
+/// Name of the marker file in the root of a `--prefix` cache
+pub const PREFIX_MARKER: &str = ".memo-prefix";
+
+/// Subdirectory of the cache directory used for the rest of the process
+static CACHE_PREFIX: Mutex<Option<String>> = Mutex::new(None);
+
+/// Scope [`get_cache_dir`] to a prefix for the rest of the process
+pub fn set_cache_prefix(prefix: Option<String>) {
+    *CACHE_PREFIX.lock().unwrap() = prefix;
+}
+
+/// Cache directory given with `--cache-dir`, for the rest of the process
+static CACHE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Use `dir` as the cache directory for the rest of the process, instead of
+/// `MEMO_CACHE_DIR` or the XDG location
+pub fn set_cache_dir(dir: Option<PathBuf>) {
+    *CACHE_DIR.lock().unwrap() = dir;
+}
+
+/// The cache directory from `--cache-dir` or `MEMO_CACHE_DIR`, if either is set
+fn explicit_cache_dir() -> Option<PathBuf> {
+    CACHE_DIR.lock().unwrap().clone().or_else(|| {
+        std::env::var_os(CACHE_DIR_VAR)
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+    })
+}
+
+/// Whether a missing home directory falls back to a temp cache
+static ALLOW_MISSING_HOME: AtomicBool = AtomicBool::new(false);
+
+/// Let [`get_cache_dir`] use the system temp directory when there is no home
+/// directory, for the rest of the process
+pub fn set_allow_missing_home(allow: bool) {
+    ALLOW_MISSING_HOME.store(allow, Ordering::Relaxed);
+}
+
+/// Whether `MEMO_CACHE_FALLBACK_TMP=1` is set
+pub fn is_temp_fallback_enabled() -> bool {
+    std::env::var("MEMO_CACHE_FALLBACK_TMP")
+        .map(|val| val == "1")
+        .unwrap_or(false)
+}
+
+/// Validate a `--prefix` value: a single, non-hidden path segment
+///
+/// Used as a clap `value_parser`, so errors are plain strings.
+pub fn parse_prefix(prefix: &str) -> std::result::Result<String, String> {
+    if prefix.is_empty() || prefix.starts_with('.') {
+        return Err("must not be empty or start with `.`".to_string());
+    }
+    if prefix.contains(['/', '\\']) {
+        return Err("must be a single path segment".to_string());
+    }
+    Ok(prefix.to_string())
+}
+
 /// Get the cache directory path
 ///
-/// Respects `$XDG_CACHE_HOME` environment variable, falling back to `~/.cache`.
+/// This is the directory set with [`set_cache_dir`] or `$MEMO_CACHE_DIR` if
+/// there is one. Otherwise it is `memo/` below `$XDG_CACHE_HOME`, falling back
+/// to `~/.cache`. Either way, the `--prefix` subdirectory is used if one was
+/// set with [`set_cache_prefix`].
 ///
 /// # Examples
 ///
@@ -65,40 +149,260 @@ pub fn is_memo_disabled() -> bool {
 /// println!("Cache directory: {:?}", cache_dir);
 /// ```
 pub fn get_cache_dir() -> Result<PathBuf> {
-    let base = if let Ok(xdg) = std::env::var("XDG_CACHE_HOME") {
-        PathBuf::from(xdg)
-    } else {
-        dirs::home_dir()
-            .ok_or(MemoError::HomeNotFound)?
-            .join(".cache")
+    let mut cache_dir = match explicit_cache_dir() {
+        Some(dir) => {
+            trace!("cache directory set explicitly: {}", dir.display());
+            dir
+        }
+        None => cache_base(
+            std::env::var("XDG_CACHE_HOME").ok(),
+            dirs::home_dir(),
+            ALLOW_MISSING_HOME.load(Ordering::Relaxed),
+        )?
+        .join("memo"),
     };
-    Ok(base.join("memo"))
+
+    if let Some(prefix) = CACHE_PREFIX.lock().unwrap().as_deref() {
+        trace!("cache prefix: {prefix}");
+        cache_dir.push(prefix);
+    }
+    Ok(cache_dir)
+}
+
+/// Pick the directory the `memo` cache directory lives in
+///
+/// Without `XDG_CACHE_HOME` or a home directory, this is the system temp
+/// directory if `allow_missing_home` is set, and an error otherwise.
+fn cache_base(
+    xdg: Option<String>,
+    home: Option<PathBuf>,
+    allow_missing_home: bool,
+) -> Result<PathBuf> {
+    if let Some(xdg) = xdg {
+        trace!("cache base from XDG_CACHE_HOME: {xdg}");
+        return Ok(PathBuf::from(xdg));
+    }
+    if let Some(home) = home {
+        let base = home.join(".cache");
+        trace!("cache base from home directory: {}", base.display());
+        return Ok(base);
+    }
+    if allow_missing_home {
+        let base = std::env::temp_dir();
+        trace!("cache base from temp directory: {}", base.display());
+        return Ok(base);
+    }
+    Err(MemoError::HomeNotFound)
+}
+
+/// Whether [`get_cache_dir`] falls back to the system temp directory
+pub fn uses_temp_cache() -> bool {
+    ALLOW_MISSING_HOME.load(Ordering::Relaxed)
+        && explicit_cache_dir().is_none()
+        && std::env::var_os("XDG_CACHE_HOME").is_none()
+        && dirs::home_dir().is_none()
 }
 
 /// Ensure the cache directory exists with appropriate permissions
 ///
 /// Creates the directory if it doesn't exist, and sets secure permissions (0o700)
-/// on Unix systems.
+/// on Unix systems. An existing directory's permissions are left alone.
 pub fn ensure_cache_dir(cache_dir: &Path) -> io::Result<()> {
-    fs::create_dir_all(cache_dir)?;
+    if !cache_dir.is_dir() {
+        fs::create_dir_all(cache_dir)?;
+        restrict_permissions(cache_dir);
+    }
+
+    // Mark a prefixed cache so the enclosing cache doesn't treat it as an entry
+    if CACHE_PREFIX.lock().unwrap().is_some() {
+        File::create(cache_dir.join(PREFIX_MARKER))?;
+    }
+
+    Ok(())
+}
 
+/// Whether newly created directories are restricted to their owner
+static CHMOD: AtomicBool = AtomicBool::new(true);
+
+/// Turn off setting permissions on created directories for the rest of the
+/// process (`--no-chmod`), for filesystems without Unix modes
+pub fn set_chmod(enabled: bool) {
+    CHMOD.store(enabled, Ordering::Relaxed);
+}
+
+/// Restrict a directory this process just created to its owner
+///
+/// Failures are ignored, as some filesystems don't support Unix modes.
+fn restrict_permissions(path: &Path) {
     #[cfg(unix)]
-    {
+    if CHMOD.load(Ordering::Relaxed) {
         let perm = fs::Permissions::from_mode(CACHE_DIR_PERMISSIONS);
-        let _ = fs::set_permissions(cache_dir, perm);
+        let _ = fs::set_permissions(path, perm);
     }
+    #[cfg(not(unix))]
+    let _ = path;
+}
 
-    Ok(())
+/// Name of the optional sidecar file recording stdout write timing
+///
+/// Each line is an `<offset_ms> <len>` record: `len` bytes of stdout were
+/// written `offset_ms` milliseconds after the command started.
+pub const TIMING_FILE: &str = "timing";
+
+/// Name of the optional sidecar file recording how stdout and stderr
+/// interleaved
+///
+/// Each line is a `<stream> <len>` record, where `stream` is
+/// [`STDOUT_STREAM`] or [`STDERR_STREAM`]: the next `len` bytes of that
+/// stream arrived next.
+pub const ORDER_FILE: &str = "order";
+
+/// Tag for stdout chunks in the [`ORDER_FILE`]
+pub const STDOUT_STREAM: &str = "out";
+
+/// Tag for stderr chunks in the [`ORDER_FILE`]
+pub const STDERR_STREAM: &str = "err";
+
+/// Name of the sidecar file counting cache hits
+///
+/// Each hit appends one byte, so the count is the file's length. Appends are
+/// atomic, so concurrent hits never lose counts or rewrite `meta.json`.
+pub const HITS_FILE: &str = "hits";
+
+/// Name of the marker file that protects an entry from pruning
+pub const PIN_FILE: &str = "pinned";
+
+/// Number of digest-prefix directory levels entries are nested under
+static SHARD_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the shard depth used by [`digest_dir`] for the rest of the process
+///
+/// A depth of 0 (the default) is the flat layout.
+pub fn set_shard_depth(depth: usize) {
+    SHARD_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+/// Size of the buffer used by [`copy`]
+static BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_BUFFER_SIZE);
+
+/// Set the copy buffer size for the rest of the process
+pub fn set_buffer_size(size: usize) {
+    BUFFER_SIZE.store(size.max(1), Ordering::Relaxed);
+}
+
+/// Copy a reader to a writer through a buffer of the configured size
+///
+/// Used for all streaming of output into and out of the cache, so
+/// `--buffer-size` bounds the size of every read and write.
+pub fn copy<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+) -> io::Result<u64> {
+    copy_with_buffer(reader, writer, BUFFER_SIZE.load(Ordering::Relaxed))
+}
+
+/// Copy a reader to a writer through a buffer of `size` bytes
+fn copy_with_buffer<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    size: usize,
+) -> io::Result<u64> {
+    let mut buf = vec![0; size];
+    let mut total = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        total += n as u64;
+    }
+}
+
+/// Get the directory holding the cache entry for a digest
+pub fn digest_dir(cache_dir: &Path, digest: &str) -> PathBuf {
+    digest_dir_at(cache_dir, digest, SHARD_DEPTH.load(Ordering::Relaxed))
+}
+
+/// Get the entry directory for a digest at an explicit shard depth
+fn digest_dir_at(cache_dir: &Path, digest: &str, depth: usize) -> PathBuf {
+    let mut dir = cache_dir.to_path_buf();
+    let hex = untagged(digest);
+    for level in 0..depth {
+        if let Some(prefix) = hex.get(level * 2..level * 2 + 2) {
+            dir.push(prefix);
+        }
+    }
+    dir.join(digest)
+}
+
+/// Check whether a directory name is a temp directory or a single-flight lock
+/// rather than a committed entry
+fn is_scratch_name(name: &str) -> bool {
+    name.contains(".tmp.") || name.ends_with(".lock")
+}
+
+/// Check whether a directory name is a shard level rather than an entry
+fn is_shard_name(name: &str) -> bool {
+    name.len() == 2 && !name.contains('.')
+}
+
+/// Collect every entry and temp directory in the cache, at any shard depth
+fn entry_dirs(cache_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+
+    // A shard directory can be removed by another process mid-scan
+    let entries = match fs::read_dir(cache_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(dirs),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let path = entry.path();
+        if path.join(PREFIX_MARKER).exists() {
+            continue;
+        }
+        // Digests never start with a dot, so hidden dirs are memo's own
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if is_shard_name(&entry.file_name().to_string_lossy()) {
+            dirs.extend(entry_dirs(&path)?);
+        } else {
+            dirs.push(path);
+        }
+    }
+
+    Ok(dirs)
 }
 
 /// Check if a memo is complete (the digest directory exists with all three files)
 ///
 /// Returns `true` if the `<digest>/` directory exists with `meta.json`, `stdout`, and `stderr`.
 pub fn memo_complete(cache_dir: &Path, digest: &str) -> bool {
-    let digest_dir = cache_dir.join(digest);
-    digest_dir.join("meta.json").exists()
-        && digest_dir.join("stdout").exists()
-        && digest_dir.join("stderr").exists()
+    memo_complete_in_dir(&digest_dir(cache_dir, digest))
+}
+
+/// Check if the entry in a digest directory has all three files
+pub fn memo_complete_in_dir(dir: &Path) -> bool {
+    ["meta.json", "stdout", "stderr"].iter().all(|name| {
+        let path = dir.join(name);
+        let exists = path.exists();
+        trace!(
+            "checked {}: {}",
+            path.display(),
+            if exists { "found" } else { "missing" }
+        );
+        exists
+    })
 }
 
 /// Get paths to the three cache files within a digest directory
@@ -112,21 +416,31 @@ pub fn get_cache_paths_in_dir(dir: &Path) -> (PathBuf, PathBuf, PathBuf) {
 /// Get paths to the cache files for a digest (convenience wrapper)
 #[cfg(test)]
 pub fn get_cache_paths(cache_dir: &Path, digest: &str) -> (PathBuf, PathBuf, PathBuf) {
-    let digest_dir = cache_dir.join(digest);
+    let digest_dir = digest_dir(cache_dir, digest);
     get_cache_paths_in_dir(&digest_dir)
 }
 
 /// Create a secure directory with appropriate permissions
 fn create_secure_dir(path: &Path) -> io::Result<()> {
     fs::create_dir(path)?;
+    restrict_permissions(path);
+    Ok(())
+}
 
-    #[cfg(unix)]
-    {
-        let perm = fs::Permissions::from_mode(CACHE_DIR_PERMISSIONS);
-        let _ = fs::set_permissions(path, perm);
+/// Create any missing parent directories of a digest directory
+fn create_shard_dirs(digest_dir: &Path, cache_dir: &Path) -> io::Result<()> {
+    let Some(parent) = digest_dir.parent() else {
+        return Ok(());
+    };
+    if parent == cache_dir || parent.exists() {
+        return Ok(());
     }
 
-    Ok(())
+    create_shard_dirs(parent, cache_dir)?;
+    match create_secure_dir(parent) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(()),
+    }
 }
 
 /// Represents a temporary directory for writing cache files before atomic commit
@@ -153,17 +467,80 @@ impl Drop for TempCacheDir {
     }
 }
 
+/// memo's piped stdin, saved to a hidden file in the cache directory so it
+/// can be hashed into the key and then given to the command
+///
+/// The file is removed on drop.
+pub struct StdinSpool {
+    /// Path to the saved stdin
+    pub path: PathBuf,
+    /// Number of bytes saved
+    pub bytes: u64,
+}
+
+impl Drop for StdinSpool {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Prefix of a [`StdinSpool`]'s file name, in the cache root
+const STDIN_SPOOL_PREFIX: &str = ".stdin.";
+
+/// Stdin spools in the cache root, including ones left by a killed memo
+fn stdin_spools(cache_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(cache_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut spools = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file()
+            && entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(STDIN_SPOOL_PREFIX)
+        {
+            spools.push(entry.path());
+        }
+    }
+    Ok(spools)
+}
+
+/// Read `input` to the end into a new [`StdinSpool`]
+///
+/// Streams to disk, so large input isn't held in memory.
+pub fn spool_stdin<R: Read>(cache_dir: &Path, input: &mut R) -> io::Result<StdinSpool> {
+    let timestamp = Utc::now().timestamp_nanos_opt().unwrap_or(0);
+    let path = cache_dir.join(format!("{STDIN_SPOOL_PREFIX}{}.{timestamp}", process::id()));
+    let mut file = File::create(&path)?;
+    // Owns the file from here, so a failed copy still removes it
+    let mut spool = StdinSpool { path, bytes: 0 };
+    spool.bytes = copy(input, &mut file)?;
+    restrict_permissions(&spool.path);
+    Ok(spool)
+}
+
 /// Create a temporary directory for writing cache files
 ///
 /// The temp directory is named `<digest>.tmp.<pid>.<timestamp>` to avoid collisions
 /// between concurrent processes working on the same digest, even across PID reuse.
+/// It is created next to the final entry directory so the commit rename stays
+/// within one directory.
 pub fn create_temp_cache_dir(cache_dir: &Path, digest: &str) -> io::Result<TempCacheDir> {
+    let final_path = digest_dir(cache_dir, digest);
+    create_shard_dirs(&final_path, cache_dir)?;
+
     let pid = process::id();
     let timestamp = Utc::now().timestamp_nanos_opt().unwrap_or(0);
     let temp_name = format!("{}.tmp.{}.{}", digest, pid, timestamp);
-    let temp_path = cache_dir.join(temp_name);
+    let temp_path = final_path.with_file_name(temp_name);
 
     create_secure_dir(&temp_path)?;
+    trace!("created temp dir {}", temp_path.display());
 
     Ok(TempCacheDir {
         path: temp_path,
@@ -177,16 +554,41 @@ pub fn create_temp_cache_dir(cache_dir: &Path, digest: &str) -> io::Result<TempC
 /// are on the same filesystem. Returns `Ok(true)` if we won the race and
 /// committed successfully, or `Ok(false)` if another process already
 /// committed a cache entry for this digest.
+///
+/// The temp directory's files and the directory itself are fsynced first, so
+/// a crash right after the rename can't leave a committed-looking entry with
+/// empty or partial files.
+///
+/// If the rename fails because the temp directory lives on a different
+/// filesystem, falls back to a non-atomic copy into the final location.
 pub fn commit_cache_dir(
     temp_dir: &mut TempCacheDir,
     cache_dir: &Path,
     digest: &str,
 ) -> io::Result<bool> {
-    let final_path = cache_dir.join(digest);
+    commit_cache_dir_with(temp_dir, cache_dir, digest, |from, to| fs::rename(from, to))
+}
+
+/// Commit a temp directory using the given rename operation
+fn commit_cache_dir_with<F>(
+    temp_dir: &mut TempCacheDir,
+    cache_dir: &Path,
+    digest: &str,
+    rename: F,
+) -> io::Result<bool>
+where
+    F: FnOnce(&Path, &Path) -> io::Result<()>,
+{
+    let final_path = digest_dir(cache_dir, digest);
+
+    sync_dir(&temp_dir.path)?;
 
-    match fs::rename(&temp_dir.path, &final_path) {
+    match rename(&temp_dir.path, &final_path) {
         Ok(()) => {
+            trace!("committed {} by rename", final_path.display());
             temp_dir.committed = true;
+            // The rename itself is only durable once the parent is synced
+            sync_parent(&final_path)?;
             Ok(true)
         }
         Err(e)
@@ -195,48 +597,249 @@ pub fn commit_cache_dir(
         {
             // Another process beat us to it - that's fine, just clean up
             // (Drop will handle cleanup since committed is still false)
+            trace!("not committed: {} already exists", final_path.display());
             Ok(false)
         }
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            trace!(
+                "rename crosses devices; copying to {}",
+                final_path.display()
+            );
+            copy_commit_cache_dir(temp_dir, &final_path)
+        }
         Err(e) => Err(e),
     }
 }
 
-/// Clean up orphaned temporary directories in the cache
+/// Flush a directory's files and then the directory itself to disk
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            File::open(entry.path())?.sync_all()?;
+        }
+    }
+    sync_dir_entry(dir)
+}
+
+/// Flush a directory itself (its list of names) to disk
+fn sync_dir_entry(dir: &Path) -> io::Result<()> {
+    // Directories can only be opened (and so synced) on Unix
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+
+    Ok(())
+}
+
+/// Flush the directory containing `path`, so a rename or create of `path`
+/// survives a crash
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => sync_dir_entry(parent),
+        None => Ok(()),
+    }
+}
+
+/// Commit a temp directory by copying its files into the final location
 ///
-/// This should be called once during startup to clean up after crashes.
+/// This is the fallback when the temp directory and the cache directory are on
+/// different filesystems (`EXDEV`). It is not atomic, but creating the final
+/// directory with `create_dir` still detects a concurrent commit, and
+/// `meta.json` is copied last so `memo_complete` only sees a finished entry.
+/// The output files are synced before `meta.json` is copied, and the entry
+/// and its parent directory afterwards, so the copy is as durable as a
+/// rename. The temp directory is left uncommitted so Drop removes it.
+fn copy_commit_cache_dir(temp_dir: &TempCacheDir, final_path: &Path) -> io::Result<bool> {
+    match create_secure_dir(final_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+        Err(e) => return Err(e),
+    }
+
+    let (json_path, out_path, err_path) = temp_dir.get_paths();
+    let (final_json, final_out, final_err) = get_cache_paths_in_dir(final_path);
+    let copy_sidecar = |name| {
+        let path = temp_dir.path.join(name);
+        if path.exists() {
+            fs::copy(&path, final_path.join(name))
+        } else {
+            Ok(0)
+        }
+    };
+
+    let copied = fs::copy(&out_path, &final_out)
+        .and_then(|_| fs::copy(&err_path, &final_err))
+        .and_then(|_| copy_sidecar(TIMING_FILE))
+        .and_then(|_| copy_sidecar(ORDER_FILE))
+        .and_then(|_| sync_dir(final_path))
+        .and_then(|_| fs::copy(&json_path, &final_json))
+        .and_then(|_| File::open(&final_json)?.sync_all())
+        .and_then(|_| sync_dir_entry(final_path))
+        .and_then(|_| sync_parent(final_path));
+
+    if let Err(e) = copied {
+        let _ = fs::remove_dir_all(final_path);
+        return Err(e);
+    }
+
+    Ok(true)
+}
+
+/// Remove an existing cache entry so a fresh result can be committed
 ///
-/// Strategy: delete any temp directory matching `*.tmp.*` whose modified time
-/// is older than 24 hours. This avoids deleting temp dirs for currently running
-/// processes while preventing unbounded growth from crashes.
-pub fn cleanup_temp_dirs(cache_dir: &Path, verbose: bool) -> io::Result<()> {
-    if !cache_dir.exists() {
-        return Ok(());
+/// The digest directory is first renamed to a temp name (matching the
+/// `*.tmp.*` pattern so crash leftovers are cleaned up on startup) and then
+/// deleted, so concurrent readers never observe a partially deleted entry.
+/// Returns `Ok(false)` if there was no entry to remove.
+pub fn remove_cache_entry(cache_dir: &Path, digest: &str) -> io::Result<bool> {
+    remove_cache_entry_in_dir(&digest_dir(cache_dir, digest))
+}
+
+/// Remove the entry in a digest directory, as [`remove_cache_entry`] does
+pub fn remove_cache_entry_in_dir(digest_dir: &Path) -> io::Result<bool> {
+    let digest = digest_dir.file_name().unwrap_or_default().to_string_lossy();
+    let pid = process::id();
+    let timestamp = Utc::now().timestamp_nanos_opt().unwrap_or(0);
+    let doomed = digest_dir.with_file_name(format!("{}.tmp.{}.{}", digest, pid, timestamp));
+
+    trace!("removing entry {}", digest_dir.display());
+    match fs::rename(digest_dir, &doomed) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
     }
 
-    let cutoff = SystemTime::now().checked_sub(Duration::from_secs(60 * 60 * 24));
+    fs::remove_dir_all(&doomed)?;
+    Ok(true)
+}
 
-    for entry in fs::read_dir(cache_dir)? {
-        let entry = entry?;
-        let path = entry.path();
+/// Remove a digest directory that exists but is missing cache files
+///
+/// A committed directory is normally complete, but one left behind by a crash
+/// would otherwise never be a hit and would block every later commit. A
+/// directory written to within [`INCOMPLETE_ENTRY_GRACE`] is left alone, since
+/// a commit across filesystems creates it before copying the files in.
+/// Returns whether a directory was removed.
+pub fn remove_incomplete_entry(cache_dir: &Path, digest: &str) -> io::Result<bool> {
+    let dir = digest_dir(cache_dir, digest);
+    if !dir.is_dir() || memo_complete(cache_dir, digest) {
+        return Ok(false);
+    }
 
-        if !path.is_dir() {
-            continue;
+    let recently_written = last_written(&dir)
+        .and_then(|written| written.elapsed().ok())
+        .is_none_or(|age| age < INCOMPLETE_ENTRY_GRACE);
+    if recently_written {
+        trace!(
+            "leaving incomplete entry {} (recently written)",
+            dir.display()
+        );
+        return Ok(false);
+    }
+    remove_cache_entry(cache_dir, digest)
+}
+
+/// The latest modified time of a directory and the files directly in it
+fn last_written(dir: &Path) -> Option<SystemTime> {
+    let mut latest = fs::metadata(dir).and_then(|m| m.modified()).ok()?;
+    for entry in fs::read_dir(dir).ok()?.flatten() {
+        if let Ok(modified) = entry.metadata().and_then(|m| m.modified()) {
+            latest = latest.max(modified);
         }
+    }
+    Some(latest)
+}
 
-        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
-            continue;
-        };
+/// Find the one committed entry whose digest starts with `prefix`, returning
+/// its digest and directory
+///
+/// A full digest matches itself, and a BLAKE3 digest also matches without its
+/// tag, as `{short_digest}` shows it. Errors if no entry or more than one entry
+/// matches, or if the prefix is shorter than [`MIN_DIGEST_PREFIX_LEN`], which
+/// in a small cache could match an entry nobody meant.
+pub fn resolve_digest_prefix(cache_dir: &Path, prefix: &str) -> Result<(String, PathBuf)> {
+    if prefix.len() < MIN_DIGEST_PREFIX_LEN {
+        return Err(MemoError::DigestPrefixTooShort(
+            prefix.to_string(),
+            MIN_DIGEST_PREFIX_LEN,
+        ));
+    }
 
-        if !name.contains(".tmp.") {
-            continue;
-        }
+    let mut matches: Vec<(String, PathBuf)> = iter_memos(cache_dir)?
+        .map(|(digest, dir, _)| (digest, dir))
+        .filter(|(digest, _)| digest.starts_with(prefix) || untagged(digest).starts_with(prefix))
+        .collect();
+
+    match matches.len() {
+        1 => Ok(matches.remove(0)),
+        0 => Err(MemoError::DigestNotFound(prefix.to_string())),
+        n => Err(MemoError::AmbiguousDigest(prefix.to_string(), n)),
+    }
+}
+
+/// Count a cache hit for an entry
+pub fn record_hit(cache_dir: &Path, digest: &str) -> io::Result<()> {
+    let path = digest_dir(cache_dir, digest).join(HITS_FILE);
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(b".")
+}
+
+/// Number of times the entry in a digest directory has been replayed (0 if
+/// never)
+pub fn hit_count_in_dir(dir: &Path) -> u64 {
+    fs::metadata(dir.join(HITS_FILE)).map_or(0, |m| m.len())
+}
+
+/// Whether the entry in a digest directory is pinned against pruning
+pub fn is_pinned_in_dir(dir: &Path) -> bool {
+    dir.join(PIN_FILE).exists()
+}
+
+/// Pin or unpin an entry by creating or removing its marker file
+pub fn set_pinned(cache_dir: &Path, digest: &str, pinned: bool) -> io::Result<()> {
+    let marker = digest_dir(cache_dir, digest).join(PIN_FILE);
+    if pinned {
+        File::create(marker)?;
+        return Ok(());
+    }
+    match fs::remove_file(marker) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Total size of the files in a digest directory
+pub fn entry_size_in_dir(dir: &Path) -> io::Result<u64> {
+    dir_size(dir)
+}
+
+/// Clean up orphaned temporary directories in the cache
+///
+/// This should be called once during startup to clean up after crashes.
+///
+/// Strategy: delete any temp directory matching `*.tmp.*` (or stale `*.lock`
+/// directory) whose modified time is older than [`TEMP_DIR_MAX_AGE`]. This
+/// avoids deleting temp dirs for currently running processes while preventing
+/// unbounded growth from crashes.
+///
+/// Stdin spools left in the cache root by a killed memo are reaped the same
+/// way.
+pub fn cleanup_temp_dirs(cache_dir: &Path, verbose: bool) -> io::Result<()> {
+    let cutoff = SystemTime::now().checked_sub(TEMP_DIR_MAX_AGE);
 
+    let temp_dirs = entry_dirs(cache_dir)?.into_iter().filter(|path| {
+        path.file_name()
+            .is_some_and(|name| is_scratch_name(&name.to_string_lossy()))
+    });
+    for path in temp_dirs.chain(stdin_spools(cache_dir)?) {
         let Some(cutoff) = cutoff else {
             if verbose {
-                eprintln!(
-                    ":: memo :: skipping temp dir {} (no cutoff)",
-                    path.display()
-                );
+                diag!("skipping temp dir {} (no cutoff)", path.display());
             }
             continue;
         };
@@ -245,10 +848,7 @@ pub fn cleanup_temp_dirs(cache_dir: &Path, verbose: bool) -> io::Result<()> {
             Ok(m) => m,
             Err(_) => {
                 if verbose {
-                    eprintln!(
-                        ":: memo :: skipping temp dir {} (metadata error)",
-                        path.display()
-                    );
+                    diag!("skipping temp dir {} (metadata error)", path.display());
                 }
                 continue;
             }
@@ -258,10 +858,7 @@ pub fn cleanup_temp_dirs(cache_dir: &Path, verbose: bool) -> io::Result<()> {
             Ok(m) => m,
             Err(_) => {
                 if verbose {
-                    eprintln!(
-                        ":: memo :: skipping temp dir {} (modified time error)",
-                        path.display()
-                    );
+                    diag!("skipping temp dir {} (modified time error)", path.display());
                 }
                 continue;
             }
@@ -269,47 +866,504 @@ pub fn cleanup_temp_dirs(cache_dir: &Path, verbose: bool) -> io::Result<()> {
 
         if modified < cutoff {
             if verbose {
-                eprintln!(":: memo :: cleaning up temp dir {}", path.display());
+                diag!("cleaning up temp dir {}", path.display());
             }
-            let _ = fs::remove_dir_all(&path);
+            let _ = remove_scratch(&path);
         } else if verbose {
-            eprintln!(":: memo :: keeping temp dir {} (recent)", path.display());
+            diag!("keeping temp dir {} (recent)", path.display());
         }
     }
 
     Ok(())
 }
 
-#[cfg(test)]
-pub fn write_memo(
-    cache_dir: &Path,
-    digest: &str,
-    memo: &Memo,
-    stdout: &[u8],
-    stderr: &[u8],
-) -> io::Result<()> {
-    let digest_dir = cache_dir.join(digest);
-    fs::create_dir_all(&digest_dir)?;
+/// A problem found by [`validate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheIssue {
+    /// A temp directory or lock older than the cleanup cutoff, left behind by
+    /// a crashed run
+    OrphanTempDir(PathBuf),
+    /// An entry directory missing some of its files
+    IncompleteEntry {
+        path: PathBuf,
+        missing: Vec<&'static str>,
+    },
+    /// An entry whose `meta.json` cannot be parsed
+    UnparsableMetadata { path: PathBuf, error: String },
+    /// An entry whose recorded digest differs from its directory name
+    DigestMismatch { path: PathBuf, recorded: String },
+}
 
-    let (json_path, out_path, err_path) = get_cache_paths_in_dir(&digest_dir);
+impl std::fmt::Display for CacheIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheIssue::OrphanTempDir(path) => {
+                write!(f, "orphaned temp dir: {}", path.display())
+            }
+            CacheIssue::IncompleteEntry { path, missing } => {
+                write!(
+                    f,
+                    "incomplete entry: {} (missing {})",
+                    path.display(),
+                    missing.join(", ")
+                )
+            }
+            CacheIssue::UnparsableMetadata { path, error } => {
+                write!(f, "unparsable metadata: {} ({})", path.display(), error)
+            }
+            CacheIssue::DigestMismatch { path, recorded } => {
+                write!(
+                    f,
+                    "digest mismatch: {} records {}",
+                    path.display(),
+                    recorded
+                )
+            }
+        }
+    }
+}
 
-    let json = serde_json::to_string_pretty(memo)?;
-    fs::write(json_path, json)?;
-    fs::write(out_path, stdout)?;
-    fs::write(err_path, stderr)?;
+/// Check a cache directory for problems without changing anything
+///
+/// Issues are returned in path order. Temp directories younger than the
+/// cleanup cutoff are assumed to belong to running processes and are not
+/// reported.
+pub fn validate(cache_dir: &Path) -> io::Result<Vec<CacheIssue>> {
+    let cutoff = SystemTime::now().checked_sub(TEMP_DIR_MAX_AGE);
+    let mut dirs = entry_dirs(cache_dir)?;
+    dirs.sort();
+
+    let mut issues = Vec::new();
+    for path in dirs {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        if is_scratch_name(&name) {
+            let modified = fs::metadata(&path)?.modified().ok();
+            if matches!((modified, cutoff), (Some(m), Some(c)) if m < c) {
+                issues.push(CacheIssue::OrphanTempDir(path));
+            }
+            continue;
+        }
 
-    Ok(())
-}
+        let missing: Vec<&'static str> = ["meta.json", "stdout", "stderr"]
+            .into_iter()
+            .filter(|file| !path.join(file).exists())
+            .collect();
+        if !missing.is_empty() {
+            issues.push(CacheIssue::IncompleteEntry { path, missing });
+            continue;
+        }
 
-#[cfg(test)]
-pub fn read_memo(cache_dir: &Path, digest: &str) -> io::Result<(Memo, Vec<u8>, Vec<u8>)> {
-    let digest_dir = cache_dir.join(digest);
-    let (json_path, out_path, err_path) = get_cache_paths_in_dir(&digest_dir);
+        match read_memo_metadata_in_dir(&path) {
+            Ok(memo) if memo.digest != name => issues.push(CacheIssue::DigestMismatch {
+                path,
+                recorded: memo.digest,
+            }),
+            Ok(_) => {}
+            Err(e) => issues.push(CacheIssue::UnparsableMetadata {
+                path,
+                error: e.to_string(),
+            }),
+        }
+    }
 
-    let json = fs::read_to_string(json_path)?;
-    let memo: Memo = serde_json::from_str(&json)?;
-    let stdout = fs::read(out_path)?;
-    let stderr = fs::read(err_path)?;
+    Ok(issues)
+}
+
+/// File in a lock or slot directory holding its owner's pid
+const OWNER_FILE: &str = "pid";
+
+/// A held single-flight lock on a cache entry, released on drop
+pub struct EntryLock {
+    path: PathBuf,
+}
+
+impl Drop for EntryLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Try to take the single-flight lock for a digest
+///
+/// The lock is a `<digest>.lock` directory next to the entry, created with an
+/// atomic `mkdir` and holding the owner's pid. A lock whose owner has died
+/// without releasing it is taken over. Returns `Ok(None)` if another process
+/// holds it.
+pub fn try_lock_entry(cache_dir: &Path, digest: &str) -> io::Result<Option<EntryLock>> {
+    let final_path = digest_dir(cache_dir, digest);
+    create_shard_dirs(&final_path, cache_dir)?;
+
+    let path = final_path.with_file_name(format!("{digest}.lock"));
+    for _ in 0..2 {
+        match create_secure_dir(&path) {
+            Ok(()) => {
+                fs::write(path.join(OWNER_FILE), process::id().to_string())?;
+                return Ok(Some(EntryLock { path }));
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if !owner_is_dead(&path) {
+                    return Ok(None);
+                }
+                trace!("taking over abandoned lock {}", path.display());
+                reclaim_abandoned(&path);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+/// Directory in the cache holding `--max-concurrency` slots
+const SLOTS_DIR: &str = ".slots";
+
+/// A held `--max-concurrency` slot, released on drop
+pub struct ConcurrencySlot {
+    path: PathBuf,
+}
+
+impl Drop for ConcurrencySlot {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Try to take one of `limit` slots for running a command
+///
+/// Each slot is a numbered directory under `<cache>/.slots`, taken with an
+/// atomic `mkdir` and holding the owner's pid. A slot whose owner has died
+/// without releasing it is reclaimed. Returns `Ok(None)` if every slot is
+/// held.
+pub fn try_acquire_slot(cache_dir: &Path, limit: usize) -> io::Result<Option<ConcurrencySlot>> {
+    let slots_dir = cache_dir.join(SLOTS_DIR);
+    fs::create_dir_all(&slots_dir)?;
+
+    for i in 0..limit {
+        let path = slots_dir.join(i.to_string());
+        match create_secure_dir(&path) {
+            Ok(()) => {
+                fs::write(path.join(OWNER_FILE), process::id().to_string())?;
+                trace!("took concurrency slot {}", path.display());
+                return Ok(Some(ConcurrencySlot { path }));
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if owner_is_dead(&path) {
+                    trace!("reclaiming abandoned slot {}", path.display());
+                    reclaim_abandoned(&path);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+/// Whether a lock or slot's recorded owner is no longer running
+///
+/// One without a readable pid may still be being set up, so it counts as
+/// alive.
+fn owner_is_dead(dir: &Path) -> bool {
+    let Some(pid) = fs::read_to_string(dir.join(OWNER_FILE))
+        .ok()
+        .and_then(|pid| pid.trim().parse::<i32>().ok())
+    else {
+        return false;
+    };
+
+    #[cfg(unix)]
+    {
+        // SAFETY: signal 0 only checks that the process exists
+        let alive = unsafe { libc::kill(pid, 0) } == 0
+            || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH);
+        !alive
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        false
+    }
+}
+
+/// Remove an abandoned lock or slot, renaming it first so only one process
+/// removes it
+///
+/// Two processes can both see the same dead owner. If the other one reclaims
+/// first and a live process takes the directory again before our rename, the
+/// directory we renamed away is live, so it is put back.
+fn reclaim_abandoned(dir: &Path) {
+    let Some(name) = dir.file_name() else {
+        return;
+    };
+    // A `.tmp.` name is reaped by cleanup if the removal fails
+    let doomed = dir.with_file_name(format!(
+        "{}.tmp.reclaim.{}",
+        name.to_string_lossy(),
+        process::id()
+    ));
+    if fs::rename(dir, &doomed).is_err() {
+        return;
+    }
+    if owner_is_dead(&doomed) {
+        let _ = fs::remove_dir_all(&doomed);
+    } else {
+        trace!("{} was taken again, putting it back", dir.display());
+        let _ = fs::rename(&doomed, dir);
+    }
+}
+
+/// Summary of a [`prune_entries`] run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    /// Number of entries removed
+    pub removed: usize,
+    /// Total size of the removed entries
+    pub bytes: u64,
+    /// Number of old enough entries kept because they are pinned
+    pub pinned: usize,
+}
+
+/// Remove entries cached more than `older_than` ago, except pinned ones
+///
+/// Entries with an unparsable timestamp count as old; entries with unreadable
+/// metadata are left for `--doctor` to report.
+pub fn prune_entries(cache_dir: &Path, older_than: Duration) -> io::Result<PruneReport> {
+    let now = Utc::now();
+    let mut report = PruneReport::default();
+
+    for (_, dir, memo) in iter_memos(cache_dir)? {
+        let Ok(memo) = memo else { continue };
+        if !memo.is_expired(older_than, now) {
+            continue;
+        }
+        if is_pinned_in_dir(&dir) {
+            report.pinned += 1;
+            continue;
+        }
+
+        let bytes = dir_size(&dir)?;
+        if remove_cache_entry_in_dir(&dir)? {
+            report.removed += 1;
+            report.bytes += bytes;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Summary of a [`clear_entries`] run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClearReport {
+    /// Digests of the removed entries
+    pub removed: Vec<String>,
+    /// Total size of the removed entries
+    pub bytes: u64,
+}
+
+/// Remove every entry, pinned or not, leaving the cache directory itself
+///
+/// Temp directories are skipped, since they may belong to a run still in
+/// progress. With `dry_run`, nothing is removed but the report is the same.
+pub fn clear_entries(cache_dir: &Path, dry_run: bool) -> io::Result<ClearReport> {
+    let mut report = ClearReport::default();
+
+    for (digest, dir, _) in iter_memos(cache_dir)? {
+        let bytes = dir_size(&dir)?;
+        if dry_run || remove_cache_entry_in_dir(&dir)? {
+            report.bytes += bytes;
+            report.removed.push(digest);
+        }
+    }
+
+    Ok(report)
+}
+
+/// Summary of an [`evict_to_size_limit`] run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EvictReport {
+    /// Number of entries removed
+    pub removed: usize,
+    /// Total size of the removed entries
+    pub bytes: u64,
+}
+
+/// When an entry was last used: its latest hit, or else when it was cached
+///
+/// The hits file is appended to on every replay, so its modified time is the
+/// last hit.
+fn last_used(dir: &Path) -> SystemTime {
+    [HITS_FILE, "meta.json"]
+        .iter()
+        .find_map(|name| fs::metadata(dir.join(name)).and_then(|m| m.modified()).ok())
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+/// Remove least recently used entries until the cache fits in `max_bytes`
+///
+/// Pinned entries and the entry for `keep` (the one just committed) are
+/// never removed, so the cache can stay over the limit if they alone exceed
+/// it.
+pub fn evict_to_size_limit(
+    cache_dir: &Path,
+    max_bytes: u64,
+    keep: &str,
+) -> io::Result<EvictReport> {
+    let mut entries = Vec::new();
+    let mut total = 0;
+    for (digest, dir, _) in iter_memos(cache_dir)? {
+        // Another process may evict or replace the entry while we scan
+        let bytes = match dir_size(&dir) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        total += bytes;
+        entries.push((last_used(&dir), digest, dir, bytes));
+    }
+    entries.sort();
+
+    let mut report = EvictReport::default();
+    for (_, digest, dir, bytes) in entries {
+        if total <= max_bytes {
+            break;
+        }
+        if digest == keep || is_pinned_in_dir(&dir) {
+            continue;
+        }
+        if remove_cache_entry_in_dir(&dir)? {
+            trace!("evicted {digest} ({bytes} bytes)");
+            total -= bytes;
+            report.removed += 1;
+            report.bytes += bytes;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Summary of a [`purge_temp_dirs`] run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PurgeReport {
+    /// Number of temp directories removed
+    pub removed: usize,
+    /// Total size of the files in the removed directories
+    pub bytes: u64,
+}
+
+/// Remove temp directories older than `max_age` right away
+///
+/// Unlike [`cleanup_temp_dirs`], the age cutoff is chosen by the caller, so a
+/// `max_age` of zero removes every temp directory, including ones a running
+/// memo may still be writing to. Stdin spools are removed too.
+pub fn purge_temp_dirs(cache_dir: &Path, max_age: Duration) -> io::Result<PurgeReport> {
+    let now = SystemTime::now();
+    let mut report = PurgeReport::default();
+
+    let temp_dirs = entry_dirs(cache_dir)?.into_iter().filter(|path| {
+        path.file_name()
+            .is_some_and(|name| is_scratch_name(&name.to_string_lossy()))
+    });
+    for path in temp_dirs.chain(stdin_spools(cache_dir)?) {
+        let age = fs::metadata(&path)?
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .unwrap_or(Duration::ZERO);
+        if age < max_age {
+            continue;
+        }
+
+        let bytes = if path.is_dir() {
+            dir_size(&path)?
+        } else {
+            fs::metadata(&path)?.len()
+        };
+        remove_scratch(&path)?;
+        report.removed += 1;
+        report.bytes += bytes;
+    }
+
+    Ok(report)
+}
+
+/// Remove a temp directory or a stdin spool file
+fn remove_scratch(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Total size of the files under a directory
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        total += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(total)
+}
+
+/// Write a complete entry for a `test` command with the given output
+#[cfg(test)]
+pub fn write_test_entry(cache_dir: &Path, digest: &str, stdout: &[u8], stderr: &[u8]) {
+    let memo = Memo {
+        cmd: vec!["test".to_string()],
+        cwd: "/test/dir".to_string(),
+        exit_code: 0,
+        signal: None,
+        timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+        digest: digest.to_string(),
+        rusage: None,
+        stdout_was_piped: true,
+        stderr_was_piped: true,
+        encryption: None,
+        duration_ms: 0,
+        started_at: None,
+        finished_at: None,
+        memo_version: "unknown".to_string(),
+    };
+    write_memo(cache_dir, digest, &memo, stdout, stderr).unwrap();
+}
+
+#[cfg(test)]
+pub fn write_memo(
+    cache_dir: &Path,
+    digest: &str,
+    memo: &Memo,
+    stdout: &[u8],
+    stderr: &[u8],
+) -> io::Result<()> {
+    let digest_dir = digest_dir(cache_dir, digest);
+    fs::create_dir_all(&digest_dir)?;
+
+    let (json_path, out_path, err_path) = get_cache_paths_in_dir(&digest_dir);
+
+    let json = serde_json::to_string_pretty(memo)?;
+    fs::write(json_path, json)?;
+    fs::write(out_path, stdout)?;
+    fs::write(err_path, stderr)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub fn read_memo(cache_dir: &Path, digest: &str) -> io::Result<(Memo, Vec<u8>, Vec<u8>)> {
+    let digest_dir = digest_dir(cache_dir, digest);
+    let (json_path, out_path, err_path) = get_cache_paths_in_dir(&digest_dir);
+
+    let json = fs::read_to_string(json_path)?;
+    let memo: Memo = serde_json::from_str(&json)?;
+    let stdout = fs::read(out_path)?;
+    let stderr = fs::read(err_path)?;
 
     Ok((memo, stdout, stderr))
 }
@@ -320,7 +1374,7 @@ pub fn stream_stdout<W: io::Write>(
     digest: &str,
     mut writer: W,
 ) -> io::Result<()> {
-    let digest_dir = cache_dir.join(digest);
+    let digest_dir = digest_dir(cache_dir, digest);
     let out_path = digest_dir.join("stdout");
     let mut file = File::open(out_path)?;
     copy(&mut file, &mut writer)?;
@@ -333,289 +1387,1557 @@ pub fn stream_stderr<W: io::Write>(
     digest: &str,
     mut writer: W,
 ) -> io::Result<()> {
-    let digest_dir = cache_dir.join(digest);
+    let digest_dir = digest_dir(cache_dir, digest);
     let err_path = digest_dir.join("stderr");
     let mut file = File::open(err_path)?;
     copy(&mut file, &mut writer)?;
     Ok(())
 }
 
+/// Decrypt cached stdout written with `--encrypt` to the given writer
+pub fn stream_stdout_decrypted<W: io::Write>(
+    cache_dir: &Path,
+    digest: &str,
+    keys: &EntryKeys,
+    writer: W,
+) -> Result<()> {
+    let file = File::open(digest_dir(cache_dir, digest).join("stdout"))?;
+    keys.decrypt_stdout(file, writer)
+}
+
+/// Decrypt cached stderr written with `--encrypt` to the given writer
+pub fn stream_stderr_decrypted<W: io::Write>(
+    cache_dir: &Path,
+    digest: &str,
+    keys: &EntryKeys,
+    writer: W,
+) -> Result<()> {
+    let file = File::open(digest_dir(cache_dir, digest).join("stderr"))?;
+    keys.decrypt_stderr(file, writer)
+}
+
+/// Stream cached stdout, reproducing the timing recorded when it was captured
+///
+/// Each recorded chunk is written once its original offset has elapsed. If the
+/// entry has no timing file, stdout is streamed all at once.
+pub fn stream_stdout_timed<W: io::Write>(
+    cache_dir: &Path,
+    digest: &str,
+    mut writer: W,
+) -> io::Result<()> {
+    let digest_dir = digest_dir(cache_dir, digest);
+    let Ok(timing) = File::open(digest_dir.join(TIMING_FILE)) else {
+        return stream_stdout(cache_dir, digest, writer);
+    };
+    let mut file = File::open(digest_dir.join("stdout"))?;
+    let start = Instant::now();
+
+    for line in BufReader::new(timing).lines() {
+        let line = line?;
+        let Some((offset, len)) = parse_timing_record(&line) else {
+            continue;
+        };
+
+        if let Some(wait) = offset.checked_sub(start.elapsed()) {
+            thread::sleep(wait);
+        }
+        copy(&mut (&mut file).take(len), &mut writer)?;
+        writer.flush()?;
+    }
+
+    // Anything not covered by a record (e.g. a truncated timing file)
+    copy(&mut file, &mut writer)?;
+    Ok(())
+}
+
+/// Stream cached stdout and stderr to their writers in the order they were
+/// captured
+///
+/// Each writer is flushed after every chunk so the interleaving survives
+/// buffering. If the entry has no order file, stdout is streamed and then
+/// stderr, as usual.
+pub fn stream_interleaved<O: io::Write, E: io::Write>(
+    cache_dir: &Path,
+    digest: &str,
+    mut stdout: O,
+    mut stderr: E,
+) -> io::Result<()> {
+    let digest_dir = digest_dir(cache_dir, digest);
+    let mut out_file = File::open(digest_dir.join("stdout"))?;
+    let mut err_file = File::open(digest_dir.join("stderr"))?;
+
+    if let Ok(order) = File::open(digest_dir.join(ORDER_FILE)) {
+        for line in BufReader::new(order).lines() {
+            let line = line?;
+            let Some((stream, len)) = line.split_once(' ') else {
+                continue;
+            };
+            let Ok(len) = len.parse::<u64>() else {
+                continue;
+            };
+
+            match stream {
+                STDOUT_STREAM => {
+                    copy(&mut (&mut out_file).take(len), &mut stdout)?;
+                    stdout.flush()?;
+                }
+                STDERR_STREAM => {
+                    copy(&mut (&mut err_file).take(len), &mut stderr)?;
+                    stderr.flush()?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Anything not covered by a record (e.g. a truncated order file)
+    copy(&mut out_file, &mut stdout)?;
+    stdout.flush()?;
+    copy(&mut err_file, &mut stderr)?;
+    Ok(())
+}
+
+/// Parse an `<offset_ms> <len>` timing record
+fn parse_timing_record(line: &str) -> Option<(Duration, u64)> {
+    let (offset, len) = line.split_once(' ')?;
+    Some((
+        Duration::from_millis(offset.parse().ok()?),
+        len.parse().ok()?,
+    ))
+}
+
+/// A portion of a cached output stream to replay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamRange {
+    /// The first `n` bytes
+    HeadBytes(u64),
+    /// The first `n` lines
+    HeadLines(u64),
+    /// The last `n` bytes
+    TailBytes(u64),
+    /// The last `n` lines
+    TailLines(u64),
+}
+
+/// Stream a portion of cached stdout to the given writer
+///
+/// Head ranges read forward from the start of the file and stop early. Tail
+/// ranges seek from the end of the file so large outputs are not read in full.
+pub fn stream_range<W: io::Write>(
+    cache_dir: &Path,
+    digest: &str,
+    range: StreamRange,
+    mut writer: W,
+) -> io::Result<()> {
+    let out_path = digest_dir(cache_dir, digest).join("stdout");
+    let mut file = File::open(out_path)?;
+
+    match range {
+        StreamRange::HeadBytes(n) => {
+            copy(&mut file.take(n), &mut writer)?;
+        }
+        StreamRange::HeadLines(n) => {
+            let mut reader = BufReader::new(file);
+            let mut line = Vec::new();
+            for _ in 0..n {
+                line.clear();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    break;
+                }
+                writer.write_all(&line)?;
+            }
+        }
+        StreamRange::TailBytes(n) => {
+            let len = file.metadata()?.len();
+            file.seek(SeekFrom::Start(len.saturating_sub(n)))?;
+            copy(&mut file, &mut writer)?;
+        }
+        StreamRange::TailLines(n) => {
+            let start = find_tail_lines_start(&mut file, n)?;
+            file.seek(SeekFrom::Start(start))?;
+            copy(&mut file, &mut writer)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the byte offset at which the last `n` lines of a file begin
+///
+/// Scans backwards from the end of the file in fixed-size chunks. A trailing
+/// newline terminates the final line rather than starting a new empty one.
+fn find_tail_lines_start(file: &mut File, n: u64) -> io::Result<u64> {
+    let len = file.metadata()?.len();
+    if n == 0 {
+        return Ok(len);
+    }
+
+    let mut end = len;
+    if len > 0 {
+        let mut last = [0u8; 1];
+        file.seek(SeekFrom::Start(len - 1))?;
+        file.read_exact(&mut last)?;
+        if last[0] == b'\n' {
+            end = len - 1;
+        }
+    }
+
+    let mut buf = [0u8; 8192];
+    let mut seen = 0;
+    while end > 0 {
+        let chunk_len = end.min(buf.len() as u64);
+        let chunk_start = end - chunk_len;
+        let chunk = &mut buf[..chunk_len as usize];
+        file.seek(SeekFrom::Start(chunk_start))?;
+        file.read_exact(chunk)?;
+
+        for (i, byte) in chunk.iter().enumerate().rev() {
+            if *byte == b'\n' {
+                seen += 1;
+                if seen == n {
+                    return Ok(chunk_start + i as u64 + 1);
+                }
+            }
+        }
+
+        end = chunk_start;
+    }
+
+    Ok(0)
+}
+
 /// Read just the memo metadata without loading output files
 pub fn read_memo_metadata(cache_dir: &Path, digest: &str) -> io::Result<Memo> {
-    let digest_dir = cache_dir.join(digest);
-    let json_path = digest_dir.join("meta.json");
+    read_memo_metadata_in_dir(&digest_dir(cache_dir, digest))
+}
+
+/// Read the memo metadata from an entry directory
+fn read_memo_metadata_in_dir(dir: &Path) -> io::Result<Memo> {
+    let json_path = dir.join("meta.json");
     let json = fs::read_to_string(json_path)?;
     let memo: Memo = serde_json::from_str(&json)?;
     Ok(memo)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+/// An entry whose recorded digest was rewritten by [`repair_digests`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DigestRepair {
+    /// The entry's directory
+    pub path: PathBuf,
+    /// The digest `meta.json` recorded before the repair
+    pub recorded: String,
+}
+
+/// Rewrite the `digest` field of every entry whose `meta.json` disagrees with
+/// its directory name
+///
+/// Entries whose metadata can't be parsed are left for `--doctor` to report.
+/// Each `meta.json` is replaced atomically, and fields memo doesn't know about
+/// are kept.
+pub fn repair_digests(cache_dir: &Path) -> io::Result<Vec<DigestRepair>> {
+    let mut dirs = entry_dirs(cache_dir)?;
+    dirs.sort();
+
+    let mut repairs = Vec::new();
+    for path in dirs {
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        if is_scratch_name(&name) {
+            continue;
+        }
+        let Ok(memo) = read_memo_metadata_in_dir(&path) else {
+            continue;
+        };
+        if memo.digest == name {
+            continue;
+        }
+
+        let meta_path = path.join("meta.json");
+        let mut meta: serde_json::Value = serde_json::from_slice(&fs::read(&meta_path)?)?;
+        meta["digest"] = serde_json::Value::String(name);
+        let staged = path.join(format!("meta.json.tmp.{}", process::id()));
+        fs::write(&staged, serde_json::to_vec_pretty(&meta)?)?;
+        fs::rename(&staged, &meta_path)?;
+
+        trace!("repaired digest of {}", path.display());
+        repairs.push(DigestRepair {
+            path,
+            recorded: memo.digest,
+        });
+    }
+    Ok(repairs)
+}
+
+/// Iterate over every committed cache entry, in digest order, as its digest,
+/// directory, and metadata
+///
+/// Entries are found at any shard depth, and temp directories are skipped.
+/// Callers use the yielded directory rather than [`digest_dir`], since an
+/// entry cached under a different `--shard` depth lives somewhere else.
+/// Entries whose `meta.json` is missing or unparsable are still yielded, with
+/// the read error in place of the memo, so callers can report them instead of
+/// aborting.
+pub fn iter_memos(
+    cache_dir: &Path,
+) -> io::Result<impl Iterator<Item = (String, PathBuf, io::Result<Memo>)> + '_> {
+    let mut entries: Vec<(String, PathBuf)> = entry_dirs(cache_dir)?
+        .into_iter()
+        .filter_map(|path| {
+            let name = path.file_name()?.to_string_lossy().to_string();
+            (!is_scratch_name(&name)).then_some((name, path))
+        })
+        .collect();
+
+    entries.sort();
+
+    Ok(entries.into_iter().map(|(digest, path)| {
+        let memo = read_memo_metadata_in_dir(&path);
+        (digest, path, memo)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn setup_test_cache() -> (TempDir, PathBuf) {
+        let temp_dir = TempDir::new().unwrap();
+        let cache_dir = temp_dir.path().join("memo");
+        (temp_dir, cache_dir)
+    }
+
+    #[test]
+    fn test_ensure_cache_dir_creates_directory() {
+        let (_temp, cache_dir) = setup_test_cache();
+
+        assert!(!cache_dir.exists());
+        ensure_cache_dir(&cache_dir).unwrap();
+        assert!(cache_dir.exists());
+        assert!(cache_dir.is_dir());
+    }
+
+    #[test]
+    fn test_resolve_digest_prefix() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        for digest in ["abcd123", "abcd456", "ff0000"] {
+            let memo = Memo {
+                cmd: vec!["test".to_string()],
+                cwd: "/test/dir".to_string(),
+                exit_code: 0,
+                signal: None,
+                timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+                digest: digest.to_string(),
+                rusage: None,
+                stdout_was_piped: true,
+                stderr_was_piped: true,
+                encryption: None,
+                duration_ms: 0,
+                started_at: None,
+                finished_at: None,
+                memo_version: "unknown".to_string(),
+            };
+            write_memo(&cache_dir, digest, &memo, b"", b"").unwrap();
+        }
+
+        assert_eq!(
+            resolve_digest_prefix(&cache_dir, "abcd1").unwrap(),
+            ("abcd123".to_string(), cache_dir.join("abcd123"))
+        );
+        assert_eq!(
+            resolve_digest_prefix(&cache_dir, "ff0000").unwrap().0,
+            "ff0000"
+        );
+        assert!(matches!(
+            resolve_digest_prefix(&cache_dir, "abcd"),
+            Err(MemoError::AmbiguousDigest(_, 2))
+        ));
+        assert!(matches!(
+            resolve_digest_prefix(&cache_dir, "0000"),
+            Err(MemoError::DigestNotFound(_))
+        ));
+
+        // Even a prefix that happens to match just one entry
+        for prefix in ["", "ff"] {
+            assert!(matches!(
+                resolve_digest_prefix(&cache_dir, prefix),
+                Err(MemoError::DigestPrefixTooShort(_, MIN_DIGEST_PREFIX_LEN))
+            ));
+        }
+    }
+
+    /// A writer that records the size of the largest write
+    #[derive(Default)]
+    struct ChunkRecorder {
+        max_chunk: usize,
+        total: usize,
+    }
+
+    impl Write for ChunkRecorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.max_chunk = self.max_chunk.max(buf.len());
+            self.total += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_copy_with_buffer_honors_size() {
+        let data = vec![7u8; 100_000];
+        for size in [1000, 4096, 65536] {
+            let mut recorder = ChunkRecorder::default();
+            let copied = copy_with_buffer(&mut data.as_slice(), &mut recorder, size).unwrap();
+            assert_eq!(copied, 100_000);
+            assert_eq!(recorder.total, 100_000);
+            assert_eq!(recorder.max_chunk, size);
+        }
+    }
+
+    #[test]
+    fn test_stream_stdout_uses_buffer_size() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        let memo = Memo {
+            cmd: vec!["test".to_string()],
+            cwd: "/test/dir".to_string(),
+            exit_code: 0,
+            signal: None,
+            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+            digest: "buffered".to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+        write_memo(&cache_dir, "buffered", &memo, &[1u8; 10_000], b"").unwrap();
+
+        set_buffer_size(1024);
+        let mut recorder = ChunkRecorder::default();
+        let result = stream_stdout(&cache_dir, "buffered", &mut recorder);
+        set_buffer_size(DEFAULT_BUFFER_SIZE);
+
+        result.unwrap();
+        assert_eq!(recorder.total, 10_000);
+        assert_eq!(recorder.max_chunk, 1024);
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        assert_eq!(parse_prefix("ci"), Ok("ci".to_string()));
+        for bad in ["", ".", "..", ".hidden", "a/b", "../up", "a\\b"] {
+            assert!(parse_prefix(bad).is_err(), "{bad:?} accepted");
+        }
+    }
+
+    #[test]
+    fn test_ensure_cache_dir_idempotent() {
+        let (_temp, cache_dir) = setup_test_cache();
+
+        ensure_cache_dir(&cache_dir).unwrap();
+        ensure_cache_dir(&cache_dir).unwrap(); // Should not error
+        assert!(cache_dir.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ensure_cache_dir_only_chmods_new_directories() {
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let (_temp, cache_dir) = setup_test_cache();
+
+        ensure_cache_dir(&cache_dir).unwrap();
+        assert_eq!(mode(&cache_dir), CACHE_DIR_PERMISSIONS);
+
+        fs::set_permissions(&cache_dir, fs::Permissions::from_mode(0o755)).unwrap();
+        ensure_cache_dir(&cache_dir).unwrap();
+        assert_eq!(mode(&cache_dir), 0o755);
+    }
+
+    #[test]
+    fn test_write_and_read_memo() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let digest = "abc123";
+        let memo = Memo {
+            cmd: vec!["echo".to_string(), "test".to_string()],
+            cwd: "/test/dir".to_string(),
+            exit_code: 0,
+            signal: None,
+            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+            digest: digest.to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+        let stdout = b"test output\n";
+        let stderr = b"test error\n";
+
+        write_memo(&cache_dir, digest, &memo, stdout, stderr).unwrap();
+
+        let (read_memo, read_stdout, read_stderr) = read_memo(&cache_dir, digest).unwrap();
+
+        assert_eq!(read_memo.cmd, memo.cmd);
+        assert_eq!(read_memo.exit_code, memo.exit_code);
+        assert_eq!(read_memo.digest, memo.digest);
+        assert_eq!(read_stdout, stdout);
+        assert_eq!(read_stderr, stderr);
+    }
+
+    #[test]
+    fn test_write_memo_empty_output() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let digest = "empty123";
+        let memo = Memo {
+            cmd: vec!["true".to_string()],
+            cwd: "/test/dir".to_string(),
+            exit_code: 0,
+            signal: None,
+            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+            digest: digest.to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+
+        write_memo(&cache_dir, digest, &memo, b"", b"").unwrap();
+
+        let (_, stdout, stderr) = read_memo(&cache_dir, digest).unwrap();
+        assert_eq!(stdout, b"");
+        assert_eq!(stderr, b"");
+    }
+
+    #[test]
+    fn test_write_memo_binary_data() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let digest = "binary123";
+        let memo = Memo {
+            cmd: vec!["binary".to_string()],
+            cwd: "/test/dir".to_string(),
+            exit_code: 0,
+            signal: None,
+            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+            digest: digest.to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+        let binary_data = vec![0x00, 0x01, 0xFF, 0xFE, 0x7F];
+
+        write_memo(&cache_dir, digest, &memo, &binary_data, &binary_data).unwrap();
+
+        let (_, stdout, stderr) = read_memo(&cache_dir, digest).unwrap();
+        assert_eq!(stdout, binary_data);
+        assert_eq!(stderr, binary_data);
+    }
+
+    #[test]
+    fn test_read_nonexistent_memo() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let result = read_memo(&cache_dir, "nonexistent");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_multiple_memos() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let digest1 = "multi1";
+        let digest2 = "multi2";
+
+        let memo1 = Memo {
+            cmd: vec!["echo".to_string(), "one".to_string()],
+            cwd: "/test/dir".to_string(),
+            exit_code: 0,
+            signal: None,
+            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+            digest: digest1.to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+
+        let memo2 = Memo {
+            cmd: vec!["echo".to_string(), "two".to_string()],
+            cwd: "/test/dir".to_string(),
+            exit_code: 1,
+            signal: None,
+            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+            digest: digest2.to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+
+        write_memo(&cache_dir, digest1, &memo1, b"one\n", b"").unwrap();
+        write_memo(&cache_dir, digest2, &memo2, b"two\n", b"err\n").unwrap();
+
+        let (read1, out1, err1) = read_memo(&cache_dir, digest1).unwrap();
+        let (read2, out2, err2) = read_memo(&cache_dir, digest2).unwrap();
+
+        assert_eq!(read1.cmd, vec!["echo", "one"]);
+        assert_eq!(read2.cmd, vec!["echo", "two"]);
+        assert_eq!(out1, b"one\n");
+        assert_eq!(out2, b"two\n");
+        assert_eq!(err1, b"");
+        assert_eq!(err2, b"err\n");
+    }
+
+    #[test]
+    fn test_cache_files_have_correct_names() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let digest = "names123";
+        let memo = Memo {
+            cmd: vec!["test".to_string()],
+            cwd: "/test/dir".to_string(),
+            exit_code: 0,
+            signal: None,
+            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+            digest: digest.to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+
+        write_memo(&cache_dir, digest, &memo, b"out", b"err").unwrap();
+
+        let digest_dir = cache_dir.join(digest);
+        assert!(digest_dir.join("meta.json").exists());
+        assert!(digest_dir.join("stdout").exists());
+        assert!(digest_dir.join("stderr").exists());
+    }
+
+    #[test]
+    fn test_get_cache_dir_respects_xdg() {
+        let temp = TempDir::new().unwrap();
+        let xdg_path = temp.path().to_path_buf();
+
+        std::env::set_var("XDG_CACHE_HOME", &xdg_path);
+        let cache_dir = get_cache_dir().unwrap();
+        std::env::remove_var("XDG_CACHE_HOME");
+
+        assert_eq!(cache_dir, xdg_path.join("memo"));
+    }
+
+    #[test]
+    fn test_cache_base_falls_back_to_temp_without_home() {
+        let home = PathBuf::from("/home/user");
+        let xdg = Some("/xdg".to_string());
+
+        assert_eq!(
+            cache_base(xdg.clone(), Some(home.clone()), true).unwrap(),
+            PathBuf::from("/xdg")
+        );
+        assert_eq!(
+            cache_base(None, Some(home), true).unwrap(),
+            PathBuf::from("/home/user/.cache")
+        );
+        assert_eq!(cache_base(None, None, true).unwrap(), std::env::temp_dir());
+        assert!(matches!(
+            cache_base(None, None, false),
+            Err(MemoError::HomeNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_large_output() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let digest = "large123";
+        let memo = Memo {
+            cmd: vec!["large".to_string()],
+            cwd: "/test/dir".to_string(),
+            exit_code: 0,
+            signal: None,
+            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+            digest: digest.to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+
+        // Create 1MB of output
+        let large_output = vec![b'A'; 1024 * 1024];
+
+        write_memo(&cache_dir, digest, &memo, &large_output, b"").unwrap();
+
+        let (_, stdout, _) = read_memo(&cache_dir, digest).unwrap();
+        assert_eq!(stdout.len(), 1024 * 1024);
+        assert_eq!(stdout, large_output);
+    }
+
+    #[test]
+    fn test_stream_stdout() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let digest = "stream123";
+        let memo = Memo {
+            cmd: vec!["test".to_string()],
+            cwd: "/test/dir".to_string(),
+            exit_code: 0,
+            signal: None,
+            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+            digest: digest.to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+
+        write_memo(&cache_dir, digest, &memo, b"output data", b"error data").unwrap();
+
+        let mut output = Vec::new();
+        stream_stdout(&cache_dir, digest, &mut output).unwrap();
+        assert_eq!(output, b"output data");
+    }
+
+    #[test]
+    fn test_stream_stderr() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let digest = "stream456";
+        let memo = Memo {
+            cmd: vec!["test".to_string()],
+            cwd: "/test/dir".to_string(),
+            exit_code: 0,
+            signal: None,
+            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+            digest: digest.to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+
+        write_memo(&cache_dir, digest, &memo, b"output data", b"error data").unwrap();
+
+        let mut errors = Vec::new();
+        stream_stderr(&cache_dir, digest, &mut errors).unwrap();
+        assert_eq!(errors, b"error data");
+    }
+
+    fn write_range_memo(cache_dir: &Path, digest: &str, stdout: &[u8]) {
+        write_test_entry(cache_dir, digest, stdout, b"");
+    }
+
+    fn range_output(cache_dir: &Path, digest: &str, range: StreamRange) -> Vec<u8> {
+        let mut output = Vec::new();
+        stream_range(cache_dir, digest, range, &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_stream_range_head() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "range1", b"one\ntwo\nthree\n");
+
+        let bytes = range_output(&cache_dir, "range1", StreamRange::HeadBytes(5));
+        assert_eq!(bytes, b"one\nt");
+
+        let lines = range_output(&cache_dir, "range1", StreamRange::HeadLines(2));
+        assert_eq!(lines, b"one\ntwo\n");
+
+        let all = range_output(&cache_dir, "range1", StreamRange::HeadLines(10));
+        assert_eq!(all, b"one\ntwo\nthree\n");
+    }
+
+    #[test]
+    fn test_stream_range_tail() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "range2", b"one\ntwo\nthree\n");
+
+        let bytes = range_output(&cache_dir, "range2", StreamRange::TailBytes(6));
+        assert_eq!(bytes, b"three\n");
+
+        let lines = range_output(&cache_dir, "range2", StreamRange::TailLines(2));
+        assert_eq!(lines, b"two\nthree\n");
+
+        let all = range_output(&cache_dir, "range2", StreamRange::TailBytes(100));
+        assert_eq!(all, b"one\ntwo\nthree\n");
+
+        let none = range_output(&cache_dir, "range2", StreamRange::TailLines(0));
+        assert_eq!(none, b"");
+    }
+
+    #[test]
+    fn test_stream_range_tail_lines_without_trailing_newline() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "range3", b"one\ntwo\nthree");
+
+        let lines = range_output(&cache_dir, "range3", StreamRange::TailLines(1));
+        assert_eq!(lines, b"three");
+    }
+
+    #[test]
+    fn test_stream_range_tail_lines_spans_chunks() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let mut stdout = Vec::new();
+        for i in 0..5000 {
+            stdout.extend_from_slice(format!("line {i}\n").as_bytes());
+        }
+        write_range_memo(&cache_dir, "range4", &stdout);
+
+        let lines = range_output(&cache_dir, "range4", StreamRange::TailLines(3));
+        assert_eq!(lines, b"line 4997\nline 4998\nline 4999\n");
+    }
+
+    #[test]
+    fn test_read_memo_metadata() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let digest = "meta123";
+        let memo = Memo {
+            cmd: vec!["echo".to_string(), "test".to_string()],
+            cwd: "/test/dir".to_string(),
+            exit_code: 42,
+            signal: None,
+            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+            digest: digest.to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+
+        write_memo(&cache_dir, digest, &memo, b"large output here", b"errors").unwrap();
+
+        let read_meta = read_memo_metadata(&cache_dir, digest).unwrap();
+        assert_eq!(read_meta.cmd, vec!["echo", "test"]);
+        assert_eq!(read_meta.exit_code, 42);
+        assert_eq!(read_meta.digest, digest);
+    }
+
+    fn write_temp_files(temp_dir: &TempCacheDir) {
+        let (json_path, out_path, err_path) = temp_dir.get_paths();
+        fs::write(json_path, "{}").unwrap();
+        fs::write(out_path, b"out").unwrap();
+        fs::write(err_path, b"err").unwrap();
+    }
+
+    fn cross_device_rename(_: &Path, _: &Path) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::CrossesDevices))
+    }
+
+    #[test]
+    fn test_commit_cache_dir_renames_into_place() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let mut temp_dir = create_temp_cache_dir(&cache_dir, "commit1").unwrap();
+        write_temp_files(&temp_dir);
+
+        assert!(commit_cache_dir(&mut temp_dir, &cache_dir, "commit1").unwrap());
+        assert!(memo_complete(&cache_dir, "commit1"));
+        assert!(!temp_dir.path.exists());
+    }
+
+    #[test]
+    fn test_commit_files_are_complete_at_rename() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let mut temp_dir = create_temp_cache_dir(&cache_dir, "sync1").unwrap();
+        write_temp_files(&temp_dir);
+
+        // The commit path syncs and then renames; the rename sees complete files
+        let committed = commit_cache_dir_with(&mut temp_dir, &cache_dir, "sync1", |from, to| {
+            let (json_path, out_path, err_path) = get_cache_paths_in_dir(from);
+            assert!(fs::metadata(json_path)?.len() > 0);
+            assert_eq!(fs::read(out_path)?, b"out");
+            assert_eq!(fs::read(err_path)?, b"err");
+            fs::rename(from, to)
+        })
+        .unwrap();
+        assert!(committed);
+        assert!(memo_complete(&cache_dir, "sync1"));
+    }
+
+    #[test]
+    fn test_commit_cache_dir_cross_device_fallback() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        let mut temp_dir = create_temp_cache_dir(&cache_dir, "xdev1").unwrap();
+        write_temp_files(&temp_dir);
+        let temp_path = temp_dir.path.clone();
+
+        let committed =
+            commit_cache_dir_with(&mut temp_dir, &cache_dir, "xdev1", cross_device_rename).unwrap();
+        assert!(committed);
+        assert!(memo_complete(&cache_dir, "xdev1"));
+
+        let (_, out_path, err_path) = get_cache_paths(&cache_dir, "xdev1");
+        assert_eq!(fs::read(out_path).unwrap(), b"out");
+        assert_eq!(fs::read(err_path).unwrap(), b"err");
+
+        // The copied-from temp dir is cleaned up on drop
+        drop(temp_dir);
+        assert!(!temp_path.exists());
+    }
+
+    #[test]
+    fn test_commit_cache_dir_cross_device_detects_existing_entry() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        fs::create_dir(cache_dir.join("xdev2")).unwrap();
+
+        let mut temp_dir = create_temp_cache_dir(&cache_dir, "xdev2").unwrap();
+        write_temp_files(&temp_dir);
+
+        let committed =
+            commit_cache_dir_with(&mut temp_dir, &cache_dir, "xdev2", cross_device_rename).unwrap();
+        assert!(!committed);
+        assert!(!memo_complete(&cache_dir, "xdev2"));
+    }
+
+    #[test]
+    fn test_remove_cache_entry() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "remove1", b"out");
+
+        assert!(remove_cache_entry(&cache_dir, "remove1").unwrap());
+        assert!(!cache_dir.join("remove1").exists());
+        assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 0);
 
-    fn setup_test_cache() -> (TempDir, PathBuf) {
-        let temp_dir = TempDir::new().unwrap();
-        let cache_dir = temp_dir.path().join("memo");
-        (temp_dir, cache_dir)
+        assert!(!remove_cache_entry(&cache_dir, "remove1").unwrap());
     }
 
     #[test]
-    fn test_ensure_cache_dir_creates_directory() {
+    fn test_concurrency_slots() {
         let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
 
-        assert!(!cache_dir.exists());
+        let first = try_acquire_slot(&cache_dir, 2).unwrap().unwrap();
+        let second = try_acquire_slot(&cache_dir, 2).unwrap().unwrap();
+        assert!(try_acquire_slot(&cache_dir, 2).unwrap().is_none());
+
+        drop(first);
+        let third = try_acquire_slot(&cache_dir, 2).unwrap();
+        assert!(third.is_some());
+        drop(second);
+
+        // Slots don't show up as entries
+        assert!(entry_dirs(&cache_dir).unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_concurrency_slot_of_dead_process_is_reclaimed() {
+        let (_temp, cache_dir) = setup_test_cache();
         ensure_cache_dir(&cache_dir).unwrap();
-        assert!(cache_dir.exists());
-        assert!(cache_dir.is_dir());
+
+        let mut child = process::Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+
+        let slot = cache_dir.join(SLOTS_DIR).join("0");
+        fs::create_dir_all(&slot).unwrap();
+        fs::write(slot.join(OWNER_FILE), dead_pid.to_string()).unwrap();
+
+        // The first attempt reclaims the slot, and the next one takes it
+        let taken = try_acquire_slot(&cache_dir, 1).unwrap();
+        let taken = taken.or_else(|| try_acquire_slot(&cache_dir, 1).unwrap());
+        assert!(taken.is_some());
     }
 
     #[test]
-    fn test_ensure_cache_dir_idempotent() {
+    fn test_reclaim_puts_back_a_retaken_slot() {
         let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        // A reclaimer that saw a dead owner, after another process reclaimed
+        // the slot and a live one took it again
+        let slot = try_acquire_slot(&cache_dir, 1).unwrap().unwrap();
+        reclaim_abandoned(&slot.path);
+
+        assert_eq!(
+            fs::read_to_string(slot.path.join(OWNER_FILE)).unwrap(),
+            process::id().to_string()
+        );
+        assert!(try_acquire_slot(&cache_dir, 1).unwrap().is_none());
+    }
 
+    #[test]
+    fn test_remove_incomplete_entry() {
+        let (_temp, cache_dir) = setup_test_cache();
         ensure_cache_dir(&cache_dir).unwrap();
-        ensure_cache_dir(&cache_dir).unwrap(); // Should not error
-        assert!(cache_dir.exists());
+        write_range_memo(&cache_dir, "complete1", b"out");
+        let partial = cache_dir.join("partial1");
+        fs::create_dir(&partial).unwrap();
+        fs::write(partial.join("meta.json"), "{}").unwrap();
+
+        assert!(!remove_incomplete_entry(&cache_dir, "complete1").unwrap());
+        assert!(memo_complete(&cache_dir, "complete1"));
+
+        // A concurrent commit may still be filling a fresh one
+        assert!(!remove_incomplete_entry(&cache_dir, "partial1").unwrap());
+        let stale = SystemTime::now() - INCOMPLETE_ENTRY_GRACE * 2;
+        File::open(&partial).unwrap().set_modified(stale).unwrap();
+        File::options()
+            .write(true)
+            .open(partial.join("meta.json"))
+            .unwrap()
+            .set_modified(stale)
+            .unwrap();
+        assert!(remove_incomplete_entry(&cache_dir, "partial1").unwrap());
+        assert!(!cache_dir.join("partial1").exists());
+        assert!(!remove_incomplete_entry(&cache_dir, "missing").unwrap());
     }
 
     #[test]
-    fn test_write_and_read_memo() {
+    fn test_record_hit() {
         let (_temp, cache_dir) = setup_test_cache();
         ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "hits1", b"out");
 
-        let digest = "abc123";
-        let memo = Memo {
-            cmd: vec!["echo".to_string(), "test".to_string()],
-            cwd: "/test/dir".to_string(),
-            exit_code: 0,
-            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
-            digest: digest.to_string(),
-        };
-        let stdout = b"test output\n";
-        let stderr = b"test error\n";
+        assert_eq!(hit_count_in_dir(&digest_dir(&cache_dir, "hits1")), 0);
+        record_hit(&cache_dir, "hits1").unwrap();
+        record_hit(&cache_dir, "hits1").unwrap();
+        assert_eq!(hit_count_in_dir(&digest_dir(&cache_dir, "hits1")), 2);
+        assert_eq!(hit_count_in_dir(&digest_dir(&cache_dir, "missing")), 0);
+    }
 
-        write_memo(&cache_dir, digest, &memo, stdout, stderr).unwrap();
+    #[test]
+    fn test_prune_entries_skips_pinned() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "prune1", b"out");
+        write_range_memo(&cache_dir, "prune2", b"out");
+        set_pinned(&cache_dir, "prune2", true).unwrap();
+        assert!(is_pinned_in_dir(&digest_dir(&cache_dir, "prune2")));
+
+        // The test entries are from 2025, so a day-long limit removes them
+        let report = prune_entries(&cache_dir, Duration::from_secs(86400)).unwrap();
+        assert_eq!(report.removed, 1);
+        assert_eq!(report.pinned, 1);
+        assert!(!memo_complete(&cache_dir, "prune1"));
+        assert!(memo_complete(&cache_dir, "prune2"));
+
+        set_pinned(&cache_dir, "prune2", false).unwrap();
+        set_pinned(&cache_dir, "prune2", false).unwrap();
+        let report = prune_entries(&cache_dir, Duration::ZERO).unwrap();
+        assert_eq!(report.removed, 1);
+        assert!(!memo_complete(&cache_dir, "prune2"));
+    }
 
-        let (read_memo, read_stdout, read_stderr) = read_memo(&cache_dir, digest).unwrap();
+    #[test]
+    fn test_clear_entries_keeps_temp_dirs() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "clear1", b"out");
+        write_range_memo(&cache_dir, "clear2", b"out");
+        set_pinned(&cache_dir, "clear2", true).unwrap();
+        fs::create_dir(cache_dir.join("clear3.tmp.1.2")).unwrap();
+
+        let report = clear_entries(&cache_dir, true).unwrap();
+        assert_eq!(report.removed, ["clear1", "clear2"]);
+        assert!(memo_complete(&cache_dir, "clear1"));
+
+        let cleared = clear_entries(&cache_dir, false).unwrap();
+        assert_eq!(cleared, report);
+        assert!(!memo_complete(&cache_dir, "clear1"));
+        assert!(!memo_complete(&cache_dir, "clear2"));
+        assert!(cache_dir.join("clear3.tmp.1.2").exists());
+    }
 
-        assert_eq!(read_memo.cmd, memo.cmd);
-        assert_eq!(read_memo.exit_code, memo.exit_code);
-        assert_eq!(read_memo.digest, memo.digest);
-        assert_eq!(read_stdout, stdout);
-        assert_eq!(read_stderr, stderr);
+    #[test]
+    fn test_evict_removes_least_recently_used() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        let day = Duration::from_secs(86400);
+        for (age, digest) in [(3, "lru1"), (1, "lru2"), (2, "lru3"), (4, "lru4")] {
+            write_range_memo(&cache_dir, digest, &[b'x'; 1000]);
+            File::options()
+                .write(true)
+                .open(cache_dir.join(digest).join("meta.json"))
+                .unwrap()
+                .set_modified(SystemTime::now() - day * age)
+                .unwrap();
+        }
+        // The oldest entry is pinned and the just-committed one is kept
+        set_pinned(&cache_dir, "lru4", true).unwrap();
+        let entry = entry_size_in_dir(&digest_dir(&cache_dir, "lru1")).unwrap();
+
+        let report = evict_to_size_limit(&cache_dir, entry * 2, "lru1").unwrap();
+        assert_eq!(report.removed, 2);
+        assert_eq!(report.bytes, entry * 2);
+        assert!(memo_complete(&cache_dir, "lru1"));
+        assert!(!memo_complete(&cache_dir, "lru2"));
+        assert!(!memo_complete(&cache_dir, "lru3"));
+        assert!(memo_complete(&cache_dir, "lru4"));
     }
 
     #[test]
-    fn test_write_memo_empty_output() {
+    fn test_evict_counts_hits_as_use() {
         let (_temp, cache_dir) = setup_test_cache();
         ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "used", b"out");
+        write_range_memo(&cache_dir, "unused", b"out");
+        File::options()
+            .write(true)
+            .open(cache_dir.join("used").join("meta.json"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(86400))
+            .unwrap();
+        record_hit(&cache_dir, "used").unwrap();
+        let entry = entry_size_in_dir(&digest_dir(&cache_dir, "unused")).unwrap();
+
+        let report = evict_to_size_limit(&cache_dir, entry + 1, "none").unwrap();
+        assert_eq!(report.removed, 1);
+        assert!(memo_complete(&cache_dir, "used"));
+        assert!(!memo_complete(&cache_dir, "unused"));
+    }
 
-        let digest = "empty123";
-        let memo = Memo {
-            cmd: vec!["true".to_string()],
-            cwd: "/test/dir".to_string(),
-            exit_code: 0,
-            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
-            digest: digest.to_string(),
-        };
+    #[test]
+    fn test_spool_stdin_is_removed_on_drop() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
 
-        write_memo(&cache_dir, digest, &memo, b"", b"").unwrap();
+        let spool = spool_stdin(&cache_dir, &mut &b"input\n"[..]).unwrap();
+        assert_eq!(spool.bytes, 6);
+        assert_eq!(fs::read(&spool.path).unwrap(), b"input\n");
+        assert!(iter_memos(&cache_dir).unwrap().next().is_none());
 
-        let (_, stdout, stderr) = read_memo(&cache_dir, digest).unwrap();
-        assert_eq!(stdout, b"");
-        assert_eq!(stderr, b"");
+        let path = spool.path.clone();
+        drop(spool);
+        assert!(!path.exists());
     }
 
     #[test]
-    fn test_write_memo_binary_data() {
+    fn test_iter_memos() {
         let (_temp, cache_dir) = setup_test_cache();
         ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "iter2", b"two");
+        write_range_memo(&cache_dir, "iter1", b"one");
+        fs::create_dir(cache_dir.join("iter3")).unwrap();
+        fs::create_dir(cache_dir.join("iter4.tmp.1.2")).unwrap();
 
-        let digest = "binary123";
-        let memo = Memo {
-            cmd: vec!["binary".to_string()],
-            cwd: "/test/dir".to_string(),
-            exit_code: 0,
-            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
-            digest: digest.to_string(),
-        };
-        let binary_data = vec![0x00, 0x01, 0xFF, 0xFE, 0x7F];
-
-        write_memo(&cache_dir, digest, &memo, &binary_data, &binary_data).unwrap();
+        let entries: Vec<_> = iter_memos(&cache_dir).unwrap().collect();
+        let digests: Vec<_> = entries.iter().map(|(d, _, _)| d.as_str()).collect();
 
-        let (_, stdout, stderr) = read_memo(&cache_dir, digest).unwrap();
-        assert_eq!(stdout, binary_data);
-        assert_eq!(stderr, binary_data);
+        assert_eq!(digests, vec!["iter1", "iter2", "iter3"]);
+        assert_eq!(entries[0].2.as_ref().unwrap().digest, "iter1");
+        assert!(entries[2].2.is_err());
     }
 
     #[test]
-    fn test_read_nonexistent_memo() {
+    fn test_iter_memos_finds_sharded_entries() {
         let (_temp, cache_dir) = setup_test_cache();
         ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "flat1", b"flat");
+        write_range_memo(&cache_dir.join("ab").join("cd"), "abcd12", b"deep");
+        fs::create_dir(cache_dir.join("ab").join("cd").join("abcd34.tmp.1.2")).unwrap();
 
-        let result = read_memo(&cache_dir, "nonexistent");
-        assert!(result.is_err());
+        let entries: Vec<_> = iter_memos(&cache_dir).unwrap().collect();
+        let digests: Vec<_> = entries.iter().map(|(d, _, _)| d.as_str()).collect();
+
+        assert_eq!(digests, vec!["abcd12", "flat1"]);
+        assert_eq!(entries[0].1, cache_dir.join("ab").join("cd").join("abcd12"));
+        assert_eq!(entries[0].2.as_ref().unwrap().digest, "abcd12");
     }
 
     #[test]
-    fn test_multiple_memos() {
+    fn test_clear_and_prune_reach_entries_at_another_shard_depth() {
         let (_temp, cache_dir) = setup_test_cache();
         ensure_cache_dir(&cache_dir).unwrap();
+        let shard = cache_dir.join("ab").join("cd");
+        write_range_memo(&shard, "abcd12", b"deep");
+        write_range_memo(&shard, "abcd34", b"deep");
+        fs::write(shard.join("abcd34").join(PIN_FILE), b"").unwrap();
+
+        // The flat layout is in effect, but the entries are two levels down
+        let report = prune_entries(&cache_dir, Duration::ZERO).unwrap();
+        assert_eq!((report.removed, report.pinned), (1, 1));
+        assert!(!shard.join("abcd12").exists());
+
+        let report = clear_entries(&cache_dir, false).unwrap();
+        assert_eq!(report.removed, vec!["abcd34".to_string()]);
+        assert!(!shard.join("abcd34").exists());
+    }
 
-        let digest1 = "multi1";
-        let digest2 = "multi2";
-
-        let memo1 = Memo {
-            cmd: vec!["echo".to_string(), "one".to_string()],
-            cwd: "/test/dir".to_string(),
-            exit_code: 0,
-            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
-            digest: digest1.to_string(),
-        };
+    #[test]
+    fn test_digest_dir_at_depth() {
+        let cache_dir = PathBuf::from("/tmp/cache");
+
+        assert_eq!(
+            digest_dir_at(&cache_dir, "abcdef", 0),
+            PathBuf::from("/tmp/cache/abcdef")
+        );
+        assert_eq!(
+            digest_dir_at(&cache_dir, "abcdef", 2),
+            PathBuf::from("/tmp/cache/ab/cd/abcdef")
+        );
+        // Tagged digests shard on their hex, not the tag
+        assert_eq!(
+            digest_dir_at(&cache_dir, "blake3abcdef", 1),
+            PathBuf::from("/tmp/cache/ab/blake3abcdef")
+        );
+    }
 
-        let memo2 = Memo {
-            cmd: vec!["echo".to_string(), "two".to_string()],
-            cwd: "/test/dir".to_string(),
-            exit_code: 1,
-            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
-            digest: digest2.to_string(),
-        };
+    #[test]
+    fn test_create_shard_dirs() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        let final_path = digest_dir_at(&cache_dir, "abcdef", 2);
 
-        write_memo(&cache_dir, digest1, &memo1, b"one\n", b"").unwrap();
-        write_memo(&cache_dir, digest2, &memo2, b"two\n", b"err\n").unwrap();
+        create_shard_dirs(&final_path, &cache_dir).unwrap();
+        assert!(cache_dir.join("ab").join("cd").is_dir());
+        assert!(!final_path.exists());
 
-        let (read1, out1, err1) = read_memo(&cache_dir, digest1).unwrap();
-        let (read2, out2, err2) = read_memo(&cache_dir, digest2).unwrap();
+        // Existing levels are fine
+        create_shard_dirs(&final_path, &cache_dir).unwrap();
+    }
 
-        assert_eq!(read1.cmd, vec!["echo", "one"]);
-        assert_eq!(read2.cmd, vec!["echo", "two"]);
-        assert_eq!(out1, b"one\n");
-        assert_eq!(out2, b"two\n");
-        assert_eq!(err1, b"");
-        assert_eq!(err2, b"err\n");
+    #[test]
+    fn test_purge_temp_dirs() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "kept", b"entry");
+
+        let old = cache_dir.join("old.tmp.1.2");
+        let fresh = cache_dir.join("fresh.tmp.3.4");
+        fs::create_dir(&old).unwrap();
+        fs::create_dir(&fresh).unwrap();
+        fs::write(old.join("stdout"), b"12345").unwrap();
+        fs::write(fresh.join("stdout"), b"123").unwrap();
+
+        let two_hours_ago = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        File::open(&old)
+            .unwrap()
+            .set_modified(two_hours_ago)
+            .unwrap();
+
+        let report = purge_temp_dirs(&cache_dir, Duration::from_secs(60 * 60)).unwrap();
+        assert_eq!(
+            report,
+            PurgeReport {
+                removed: 1,
+                bytes: 5
+            }
+        );
+        assert!(!old.exists());
+        assert!(fresh.exists());
+        assert!(memo_complete(&cache_dir, "kept"));
+
+        let report = purge_temp_dirs(&cache_dir, Duration::ZERO).unwrap();
+        assert_eq!(report.removed, 1);
+        assert!(!fresh.exists());
     }
 
     #[test]
-    fn test_cache_files_have_correct_names() {
+    fn test_cleanup_reaps_orphaned_stdin_spools() {
         let (_temp, cache_dir) = setup_test_cache();
         ensure_cache_dir(&cache_dir).unwrap();
 
-        let digest = "names123";
-        let memo = Memo {
-            cmd: vec!["test".to_string()],
-            cwd: "/test/dir".to_string(),
-            exit_code: 0,
-            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
-            digest: digest.to_string(),
-        };
+        // As left behind by a memo killed before its spool was dropped
+        let spool = spool_stdin(&cache_dir, &mut &b"input"[..]).unwrap();
+        let path = spool.path.clone();
+        std::mem::forget(spool);
+        let fresh = spool_stdin(&cache_dir, &mut &b"fresh"[..]).unwrap();
+
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() - TEMP_DIR_MAX_AGE * 2)
+            .unwrap();
+        cleanup_temp_dirs(&cache_dir, false).unwrap();
+        assert!(!path.exists());
+        assert!(fresh.path.exists());
+
+        let report = purge_temp_dirs(&cache_dir, Duration::ZERO).unwrap();
+        assert_eq!(
+            report,
+            PurgeReport {
+                removed: 1,
+                bytes: 5
+            }
+        );
+        assert!(!fresh.path.exists());
+    }
 
-        write_memo(&cache_dir, digest, &memo, b"out", b"err").unwrap();
+    #[test]
+    fn test_stream_stdout_timed() {
+        let (_temp, cache_dir) = setup_test_cache();
+        write_range_memo(&cache_dir, "timed", b"onetwo!");
+        fs::write(
+            cache_dir.join("timed").join(TIMING_FILE),
+            "0 3\nbad\n100 3\n",
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        let mut output = Vec::new();
+        stream_stdout_timed(&cache_dir, "timed", &mut output).unwrap();
 
-        let digest_dir = cache_dir.join(digest);
-        assert!(digest_dir.join("meta.json").exists());
-        assert!(digest_dir.join("stdout").exists());
-        assert!(digest_dir.join("stderr").exists());
+        assert_eq!(output, b"onetwo!");
+        assert!(start.elapsed() >= Duration::from_millis(100));
     }
 
     #[test]
-    fn test_get_cache_dir_respects_xdg() {
-        let temp = TempDir::new().unwrap();
-        let xdg_path = temp.path().to_path_buf();
+    fn test_stream_interleaved() {
+        use std::cell::RefCell;
 
-        std::env::set_var("XDG_CACHE_HOME", &xdg_path);
-        let cache_dir = get_cache_dir().unwrap();
-        std::env::remove_var("XDG_CACHE_HOME");
+        /// Appends each write to a log shared by both streams
+        struct Tagged<'a>(&'a RefCell<Vec<String>>, &'static str);
 
-        assert_eq!(cache_dir, xdg_path.join("memo"));
-    }
+        impl Write for Tagged<'_> {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let text = String::from_utf8_lossy(buf);
+                self.0.borrow_mut().push(format!("{}{text}", self.1));
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
 
-    #[test]
-    fn test_large_output() {
         let (_temp, cache_dir) = setup_test_cache();
-        ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "mixed", b"onethree");
+        let dir = cache_dir.join("mixed");
+        fs::write(dir.join("stderr"), "two").unwrap();
+        fs::write(dir.join(ORDER_FILE), "out 3\nbad\nerr 3\nout 2\n").unwrap();
 
-        let digest = "large123";
-        let memo = Memo {
-            cmd: vec!["large".to_string()],
-            cwd: "/test/dir".to_string(),
-            exit_code: 0,
-            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
-            digest: digest.to_string(),
-        };
+        let log = RefCell::new(Vec::new());
+        stream_interleaved(&cache_dir, "mixed", Tagged(&log, "1:"), Tagged(&log, "2:")).unwrap();
 
-        // Create 1MB of output
-        let large_output = vec![b'A'; 1024 * 1024];
+        // The last stdout chunk wasn't recorded, so it comes at the end
+        assert_eq!(log.into_inner(), ["1:one", "2:two", "1:th", "1:ree"]);
+    }
 
-        write_memo(&cache_dir, digest, &memo, &large_output, b"").unwrap();
+    #[test]
+    fn test_stream_stdout_timed_without_timing_file() {
+        let (_temp, cache_dir) = setup_test_cache();
+        write_range_memo(&cache_dir, "untimed", b"all at once");
 
-        let (_, stdout, _) = read_memo(&cache_dir, digest).unwrap();
-        assert_eq!(stdout.len(), 1024 * 1024);
-        assert_eq!(stdout, large_output);
+        let mut output = Vec::new();
+        stream_stdout_timed(&cache_dir, "untimed", &mut output).unwrap();
+        assert_eq!(output, b"all at once");
     }
 
     #[test]
-    fn test_stream_stdout() {
+    fn test_try_lock_entry() {
         let (_temp, cache_dir) = setup_test_cache();
         ensure_cache_dir(&cache_dir).unwrap();
 
-        let digest = "stream123";
-        let memo = Memo {
-            cmd: vec!["test".to_string()],
-            cwd: "/test/dir".to_string(),
-            exit_code: 0,
-            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
-            digest: digest.to_string(),
-        };
+        let lock = try_lock_entry(&cache_dir, "locked").unwrap();
+        assert!(lock.is_some());
+        assert!(cache_dir.join("locked.lock").is_dir());
+        assert!(try_lock_entry(&cache_dir, "locked").unwrap().is_none());
 
-        write_memo(&cache_dir, digest, &memo, b"output data", b"error data").unwrap();
+        // Locks are not listed as entries
+        assert_eq!(iter_memos(&cache_dir).unwrap().count(), 0);
 
-        let mut output = Vec::new();
-        stream_stdout(&cache_dir, digest, &mut output).unwrap();
-        assert_eq!(output, b"output data");
+        drop(lock);
+        assert!(!cache_dir.join("locked.lock").exists());
+        assert!(try_lock_entry(&cache_dir, "locked").unwrap().is_some());
     }
 
+    #[cfg(unix)]
     #[test]
-    fn test_stream_stderr() {
+    fn test_lock_of_dead_process_is_taken_over() {
         let (_temp, cache_dir) = setup_test_cache();
         ensure_cache_dir(&cache_dir).unwrap();
 
-        let digest = "stream456";
-        let memo = Memo {
-            cmd: vec!["test".to_string()],
-            cwd: "/test/dir".to_string(),
-            exit_code: 0,
-            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
-            digest: digest.to_string(),
-        };
+        let mut child = process::Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+
+        let lock = cache_dir.join("locked.lock");
+        fs::create_dir_all(&lock).unwrap();
+        fs::write(lock.join(OWNER_FILE), dead_pid.to_string()).unwrap();
+        let taken = try_lock_entry(&cache_dir, "locked").unwrap();
+        assert!(taken.is_some());
+        assert_eq!(
+            fs::read_to_string(lock.join(OWNER_FILE)).unwrap(),
+            process::id().to_string()
+        );
+
+        // A live owner keeps its lock
+        assert!(try_lock_entry(&cache_dir, "locked").unwrap().is_none());
+        drop(taken);
+        assert!(!lock.exists());
+    }
 
-        write_memo(&cache_dir, digest, &memo, b"output data", b"error data").unwrap();
+    #[test]
+    fn test_validate_clean_cache() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "good", b"ok");
+        fs::create_dir(cache_dir.join("good.tmp.1.2")).unwrap();
 
-        let mut errors = Vec::new();
-        stream_stderr(&cache_dir, digest, &mut errors).unwrap();
-        assert_eq!(errors, b"error data");
+        assert_eq!(validate(&cache_dir).unwrap(), vec![]);
     }
 
     #[test]
-    fn test_read_memo_metadata() {
+    fn test_validate_orphan_temp_dir() {
         let (_temp, cache_dir) = setup_test_cache();
         ensure_cache_dir(&cache_dir).unwrap();
+        let orphan = cache_dir.join("old.tmp.1.2");
+        fs::create_dir(&orphan).unwrap();
+        File::open(&orphan)
+            .unwrap()
+            .set_modified(SystemTime::now() - TEMP_DIR_MAX_AGE - Duration::from_secs(60))
+            .unwrap();
+
+        assert_eq!(
+            validate(&cache_dir).unwrap(),
+            vec![CacheIssue::OrphanTempDir(orphan)]
+        );
+    }
 
-        let digest = "meta123";
-        let memo = Memo {
-            cmd: vec!["echo".to_string(), "test".to_string()],
-            cwd: "/test/dir".to_string(),
-            exit_code: 42,
-            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
-            digest: digest.to_string(),
-        };
+    #[test]
+    fn test_validate_incomplete_entry() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "partial", b"ok");
+        fs::remove_file(cache_dir.join("partial").join("stderr")).unwrap();
+
+        assert_eq!(
+            validate(&cache_dir).unwrap(),
+            vec![CacheIssue::IncompleteEntry {
+                path: cache_dir.join("partial"),
+                missing: vec!["stderr"],
+            }]
+        );
+    }
 
-        write_memo(&cache_dir, digest, &memo, b"large output here", b"errors").unwrap();
+    #[test]
+    fn test_validate_unparsable_metadata() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "garbled", b"ok");
+        fs::write(cache_dir.join("garbled").join("meta.json"), "{not json").unwrap();
+
+        let issues = validate(&cache_dir).unwrap();
+        assert_eq!(issues.len(), 1);
+        assert!(matches!(
+            &issues[0],
+            CacheIssue::UnparsableMetadata { path, .. } if *path == cache_dir.join("garbled")
+        ));
+    }
 
-        let read_meta = read_memo_metadata(&cache_dir, digest).unwrap();
-        assert_eq!(read_meta.cmd, vec!["echo", "test"]);
-        assert_eq!(read_meta.exit_code, 42);
-        assert_eq!(read_meta.digest, digest);
+    #[test]
+    fn test_validate_digest_mismatch() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "original", b"ok");
+        fs::rename(cache_dir.join("original"), cache_dir.join("renamed")).unwrap();
+
+        assert_eq!(
+            validate(&cache_dir).unwrap(),
+            vec![CacheIssue::DigestMismatch {
+                path: cache_dir.join("renamed"),
+                recorded: "original".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_repair_digests() {
+        let (_temp, cache_dir) = setup_test_cache();
+        ensure_cache_dir(&cache_dir).unwrap();
+        write_range_memo(&cache_dir, "original", b"ok");
+        write_range_memo(&cache_dir, "untouched", b"ok");
+        fs::rename(cache_dir.join("original"), cache_dir.join("renamed")).unwrap();
+
+        assert_eq!(
+            repair_digests(&cache_dir).unwrap(),
+            vec![DigestRepair {
+                path: cache_dir.join("renamed"),
+                recorded: "original".to_string(),
+            }]
+        );
+        assert_eq!(
+            read_memo_metadata(&cache_dir, "renamed").unwrap().digest,
+            "renamed"
+        );
+        assert!(validate(&cache_dir).unwrap().is_empty());
+        assert!(repair_digests(&cache_dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_iter_memos_missing_cache_dir() {
+        let (_temp, cache_dir) = setup_test_cache();
+        assert_eq!(iter_memos(&cache_dir).unwrap().count(), 0);
     }
 
     #[test]
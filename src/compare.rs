@@ -0,0 +1,124 @@
+//! Byte-level comparison of output files
+//!
+//! Used to check a fresh execution against a cached result without loading
+//! either output fully into memory.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+/// Find the offset of the first byte at which two files differ
+///
+/// Returns `None` if the files are byte-identical. If one file is a prefix of
+/// the other, the offset is the length of the shorter file.
+pub fn first_difference(a: &Path, b: &Path) -> io::Result<Option<u64>> {
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+
+    let mut buf_a = [0u8; 8192];
+    let mut buf_b = [0u8; 8192];
+    let mut offset = 0u64;
+
+    loop {
+        let len_a = read_full(&mut a, &mut buf_a)?;
+        let len_b = read_full(&mut b, &mut buf_b)?;
+        let len = len_a.min(len_b);
+
+        if let Some(i) = (0..len).find(|&i| buf_a[i] != buf_b[i]) {
+            return Ok(Some(offset + i as u64));
+        }
+
+        if len_a != len_b {
+            return Ok(Some(offset + len as u64));
+        }
+
+        if len_a == 0 {
+            return Ok(None);
+        }
+
+        offset += len as u64;
+    }
+}
+
+/// Find where `new` continues past `old`, if `old` is a prefix of `new`
+///
+/// Returns the length of `old` if `new` starts with all of it (including when
+/// they are identical), or `None` if the files diverge or `new` is shorter.
+pub fn appended_offset(old: &Path, new: &Path) -> io::Result<Option<u64>> {
+    let old_len = old.metadata()?.len();
+    Ok(match first_difference(old, new)? {
+        None => Some(old_len),
+        Some(offset) if offset == old_len => Some(old_len),
+        Some(_) => None,
+    })
+}
+
+/// Read until the buffer is full or the reader is exhausted
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn difference(a: &[u8], b: &[u8]) -> Option<u64> {
+        let temp_dir = TempDir::new().unwrap();
+        let path_a = temp_dir.path().join("a");
+        let path_b = temp_dir.path().join("b");
+        fs::write(&path_a, a).unwrap();
+        fs::write(&path_b, b).unwrap();
+        first_difference(&path_a, &path_b).unwrap()
+    }
+
+    #[test]
+    fn test_identical_files() {
+        assert_eq!(difference(b"hello\n", b"hello\n"), None);
+        assert_eq!(difference(b"", b""), None);
+    }
+
+    #[test]
+    fn test_differing_byte() {
+        assert_eq!(difference(b"hello", b"help!"), Some(3));
+    }
+
+    #[test]
+    fn test_prefix_differs_at_shorter_length() {
+        assert_eq!(difference(b"hello", b"hello world"), Some(5));
+        assert_eq!(difference(b"hello world", b""), Some(0));
+    }
+
+    fn appended(old: &[u8], new: &[u8]) -> Option<u64> {
+        let temp_dir = TempDir::new().unwrap();
+        let (path_old, path_new) = (temp_dir.path().join("old"), temp_dir.path().join("new"));
+        fs::write(&path_old, old).unwrap();
+        fs::write(&path_new, new).unwrap();
+        appended_offset(&path_old, &path_new).unwrap()
+    }
+
+    #[test]
+    fn test_appended_offset() {
+        assert_eq!(appended(b"line 1\n", b"line 1\nline 2\n"), Some(7));
+        assert_eq!(appended(b"same", b"same"), Some(4));
+        assert_eq!(appended(b"", b"anything"), Some(0));
+        assert_eq!(appended(b"line 1\n", b"LINE 1\nline 2\n"), None);
+        assert_eq!(appended(b"longer", b"long"), None);
+    }
+
+    #[test]
+    fn test_difference_beyond_first_chunk() {
+        let a = vec![b'x'; 20000];
+        let mut b = a.clone();
+        b[12345] = b'y';
+        assert_eq!(difference(&a, &b), Some(12345));
+    }
+}
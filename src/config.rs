@@ -0,0 +1,138 @@
+//! Configuration file support
+//!
+//! A config file supplies default values for command-line options. Options
+//! given explicitly on the command line take precedence.
+//!
+//! ```toml
+//! verbose = true
+//! auto_invalidate_binary = true
+//! shard = 2
+//! digest = "blake3"
+//! buffer_size = 1048576
+//! ```
+
+use crate::constants::MAX_SHARD_DEPTH;
+use crate::digest::DigestAlgorithm;
+use crate::error::{MemoError, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// Default option values loaded from a TOML config file
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Print memoization information
+    pub verbose: Option<bool>,
+    /// Treat a hit as a miss if the command's binary is newer than the entry
+    pub auto_invalidate_binary: Option<bool>,
+    /// Number of digest-prefix directory levels to nest cache entries under
+    pub shard: Option<u8>,
+    /// Hash algorithm for cache keys
+    pub digest: Option<DigestAlgorithm>,
+    /// Size in bytes of the buffer used to copy output
+    pub buffer_size: Option<usize>,
+}
+
+impl Config {
+    /// Load a config file from an explicit path
+    ///
+    /// Returns an error naming the path if the file cannot be read or parsed.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| MemoError::Config(format!("cannot read {}: {}", path.display(), e)))?;
+        let config = Self::parse(&text)
+            .map_err(|e| MemoError::Config(format!("cannot parse {}: {}", path.display(), e)))?;
+
+        if config.shard.is_some_and(|depth| depth > MAX_SHARD_DEPTH) {
+            return Err(MemoError::Config(format!(
+                "{}: shard must be at most {}",
+                path.display(),
+                MAX_SHARD_DEPTH
+            )));
+        }
+
+        if config.buffer_size == Some(0) {
+            return Err(MemoError::Config(format!(
+                "{}: buffer_size must be positive",
+                path.display()
+            )));
+        }
+
+        Ok(config)
+    }
+
+    /// Parse config file contents
+    fn parse(text: &str) -> std::result::Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_empty_config() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_parse_config_values() {
+        let config = Config::parse("verbose = true\nauto_invalidate_binary = false\n").unwrap();
+        assert_eq!(config.verbose, Some(true));
+        assert_eq!(config.auto_invalidate_binary, Some(false));
+    }
+
+    #[test]
+    fn test_parse_digest_algorithm() {
+        let config = Config::parse("digest = \"blake3\"\n").unwrap();
+        assert_eq!(config.digest, Some(DigestAlgorithm::Blake3));
+        assert!(Config::parse("digest = \"md5\"\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_unknown_key_fails() {
+        assert!(Config::parse("verbsoe = true\n").is_err());
+    }
+
+    #[test]
+    fn test_load_zero_buffer_size() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("memo.toml");
+        fs::write(&path, "buffer_size = 0\n").unwrap();
+
+        let err = Config::load(&path).unwrap_err();
+        assert!(err.to_string().contains("buffer_size must be positive"));
+    }
+
+    #[test]
+    fn test_load_shard_out_of_range() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("memo.toml");
+        fs::write(&path, "shard = 9\n").unwrap();
+
+        let err = Config::load(&path).unwrap_err();
+        assert!(err.to_string().contains("shard must be at most"));
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("missing.toml");
+
+        let err = Config::load(&path).unwrap_err();
+        assert!(err.to_string().contains("missing.toml"));
+    }
+
+    #[test]
+    fn test_load_invalid_file() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("memo.toml");
+        fs::write(&path, "verbose = \"yes\"\n").unwrap();
+
+        let err = Config::load(&path).unwrap_err();
+        assert!(err.to_string().contains("cannot parse"));
+    }
+}
@@ -1,5 +1,7 @@
 //! Constants used throughout the memo application
 
+use std::time::Duration;
+
 /// Permission mode for cache directory (owner read/write/execute only)
 #[cfg(unix)]
 pub const CACHE_DIR_PERMISSIONS: u32 = 0o700;
@@ -7,3 +9,81 @@ pub const CACHE_DIR_PERMISSIONS: u32 = 0o700;
 /// Permission mode for cache files (owner read/write only)
 #[cfg(unix)]
 pub const FILE_PERMISSIONS: u32 = 0o600;
+
+/// How often `--watch` polls dependency paths for changes
+pub const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long dependency paths must be quiet before `--watch` re-executes
+pub const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Exit code when the user declines to run a command at a `--confirm-miss` prompt
+pub const CONFIRM_ABORT_EXIT_CODE: i32 = 125;
+
+/// Added to a signal number to give the exit status of a command killed by
+/// that signal, as shells do (e.g. 137 for `SIGKILL`)
+pub const SIGNAL_EXIT_BASE: i32 = 128;
+
+/// Exit code when a command is killed for running past `--timeout` (as with
+/// GNU `timeout`)
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Deepest supported `--shard` nesting of cache entries
+pub const MAX_SHARD_DEPTH: u8 = 4;
+
+/// Exit code recorded when the command's real exit code is unknown (e.g. it
+/// was killed by a signal)
+pub const UNKNOWN_EXIT_CODE: i32 = -1;
+
+/// How often `--single-flight` waiters check for the leader's entry
+pub const SINGLE_FLIGHT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Default time `--single-flight` waiters wait before running the command
+/// themselves
+pub const SINGLE_FLIGHT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How often a miss waiting for a `--max-concurrency` slot checks again
+pub const CONCURRENCY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Environment variable giving the default `--cache-dir`
+pub const CACHE_DIR_VAR: &str = "MEMO_CACHE_DIR";
+
+/// Environment variable giving the default `--ttl`
+pub const TTL_VAR: &str = "MEMO_TTL";
+
+/// Environment variable listing the default `--env` names, comma-separated
+pub const ENV_VAR: &str = "MEMO_ENV";
+
+/// Environment variable giving the default `--max-size`
+pub const MAX_SIZE_VAR: &str = "MEMO_MAX_SIZE";
+
+/// Timeout for each request to the remote cache
+pub const REMOTE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Template for `--list` when no `--format` is given: the short digest, exit
+/// code, timestamp, and command, tab-separated
+pub const LIST_FORMAT: &str = "{short_digest}\t{exit}\t{timestamp}\t{cmd}";
+
+/// Default number of entries printed by `--rank`
+pub const RANK_LIMIT: usize = 10;
+
+/// Shortest digest prefix `--drop-digest` accepts
+pub const MIN_DIGEST_PREFIX_LEN: usize = 4;
+
+/// Default maximum width of command strings in verbose and `--list` output
+pub const DISPLAY_WIDTH: usize = 120;
+
+/// Age after which a temp directory is considered orphaned by a crashed run
+pub const TEMP_DIR_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// How long an incomplete entry is left alone after its last write, in case
+/// a concurrent commit is still copying files into it
+pub const INCOMPLETE_ENTRY_GRACE: Duration = Duration::from_secs(60);
+
+/// Shell used by `--exec-shell`
+pub const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// Version recorded in the metadata of new entries
+pub const MEMO_VERSION: &str = env!("VERGEN_GIT_DESCRIBE");
+
+/// Default size of the buffer used to copy output to and from the cache
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
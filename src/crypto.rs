@@ -0,0 +1,289 @@
+//! At-rest encryption of cached output (`--encrypt`)
+//!
+//! Output files are encrypted with ChaCha20-Poly1305 in the STREAM
+//! construction, so arbitrarily large outputs are encrypted and decrypted in
+//! fixed-size segments without loading them into memory. Each segment is
+//! authenticated, and the final segment is marked as such, so truncation and
+//! tampering are detected as well as a wrong passphrase.
+//!
+//! The key is derived from `MEMO_PASSPHRASE` with Argon2id and a random
+//! per-entry salt. The salt and the per-file nonces are stored in `meta.json`.
+
+use crate::compare::read_full;
+use crate::error::{MemoError, Result};
+use crate::memo::Encryption;
+use argon2::Argon2;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// Environment variable holding the encryption passphrase
+pub const PASSPHRASE_VAR: &str = "MEMO_PASSPHRASE";
+
+/// Plaintext bytes per encrypted segment
+const SEGMENT_LEN: usize = 64 * 1024;
+
+/// Authentication tag bytes added to each segment
+const TAG_LEN: usize = 16;
+
+/// Length of the random salt used for key derivation
+const SALT_LEN: usize = 16;
+
+/// Length of the STREAM nonce prefix (the AEAD nonce minus the 5-byte counter)
+const NONCE_LEN: usize = 7;
+
+/// Read the passphrase from the environment
+pub fn passphrase() -> Result<String> {
+    match std::env::var(PASSPHRASE_VAR) {
+        Ok(passphrase) if !passphrase.is_empty() => Ok(passphrase),
+        _ => Err(MemoError::Crypto(format!("{PASSPHRASE_VAR} is not set"))),
+    }
+}
+
+/// Keys and metadata for encrypting one cache entry
+#[derive(Clone)]
+pub struct EntryKeys {
+    key: Key,
+    /// Salt and nonces to record in the entry's metadata
+    pub encryption: Encryption,
+}
+
+impl fmt::Debug for EntryKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EntryKeys")
+            .field("encryption", &self.encryption)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EntryKeys {
+    /// Derive a key for a new entry, with a fresh salt and nonces
+    pub fn generate(passphrase: &str) -> Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut stdout_nonce = [0u8; NONCE_LEN];
+        let mut stderr_nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut stdout_nonce);
+        OsRng.fill_bytes(&mut stderr_nonce);
+
+        let encryption = Encryption {
+            salt: hex::encode(salt),
+            stdout_nonce: hex::encode(stdout_nonce),
+            stderr_nonce: hex::encode(stderr_nonce),
+        };
+        Self::for_entry(passphrase, &encryption)
+    }
+
+    /// Derive the key for an existing entry from its recorded salt
+    pub fn for_entry(passphrase: &str, encryption: &Encryption) -> Result<Self> {
+        let salt = decode_hex(&encryption.salt, SALT_LEN)?;
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| MemoError::Crypto(format!("key derivation failed: {e}")))?;
+
+        Ok(Self {
+            key,
+            encryption: encryption.clone(),
+        })
+    }
+
+    /// Wrap a writer so everything written to it is encrypted as stdout
+    pub fn encrypt_stdout<W: Write>(&self, inner: W) -> Result<EncryptingWriter<W>> {
+        self.encryptor(&self.encryption.stdout_nonce, inner)
+    }
+
+    /// Wrap a writer so everything written to it is encrypted as stderr
+    pub fn encrypt_stderr<W: Write>(&self, inner: W) -> Result<EncryptingWriter<W>> {
+        self.encryptor(&self.encryption.stderr_nonce, inner)
+    }
+
+    /// Decrypt a stdout file written by [`EntryKeys::encrypt_stdout`]
+    pub fn decrypt_stdout<R: Read, W: Write>(&self, reader: R, writer: W) -> Result<()> {
+        self.decrypt(&self.encryption.stdout_nonce, reader, writer)
+    }
+
+    /// Decrypt a stderr file written by [`EntryKeys::encrypt_stderr`]
+    pub fn decrypt_stderr<R: Read, W: Write>(&self, reader: R, writer: W) -> Result<()> {
+        self.decrypt(&self.encryption.stderr_nonce, reader, writer)
+    }
+
+    fn encryptor<W: Write>(&self, nonce: &str, inner: W) -> Result<EncryptingWriter<W>> {
+        let nonce = decode_hex(nonce, NONCE_LEN)?;
+        let cipher = ChaCha20Poly1305::new(&self.key);
+        Ok(EncryptingWriter {
+            inner,
+            encryptor: Some(EncryptorBE32::from_aead(
+                cipher,
+                GenericArray::from_slice(&nonce),
+            )),
+            buffer: Vec::with_capacity(SEGMENT_LEN),
+        })
+    }
+
+    /// Decrypt segment by segment, writing each only after it authenticates
+    ///
+    /// A wrong passphrase fails on the first segment, before any output.
+    fn decrypt<R: Read, W: Write>(&self, nonce: &str, mut reader: R, mut writer: W) -> Result<()> {
+        let failed = || MemoError::Crypto("decryption failed (wrong passphrase?)".to_string());
+
+        let nonce = decode_hex(nonce, NONCE_LEN)?;
+        let cipher = ChaCha20Poly1305::new(&self.key);
+        let mut decryptor = DecryptorBE32::from_aead(cipher, GenericArray::from_slice(&nonce));
+
+        let mut current = vec![0u8; SEGMENT_LEN + TAG_LEN];
+        let mut next = vec![0u8; SEGMENT_LEN + TAG_LEN];
+        let mut current_len = read_full(&mut reader, &mut current)?;
+
+        loop {
+            let next_len = read_full(&mut reader, &mut next)?;
+            if next_len == 0 {
+                let plaintext = decryptor
+                    .decrypt_last(&current[..current_len])
+                    .map_err(|_| failed())?;
+                writer.write_all(&plaintext)?;
+                return Ok(());
+            }
+
+            let plaintext = decryptor
+                .decrypt_next(&current[..current_len])
+                .map_err(|_| failed())?;
+            writer.write_all(&plaintext)?;
+
+            std::mem::swap(&mut current, &mut next);
+            current_len = next_len;
+        }
+    }
+}
+
+/// A writer that encrypts everything written to it in STREAM segments
+///
+/// [`EncryptingWriter::finish`] must be called to write the final segment;
+/// without it the output is rejected as truncated on decryption. The last
+/// segment may be empty.
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    encryptor: Option<EncryptorBE32<ChaCha20Poly1305>>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// Encrypt the buffered tail as the final segment
+    ///
+    /// Further writes fail once the stream is finished.
+    pub fn finish(&mut self) -> io::Result<()> {
+        let encryptor = self.encryptor.take().ok_or_else(finished)?;
+        let ciphertext = encryptor
+            .encrypt_last(self.buffer.as_slice())
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        self.buffer.clear();
+        self.inner.write_all(&ciphertext)?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.encryptor.is_none() {
+            return Err(finished());
+        }
+        self.buffer.extend_from_slice(buf);
+
+        // A full trailing segment stays buffered until finish or more input
+        while self.buffer.len() > SEGMENT_LEN {
+            let encryptor = self.encryptor.as_mut().ok_or_else(finished)?;
+            let ciphertext = encryptor
+                .encrypt_next(&self.buffer[..SEGMENT_LEN])
+                .map_err(|_| io::Error::other("encryption failed"))?;
+            self.inner.write_all(&ciphertext)?;
+            self.buffer.drain(..SEGMENT_LEN);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn finished() -> io::Error {
+    io::Error::other("encrypted stream already finished")
+}
+
+/// Decode a hex field from metadata, checking its length
+fn decode_hex(text: &str, len: usize) -> Result<Vec<u8>> {
+    match hex::decode(text) {
+        Ok(bytes) if bytes.len() == len => Ok(bytes),
+        _ => Err(MemoError::Crypto("invalid encryption metadata".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(keys: &EntryKeys, data: &[u8]) -> Vec<u8> {
+        let mut writer = keys.encrypt_stdout(Vec::new()).unwrap();
+        // Uneven chunks exercise segment boundaries
+        for chunk in data.chunks(10_000) {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+        writer.inner
+    }
+
+    #[test]
+    fn test_roundtrip_across_segments() {
+        let keys = EntryKeys::generate("secret").unwrap();
+        for len in [0, 1, SEGMENT_LEN, SEGMENT_LEN + 1, 3 * SEGMENT_LEN + 7] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let ciphertext = encrypt(&keys, &data);
+            assert_ne!(ciphertext, data);
+
+            let mut plaintext = Vec::new();
+            keys.decrypt_stdout(ciphertext.as_slice(), &mut plaintext)
+                .unwrap();
+            assert_eq!(plaintext, data, "length {len}");
+        }
+    }
+
+    #[test]
+    fn test_wrong_passphrase_fails_without_output() {
+        let keys = EntryKeys::generate("secret").unwrap();
+        let ciphertext = encrypt(&keys, &vec![b'x'; 2 * SEGMENT_LEN]);
+
+        let wrong = EntryKeys::for_entry("guess", &keys.encryption).unwrap();
+        let mut plaintext = Vec::new();
+        let result = wrong.decrypt_stdout(ciphertext.as_slice(), &mut plaintext);
+
+        assert!(matches!(result, Err(MemoError::Crypto(_))));
+        assert!(plaintext.is_empty());
+    }
+
+    #[test]
+    fn test_truncation_is_detected() {
+        let keys = EntryKeys::generate("secret").unwrap();
+        let ciphertext = encrypt(&keys, &vec![b'x'; 2 * SEGMENT_LEN]);
+
+        // Drop the final segment
+        let truncated = &ciphertext[..SEGMENT_LEN + TAG_LEN];
+        let result = keys.decrypt_stdout(truncated, io::sink());
+        assert!(matches!(result, Err(MemoError::Crypto(_))));
+    }
+
+    #[test]
+    fn test_streams_use_distinct_nonces() {
+        let keys = EntryKeys::generate("secret").unwrap();
+        let mut writer = keys.encrypt_stderr(Vec::new()).unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer.finish().unwrap();
+
+        let result = keys.decrypt_stdout(writer.inner.as_slice(), io::sink());
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,209 @@
+//! Retroactive deduplication of cached output (`--dedupe`)
+//!
+//! Many commands produce identical output (empty stderr, the same `--help`
+//! text from different directories, ...). Deduplication finds byte-identical
+//! `stdout`/`stderr` files across entries and replaces the copies with hard
+//! links to one of them.
+//!
+//! Each replacement links the kept file into a scratch `.tmp.` directory next
+//! to the duplicate's entry and then renames it over the duplicate, so an
+//! entry always has a complete output file, even after a crash. A scratch
+//! directory left by a crash is reaped like any other temp directory.
+//!
+//! Other memo processes may remove or replace entries during a run, so files
+//! that disappear mid-scan are skipped.
+
+use crate::cache::iter_memos;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Output files considered for deduplication
+const OUTPUT_FILES: [&str; 2] = ["stdout", "stderr"];
+
+/// Summary of a [`dedupe`] run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupeReport {
+    /// Number of files replaced by hard links
+    pub linked: usize,
+    /// Total size of the replaced files
+    pub bytes: u64,
+}
+
+/// Replace identical output files across cache entries with hard links
+///
+/// Empty files and files that are already links to the kept copy are left
+/// alone. If the filesystem doesn't support hard links, the duplicate is kept
+/// and not counted.
+pub fn dedupe(cache_dir: &Path) -> io::Result<DedupeReport> {
+    // Group candidate files by size first, so only same-size files are hashed
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    for (_, dir, _) in iter_memos(cache_dir)? {
+        for name in OUTPUT_FILES {
+            let path = dir.join(name);
+            let Ok(metadata) = fs::symlink_metadata(&path) else {
+                continue;
+            };
+            if metadata.is_file() && metadata.len() > 0 {
+                by_size.entry(metadata.len()).or_default().push(path);
+            }
+        }
+    }
+
+    let mut report = DedupeReport::default();
+    for (size, paths) in by_size {
+        if paths.len() < 2 {
+            continue;
+        }
+
+        let mut by_hash: HashMap<Vec<u8>, PathBuf> = HashMap::new();
+        for path in paths {
+            let Some(hash) = skip_vanished(hash_file(&path))? else {
+                continue;
+            };
+            let Some(kept) = by_hash.get(&hash) else {
+                by_hash.insert(hash, path);
+                continue;
+            };
+            if skip_vanished(same_file(kept, &path))?.unwrap_or(true) {
+                continue;
+            }
+            if skip_vanished(link_over(kept, &path))?.unwrap_or(false) {
+                report.linked += 1;
+                report.bytes += size;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Turn an error for a file that no longer exists into `None`
+fn skip_vanished<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// SHA-256 of a file's contents
+fn hash_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Whether two paths are already the same file
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Atomically replace `duplicate` with a hard link to `kept`
+///
+/// Returns `Ok(false)` if the link could not be created (e.g. the filesystem
+/// doesn't support hard links), leaving `duplicate` untouched.
+fn link_over(kept: &Path, duplicate: &Path) -> io::Result<bool> {
+    let (Some(entry), Some(name)) = (duplicate.parent(), duplicate.file_name()) else {
+        return Ok(false);
+    };
+    let Some(digest) = entry.file_name() else {
+        return Ok(false);
+    };
+    let scratch_dir = entry.with_file_name(format!(
+        "{}.tmp.dedupe.{}",
+        digest.to_string_lossy(),
+        process::id()
+    ));
+    fs::create_dir(&scratch_dir)?;
+
+    let scratch = scratch_dir.join(name);
+    let linked = match fs::hard_link(kept, &scratch) {
+        Ok(()) => fs::rename(&scratch, duplicate).map(|()| true),
+        Err(_) => Ok(false),
+    };
+    let _ = fs::remove_dir_all(&scratch_dir);
+    linked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{ensure_cache_dir, stream_stdout, write_test_entry};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_dedupe_links_identical_outputs() {
+        let temp = TempDir::new().unwrap();
+        let cache_dir = temp.path().join("memo");
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        write_test_entry(&cache_dir, "dedupe1", b"same output", b"warning");
+        write_test_entry(&cache_dir, "dedupe2", b"same output", b"");
+        write_test_entry(&cache_dir, "dedupe3", b"same output", b"warning");
+        write_test_entry(&cache_dir, "dedupe4", b"different!!", b"");
+
+        let report = dedupe(&cache_dir).unwrap();
+        assert_eq!(report.linked, 3);
+        assert_eq!(report.bytes, 2 * 11 + 7);
+
+        for digest in ["dedupe1", "dedupe2", "dedupe3"] {
+            let mut out = Vec::new();
+            stream_stdout(&cache_dir, digest, &mut out).unwrap();
+            assert_eq!(out, b"same output");
+        }
+        let mut out = Vec::new();
+        stream_stdout(&cache_dir, "dedupe4", &mut out).unwrap();
+        assert_eq!(out, b"different!!");
+
+        // Already-linked files are not counted again
+        assert_eq!(dedupe(&cache_dir).unwrap(), DedupeReport::default());
+    }
+
+    #[test]
+    fn test_dedupe_leaves_no_scratch_files() {
+        let temp = TempDir::new().unwrap();
+        let cache_dir = temp.path().join("memo");
+        ensure_cache_dir(&cache_dir).unwrap();
+
+        write_test_entry(&cache_dir, "scratch1", b"output", b"");
+        write_test_entry(&cache_dir, "scratch2", b"output", b"");
+        dedupe(&cache_dir).unwrap();
+
+        let mut names: Vec<_> = fs::read_dir(cache_dir.join("scratch2"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["meta.json", "stderr", "stdout"]);
+
+        // Nor scratch directories next to the entries
+        let mut entries: Vec<_> = fs::read_dir(&cache_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, ["scratch1", "scratch2"]);
+    }
+
+    #[test]
+    fn test_skip_vanished() {
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(skip_vanished::<u8>(Err(missing)).unwrap(), None);
+        assert_eq!(skip_vanished(Ok(1)).unwrap(), Some(1));
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(skip_vanished::<u8>(Err(denied)).is_err());
+    }
+}
@@ -0,0 +1,388 @@
+//! Dependency files that feed into the cache key
+//!
+//! Dependencies are files or directories whose contents are hashed into the
+//! digest, so a cached entry is only reused while they are unchanged.
+//! Directories are walked recursively in sorted order so the hash is
+//! deterministic. A probe command's output can be hashed the same way, and so
+//! can the state of the enclosing git repository.
+
+use crate::digest::DigestAlgorithm;
+use crate::error::{MemoError, Result};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Hash the contents of a set of dependency paths
+///
+/// Each file contributes its path, length, and contents, so renames and
+/// moved bytes change the hash as well as edits. Paths are hashed in the
+/// order given. Returns an error if any path does not exist.
+pub fn hash_dependencies(paths: &[PathBuf], algorithm: DigestAlgorithm) -> Result<String> {
+    let mut hasher = algorithm.hasher();
+
+    for path in paths {
+        if !path.exists() {
+            return Err(MemoError::MissingDependency(path.clone()));
+        }
+
+        for file in list_files(path)? {
+            let len = fs::metadata(&file)?.len();
+            hasher.update(serde_json::to_vec(&file.to_string_lossy())?);
+            hasher.update(len.to_le_bytes());
+            io::copy(&mut File::open(&file)?, &mut hasher)?;
+        }
+    }
+
+    Ok(hasher.finalize_hex())
+}
+
+/// Hash the size and modification time of each file under a set of
+/// dependency paths
+///
+/// A cheaper stand-in for [`hash_dependencies`] that never reads file
+/// contents, so an edit that keeps both the size and the mtime goes unnoticed.
+/// Returns an error if any path does not exist.
+pub fn hash_dependency_stats(paths: &[PathBuf], algorithm: DigestAlgorithm) -> Result<String> {
+    let mut hasher = algorithm.hasher();
+
+    for path in paths {
+        if !path.exists() {
+            return Err(MemoError::MissingDependency(path.clone()));
+        }
+
+        for file in list_files(path)? {
+            let metadata = fs::metadata(&file)?;
+            let mtime = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            hasher.update(serde_json::to_vec(&file.to_string_lossy())?);
+            hasher.update(metadata.len().to_le_bytes());
+            hasher.update(mtime.as_nanos().to_le_bytes());
+        }
+    }
+
+    Ok(hasher.finalize_hex())
+}
+
+/// Hash the contents of a pre-computed key file
+///
+/// Unlike [`hash_dependencies`], only the contents count, so the same key
+/// written to a different path gives the same hash.
+pub fn hash_key_file(path: &Path, algorithm: DigestAlgorithm) -> Result<String> {
+    hash_contents(path, algorithm).map_err(|e| MemoError::KeyFile(path.to_path_buf(), e))
+}
+
+/// Hash the contents of the file given as the command's stdin
+pub fn hash_stdin_file(path: &Path, algorithm: DigestAlgorithm) -> Result<String> {
+    hash_contents(path, algorithm).map_err(|e| MemoError::StdinFile(path.to_path_buf(), e))
+}
+
+/// Hash a single file's contents, streaming it rather than reading it whole
+fn hash_contents(path: &Path, algorithm: DigestAlgorithm) -> io::Result<String> {
+    let mut hasher = algorithm.hasher();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize_hex())
+}
+
+/// Run a probe shell command and hash its stdout
+///
+/// The probe is run with `sh -c` every time (it is not memoized) and its
+/// stderr is passed through. A probe that cannot be started or exits
+/// unsuccessfully is an error, so a broken probe never keys on empty output.
+pub fn hash_probe_output(probe: &str, algorithm: DigestAlgorithm) -> Result<String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(probe)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| MemoError::Probe(format!("`{probe}`: {e}")))?;
+
+    if !output.status.success() {
+        return Err(MemoError::Probe(format!(
+            "`{probe}` exited with {}",
+            output.status
+        )));
+    }
+
+    let mut hasher = algorithm.hasher();
+    hasher.update(&output.stdout);
+    Ok(hasher.finalize_hex())
+}
+
+/// The checked-out commit of a git work tree and whether it has changes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitState {
+    /// Full hash of `HEAD`
+    pub head: String,
+    /// Whether `git status --porcelain` reports anything (including untracked
+    /// files)
+    pub dirty: bool,
+}
+
+/// Read the git state of the work tree containing `dir`
+///
+/// Returns `None` if `dir` is not inside a git work tree, the repository has
+/// no commits yet, or `git` can't be run.
+pub fn git_state(dir: &Path) -> Option<GitState> {
+    let git = |args: &[&str]| {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        output.status.success().then_some(output.stdout)
+    };
+
+    let head = String::from_utf8(git(&["rev-parse", "--verify", "HEAD"])?).ok()?;
+    let status = git(&["status", "--porcelain"])?;
+    Some(GitState {
+        head: head.trim().to_string(),
+        dirty: !status.is_empty(),
+    })
+}
+
+/// List the files under a dependency path in sorted order
+///
+/// A file yields just itself; a directory yields every file beneath it.
+fn list_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut children: Vec<PathBuf> = fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<_>>()?;
+    children.sort();
+
+    let mut files = Vec::new();
+    for child in children {
+        files.extend(list_files(&child)?);
+    }
+    Ok(files)
+}
+
+/// A point-in-time view of dependency files, used to detect changes
+///
+/// Records each file's size and modification time. Unreadable or missing
+/// paths are recorded as absent, so their reappearance is also a change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot(Vec<(PathBuf, Option<(u64, SystemTime)>)>);
+
+/// Take a snapshot of the files under a set of dependency paths
+pub fn snapshot(paths: &[PathBuf]) -> Snapshot {
+    let mut entries = Vec::new();
+
+    for path in paths {
+        let files = list_files(path).unwrap_or_else(|_| vec![path.clone()]);
+        for file in files {
+            let stat = fs::metadata(&file)
+                .and_then(|m| Ok((m.len(), m.modified()?)))
+                .ok();
+            entries.push((file, stat));
+        }
+    }
+
+    Snapshot(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const SHA256: DigestAlgorithm = DigestAlgorithm::Sha256;
+
+    #[test]
+    fn test_hash_changes_with_contents() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("schema.sql");
+
+        let paths = vec![file.clone()];
+
+        fs::write(&file, "create table a;").unwrap();
+        let hash1 = hash_dependencies(&paths, SHA256).unwrap();
+        let hash2 = hash_dependencies(&paths, SHA256).unwrap();
+        assert_eq!(hash1, hash2);
+
+        fs::write(&file, "create table b;").unwrap();
+        let hash3 = hash_dependencies(&paths, SHA256).unwrap();
+        assert_ne!(hash1, hash3);
+    }
+
+    #[test]
+    fn test_hash_directory_contents() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("src");
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("a.rs"), "a").unwrap();
+        fs::write(dir.join("nested").join("b.rs"), "b").unwrap();
+
+        let paths = vec![dir.clone()];
+        let hash1 = hash_dependencies(&paths, SHA256).unwrap();
+
+        fs::write(dir.join("nested").join("b.rs"), "changed").unwrap();
+        let hash2 = hash_dependencies(&paths, SHA256).unwrap();
+        assert_ne!(hash1, hash2);
+
+        fs::write(dir.join("c.rs"), "").unwrap();
+        let hash3 = hash_dependencies(&paths, SHA256).unwrap();
+        assert_ne!(hash2, hash3);
+    }
+
+    #[test]
+    fn test_hash_stats_change_with_mtime_not_contents() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("schema.sql");
+        let paths = vec![file.clone()];
+        let touch = |secs| {
+            File::options()
+                .write(true)
+                .open(&file)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + std::time::Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        fs::write(&file, "create table a;").unwrap();
+        touch(1_000_000);
+        let hash1 = hash_dependency_stats(&paths, SHA256).unwrap();
+
+        // Same size and mtime: not noticed
+        fs::write(&file, "create table b;").unwrap();
+        touch(1_000_000);
+        assert_eq!(hash1, hash_dependency_stats(&paths, SHA256).unwrap());
+
+        touch(2_000_000);
+        assert_ne!(hash1, hash_dependency_stats(&paths, SHA256).unwrap());
+
+        let err = hash_dependency_stats(&[temp.path().join("missing")], SHA256).unwrap_err();
+        assert!(matches!(err, MemoError::MissingDependency(_)));
+    }
+
+    #[test]
+    fn test_hash_missing_dependency_fails() {
+        let temp = TempDir::new().unwrap();
+        let missing = temp.path().join("missing");
+
+        let err = hash_dependencies(&[missing], SHA256).unwrap_err();
+        assert!(matches!(err, MemoError::MissingDependency(_)));
+    }
+
+    #[test]
+    fn test_hash_key_file_uses_contents_only() {
+        let temp = TempDir::new().unwrap();
+        let a = temp.path().join("a.key");
+        let b = temp.path().join("b.key");
+        fs::write(&a, "lock 1a2b").unwrap();
+        fs::write(&b, "lock 1a2b").unwrap();
+
+        let hash = hash_key_file(&a, SHA256).unwrap();
+        assert_eq!(hash, hash_key_file(&a, SHA256).unwrap());
+        assert_eq!(hash, hash_key_file(&b, SHA256).unwrap());
+
+        fs::write(&b, "lock 3c4d").unwrap();
+        assert_ne!(hash, hash_key_file(&b, SHA256).unwrap());
+
+        let err = hash_key_file(&temp.path().join("missing"), SHA256).unwrap_err();
+        assert!(matches!(err, MemoError::KeyFile(..)));
+    }
+
+    #[test]
+    fn test_hash_follows_algorithm() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("input");
+        fs::write(&file, "data").unwrap();
+
+        let sha256 = hash_stdin_file(&file, SHA256).unwrap();
+        let blake3 = hash_stdin_file(&file, DigestAlgorithm::Blake3).unwrap();
+        assert_eq!(sha256.len(), 64);
+        assert_eq!(blake3.len(), 64);
+        assert_ne!(sha256, blake3);
+    }
+
+    #[test]
+    fn test_hash_probe_output() {
+        let v1 = hash_probe_output("echo v1", SHA256).unwrap();
+        assert_eq!(v1, hash_probe_output("printf 'v1\\n'", SHA256).unwrap());
+        assert_ne!(v1, hash_probe_output("echo v2", SHA256).unwrap());
+    }
+
+    #[test]
+    fn test_hash_probe_failure() {
+        let err = hash_probe_output("exit 3", SHA256).unwrap_err();
+        assert!(matches!(err, MemoError::Probe(_)));
+    }
+
+    #[test]
+    fn test_list_files_sorted() {
+        let temp = TempDir::new().unwrap();
+        fs::write(temp.path().join("b"), "").unwrap();
+        fs::write(temp.path().join("a"), "").unwrap();
+
+        let files = list_files(temp.path()).unwrap();
+        assert_eq!(files, vec![temp.path().join("a"), temp.path().join("b")]);
+    }
+
+    #[test]
+    fn test_snapshot_detects_changes() {
+        let temp = TempDir::new().unwrap();
+        let file = temp.path().join("input");
+        fs::write(&file, "one").unwrap();
+
+        let paths = vec![file.clone()];
+        let before = snapshot(&paths);
+        assert_eq!(before, snapshot(&paths));
+
+        fs::write(&file, "three").unwrap();
+        assert_ne!(before, snapshot(&paths));
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=memo", "-c", "user.email=memo@example.com"])
+            .args(args)
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_git_state_tracks_commits_and_changes() {
+        let temp = TempDir::new().unwrap();
+        let repo = temp.path();
+        git(repo, &["init", "-q"]);
+        fs::write(repo.join("file"), "one").unwrap();
+        git(repo, &["add", "file"]);
+        git(repo, &["commit", "-q", "-m", "one"]);
+
+        let first = git_state(repo).unwrap();
+        assert_eq!(first.head.len(), 40);
+        assert!(!first.dirty);
+
+        fs::write(repo.join("file"), "two").unwrap();
+        let edited = git_state(repo).unwrap();
+        assert_eq!(edited.head, first.head);
+        assert!(edited.dirty);
+
+        git(repo, &["commit", "-q", "-am", "two"]);
+        let second = git_state(repo).unwrap();
+        assert_ne!(second.head, first.head);
+        assert!(!second.dirty);
+    }
+
+    #[test]
+    fn test_git_state_outside_repo() {
+        let temp = TempDir::new().unwrap();
+        assert_eq!(git_state(temp.path()), None);
+    }
+}
@@ -0,0 +1,225 @@
+//! memo's own diagnostic lines on stderr (`:: memo :: ...`)
+//!
+//! Like tracing, whether diagnostics are colored or written as JSON is a
+//! process-wide setting, so any module can print them with the [`diag!`]
+//! macro. Only memo's prefix is colored; the command's own output is never
+//! touched.
+//!
+//! With `--log-format json`, each diagnostic is instead one JSON object per
+//! line:
+//!
+//! ```text
+//! {"level":"info","event":"miss","command":"make","digest":"3f2a…","message":"miss `make` => 3f2a…"}
+//! {"level":"warning","event":"message","message":"remote cache unavailable: …"}
+//! ```
+//!
+//! `level` is `info`, `warning`, `error`, or `trace`. Cache lookups have their
+//! own `event` (`hit`, `miss`, `stale`, `fetched`, `cold-start`, `sample`)
+//! and carry the `command` and `digest`; everything else is a
+//! `message`.
+//!
+//! `--quiet` turns all of them off, leaving stderr to the command.
+
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The prefix of every diagnostic line
+pub const PREFIX: &str = ":: memo ::";
+
+/// The prefix in bold blue
+const COLORED_PREFIX: &str = "\x1b[1;34m:: memo ::\x1b[0m";
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// How diagnostics are written (`--log-format`)
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `:: memo ::` lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// One diagnostic in `--log-format json`
+#[derive(Serialize)]
+struct Record<'a> {
+    level: &'a str,
+    event: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    digest: Option<&'a str>,
+    message: &'a str,
+}
+
+/// When to color diagnostics (`--color`)
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color when stderr is a terminal and `NO_COLOR` is not set
+    #[default]
+    Auto,
+    /// Always color, even when stderr is redirected
+    Always,
+    /// Never color
+    Never,
+}
+
+impl ColorChoice {
+    /// Decide whether to color, given the terminal and `NO_COLOR` state
+    ///
+    /// `NO_COLOR` only counts when it is non-empty, per <https://no-color.org>.
+    fn resolve(self, is_terminal: bool, no_color: Option<&str>) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => is_terminal && no_color.is_none_or(str::is_empty),
+        }
+    }
+}
+
+/// Set whether diagnostics are colored for the rest of the process
+pub fn set_color(choice: ColorChoice) {
+    let no_color = std::env::var("NO_COLOR").ok();
+    let color = choice.resolve(io::stderr().is_terminal(), no_color.as_deref());
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+/// Set how diagnostics are written for the rest of the process
+pub fn set_log_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// Set whether diagnostics are suppressed for the rest of the process
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Whether diagnostics are written as JSON
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Write one JSON diagnostic line
+pub fn emit_json(
+    level: &str,
+    event: &str,
+    command: Option<&str>,
+    digest: Option<&str>,
+    message: &str,
+) {
+    let record = Record {
+        level,
+        event,
+        command,
+        digest,
+        message,
+    };
+    if let Ok(line) = serde_json::to_string(&record) {
+        eprintln!("{line}");
+    }
+}
+
+/// Split the level off a message like `WARNING: ...`
+fn split_level(message: &str) -> (&str, &str) {
+    if let Some(rest) = message.strip_prefix("WARNING: ") {
+        ("warning", rest)
+    } else if let Some(rest) = message.strip_prefix("ERROR: ") {
+        ("error", rest)
+    } else {
+        ("info", message)
+    }
+}
+
+/// The diagnostic prefix, colored if enabled
+pub fn prefix() -> &'static str {
+    if COLOR.load(Ordering::Relaxed) {
+        COLORED_PREFIX
+    } else {
+        PREFIX
+    }
+}
+
+/// Write one diagnostic line; use the [`diag!`] macro instead
+pub fn emit(message: fmt::Arguments<'_>) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    if is_json() {
+        let message = message.to_string();
+        let (level, message) = split_level(&message);
+        emit_json(level, "message", None, None, message);
+    } else {
+        eprintln!("{} {message}", prefix());
+    }
+}
+
+/// Write one cache lookup event; use the [`diag!`] macro instead
+pub fn emit_event(event: &str, command: &str, digest: &str, message: fmt::Arguments<'_>) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    if is_json() {
+        emit_json(
+            "info",
+            event,
+            Some(command),
+            Some(digest),
+            &message.to_string(),
+        );
+    } else {
+        eprintln!("{} {message}", prefix());
+    }
+}
+
+/// Print a formatted diagnostic line to stderr after the `:: memo ::` prefix
+///
+/// Cache lookup events name the event, command, and digest first, for
+/// `--log-format json`:
+///
+/// ```ignore
+/// diag!(event = "miss", command = &cmd, digest = &digest; "miss `{cmd}`");
+/// ```
+macro_rules! diag {
+    (event = $event:expr, command = $command:expr, digest = $digest:expr; $($arg:tt)*) => {
+        $crate::diag::emit_event($event, $command, $digest, format_args!($($arg)*))
+    };
+    ($($arg:tt)*) => {
+        $crate::diag::emit(format_args!($($arg)*))
+    };
+}
+
+pub(crate) use diag;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_colors_only_terminals_without_no_color() {
+        assert!(ColorChoice::Auto.resolve(true, None));
+        assert!(ColorChoice::Auto.resolve(true, Some("")));
+        assert!(!ColorChoice::Auto.resolve(true, Some("1")));
+        assert!(!ColorChoice::Auto.resolve(false, None));
+    }
+
+    #[test]
+    fn test_split_level() {
+        assert_eq!(split_level("WARNING: slow"), ("warning", "slow"));
+        assert_eq!(split_level("ERROR: failed"), ("error", "failed"));
+        assert_eq!(split_level("hit `ls`"), ("info", "hit `ls`"));
+    }
+
+    #[test]
+    fn test_explicit_choice_overrides_terminal_and_no_color() {
+        assert!(ColorChoice::Always.resolve(false, Some("1")));
+        assert!(!ColorChoice::Never.resolve(true, None));
+    }
+}
@@ -3,9 +3,24 @@
 //! This module handles SHA-256 digest computation for command memoization.
 //! The digest includes both the command arguments and the current working directory
 //! to ensure different contexts produce different cache entries.
+//!
+//! BLAKE3 can be selected instead (`--digest blake3`). Its digests carry a
+//! `blake3` tag in front of the hex, so they can never collide with SHA-256
+//! digests, which stay untagged for compatibility with existing caches.
 
 use crate::error::Result;
+use crate::executor::resolve_program_in;
+use crate::trace::trace;
+use clap::ValueEnum;
+use serde::Deserialize;
 use sha2::{Digest, Sha256};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Tag in front of BLAKE3 digests; not hex, so it can't begin a SHA-256 digest
+const BLAKE3_TAG: &str = "blake3";
 
 /// Compute SHA-256 digest for command arguments and working directory
 ///
@@ -22,25 +37,221 @@ use sha2::{Digest, Sha256};
 ///
 /// Returns a hex-encoded SHA-256 digest (64 characters).
 ///
-/// # Examples
-///
-/// ```
-/// # use memo::digest::compute_digest_for_args;
-/// let args = vec!["echo".to_string(), "hello".to_string()];
-/// let digest = compute_digest_for_args(&args, "/home/user").unwrap();
-/// assert_eq!(digest.len(), 64);
-/// assert!(digest.chars().all(|c| c.is_ascii_hexdigit()));
-/// ```
+/// Thin wrapper around [`cache_key`] with default options, kept for
+/// compatibility.
+#[allow(dead_code)] // memo itself calls `cache_key`
 pub fn compute_digest_for_args(args: &[String], cwd: &str) -> Result<String> {
+    compute_digest(args, cwd, &[])
+}
+
+/// Compute SHA-256 digest for command arguments, working directory, and extra
+/// key material
+///
+/// Thin wrapper around [`cache_key`] with only `extras` set, kept for
+/// compatibility.
+#[allow(dead_code)] // memo itself calls `cache_key`
+pub fn compute_digest(args: &[String], cwd: &str, extras: &[(String, String)]) -> Result<String> {
+    let opts = DigestOptions {
+        extras: extras.to_vec(),
+        ..DigestOptions::default()
+    };
+    cache_key(args, cwd, &opts)
+}
+
+/// Hash algorithm used to compute cache keys (`--digest`)
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    /// SHA-256, hex-encoded (64 characters)
+    #[default]
+    Sha256,
+    /// BLAKE3, hex-encoded behind a `blake3` tag (70 characters); faster on
+    /// large inputs
+    Blake3,
+}
+
+impl DigestAlgorithm {
+    /// Start a new hash with this algorithm
+    pub fn hasher(self) -> Hasher {
+        match self {
+            DigestAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            DigestAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+        }
+    }
+}
+
+/// An in-progress hash for either [`DigestAlgorithm`]
+///
+/// Implements [`Write`] so files can be streamed into it with `io::copy`.
+pub enum Hasher {
+    /// SHA-256 state
+    Sha256(Sha256),
+    /// BLAKE3 state, boxed since it is far larger than SHA-256's
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    /// Add bytes to the hash
+    pub fn update(&mut self, data: impl AsRef<[u8]>) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data.as_ref());
+            }
+        }
+    }
+
+    /// Finish the hash as plain hex, without an algorithm tag
+    pub fn finalize_hex(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => hex::encode(hasher.finalize()),
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The hex part of a digest, without any algorithm tag
+///
+/// Shard directories are named from this, so tagged digests spread across
+/// them as evenly as untagged ones.
+pub fn untagged(digest: &str) -> &str {
+    digest.strip_prefix(BLAKE3_TAG).unwrap_or(digest)
+}
+
+/// Everything besides argv and cwd that influences a cache key
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DigestOptions {
+    /// Environment variables to include, as `(name, value)` pairs; `None` is
+    /// an unset variable, which hashes differently from an empty one
+    pub env: Vec<(String, Option<String>)>,
+    /// `(name, value)` pairs contributed by key-influencing options (e.g.
+    /// dependency hashes)
+    pub extras: Vec<(String, String)>,
+    /// Hash algorithm
+    pub algorithm: DigestAlgorithm,
+}
+
+/// Compute the cache key for a command without touching the filesystem
+///
+/// With default options the key is identical to [`compute_digest_for_args`],
+/// so existing entries stay valid. Environment variables and extras are only
+/// hashed when present.
+pub fn cache_key(args: &[String], cwd: &str, opts: &DigestOptions) -> Result<String> {
     // Hash a canonical encoding of argv and cwd to avoid collisions like:
     // ["echo", "a b"] vs ["echo", "a", "b"].
     let encoded_args = serde_json::to_vec(args)?;
     let encoded_cwd = serde_json::to_vec(cwd)?;
-    let mut hasher = Sha256::new();
+
+    let mut hasher = opts.algorithm.hasher();
     hasher.update(&encoded_args);
     hasher.update(&encoded_cwd);
-    let result = hasher.finalize();
-    Ok(hex::encode(result))
+    if !opts.extras.is_empty() {
+        hasher.update(serde_json::to_vec(&opts.extras)?);
+    }
+    if !opts.env.is_empty() {
+        hasher.update(serde_json::to_vec(&("env", &opts.env))?);
+    }
+
+    let hex = hasher.finalize_hex();
+    let digest = match opts.algorithm {
+        DigestAlgorithm::Sha256 => hex,
+        DigestAlgorithm::Blake3 => format!("{BLAKE3_TAG}{hex}"),
+    };
+    trace!(
+        "digest {digest} from argv {} cwd {} extras {:?} env {:?}",
+        String::from_utf8_lossy(&encoded_args),
+        String::from_utf8_lossy(&encoded_cwd),
+        opts.extras,
+        opts.env
+    );
+    Ok(digest)
+}
+
+/// The part of the working directory used as the key with `--cwd-basename`
+///
+/// Returns the final path component, or the whole path for a directory
+/// without one (the root). Since real working directories are absolute, a
+/// basename never collides with a full path.
+pub fn cwd_basename(cwd: &str) -> &str {
+    Path::new(cwd)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(cwd)
+}
+
+/// Replace arguments that name existing paths with their canonical form
+///
+/// Relative paths are resolved against `cwd`, so `./file`, `file`, and
+/// `/abs/file` (or a symlink to it) all become `/abs/file`. Arguments that
+/// don't resolve to an existing path, or whose canonical form isn't valid
+/// UTF-8, are returned unchanged.
+pub fn canonicalize_path_args(args: &[String], cwd: &str) -> Vec<String> {
+    args.iter()
+        .map(|arg| {
+            if arg.is_empty() {
+                return arg.clone();
+            }
+            fs::canonicalize(Path::new(cwd).join(arg))
+                .ok()
+                .and_then(|path| path.to_str().map(str::to_string))
+                .unwrap_or_else(|| arg.clone())
+        })
+        .collect()
+}
+
+/// Sort the flags of a command so their order doesn't change the key
+///
+/// Arguments after the program name that start with `-` (up to a `--`) are
+/// flags and are sorted; everything else keeps its order and goes after them.
+/// A flag's separate value (the `out` in `-o out`) counts as an operand, so
+/// `-o a -I b` and `-I a -o b` share a key. Only use this for commands where
+/// that can't matter.
+pub fn sort_flags(args: &[String]) -> Vec<String> {
+    let Some((program, rest)) = args.split_first() else {
+        return Vec::new();
+    };
+
+    let end = rest
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(rest.len());
+    let (mut flags, mut operands): (Vec<String>, Vec<String>) = rest[..end]
+        .iter()
+        .cloned()
+        .partition(|arg| arg.len() > 1 && arg.starts_with('-'));
+    flags.sort();
+    operands.extend_from_slice(&rest[end..]);
+
+    let mut sorted = vec![program.clone()];
+    sorted.extend(flags);
+    sorted.extend(operands);
+    sorted
+}
+
+/// Replace the program name with the absolute path it resolves to
+///
+/// The name is looked up like [`resolve_program_in`] does, and a relative
+/// result is made absolute against `cwd`. Symlinks are not followed, so this
+/// keys on where the program was found rather than on what it is. Returns
+/// `None` if the program can't be found.
+pub fn resolve_argv0(args: &[String], cwd: &str, path_var: Option<&OsStr>) -> Option<Vec<String>> {
+    let (program, rest) = args.split_first()?;
+    let resolved = Path::new(cwd).join(resolve_program_in(program, path_var)?);
+
+    let mut resolved_args = vec![resolved.to_str()?.to_string()];
+    resolved_args.extend_from_slice(rest);
+    Some(resolved_args)
 }
 
 #[cfg(test)]
@@ -81,6 +292,28 @@ mod tests {
         assert!(digest.chars().all(|c| c.is_ascii_hexdigit()));
     }
 
+    #[test]
+    fn test_blake3_digest_is_tagged() {
+        let args: Vec<String> = vec!["echo".into(), "hello".into()];
+        let opts = DigestOptions {
+            algorithm: DigestAlgorithm::Blake3,
+            ..DigestOptions::default()
+        };
+        let digest = cache_key(&args, TEST_CWD, &opts).unwrap();
+
+        assert_eq!(digest.len(), 70);
+        let hex = digest.strip_prefix("blake3").unwrap();
+        assert!(hex.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(untagged(&digest), hex);
+        assert_ne!(hex, digest_for_args(&args));
+    }
+
+    #[test]
+    fn test_untagged_leaves_sha256_digests_alone() {
+        let digest = digest_for_command("echo test");
+        assert_eq!(untagged(&digest), digest);
+    }
+
     #[test]
     fn test_digest_whitespace_collapses() {
         let digest1 = digest_for_command("echo   hello");
@@ -139,6 +372,252 @@ mod tests {
         assert_eq!(digest1, digest2);
     }
 
+    #[test]
+    fn test_digest_without_extras_matches_args_digest() {
+        let args: Vec<String> = vec!["echo".into(), "hello".into()];
+        let plain = compute_digest_for_args(&args, TEST_CWD).unwrap();
+        let with_none = compute_digest(&args, TEST_CWD, &[]).unwrap();
+        assert_eq!(plain, with_none);
+    }
+
+    #[test]
+    fn test_digest_extras_change_output() {
+        let args: Vec<String> = vec!["echo".into(), "hello".into()];
+        let extra1 = vec![("depends-on".to_string(), "aaa".to_string())];
+        let extra2 = vec![("depends-on".to_string(), "bbb".to_string())];
+
+        let plain = compute_digest(&args, TEST_CWD, &[]).unwrap();
+        let digest1 = compute_digest(&args, TEST_CWD, &extra1).unwrap();
+        let digest2 = compute_digest(&args, TEST_CWD, &extra2).unwrap();
+
+        assert_ne!(plain, digest1);
+        assert_ne!(digest1, digest2);
+        assert_eq!(digest1, compute_digest(&args, TEST_CWD, &extra1).unwrap());
+    }
+
+    #[test]
+    fn test_digest_follows_cache_key_file_contents() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let key_file = temp.path().join("lock.hash");
+        let args: Vec<String> = vec!["cargo".into(), "build".into()];
+        let digest = || {
+            let hash = crate::deps::hash_key_file(&key_file, DigestAlgorithm::Sha256).unwrap();
+            compute_digest(&args, TEST_CWD, &[("cache-key-file".to_string(), hash)]).unwrap()
+        };
+
+        std::fs::write(&key_file, "toolchain 1.80\n").unwrap();
+        let digest1 = digest();
+        assert_eq!(digest1, digest());
+        assert_ne!(digest1, compute_digest(&args, TEST_CWD, &[]).unwrap());
+
+        std::fs::write(&key_file, "toolchain 1.81\n").unwrap();
+        assert_ne!(digest1, digest());
+    }
+
+    #[test]
+    fn test_sort_flags_shares_digest_across_flag_orders() {
+        let a = split("gcc -O2 -Wall main.c util.c").unwrap();
+        let b = split("gcc -Wall main.c -O2 util.c").unwrap();
+
+        assert_ne!(digest_for_args(&a), digest_for_args(&b));
+        assert_eq!(
+            digest_for_args(&sort_flags(&a)),
+            digest_for_args(&sort_flags(&b))
+        );
+
+        // Operand order still matters
+        let c = split("gcc -O2 -Wall util.c main.c").unwrap();
+        assert_ne!(
+            digest_for_args(&sort_flags(&a)),
+            digest_for_args(&sort_flags(&c))
+        );
+    }
+
+    #[test]
+    fn test_sort_flags_stops_at_double_dash() {
+        let args = split("cmd -b x -a -- -z -y").unwrap();
+        assert_eq!(
+            sort_flags(&args),
+            vec!["cmd", "-a", "-b", "x", "--", "-z", "-y"]
+        );
+        assert_eq!(sort_flags(&[]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_cache_key_default_options_match_args_digest() {
+        let args: Vec<String> = vec!["echo".into(), "hello".into()];
+        let plain = compute_digest_for_args(&args, TEST_CWD).unwrap();
+        let key = cache_key(&args, TEST_CWD, &DigestOptions::default()).unwrap();
+        assert_eq!(plain, key);
+    }
+
+    #[test]
+    fn test_cache_key_env_changes_output() {
+        let args: Vec<String> = vec!["env".into()];
+        let with_env = |value: &str| DigestOptions {
+            env: vec![("LANG".to_string(), Some(value.to_string()))],
+            ..DigestOptions::default()
+        };
+
+        let plain = cache_key(&args, TEST_CWD, &DigestOptions::default()).unwrap();
+        let digest1 = cache_key(&args, TEST_CWD, &with_env("C")).unwrap();
+        let digest2 = cache_key(&args, TEST_CWD, &with_env("en_US.UTF-8")).unwrap();
+
+        assert_ne!(plain, digest1);
+        assert_ne!(digest1, digest2);
+    }
+
+    #[test]
+    fn test_cache_key_unset_env_differs_from_empty() {
+        let args: Vec<String> = vec!["env".into()];
+        let with_foo = |value: Option<&str>| DigestOptions {
+            env: vec![("FOO".to_string(), value.map(str::to_string))],
+            ..DigestOptions::default()
+        };
+
+        assert_ne!(
+            cache_key(&args, TEST_CWD, &with_foo(None)).unwrap(),
+            cache_key(&args, TEST_CWD, &with_foo(Some(""))).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cache_key_env_and_extras_are_distinct() {
+        let args: Vec<String> = vec!["echo".into()];
+        let pair = vec![("NAME".to_string(), "value".to_string())];
+        let as_env = DigestOptions {
+            env: vec![("NAME".to_string(), Some("value".to_string()))],
+            ..DigestOptions::default()
+        };
+        let as_extra = DigestOptions {
+            extras: pair,
+            ..DigestOptions::default()
+        };
+
+        assert_ne!(
+            cache_key(&args, TEST_CWD, &as_env).unwrap(),
+            cache_key(&args, TEST_CWD, &as_extra).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cache_key_extras_match_compute_digest() {
+        let args: Vec<String> = vec!["echo".into()];
+        let extras = vec![("pty".to_string(), "true".to_string())];
+        let opts = DigestOptions {
+            extras: extras.clone(),
+            ..DigestOptions::default()
+        };
+
+        assert_eq!(
+            cache_key(&args, TEST_CWD, &opts).unwrap(),
+            compute_digest(&args, TEST_CWD, &extras).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cwd_basename_shares_digest_across_parents() {
+        let args: Vec<String> = vec!["make".into()];
+        let digest = |cwd: &str| compute_digest_for_args(&args, cwd_basename(cwd)).unwrap();
+
+        assert_eq!(digest("/home/me/work/projA"), digest("/tmp/projA"));
+        assert_eq!(digest("/tmp/projA/"), digest("/tmp/projA"));
+        assert_ne!(digest("/tmp/projA"), digest("/tmp/projB"));
+    }
+
+    #[test]
+    fn test_cwd_basename_of_root() {
+        assert_eq!(cwd_basename("/"), "/");
+        assert_eq!(cwd_basename("/usr"), "usr");
+    }
+
+    #[test]
+    fn test_canonicalized_relative_and_absolute_paths_match() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cwd = fs::canonicalize(dir.path()).unwrap();
+        let cwd = cwd.to_str().unwrap();
+        fs::write(dir.path().join("file"), "").unwrap();
+        let absolute = format!("{cwd}/file");
+
+        let digest = |arg: &str| {
+            let args = canonicalize_path_args(&["cat".into(), arg.into()], cwd);
+            compute_digest_for_args(&args, cwd).unwrap()
+        };
+
+        assert_eq!(digest("./file"), digest(&absolute));
+        assert_eq!(digest("file"), digest(&absolute));
+        assert_ne!(
+            compute_digest_for_args(&["cat".into(), "./file".into()], cwd).unwrap(),
+            compute_digest_for_args(&["cat".into(), absolute.clone()], cwd).unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_canonicalize_resolves_symlinks() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cwd = dir.path().to_str().unwrap();
+        fs::write(dir.path().join("target"), "").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("target"), dir.path().join("link")).unwrap();
+
+        assert_eq!(
+            canonicalize_path_args(&["link".into()], cwd),
+            canonicalize_path_args(&["target".into()], cwd)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_argv0_distinguishes_path_entries() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let (bin_a, bin_b) = (dir.path().join("a"), dir.path().join("b"));
+        for bin in [&bin_a, &bin_b] {
+            fs::create_dir(bin).unwrap();
+            let tool = bin.join("tool");
+            fs::write(&tool, "#!/bin/sh\n").unwrap();
+            fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let args: Vec<String> = vec!["tool".into(), "--flag".into()];
+        let digest = |path_var: &Path| {
+            let resolved = resolve_argv0(&args, TEST_CWD, Some(path_var.as_os_str())).unwrap();
+            compute_digest_for_args(&resolved, TEST_CWD).unwrap()
+        };
+
+        assert_ne!(digest(&bin_a), digest(&bin_b));
+        assert_eq!(digest(&bin_a), digest(&bin_a));
+
+        let resolved = resolve_argv0(&args, TEST_CWD, Some(bin_b.as_os_str())).unwrap();
+        assert_eq!(resolved, [bin_b.join("tool").to_str().unwrap(), "--flag"]);
+    }
+
+    #[test]
+    fn test_resolve_argv0_unresolvable() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let args: Vec<String> = vec!["no-such-tool".into()];
+        assert_eq!(
+            resolve_argv0(&args, TEST_CWD, Some(dir.path().as_os_str())),
+            None
+        );
+        assert_eq!(resolve_argv0(&[], TEST_CWD, None), None);
+    }
+
+    #[test]
+    fn test_canonicalize_leaves_non_paths_unchanged() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cwd = dir.path().to_str().unwrap();
+        let args: Vec<String> = vec![
+            "echo".into(),
+            "./missing".into(),
+            "--flag".into(),
+            "".into(),
+        ];
+
+        assert_eq!(canonicalize_path_args(&args, cwd), args);
+    }
+
     #[test]
     fn test_digest_special_characters_are_preserved() {
         let digest1 = digest_for_command("echo \"hello\" 'world' $USER");
@@ -1,6 +1,7 @@
 //! Error types for the memo application
 
 use std::io;
+use std::path::PathBuf;
 use thiserror::Error;
 
 /// The main error type for memo operations
@@ -25,6 +26,58 @@ pub enum MemoError {
     /// Invalid command
     #[error("Invalid command: {0}")]
     InvalidCommand(String),
+
+    /// Config file could not be read or parsed
+    #[error("Config error: {0}")]
+    Config(String),
+
+    /// A dependency path given on the command line does not exist
+    #[error("Dependency not found: {}", .0.display())]
+    MissingDependency(PathBuf),
+
+    /// The `--cache-key-file` does not exist or can't be read
+    #[error("Cannot read cache key file {}: {1}", .0.display())]
+    KeyFile(PathBuf, io::Error),
+
+    /// The `--stdin-file` does not exist or can't be read
+    #[error("Cannot read stdin file {}: {1}", .0.display())]
+    StdinFile(PathBuf, io::Error),
+
+    /// A `--key-from` probe command could not be run or failed
+    #[error("Key probe failed: {0}")]
+    Probe(String),
+
+    /// Cached output could not be encrypted or decrypted
+    #[error("Encryption error: {0}")]
+    Crypto(String),
+
+    /// The remote cache could not be reached or returned bad data
+    #[error("Remote cache error: {0}")]
+    Remote(String),
+
+    /// `MEMO_SESSION` is missing or not a valid session name
+    #[error("Session error: {0}")]
+    Session(String),
+
+    /// `--deterministic-check` found that two runs of the command differ
+    #[error("`{0}` is not deterministic ({1}), not caching")]
+    Nondeterministic(String, String),
+
+    /// No cached entry exists for the command
+    #[error("No cached entry for `{0}`")]
+    NotCached(String),
+
+    /// A digest prefix too short to pick out an entry safely
+    #[error("Digest prefix `{0}` is too short (use at least {1} characters)")]
+    DigestPrefixTooShort(String, usize),
+
+    /// No cached entry's digest starts with the given prefix
+    #[error("No cached entry matches digest `{0}`")]
+    DigestNotFound(String),
+
+    /// More than one cached entry's digest starts with the given prefix
+    #[error("Digest prefix `{0}` is ambiguous ({1} entries match)")]
+    AmbiguousDigest(String, usize),
 }
 
 /// Result type alias for memo operations
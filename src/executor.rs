@@ -4,25 +4,76 @@
 //! directly to cache files and console simultaneously. This avoids loading large
 //! outputs into memory while providing real-time console feedback.
 
-use crate::constants::FILE_PERMISSIONS;
+use crate::cache::{copy, STDERR_STREAM, STDOUT_STREAM};
+use crate::constants::{FILE_PERMISSIONS, TIMEOUT_EXIT_CODE, UNKNOWN_EXIT_CODE};
+use crate::crypto::{EncryptingWriter, EntryKeys};
+use crate::diag::diag;
 use crate::error::{MemoError, Result};
+use crate::memo::{exit_status, ResourceUsage};
 use std::cell::RefCell;
+use std::ffi::OsStr;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[cfg(unix)]
 use std::os::unix::fs::OpenOptionsExt;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Signals passed on to a command that runs in its own process group
+#[cfg(unix)]
+const FORWARDED_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// The process group that [`forward_signal`] passes signals on to
+#[cfg(unix)]
+static FORWARD_GROUP: AtomicI32 = AtomicI32::new(0);
+
+/// Options controlling how a command is executed
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    /// Connect the child's stdout to a pseudo-terminal instead of a pipe, so
+    /// programs that check `isatty` behave as if run interactively
+    pub pty: bool,
+    /// Record when each chunk of stdout was written to this sidecar file
+    pub timing_path: Option<PathBuf>,
+    /// Record the order in which stdout and stderr chunks arrive to this
+    /// sidecar file
+    pub order_path: Option<PathBuf>,
+    /// Encrypt the output files (but not the console output) with these keys
+    pub encryption: Option<EntryKeys>,
+    /// Give the child an empty stdin instead of inheriting ours
+    pub null_stdin: bool,
+    /// Read the child's stdin from this file (takes precedence over
+    /// `null_stdin`)
+    pub stdin_path: Option<PathBuf>,
+    /// Kill the command if it is still running after this long
+    pub timeout: Option<Duration>,
+}
 
 /// Result of command execution
 pub struct ExecutionResult {
     /// The exit code returned by the command
     pub exit_code: i32,
+    /// The signal that killed the command, if any (Unix only)
+    pub signal: Option<i32>,
     /// Error encountered while writing to stdout file (if any)
     pub stdout_error: Option<PathBuf>,
     /// Error encountered while writing to stderr file (if any)
     pub stderr_error: Option<PathBuf>,
+    /// Resource usage of the command (Unix only)
+    pub rusage: Option<ResourceUsage>,
+    /// Number of bytes the command wrote to stdout
+    pub stdout_bytes: u64,
+    /// Number of bytes the command wrote to stderr
+    pub stderr_bytes: u64,
+    /// Whether the command was killed for running past its timeout
+    pub timed_out: bool,
 }
 
 /// A writer that duplicates writes to two destinations
@@ -31,19 +82,98 @@ pub struct ExecutionResult {
 /// real-time output while caching. If file writes fail, it continues with
 /// console output and stores the error for later reporting.
 struct TeeWriter<W: Write> {
-    file: File,
+    file: CacheFile,
     console: W,
     file_path: PathBuf,
     error: RefCell<Option<io::Error>>,
+    timing: Option<TimingRecorder>,
+    order: Option<OrderRecorder>,
+    bytes_written: u64,
+}
+
+/// The file side of a [`TeeWriter`], optionally encrypted
+enum CacheFile {
+    Plain(File),
+    Encrypted(EncryptingWriter<File>),
+}
+
+impl CacheFile {
+    /// Write any trailing data that buffering held back
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            CacheFile::Plain(file) => file.flush(),
+            CacheFile::Encrypted(writer) => writer.finish(),
+        }
+    }
+}
+
+impl Write for CacheFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CacheFile::Plain(file) => file.write(buf),
+            CacheFile::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CacheFile::Plain(file) => file.flush(),
+            CacheFile::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Records each write as an `<offset_ms> <len>` line, where the offset is the
+/// time since the command started
+struct TimingRecorder {
+    file: File,
+    start: Instant,
+}
+
+/// Records each write as a `<stream> <len>` line in a file shared by the
+/// stdout and stderr writers, so the file holds the order chunks arrived in
+struct OrderRecorder {
+    file: Arc<Mutex<File>>,
+    stream: &'static str,
+}
+
+impl ExecutionResult {
+    /// The status memo exits with: the exit code, or `128 + signal` for a
+    /// command killed by a signal
+    pub fn exit_status(&self) -> i32 {
+        exit_status(self.exit_code, self.signal)
+    }
 }
 
 impl<W: Write> TeeWriter<W> {
-    fn new(file: File, console: W, file_path: PathBuf) -> Self {
+    fn new(file: CacheFile, console: W, file_path: PathBuf) -> Self {
         Self {
             file,
             console,
             file_path,
             error: RefCell::new(None),
+            timing: None,
+            order: None,
+            bytes_written: 0,
+        }
+    }
+
+    fn with_timing(mut self, timing: Option<TimingRecorder>) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    fn with_order(mut self, order: Option<OrderRecorder>) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Complete the cached file, storing any error like a failed write
+    fn finish(&mut self) {
+        if let Err(e) = self.file.finish() {
+            if self.error.borrow().is_none() {
+                *self.error.borrow_mut() = Some(e);
+            }
         }
     }
 
@@ -75,6 +205,21 @@ impl<W: Write> Write for TeeWriter<W> {
             }
         }
 
+        self.bytes_written += buf.len() as u64;
+
+        // Timing is best-effort: a failed record only degrades replay cadence
+        if let Some(timing) = &mut self.timing {
+            let offset = timing.start.elapsed().as_millis();
+            let _ = writeln!(timing.file, "{} {}", offset, buf.len());
+        }
+
+        // Ordering is best-effort too: unrecorded chunks replay at the end
+        if let Some(order) = &self.order {
+            if let Ok(mut file) = order.file.lock() {
+                let _ = writeln!(file, "{} {}", order.stream, buf.len());
+            }
+        }
+
         // Return console result (file errors are stored, not returned)
         console_result?;
         Ok(buf.len())
@@ -89,6 +234,46 @@ impl<W: Write> Write for TeeWriter<W> {
     }
 }
 
+/// A console writer that also copies everything to an extra file (`--tee-to`)
+///
+/// The extra file is best-effort: if it can't be created or written, memo
+/// warns once and carries on with the console alone.
+pub struct ExtraCopy<W: Write> {
+    console: W,
+    extra: Option<(File, PathBuf)>,
+}
+
+impl<W: Write> ExtraCopy<W> {
+    /// Wrap `console`, creating (or truncating) `path` if one is given
+    pub fn new(console: W, path: Option<&Path>) -> Self {
+        let extra = path.and_then(|path| match File::create(path) {
+            Ok(file) => Some((file, path.to_path_buf())),
+            Err(e) => {
+                diag!("WARNING: could not create {}: {e}", path.display());
+                None
+            }
+        });
+        Self { console, extra }
+    }
+}
+
+impl<W: Write> Write for ExtraCopy<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.console.write(buf)?;
+        if let Some((file, path)) = &mut self.extra {
+            if let Err(e) = file.write_all(&buf[..n]) {
+                diag!("WARNING: could not write {}: {e}", path.display());
+                self.extra = None;
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.console.flush()
+    }
+}
+
 /// Build a display string from command arguments
 ///
 /// Joins arguments with spaces for user-friendly display.
@@ -104,6 +289,267 @@ pub fn build_command_string(args: &[String]) -> String {
     args.join(" ")
 }
 
+/// Shorten a display string to at most `max_width` characters
+///
+/// Longer strings are cut and end with `…`. A `max_width` of 0 disables
+/// truncation. Only for display: the full argv is still hashed and stored.
+///
+/// # Examples
+///
+/// ```
+/// # use memo::executor::truncate_display;
+/// assert_eq!(truncate_display("echo hello", 7), "echo h…");
+/// assert_eq!(truncate_display("echo hello", 0), "echo hello");
+/// ```
+pub fn truncate_display(text: &str, max_width: usize) -> String {
+    if max_width == 0 || text.chars().count() <= max_width {
+        return text.to_string();
+    }
+
+    let mut truncated: String = text.chars().take(max_width - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Open a pseudo-terminal, returning its `(master, slave)` ends
+///
+/// Output post-processing is disabled on the terminal so the child's bytes are
+/// captured verbatim (e.g. `\n` is not rewritten to `\r\n`).
+#[cfg(unix)]
+fn open_pty() -> io::Result<(File, File)> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    let mut master: libc::c_int = -1;
+    let mut slave: libc::c_int = -1;
+
+    // SAFETY: the fd out-pointers refer to live locals, and null is allowed
+    // for the name, termios, and winsize arguments.
+    let ret = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: openpty succeeded, so both fds are open and owned by us alone.
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+
+    // SAFETY: termios is a plain C struct filled in by tcgetattr, and the fd
+    // is the valid slave end opened above.
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        termios.c_oflag &= !libc::OPOST;
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok((master, slave))
+}
+
+/// Pseudo-terminals are only supported on Unix
+#[cfg(not(unix))]
+fn open_pty() -> io::Result<(File, File)> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pseudo-terminals are only supported on Unix",
+    ))
+}
+
+/// Pass a signal on to the command's process group, then die of it as usual
+#[cfg(unix)]
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let group = FORWARD_GROUP.load(Ordering::Relaxed);
+    // SAFETY: kill, signal, and raise are async-signal-safe.
+    unsafe {
+        if group > 0 {
+            libc::kill(-group, signal);
+        }
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+/// Forwards memo's terminal signals to a command in its own process group
+///
+/// A command with a timeout is moved out of memo's process group so the whole
+/// group can be killed, which also takes it out of reach of Ctrl-C. Signals
+/// that memo ignores are left ignored. The previous handlers come back on
+/// drop.
+#[cfg(unix)]
+struct SignalForwarding {
+    previous: Vec<(libc::c_int, libc::sighandler_t)>,
+}
+
+#[cfg(unix)]
+impl SignalForwarding {
+    fn install(group: u32) -> Self {
+        FORWARD_GROUP.store(group as i32, Ordering::Relaxed);
+        let handler = forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        let previous = FORWARDED_SIGNALS
+            .iter()
+            .filter_map(|&signal| {
+                // SAFETY: forward_signal only makes async-signal-safe calls.
+                let previous = unsafe { libc::signal(signal, handler) };
+                if previous == libc::SIG_IGN {
+                    // SAFETY: restores the disposition we just replaced.
+                    unsafe { libc::signal(signal, previous) };
+                    return None;
+                }
+                Some((signal, previous))
+            })
+            .collect();
+        Self { previous }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SignalForwarding {
+    fn drop(&mut self) {
+        for &(signal, previous) in &self.previous {
+            // SAFETY: restores a disposition returned by signal.
+            unsafe { libc::signal(signal, previous) };
+        }
+        FORWARD_GROUP.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Kill a command run in its own process group, along with anything it started
+#[cfg(unix)]
+fn kill_group(child: &mut Child) -> io::Result<()> {
+    // SAFETY: kill has no memory-safety preconditions.
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Kill a command (there are no process groups)
+#[cfg(not(unix))]
+fn kill_group(child: &mut Child) -> io::Result<()> {
+    child.kill()
+}
+
+/// Wait for a child to exit, collecting its resource usage
+///
+/// On Unix this reaps the child with `wait4`, which reports the exit status
+/// and the child's `rusage` in a single call.
+#[cfg(unix)]
+fn wait_with_rusage(child: &mut Child) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let pid = child.id() as libc::pid_t;
+    let mut status: libc::c_int = 0;
+    // SAFETY: rusage is a plain C struct for which all-zero bytes are valid.
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    loop {
+        // SAFETY: pid is our own unreaped child, and both out-pointers refer
+        // to live, correctly typed locals.
+        let ret = unsafe { libc::wait4(pid, &mut status, 0, &mut usage) };
+        if ret == pid {
+            break;
+        }
+
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+
+    Ok((ExitStatus::from_raw(status), Some(rusage_from(&usage))))
+}
+
+/// Split an exit status into its exit code and the signal that killed it
+///
+/// A command killed by a signal has no exit code, so it gets
+/// [`UNKNOWN_EXIT_CODE`].
+#[cfg(unix)]
+fn exit_code_and_signal(status: ExitStatus) -> (i32, Option<i32>) {
+    use std::os::unix::process::ExitStatusExt;
+
+    (status.code().unwrap_or(UNKNOWN_EXIT_CODE), status.signal())
+}
+
+/// Get the exit code of an exit status (there are no signals)
+#[cfg(not(unix))]
+fn exit_code_and_signal(status: ExitStatus) -> (i32, Option<i32>) {
+    (status.code().unwrap_or(UNKNOWN_EXIT_CODE), None)
+}
+
+/// Wait for a child to exit (resource usage is not available)
+#[cfg(not(unix))]
+fn wait_with_rusage(child: &mut Child) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    Ok((child.wait()?, None))
+}
+
+/// Convert a raw `rusage` into the units stored in metadata
+#[cfg(unix)]
+fn rusage_from(usage: &libc::rusage) -> ResourceUsage {
+    let millis = |tv: libc::timeval| tv.tv_sec as u64 * 1000 + tv.tv_usec as u64 / 1000;
+
+    // macOS reports ru_maxrss in bytes; Linux and the BSDs use kilobytes
+    #[cfg(target_os = "macos")]
+    let max_rss_kb = usage.ru_maxrss as u64 / 1024;
+    #[cfg(not(target_os = "macos"))]
+    let max_rss_kb = usage.ru_maxrss as u64;
+
+    ResourceUsage {
+        max_rss_kb,
+        user_ms: millis(usage.ru_utime),
+        sys_ms: millis(usage.ru_stime),
+    }
+}
+
+/// Resolve a program name to the path that would be executed
+///
+/// Names containing a path separator are used as-is (relative to the current
+/// directory). Bare names are looked up in each `$PATH` entry in order.
+/// Returns `None` if no matching file is found.
+pub fn resolve_program(program: &str) -> Option<PathBuf> {
+    resolve_program_in(program, std::env::var_os("PATH").as_deref())
+}
+
+/// Like [`resolve_program`], but searching `path_var` instead of `$PATH`
+pub fn resolve_program_in(program: &str, path_var: Option<&OsStr>) -> Option<PathBuf> {
+    if program.contains(std::path::MAIN_SEPARATOR) {
+        let path = PathBuf::from(program);
+        return path.is_file().then_some(path);
+    }
+
+    std::env::split_paths(path_var?)
+        .map(|dir| dir.join(program))
+        .find(|candidate| is_executable(candidate))
+}
+
+/// Check whether a path is an executable file
+fn is_executable(path: &Path) -> bool {
+    let Ok(metadata) = path.metadata() else {
+        return false;
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+    }
+
+    #[cfg(not(unix))]
+    {
+        metadata.is_file()
+    }
+}
+
 /// Create a new file with secure permissions (owner read/write only)
 fn create_secure_file(path: &Path) -> std::io::Result<File> {
     let mut opts = OpenOptions::new();
@@ -129,6 +575,7 @@ fn create_secure_file(path: &Path) -> std::io::Result<File> {
 /// * `args` - Command and its arguments (first element is the command)
 /// * `stdout_path` - Path where stdout will be written
 /// * `stderr_path` - Path where stderr will be written
+/// * `options` - How to connect the child's output streams
 ///
 /// # Returns
 ///
@@ -144,19 +591,65 @@ fn create_secure_file(path: &Path) -> std::io::Result<File> {
 /// # Examples
 ///
 /// ```no_run
-/// # use memo::executor::execute_and_stream;
+/// # use memo::executor::{execute_and_stream, ExecOptions};
 /// # use std::path::Path;
 /// let result = execute_and_stream(
 ///     &["echo", "hello"],
 ///     Path::new("/tmp/out.txt"),
-///     Path::new("/tmp/err.txt")
+///     Path::new("/tmp/err.txt"),
+///     &ExecOptions::default(),
 /// ).expect("Command failed");
 /// assert_eq!(result.exit_code, 0);
 /// ```
+#[cfg(test)]
 pub fn execute_and_stream(
     args: &[&str],
     stdout_path: &Path,
     stderr_path: &Path,
+    options: &ExecOptions,
+) -> Result<ExecutionResult> {
+    execute_and_tee(
+        args,
+        stdout_path,
+        stderr_path,
+        options,
+        io::stdout(),
+        io::stderr(),
+    )
+}
+
+/// Execute a command and write its output only to files
+///
+/// Like [`execute_and_tee`], but nothing is echoed to the console. Useful
+/// when the fresh output is only needed for comparison.
+pub fn execute_to_files(
+    args: &[&str],
+    stdout_path: &Path,
+    stderr_path: &Path,
+    options: &ExecOptions,
+) -> Result<ExecutionResult> {
+    execute_and_tee(
+        args,
+        stdout_path,
+        stderr_path,
+        options,
+        io::sink(),
+        io::sink(),
+    )
+}
+
+/// Execute a command, teeing its output to files and the given console writers
+///
+/// stdout and stderr are drained at the same time (stderr on a second
+/// thread), so a command that fills one pipe while memo is reading the other
+/// can't deadlock.
+pub fn execute_and_tee<O: Write, E: Write + Send>(
+    args: &[&str],
+    stdout_path: &Path,
+    stderr_path: &Path,
+    options: &ExecOptions,
+    stdout_console: O,
+    stderr_console: E,
 ) -> Result<ExecutionResult> {
     if args.is_empty() {
         return Err(MemoError::InvalidCommand("No command provided".to_string()));
@@ -164,30 +657,130 @@ pub fn execute_and_stream(
 
     let stdout_file = create_secure_file(stdout_path)?;
     let stderr_file = create_secure_file(stderr_path)?;
+    let (stdout_file, stderr_file) = match &options.encryption {
+        Some(keys) => (
+            CacheFile::Encrypted(keys.encrypt_stdout(stdout_file)?),
+            CacheFile::Encrypted(keys.encrypt_stderr(stderr_file)?),
+        ),
+        None => (CacheFile::Plain(stdout_file), CacheFile::Plain(stderr_file)),
+    };
+
+    let timing = match &options.timing_path {
+        Some(path) => Some(TimingRecorder {
+            file: create_secure_file(path)?,
+            start: Instant::now(),
+        }),
+        None => None,
+    };
+
+    let order_file = match &options.order_path {
+        Some(path) => Some(Arc::new(Mutex::new(create_secure_file(path)?))),
+        None => None,
+    };
+    let order = |stream| {
+        order_file.as_ref().map(|file| OrderRecorder {
+            file: Arc::clone(file),
+            stream,
+        })
+    };
 
     // Create TeeWriters that write to both file and console
-    let mut stdout_tee = TeeWriter::new(stdout_file, io::stdout(), stdout_path.to_path_buf());
-    let mut stderr_tee = TeeWriter::new(stderr_file, io::stderr(), stderr_path.to_path_buf());
+    let mut stdout_tee = TeeWriter::new(stdout_file, stdout_console, stdout_path.to_path_buf())
+        .with_timing(timing)
+        .with_order(order(STDOUT_STREAM));
+    let mut stderr_tee = TeeWriter::new(stderr_file, stderr_console, stderr_path.to_path_buf())
+        .with_order(order(STDERR_STREAM));
 
-    // Spawn the command with piped stdout/stderr
-    let mut child = Command::new(args[0])
-        .args(&args[1..])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
+    // Spawn the command with piped stderr and piped (or pty) stdout
+    let mut command = Command::new(args[0]);
+    command.args(&args[1..]).stderr(Stdio::piped());
+    if let Some(path) = &options.stdin_path {
+        command.stdin(File::open(path)?);
+    } else if options.null_stdin {
+        command.stdin(Stdio::null());
+    }
+
+    // A timeout kills the command's whole process group, so that whatever it
+    // started can't keep running (and keep our pipes open) past the deadline
+    #[cfg(unix)]
+    if options.timeout.is_some() {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    let pty_master = if options.pty {
+        let (master, slave) = open_pty()?;
+        command.stdout(Stdio::from(slave));
+        Some(master)
+    } else {
+        command.stdout(Stdio::piped());
+        None
+    };
+
+    let mut child = command.spawn()?;
+    #[cfg(unix)]
+    let _forwarding = options
+        .timeout
+        .is_some()
+        .then(|| SignalForwarding::install(child.id()));
+
+    // Close our copy of the pty slave so reads see EOF once the child exits
+    drop(command);
 
     // Take the stdout and stderr handles
-    let mut child_stdout = child.stdout.take().expect("Failed to capture stdout");
+    let mut child_stdout: Box<dyn Read> = match pty_master {
+        Some(master) => Box::new(master),
+        None => Box::new(child.stdout.take().expect("Failed to capture stdout")),
+    };
     let mut child_stderr = child.stderr.take().expect("Failed to capture stderr");
 
     // Copy from child's stdout/stderr to our TeeWriters
-    // We ignore copy errors since TeeWriter handles them internally
-    let _ = io::copy(&mut child_stdout, &mut stdout_tee);
-    let _ = io::copy(&mut child_stderr, &mut stderr_tee);
+    // We ignore copy errors since TeeWriter handles them internally (and a pty
+    // master reports EIO rather than EOF once the child closes its end)
+    let mut timed_out = false;
+    let mut exited = None;
+    thread::scope(|scope| {
+        let stderr_thread = scope.spawn(|| {
+            let _ = copy(&mut child_stderr, &mut stderr_tee);
+            stderr_tee.finish();
+        });
+
+        // The pipes close once the command and everything it started exit, so
+        // a watchdog that hears nothing before the deadline kills the group.
+        // It only counts as a timeout if the command itself was still running;
+        // otherwise the kill just stops leftovers holding the pipes open. The
+        // child isn't reaped before the kill, so its pid can't have been
+        // reused.
+        let (done, finished) = mpsc::channel::<()>();
+        if let Some(timeout) = options.timeout {
+            let (child, timed_out, exited) = (&mut child, &mut timed_out, &mut exited);
+            scope.spawn(move || {
+                if finished.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+                    // try_wait reaps an exited child, so keep its status
+                    *exited = child.try_wait().ok().flatten();
+                    *timed_out = exited.is_none();
+                    let _ = kill_group(child);
+                }
+            });
+        }
+
+        let _ = copy(&mut child_stdout, &mut stdout_tee);
+        stdout_tee.finish();
+        let _ = stderr_thread.join();
+        drop(done);
+    });
 
-    // Wait for the command to complete
-    let status = child.wait()?;
-    let exit_code = status.code().unwrap_or(-1);
+    // Wait for the command to complete (its resource usage is lost if the
+    // watchdog already reaped it)
+    let (status, rusage) = match exited {
+        Some(status) => (status, None),
+        None => wait_with_rusage(&mut child)?,
+    };
+    let (exit_code, signal) = if timed_out {
+        (TIMEOUT_EXIT_CODE, None)
+    } else {
+        exit_code_and_signal(status)
+    };
 
     // Collect any file write errors
     let stdout_error = stdout_tee.take_error_path();
@@ -195,8 +788,13 @@ pub fn execute_and_stream(
 
     Ok(ExecutionResult {
         exit_code,
+        signal,
         stdout_error,
         stderr_error,
+        rusage,
+        stdout_bytes: stdout_tee.bytes_written,
+        stderr_bytes: stderr_tee.bytes_written,
+        timed_out,
     })
 }
 
@@ -208,6 +806,8 @@ pub fn execute_and_stream(
 /// # Arguments
 ///
 /// * `args` - Command and its arguments (first element is the command)
+/// * `stdin_path` - File to read the command's stdin from, instead of
+///   inheriting ours
 ///
 /// # Returns
 ///
@@ -223,22 +823,32 @@ pub fn execute_and_stream(
 ///
 /// ```no_run
 /// # use memo::executor::execute_direct;
-/// let result = execute_direct(&["echo", "hello"]).expect("Command failed");
+/// let result = execute_direct(&["echo", "hello"], None).expect("Command failed");
 /// assert_eq!(result.exit_code, 0);
 /// ```
-pub fn execute_direct(args: &[&str]) -> Result<ExecutionResult> {
+pub fn execute_direct(args: &[&str], stdin_path: Option<&Path>) -> Result<ExecutionResult> {
     if args.is_empty() {
         return Err(MemoError::InvalidCommand("No command provided".to_string()));
     }
 
-    let status = Command::new(args[0]).args(&args[1..]).status()?;
+    let mut command = Command::new(args[0]);
+    command.args(&args[1..]);
+    if let Some(path) = stdin_path {
+        command.stdin(File::open(path)?);
+    }
+    let status = command.status()?;
 
-    let exit_code = status.code().unwrap_or(-1);
+    let (exit_code, signal) = exit_code_and_signal(status);
 
     Ok(ExecutionResult {
         exit_code,
+        signal,
         stdout_error: None,
         stderr_error: None,
+        rusage: None,
+        stdout_bytes: 0,
+        stderr_bytes: 0,
+        timed_out: false,
     })
 }
 
@@ -258,7 +868,7 @@ fn execute_command(args: &[&str]) -> std::io::Result<TestExecutionResult> {
         .stderr(Stdio::piped())
         .output()?;
 
-    let exit_code = output.status.code().unwrap_or(-1);
+    let exit_code = output.status.code().unwrap_or(UNKNOWN_EXIT_CODE);
 
     Ok(TestExecutionResult {
         stdout: output.stdout,
@@ -367,6 +977,7 @@ mod tests {
             &["sh", "-c", "echo hello; echo world >&2"],
             &stdout_path,
             &stderr_path,
+            &ExecOptions::default(),
         )
         .unwrap();
 
@@ -375,19 +986,331 @@ mod tests {
         assert_eq!(fs::read(&stderr_path).unwrap(), b"world\n");
     }
 
+    #[test]
+    fn test_execute_and_stream_drains_both_pipes() {
+        let temp_dir = TempDir::new().unwrap();
+        let stdout_path = temp_dir.path().join("out");
+        let stderr_path = temp_dir.path().join("err");
+
+        // Far more stderr than a pipe buffer holds, before any stdout
+        let result = execute_to_files(
+            &["sh", "-c", "head -c 1000000 /dev/zero >&2; echo done"],
+            &stdout_path,
+            &stderr_path,
+            &ExecOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(fs::read(&stdout_path).unwrap(), b"done\n");
+        assert_eq!(result.stderr_bytes, 1_000_000);
+    }
+
+    #[test]
+    fn test_execute_and_stream_kills_on_timeout() {
+        let temp_dir = TempDir::new().unwrap();
+        let stdout_path = temp_dir.path().join("out");
+        let stderr_path = temp_dir.path().join("err");
+
+        let started = Instant::now();
+        let result = execute_to_files(
+            &["sh", "-c", "echo partial; exec sleep 10"],
+            &stdout_path,
+            &stderr_path,
+            &ExecOptions {
+                timeout: Some(Duration::from_millis(200)),
+                ..ExecOptions::default()
+            },
+        )
+        .unwrap();
+
+        assert!(result.timed_out);
+        assert_eq!(result.exit_code, TIMEOUT_EXIT_CODE);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(fs::read(&stdout_path).unwrap(), b"partial\n");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_timeout_kills_the_whole_process_group() {
+        let temp_dir = TempDir::new().unwrap();
+        let stdout_path = temp_dir.path().join("out");
+        let stderr_path = temp_dir.path().join("err");
+        let options = ExecOptions {
+            timeout: Some(Duration::from_millis(500)),
+            ..ExecOptions::default()
+        };
+
+        // The shell waits on a grandchild, which must be killed too
+        let started = Instant::now();
+        let result = execute_to_files(
+            &["sh", "-c", "sleep 10; echo never"],
+            &stdout_path,
+            &stderr_path,
+            &options,
+        )
+        .unwrap();
+        assert!(result.timed_out);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(fs::read(&stdout_path).unwrap(), b"");
+
+        // A command that finished in time isn't a timeout, even if something
+        // it left in the background still holds the pipes open
+        let stdout_path = temp_dir.path().join("out2");
+        let stderr_path = temp_dir.path().join("err2");
+        let started = Instant::now();
+        let result = execute_to_files(
+            &["sh", "-c", "sleep 10 & echo done"],
+            &stdout_path,
+            &stderr_path,
+            &options,
+        )
+        .unwrap();
+        assert!(!result.timed_out);
+        assert_eq!(result.exit_code, 0);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(fs::read(&stdout_path).unwrap(), b"done\n");
+    }
+
     #[test]
     fn test_execute_and_stream_binary() {
         let temp_dir = TempDir::new().unwrap();
         let stdout_path = temp_dir.path().join("out");
         let stderr_path = temp_dir.path().join("err");
 
-        let result =
-            execute_and_stream(&["printf", "\\x00\\x01\\xFF"], &stdout_path, &stderr_path).unwrap();
+        let result = execute_and_stream(
+            &["printf", "\\x00\\x01\\xFF"],
+            &stdout_path,
+            &stderr_path,
+            &ExecOptions::default(),
+        )
+        .unwrap();
 
         assert_eq!(result.exit_code, 0);
         assert_eq!(fs::read(&stdout_path).unwrap(), vec![0x00, 0x01, 0xFF]);
     }
 
+    #[test]
+    fn test_execute_to_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let stdout_path = temp_dir.path().join("out");
+        let stderr_path = temp_dir.path().join("err");
+
+        let result = execute_to_files(
+            &["sh", "-c", "echo hello; echo world >&2; exit 3"],
+            &stdout_path,
+            &stderr_path,
+            &ExecOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(result.exit_code, 3);
+        assert_eq!(fs::read(&stdout_path).unwrap(), b"hello\n");
+        assert_eq!(fs::read(&stderr_path).unwrap(), b"world\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_execute_and_stream_records_rusage() {
+        let temp_dir = TempDir::new().unwrap();
+        let stdout_path = temp_dir.path().join("out");
+        let stderr_path = temp_dir.path().join("err");
+
+        let result = execute_to_files(
+            &[
+                "sh",
+                "-c",
+                "i=0; while [ $i -lt 200000 ]; do i=$((i+1)); done",
+            ],
+            &stdout_path,
+            &stderr_path,
+            &ExecOptions::default(),
+        )
+        .unwrap();
+
+        let rusage = result.rusage.unwrap();
+        assert_eq!(result.exit_code, 0);
+        assert!(rusage.user_ms > 0, "expected CPU time, got {:?}", rusage);
+        assert!(rusage.max_rss_kb > 0);
+    }
+
+    #[test]
+    fn test_execute_counts_output_bytes() {
+        let temp_dir = TempDir::new().unwrap();
+        let stdout_path = temp_dir.path().join("out");
+        let stderr_path = temp_dir.path().join("err");
+
+        let result = execute_to_files(
+            &["sh", "-c", "printf hello; printf ab >&2"],
+            &stdout_path,
+            &stderr_path,
+            &ExecOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(result.stdout_bytes, 5);
+        assert_eq!(result.stderr_bytes, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_execute_with_pty() {
+        let temp_dir = TempDir::new().unwrap();
+        let stdout_path = temp_dir.path().join("out");
+        let stderr_path = temp_dir.path().join("err");
+        let options = ExecOptions {
+            pty: true,
+            ..ExecOptions::default()
+        };
+
+        let result = execute_to_files(
+            &[
+                "sh",
+                "-c",
+                "[ -t 1 ] && echo tty || echo pipe; [ -t 2 ] || echo pipe >&2",
+            ],
+            &stdout_path,
+            &stderr_path,
+            &options,
+        )
+        .unwrap();
+
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(fs::read(&stdout_path).unwrap(), b"tty\n");
+        assert_eq!(fs::read(&stderr_path).unwrap(), b"pipe\n");
+    }
+
+    #[test]
+    fn test_extra_copy_survives_missing_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let extra_path = temp_dir.path().join("extra");
+
+        let mut console = Vec::new();
+        let mut writer = ExtraCopy::new(&mut console, Some(&extra_path));
+        writer.write_all(b"both").unwrap();
+        drop(writer);
+        assert_eq!(console, b"both");
+        assert_eq!(fs::read(&extra_path).unwrap(), b"both");
+
+        let mut console = Vec::new();
+        let missing = temp_dir.path().join("missing").join("extra");
+        let mut writer = ExtraCopy::new(&mut console, Some(&missing));
+        writer.write_all(b"console only").unwrap();
+        drop(writer);
+        assert_eq!(console, b"console only");
+    }
+
+    #[test]
+    fn test_execute_with_stdin_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let stdin_path = temp_dir.path().join("in");
+        let stdout_path = temp_dir.path().join("out");
+        let stderr_path = temp_dir.path().join("err");
+        fs::write(&stdin_path, "b\na\n").unwrap();
+        let options = ExecOptions {
+            null_stdin: true,
+            stdin_path: Some(stdin_path),
+            ..ExecOptions::default()
+        };
+
+        execute_to_files(&["sort"], &stdout_path, &stderr_path, &options).unwrap();
+        assert_eq!(fs::read(&stdout_path).unwrap(), b"a\nb\n");
+    }
+
+    #[test]
+    fn test_execute_records_order() {
+        let temp_dir = TempDir::new().unwrap();
+        let stdout_path = temp_dir.path().join("out");
+        let stderr_path = temp_dir.path().join("err");
+        let order_path = temp_dir.path().join("order");
+        let options = ExecOptions {
+            order_path: Some(order_path.clone()),
+            ..ExecOptions::default()
+        };
+
+        execute_to_files(
+            &[
+                "sh",
+                "-c",
+                "printf one; sleep 0.1; printf two >&2; sleep 0.1; printf three",
+            ],
+            &stdout_path,
+            &stderr_path,
+            &options,
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&order_path).unwrap(),
+            "out 3\nerr 3\nout 5\n"
+        );
+    }
+
+    #[test]
+    fn test_execute_records_timing() {
+        let temp_dir = TempDir::new().unwrap();
+        let stdout_path = temp_dir.path().join("out");
+        let stderr_path = temp_dir.path().join("err");
+        let timing_path = temp_dir.path().join("timing");
+        let options = ExecOptions {
+            timing_path: Some(timing_path.clone()),
+            ..ExecOptions::default()
+        };
+
+        execute_to_files(
+            &["sh", "-c", "printf one; sleep 0.2; printf two"],
+            &stdout_path,
+            &stderr_path,
+            &options,
+        )
+        .unwrap();
+
+        let timing = fs::read_to_string(&timing_path).unwrap();
+        let records: Vec<(u64, u64)> = timing
+            .lines()
+            .map(|line| {
+                let (offset, len) = line.split_once(' ').unwrap();
+                (offset.parse().unwrap(), len.parse().unwrap())
+            })
+            .collect();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].1, 3);
+        assert_eq!(records[1].1, 3);
+        assert!(records[1].0 > records[0].0);
+        assert_eq!(fs::read(&stdout_path).unwrap(), b"onetwo");
+    }
+
+    #[test]
+    fn test_truncate_display() {
+        assert_eq!(truncate_display("echo hello", 20), "echo hello");
+        assert_eq!(truncate_display("echo hello", 10), "echo hello");
+        assert_eq!(truncate_display("echo hello", 7), "echo h…");
+        assert_eq!(truncate_display("echo hello", 0), "echo hello");
+        assert_eq!(truncate_display("echo ééééé", 7), "echo é…");
+    }
+
+    #[test]
+    fn test_resolve_program_from_path() {
+        let resolved = resolve_program("sh").unwrap();
+        assert!(resolved.is_absolute());
+        assert!(resolved.ends_with("sh"));
+    }
+
+    #[test]
+    fn test_resolve_program_with_separator() {
+        let temp_dir = TempDir::new().unwrap();
+        let script = temp_dir.path().join("script");
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+
+        assert_eq!(resolve_program(script.to_str().unwrap()), Some(script));
+    }
+
+    #[test]
+    fn test_resolve_program_missing() {
+        assert!(resolve_program("this-command-does-not-exist-xyz").is_none());
+    }
+
     #[test]
     fn test_build_command_string() {
         let cmd =
@@ -0,0 +1,258 @@
+//! Output templates for `--list --format`
+//!
+//! A template is literal text with `{field}` placeholders, e.g.
+//! `{exit}\t{cmd}`. Use `{{` and `}}` for literal braces. Templates are
+//! validated when parsed, so an unknown placeholder is reported before any
+//! entry is read.
+
+use crate::digest::untagged;
+use crate::executor::{build_command_string, truncate_display};
+use crate::memo::Memo;
+
+/// Hex characters in a `{short_digest}`, enough to tell entries apart at a
+/// glance
+const SHORT_DIGEST_LEN: usize = 12;
+
+/// A value that can be substituted into a template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    /// The entry's digest
+    Digest,
+    /// The first [`SHORT_DIGEST_LEN`] hex characters of the digest, without
+    /// any algorithm tag
+    ShortDigest,
+    /// The command string
+    Cmd,
+    /// The stored exit code
+    Exit,
+    /// When the command was executed
+    Timestamp,
+    /// The working directory the command ran in
+    Cwd,
+    /// How long the command ran, in milliseconds
+    Duration,
+    /// How many times the entry has been replayed
+    Hits,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "digest" => Some(Field::Digest),
+            "short_digest" => Some(Field::ShortDigest),
+            "cmd" => Some(Field::Cmd),
+            "exit" => Some(Field::Exit),
+            "timestamp" => Some(Field::Timestamp),
+            "cwd" => Some(Field::Cwd),
+            "duration" => Some(Field::Duration),
+            "hits" => Some(Field::Hits),
+            _ => None,
+        }
+    }
+}
+
+/// A piece of a parsed template
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// A parsed output template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parse a template, rejecting unknown placeholders and unbalanced braces
+    ///
+    /// Used as a clap `value_parser`, so errors are plain strings.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    if !closed {
+                        return Err(format!("unclosed placeholder `{{{name}`"));
+                    }
+
+                    let field = Field::from_name(&name)
+                        .ok_or_else(|| format!("unknown placeholder `{{{name}}}`"))?;
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '}' => return Err("unmatched `}` (use `}}` for a literal brace)".to_string()),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self { parts })
+    }
+
+    /// Render the template for one cache entry that has been hit `hits` times
+    ///
+    /// `memo` is `None` for an entry with unreadable metadata; its command
+    /// renders as `<corrupt>` and the other metadata fields as empty. The
+    /// command is truncated to `cmd_width` characters (0 for no limit).
+    pub fn render(&self, digest: &str, memo: Option<&Memo>, hits: u64, cmd_width: usize) -> String {
+        let mut out = String::new();
+
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => out.push_str(text),
+                Part::Field(Field::Digest) => out.push_str(digest),
+                Part::Field(Field::ShortDigest) => {
+                    let hex = untagged(digest);
+                    out.push_str(hex.get(..SHORT_DIGEST_LEN).unwrap_or(hex))
+                }
+                Part::Field(Field::Hits) => out.push_str(&hits.to_string()),
+                Part::Field(field) => {
+                    let Some(memo) = memo else {
+                        if *field == Field::Cmd {
+                            out.push_str("<corrupt>");
+                        }
+                        continue;
+                    };
+                    match field {
+                        Field::Digest | Field::ShortDigest | Field::Hits => {
+                            unreachable!("handled above")
+                        }
+                        Field::Cmd => out.push_str(&truncate_display(
+                            &build_command_string(&memo.cmd),
+                            cmd_width,
+                        )),
+                        Field::Exit => out.push_str(&memo.exit_code.to_string()),
+                        Field::Timestamp => out.push_str(&memo.timestamp),
+                        Field::Cwd => out.push_str(&memo.cwd),
+                        Field::Duration => out.push_str(&memo.duration_ms.to_string()),
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memo() -> Memo {
+        Memo {
+            cmd: vec!["echo".to_string(), "hi".to_string()],
+            cwd: "/work".to_string(),
+            exit_code: 3,
+            signal: None,
+            timestamp: "2025-12-22T01:51:52.369Z".to_string(),
+            digest: "abc".to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render_fields() {
+        let template = Template::parse("{digest} {exit} {cmd} @ {cwd} {timestamp}").unwrap();
+        assert_eq!(
+            template.render("abc", Some(&memo()), 0, 0),
+            "abc 3 echo hi @ /work 2025-12-22T01:51:52.369Z"
+        );
+    }
+
+    #[test]
+    fn test_render_duration() {
+        let template = Template::parse("{duration}ms").unwrap();
+        let memo = Memo {
+            duration_ms: 1240,
+            ..memo()
+        };
+        assert_eq!(template.render("abc", Some(&memo), 0, 0), "1240ms");
+    }
+
+    #[test]
+    fn test_render_hits() {
+        let template = Template::parse("{hits}\t{cmd}").unwrap();
+        assert_eq!(template.render("abc", Some(&memo()), 7, 0), "7\techo hi");
+        // Hits are counted outside meta.json, so corrupt entries have them too
+        assert_eq!(template.render("abc", None, 2, 0), "2\t<corrupt>");
+    }
+
+    #[test]
+    fn test_render_short_digest() {
+        let template = Template::parse("{short_digest}").unwrap();
+        assert_eq!(
+            template.render("0123456789abcdef", Some(&memo()), 0, 0),
+            "0123456789ab"
+        );
+        assert_eq!(template.render("abc", None, 0, 0), "abc");
+        // BLAKE3 digests show as many hex characters, without their tag
+        assert_eq!(
+            template.render("blake30123456789abcdef", None, 0, 0),
+            "0123456789ab"
+        );
+    }
+
+    #[test]
+    fn test_render_escaped_braces() {
+        let template = Template::parse("{{{exit}}}").unwrap();
+        assert_eq!(template.render("abc", Some(&memo()), 0, 0), "{3}");
+    }
+
+    #[test]
+    fn test_render_truncates_cmd() {
+        let template = Template::parse("{digest} {cmd}").unwrap();
+        assert_eq!(template.render("abc", Some(&memo()), 0, 4), "abc ech…");
+    }
+
+    #[test]
+    fn test_render_corrupt_entry() {
+        let template = Template::parse("{digest}:{exit}:{cmd}").unwrap();
+        assert_eq!(template.render("abc", None, 0, 0), "abc::<corrupt>");
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_placeholder() {
+        let err = Template::parse("{digest} {bogus}").unwrap_err();
+        assert!(err.contains("{bogus}"));
+    }
+
+    #[test]
+    fn test_parse_rejects_unmatched_brace() {
+        assert!(Template::parse("{digest").is_err());
+        assert!(Template::parse("digest}").is_err());
+    }
+}
@@ -0,0 +1,131 @@
+//! Per-directory opt-out via `.memoignore` files
+//!
+//! Memo walks up from the working directory to the nearest `.memoignore`. An
+//! empty file (or one with only blank lines and `#` comments) disables caching
+//! for every command under that directory. Otherwise each line is a glob
+//! pattern (`*` and `?` wildcards) matched against the full command string,
+//! and only matching commands bypass the cache.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name of the per-directory ignore file
+pub const IGNORE_FILE: &str = ".memoignore";
+
+/// Find the `.memoignore` that excludes a command, if any
+///
+/// Only the nearest ignore file (starting at `start` and walking up through
+/// its ancestors) is consulted. Returns its path if it excludes `command`.
+pub fn find_ignoring_file(start: &Path, command: &str) -> io::Result<Option<PathBuf>> {
+    let Some(path) = start
+        .ancestors()
+        .map(|dir| dir.join(IGNORE_FILE))
+        .find(|path| path.is_file())
+    else {
+        return Ok(None);
+    };
+
+    let text = fs::read_to_string(&path)?;
+    Ok(ignores(&text, command).then_some(path))
+}
+
+/// Check whether ignore file contents exclude a command
+fn ignores(text: &str, command: &str) -> bool {
+    let mut patterns = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .peekable();
+
+    if patterns.peek().is_none() {
+        return true;
+    }
+
+    patterns.any(|pattern| glob_match(pattern, command))
+}
+
+/// Match text against a glob pattern supporting `*` and `?`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // Let the last `*` absorb one more character and retry
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("echo hello", "echo hello"));
+        assert!(glob_match("echo *", "echo hello"));
+        assert!(glob_match("*secret*", "vault read secret/db"));
+        assert!(glob_match("ech? *", "echo hi"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("echo", "echo hello"));
+        assert!(!glob_match("*secret", "secret/db"));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn test_empty_file_ignores_everything() {
+        assert!(ignores("", "echo hello"));
+        assert!(ignores("\n# comment only\n\n", "ls"));
+    }
+
+    #[test]
+    fn test_patterns_select_commands() {
+        let text = "# secrets\nvault *\n*password*\n";
+        assert!(ignores(text, "vault read secret/db"));
+        assert!(ignores(text, "get-password --user me"));
+        assert!(!ignores(text, "echo hello"));
+    }
+
+    #[test]
+    fn test_find_in_ancestor() {
+        let temp = TempDir::new().unwrap();
+        let nested = temp.path().join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(temp.path().join(IGNORE_FILE), "").unwrap();
+
+        let found = find_ignoring_file(&nested, "echo hello").unwrap();
+        assert_eq!(found, Some(temp.path().join(IGNORE_FILE)));
+    }
+
+    #[test]
+    fn test_nearest_file_wins() {
+        let temp = TempDir::new().unwrap();
+        let nested = temp.path().join("a");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(temp.path().join(IGNORE_FILE), "").unwrap();
+        fs::write(nested.join(IGNORE_FILE), "vault *\n").unwrap();
+
+        assert_eq!(find_ignoring_file(&nested, "echo hello").unwrap(), None);
+        assert!(find_ignoring_file(&nested, "vault read x")
+            .unwrap()
+            .is_some());
+    }
+}
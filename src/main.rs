@@ -12,7 +12,8 @@
 //!   - `<digest>/meta.json` - Metadata (command, exit code, timestamp)
 //!   - `<digest>/stdout` - Captured stdout
 //!   - `<digest>/stderr` - Captured stderr
-//! - **Location**: `$XDG_CACHE_HOME/memo/` (defaults to `~/.cache/memo/`)
+//! - **Location**: `$XDG_CACHE_HOME/memo/` (defaults to `~/.cache/memo/`), or
+//!   the directory given with `--cache-dir` or `$MEMO_CACHE_DIR`
 //!
 //! ## Usage Examples
 //!
@@ -39,28 +40,72 @@
 //! - Atomic directory-based concurrency control (lock-free)
 //! - Secure file permissions on Unix systems
 
+mod bench;
+mod bundle;
 mod cache;
+mod compare;
+mod config;
 mod constants;
+mod crypto;
+mod dedupe;
+mod deps;
+mod diag;
 mod digest;
+mod duration;
 mod error;
 mod executor;
+mod format;
+mod ignore;
 mod memo;
+mod prompt;
+mod remote;
+mod replace;
+mod sample;
+mod session;
+mod size;
+mod status;
+mod trace;
 
 use cache::{
-    cleanup_temp_dirs, commit_cache_dir, create_temp_cache_dir, ensure_cache_dir, get_cache_dir,
-    is_memo_disabled, memo_complete, read_memo_metadata, stream_stderr, stream_stdout,
+    cleanup_temp_dirs, commit_cache_dir, create_temp_cache_dir, digest_dir, ensure_cache_dir,
+    entry_size_in_dir, evict_to_size_limit, get_cache_dir, get_cache_paths_in_dir,
+    hit_count_in_dir, is_memo_disabled, is_pinned_in_dir, iter_memos, memo_complete, prune_entries,
+    purge_temp_dirs, read_memo_metadata, record_hit, remove_cache_entry, remove_cache_entry_in_dir,
+    remove_incomplete_entry, resolve_digest_prefix, set_buffer_size, set_cache_prefix, set_pinned,
+    set_shard_depth, stream_interleaved, stream_range, stream_stderr, stream_stderr_decrypted,
+    stream_stdout, stream_stdout_decrypted, stream_stdout_timed, try_acquire_slot, try_lock_entry,
+    ConcurrencySlot, EntryLock, StreamRange, ORDER_FILE, TIMING_FILE,
 };
-use chrono::Utc;
-use clap::Parser;
-use digest::compute_digest_for_args;
-use error::Result;
-use executor::{build_command_string, execute_and_stream, execute_direct};
+use chrono::{DateTime, Utc};
+use clap::{ArgGroup, Parser, ValueEnum};
+use compare::first_difference;
+use config::Config;
+use constants::{
+    CONCURRENCY_POLL_INTERVAL, CONFIRM_ABORT_EXIT_CODE, DEFAULT_BUFFER_SIZE, DEFAULT_SHELL,
+    DISPLAY_WIDTH, ENV_VAR, LIST_FORMAT, MAX_SHARD_DEPTH, MAX_SIZE_VAR, MEMO_VERSION, RANK_LIMIT,
+    SINGLE_FLIGHT_POLL_INTERVAL, SINGLE_FLIGHT_TIMEOUT, TTL_VAR, UNKNOWN_EXIT_CODE, WATCH_DEBOUNCE,
+    WATCH_POLL_INTERVAL,
+};
+use crypto::EntryKeys;
+use diag::{diag, ColorChoice, LogFormat};
+use digest::{DigestAlgorithm, DigestOptions};
+use error::{MemoError, Result};
+use executor::{
+    build_command_string, execute_and_tee, execute_direct, execute_to_files, resolve_program,
+    truncate_display, ExecOptions, ExecutionResult, ExtraCopy,
+};
+use format::Template;
 use memo::Memo;
+use remote::HttpBackend;
+use replace::{Replacement, ReplacingWriter};
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, BufRead, IsTerminal, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(
     name = "memo",
     version = env!("VERGEN_GIT_DESCRIBE"),
@@ -68,11 +113,15 @@ use std::process;
         env!("VERGEN_GIT_DESCRIBE"),
         "\ncommit-id     ", env!("VERGEN_GIT_SHA"),
         "\ncommit-time   ", env!("VERGEN_GIT_COMMIT_TIMESTAMP"),
+        "\nbuild-date    ", env!("VERGEN_BUILD_DATE"),
         "\nrustc-version ", env!("VERGEN_RUSTC_SEMVER"),
         "\ntarget-arch   ", env!("VERGEN_CARGO_TARGET_TRIPLE"),
     ),
 )]
 #[command(about = "Memoize shell command execution", long_about = None)]
+#[command(group(ArgGroup::new("listing").args(["list", "rank"])))]
+#[command(group(ArgGroup::new("json_target").args(["list", "command", "command_file"])))]
+#[command(group(ArgGroup::new("command_source").args(["command", "command_file"])))]
 #[command(after_help = "** SECURITY WARNING **\n\n\
     Memoization caches stdout/stderr to disk UNENCRYPTED. Do NOT use memo with commands \
 that output sensitive information such as:\n\
@@ -81,42 +130,1415 @@ that output sensitive information such as:\n\
     - Private keys or certificates\n\
     - Personally identifiable information\n\n\
     Cached files are stored in ~/.cache/memo/ and may be accessible to other users on shared systems.\n\
-    Use MEMO_DISABLE=1 to bypass caching for individual commands with sensitive output.")]
+    Use MEMO_DISABLE=1 to bypass caching for individual commands with sensitive output, or \
+--encrypt to encrypt the cached output with a passphrase from MEMO_PASSPHRASE.")]
 struct Cli {
+    /// Load default option values from a TOML config file
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
     /// Print memoization information
     #[arg(short, long)]
     verbose: bool,
 
+    /// Print none of memo's own diagnostics, only the command's output
+    /// (fatal errors are still reported)
+    #[arg(short, long, conflicts_with_all = ["verbose", "trace"])]
+    quiet: bool,
+
+    /// Log every cache lookup, digest input, and commit step to stderr with
+    /// timestamps (more detail than --verbose)
+    #[arg(long)]
+    trace: bool,
+
+    /// When to color memo's own diagnostics (the command's output is never
+    /// changed); `auto` colors a terminal unless NO_COLOR is set
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Write memo's diagnostics as text lines or as one JSON object per line
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Keep the cache in DIR instead of `$XDG_CACHE_HOME/memo` (e.g. a
+    /// per-project `.memo-cache`) [env: MEMO_CACHE_DIR]
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// If neither XDG_CACHE_HOME nor a home directory is available, cache in
+    /// the system temp directory instead of failing
+    #[arg(long)]
+    allow_missing_home: bool,
+
+    /// Don't restrict the permissions of cache directories memo creates, for
+    /// filesystems that don't support Unix modes
+    #[arg(long)]
+    no_chmod: bool,
+
+    /// Truncate commands in verbose and --list output to N characters
+    /// (0 for no limit)
+    #[arg(long, value_name = "N", default_value_t = DISPLAY_WIDTH)]
+    display_width: usize,
+
+    /// On a cache hit, replay only the first N lines of stdout
+    #[arg(long, value_name = "N", conflicts_with = "tail")]
+    head: Option<u64>,
+
+    /// On a cache hit, replay only the last N lines of stdout
+    #[arg(long, value_name = "N")]
+    tail: Option<u64>,
+
+    /// Record output timing on a miss and reproduce it on a hit, instead of
+    /// replaying everything at once
+    #[arg(long, conflicts_with_all = ["head", "tail"])]
+    replay_timing: bool,
+
+    /// On a cache hit, replay cached stderr to stdout after cached stdout
+    #[arg(long)]
+    merge_streams: bool,
+
+    /// Record how stdout and stderr interleave on a miss and reproduce it on
+    /// a hit, instead of replaying stdout and then stderr
+    #[arg(long, conflicts_with_all = ["head", "tail", "replay_timing", "replace", "encrypt"])]
+    interleave: bool,
+
+    /// On a cache hit, replace FROM with TO in the replayed text output
+    /// (repeatable, applied in order; the cache is not modified)
+    #[arg(
+        long,
+        value_name = "FROM=TO",
+        value_parser = Replacement::parse,
+        conflicts_with = "replay_timing"
+    )]
+    replace: Vec<Replacement>,
+
+    /// On a cache hit, print a `::memo-exit=N::` line after the replayed output
+    #[arg(long)]
+    exit_trailer: bool,
+
+    /// Write stdout and stderr to `stdout` and `stderr` files in DIR instead
+    /// of the console on a hit, and in addition to it on a miss
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = [
+            "stdout_file",
+            "stderr_file",
+            "head",
+            "tail",
+            "replay_timing",
+            "merge_streams",
+            "interleave",
+            "batch"
+        ]
+    )]
+    output_dir: Option<PathBuf>,
+
+    /// Like --output-dir, but only for stdout
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "head",
+            "tail",
+            "replay_timing",
+            "merge_streams",
+            "interleave",
+            "batch"
+        ]
+    )]
+    stdout_file: Option<PathBuf>,
+
+    /// Like --output-dir, but only for stderr
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["merge_streams", "interleave", "batch"]
+    )]
+    stderr_file: Option<PathBuf>,
+
+    /// On a cache hit, print nothing and only exit with the cached exit code,
+    /// without reading the cached output
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "output_dir",
+            "stdout_file",
+            "stderr_file",
+            "head",
+            "tail",
+            "replay_timing",
+            "merge_streams",
+            "interleave",
+            "replace",
+            "diff"
+        ]
+    )]
+    replay_exit_only: bool,
+
+    /// On a cache hit, replay the cached stdout to stderr (the cached stderr
+    /// still goes to stderr), keeping stdout clean
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "merge_streams",
+            "interleave",
+            "output_dir",
+            "stdout_file",
+            "replay_exit_only"
+        ]
+    )]
+    replay_stdout_to_stderr: bool,
+
+    /// On a cache hit, exit 0 instead of the cached exit code
+    #[arg(long)]
+    ignore_exit: bool,
+
+    /// Interpret --head/--tail counts as bytes instead of lines
+    #[arg(long)]
+    bytes: bool,
+
+    /// Treat a hit as a miss if the command's binary was modified after it was cached
+    #[arg(long)]
+    auto_invalidate_binary: bool,
+
+    /// Run the command fresh and compare it against the cached entry without
+    /// updating the cache (exits 0 if identical, 1 if different)
+    #[arg(long)]
+    diff: bool,
+
+    /// List cached entries instead of running a command
+    #[arg(long, conflicts_with_all = ["command", "command_file"])]
+    list: bool,
+
+    /// Print the top cached entries by --by metric instead of running a command
+    #[arg(long, conflicts_with_all = ["command", "command_file"])]
+    rank: bool,
+
+    /// Metric for --rank
+    #[arg(long, value_enum, requires = "rank", default_value_t = RankBy::Hits)]
+    by: RankBy,
+
+    /// Check the cache for problems, print them, and exit (1 if any were found)
+    #[arg(long, conflicts_with_all = ["command", "command_file", "list"])]
+    doctor: bool,
+
+    /// Print the commands, hits, and time saved in the MEMO_SESSION session,
+    /// and exit
+    #[arg(long, conflicts_with_all = ["command", "command_file", "list"])]
+    report_saved: bool,
+
+    /// Remove leftover temp directories from interrupted runs and exit
+    #[arg(long, conflicts_with_all = ["command", "command_file", "list"])]
+    purge_orphans: bool,
+
+    /// Pin the command's cached entry so --prune keeps it, and exit
+    #[arg(
+        long,
+        requires = "command_source",
+        conflicts_with_all = ["unpin", "list", "batch"]
+    )]
+    pin: bool,
+
+    /// Benchmark cache lookups, commits, and replays in a scratch cache
+    #[arg(long, hide = true, conflicts_with_all = ["command", "command_file"])]
+    self_bench: bool,
+
+    /// Run a shorter --self-bench workload
+    #[arg(long, hide = true, requires = "self_bench")]
+    quick: bool,
+
+    /// Print the command's cached metadata as JSON and exit (1 if not cached)
+    #[arg(
+        long,
+        requires = "command_source",
+        conflicts_with_all = ["pin", "unpin", "list", "batch"]
+    )]
+    json_meta: bool,
+
+    /// Remove the command's cached entry and exit (1 if not cached)
+    #[arg(
+        long,
+        requires = "command_source",
+        conflicts_with_all = ["pin", "unpin", "json_meta", "list", "batch"]
+    )]
+    drop: bool,
+
+    /// Remove the cached entry whose digest starts with DIGEST and exit
+    #[arg(
+        long,
+        value_name = "DIGEST",
+        conflicts_with_all = ["command", "command_file", "list", "batch"]
+    )]
+    drop_digest: Option<String>,
+
+    /// Unpin the command's cached entry and exit
+    #[arg(long, requires = "command_source", conflicts_with_all = ["list", "batch"])]
+    unpin: bool,
+
+    /// Remove unpinned entries cached longer than --older-than ago, and exit
+    #[arg(
+        long,
+        requires = "older_than",
+        conflicts_with_all = ["command", "command_file", "list"]
+    )]
+    prune: bool,
+
+    /// Minimum age of entries removed by --prune (e.g. 7d, 12h, 0s)
+    #[arg(
+        long,
+        value_name = "AGE",
+        requires = "prune",
+        value_parser = duration::parse_duration
+    )]
+    older_than: Option<Duration>,
+
+    /// Remove every cached entry (even pinned ones), print how much was
+    /// freed, and exit
+    #[arg(long, conflicts_with_all = ["command", "command_file", "list", "prune"])]
+    clear: bool,
+
+    /// With --clear, only print the entries that would be removed
+    #[arg(long, requires = "clear")]
+    dry_run: bool,
+
+    /// Replace identical cached output files with hard links and exit
+    #[arg(long, conflicts_with_all = ["command", "command_file", "list"])]
+    dedupe: bool,
+
+    /// Fix entries whose recorded digest doesn't match their directory name,
+    /// print each repair, and exit
+    #[arg(long, conflicts_with_all = ["command", "command_file", "list"])]
+    repair: bool,
+
+    /// Archive every cached entry into a zstd-compressed tarball and exit
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["command", "command_file", "list", "unbundle"]
+    )]
+    bundle: Option<PathBuf>,
+
+    /// Restore the entries in a `--bundle` tarball into the cache, keeping
+    /// entries that are already cached, and exit
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["command", "command_file", "list"])]
+    unbundle: Option<PathBuf>,
+
+    /// Only purge temp directories at least this old (e.g. 30m, 2h; default 0)
+    #[arg(
+        long,
+        value_name = "AGE",
+        requires = "purge_orphans",
+        value_parser = duration::parse_duration
+    )]
+    temp_max_age: Option<Duration>,
+
+    /// Terminate --list records with NUL instead of newline (for `xargs -0`)
+    #[arg(long, requires = "list")]
+    print0: bool,
+
+    /// Print --list output as a JSON array of entry metadata; when running a
+    /// command, print one JSON status object to stderr instead of memo's
+    /// diagnostics
+    #[arg(
+        long,
+        requires = "json_target",
+        conflicts_with_all = ["print0", "batch", "watch", "diff"]
+    )]
+    json: bool,
+
+    /// Print each --list entry using a template with {digest}, {cmd},
+    /// {exit}, {timestamp}, {cwd}, {duration}, and {hits} placeholders
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        requires = "list",
+        conflicts_with = "json",
+        value_parser = Template::parse
+    )]
+    format: Option<Template>,
+
+    /// Print at most N --list or --rank entries (--rank default: 10)
+    #[arg(long, value_name = "N", requires = "listing")]
+    limit: Option<usize>,
+
+    /// Skip the first N --list entries
+    #[arg(long, value_name = "N", requires = "list", default_value_t = 0)]
+    offset: usize,
+
+    /// Include the contents of a file or directory in the cache key (repeatable)
+    #[arg(long, visible_alias = "input", value_name = "PATH")]
+    depends_on: Vec<PathBuf>,
+
+    /// Key --depends-on files on their size and modification time instead of
+    /// their contents, which is cheaper for large inputs
+    #[arg(long, requires = "depends_on")]
+    depends_on_mtime: bool,
+
+    /// Include the stdout of a probe shell command in the cache key (the
+    /// probe runs every time and is not memoized)
+    #[arg(long, value_name = "PROBE")]
+    key_from: Option<String>,
+
+    /// Include the contents of a pre-computed key file (e.g. a lockfile
+    /// hash) in the cache key
+    #[arg(long, value_name = "PATH")]
+    cache_key_file: Option<PathBuf>,
+
+    /// Give the command this file as its stdin, and include the file's
+    /// contents in the cache key
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["batch", "passthrough_tty"])]
+    stdin_file: Option<PathBuf>,
+
+    /// Include the git commit checked out in the working directory, and
+    /// whether the work tree has changes, in the cache key
+    #[arg(long)]
+    git_aware: bool,
+
+    /// Keep running, re-executing the command whenever a --depends-on path changes
+    #[arg(long, requires = "depends_on", conflicts_with = "diff")]
+    watch: bool,
+
+    /// Leave piped stdin to the command instead of reading it first and
+    /// including it in the cache key
+    #[arg(long, conflicts_with = "stdin_file")]
+    ignore_stdin: bool,
+
+    /// Read commands from stdin, one per line (shell-quoted), and cache each
+    /// without printing its output, reporting `hit` or `miss` per line
+    #[arg(
+        long,
+        conflicts_with_all = ["command", "command_file", "diff", "watch", "confirm_miss"]
+    )]
+    batch: bool,
+
+    /// In --batch mode, run every command even if some fail, and report the
+    /// failures at the end (the default)
+    #[arg(long, requires = "batch", conflicts_with = "fail_fast")]
+    keep_going: bool,
+
+    /// In --batch mode, stop at the first command that exits non-zero and
+    /// exit with its code
+    #[arg(long, requires = "batch")]
+    fail_fast: bool,
+
+    /// Cache results that exit non-zero too (by default only successes are
+    /// cached)
+    #[arg(long)]
+    cache_failures: bool,
+
+    /// Don't cache the result if the command exits with this code (repeatable)
+    #[arg(long, value_name = "CODE", allow_negative_numbers = true)]
+    no_cache_exit: Vec<i32>,
+
+    /// Don't cache a successful result that produced no output
+    #[arg(long)]
+    no_commit_empty: bool,
+
+    /// After caching a new entry, evict the least recently used unpinned
+    /// entries until the cache is at most this size (e.g. 500M, 2G)
+    /// [env: MEMO_MAX_SIZE]
+    #[arg(long, value_name = "SIZE", value_parser = size::parse_size)]
+    max_size: Option<u64>,
+
+    /// Don't cache a result that took less than this many milliseconds to
+    /// run, since it's as cheap to run again
+    #[arg(long, value_name = "MS")]
+    min_duration: Option<u64>,
+
+    /// Warn on stderr when a cached result's combined output exceeds N bytes
+    #[arg(long, value_name = "BYTES")]
+    warn_output_size: Option<u64>,
+
+    /// Run the command with stdout attached to a pseudo-terminal (Unix only)
+    #[arg(long)]
+    pty: bool,
+
+    /// On a miss, run the command with memo's own stdin, stdout, and stderr
+    /// (for interactive prompts) and don't cache the result
+    #[arg(long, conflicts_with_all = ["pty", "batch", "diff"])]
+    passthrough_tty: bool,
+
+    /// Canonicalize arguments that name existing paths before hashing, so
+    /// `./file` and `/abs/file` share a cache entry
+    #[arg(long)]
+    canonicalize_paths_in_args: bool,
+
+    /// Sort flags (arguments starting with `-`) before hashing, so their order
+    /// doesn't matter; operands keep their order. Heuristic: a flag's separate
+    /// value counts as an operand
+    #[arg(long)]
+    order_insensitive: bool,
+
+    /// Key on the name of the working directory rather than its full path, so
+    /// `~/work/proj` and `/tmp/proj` share a cache entry
+    #[arg(long)]
+    cwd_basename: bool,
+
+    /// Include this environment variable's value in the cache key
+    /// (repeatable; unset and empty are different) [env: MEMO_ENV, as a
+    /// comma-separated list]
+    #[arg(long, value_name = "NAME")]
+    env: Vec<String>,
+
+    /// Key on the absolute path the program resolves to through `$PATH`
+    /// rather than on its name, so the same name found in different
+    /// directories gets separate entries
+    #[arg(long)]
+    resolve_argv0: bool,
+
+    /// Run PROGRAM in place of the command's first word; the cache key and
+    /// stored metadata still use the command as written
+    #[arg(long, value_name = "PROGRAM")]
+    argv0: Option<String>,
+
+    /// Encrypt cached output with a key derived from $MEMO_PASSPHRASE
+    #[arg(
+        long,
+        conflicts_with_all = ["head", "tail", "replay_timing", "diff"]
+    )]
+    encrypt: bool,
+
+    /// Re-execute instead of replaying an entry older than this (e.g. 30s,
+    /// 10m, 2h; 0 always re-executes) [env: MEMO_TTL]
+    #[arg(long, value_name = "AGE", value_parser = duration::parse_duration)]
+    ttl: Option<Duration>,
+
+    /// Re-execute instead of replaying a successful entry older than this
+    /// (e.g. 1h, 1d), overriding --ttl
+    #[arg(long, value_name = "AGE", value_parser = duration::parse_duration)]
+    ttl_success: Option<Duration>,
+
+    /// Re-execute instead of replaying a failed entry older than this
+    /// (e.g. 60s, 15m), overriding --ttl
+    #[arg(long, value_name = "AGE", value_parser = duration::parse_duration)]
+    ttl_failure: Option<Duration>,
+
+    /// Re-execute instead of replaying an entry whose exit code is unknown
+    /// (e.g. the command was killed by a signal)
+    #[arg(long, visible_alias = "require-clean-exit-for-replay")]
+    strict_exit: bool,
+
+    /// Nest cache entries under DEPTH levels of digest-prefix directories
+    /// (e.g. `ab/cd/<digest>` for 2); use the same depth for every run
+    #[arg(
+        long,
+        value_name = "DEPTH",
+        value_parser = clap::value_parser!(u8).range(0..=MAX_SHARD_DEPTH as i64)
+    )]
+    shard: Option<u8>,
+
+    /// Hash algorithm for cache keys; entries made with one algorithm are
+    /// never hits for the other [default: sha256]
+    #[arg(long, value_enum, value_name = "ALGORITHM")]
+    digest: Option<DigestAlgorithm>,
+
+    /// Size in bytes of the buffer used to copy output to and from the cache
+    /// [default: 65536]
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    buffer_size: Option<u64>,
+
+    /// Use a separate cache in the NAME subdirectory of the cache directory,
+    /// for every operation (entries outside it are invisible, and vice versa)
+    #[arg(
+        long,
+        visible_alias = "namespace",
+        value_name = "NAME",
+        value_parser = cache::parse_prefix
+    )]
+    prefix: Option<String>,
+
+    /// On a miss, let only one concurrent memo run the command while the
+    /// others wait for its cached result
+    #[arg(long)]
+    single_flight: bool,
+
+    /// Let at most N memo processes sharing the cache run missed commands at
+    /// once; further misses wait for a free slot
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_concurrency: Option<u64>,
+
+    /// Re-run the command even if it is cached, and if its stdout extends
+    /// the cached stdout, print only the appended part (for growing logs)
+    #[arg(
+        long,
+        conflicts_with_all = ["single_flight", "encrypt", "batch", "diff", "passthrough_tty"]
+    )]
+    incremental: bool,
+
+    /// On a miss, write the command's output only to the cache, not the
+    /// console (the exit code still propagates)
+    #[arg(long, conflicts_with_all = ["incremental", "passthrough_tty"])]
+    no_tee: bool,
+
+    /// Run the command even if it is cached, replacing any cached entry with
+    /// the fresh result (the remote cache isn't consulted)
+    #[arg(
+        short = 'f',
+        long,
+        visible_alias = "force",
+        conflicts_with_all = ["incremental", "diff", "passthrough_tty", "single_flight"]
+    )]
+    cold_start: bool,
+
+    /// Also copy stdout to this file, on a hit and on a miss (failing to
+    /// write it only warns)
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["stdout_file", "output_dir"])]
+    tee_to: Option<PathBuf>,
+
+    /// On a miss, run the command twice and only cache the result if both
+    /// runs match exactly (otherwise fail without caching)
+    #[arg(long, conflicts_with_all = ["encrypt", "incremental", "passthrough_tty"])]
+    deterministic_check: bool,
+
+    /// Re-run this fraction of cache hits (0.0 to 1.0) and warn if the fresh
+    /// result differs from the cached one
+    #[arg(
+        long,
+        value_name = "RATE",
+        default_value_t = 0.0,
+        value_parser = sample::parse_rate,
+        conflicts_with_all = ["encrypt", "incremental", "cold_start", "passthrough_tty"]
+    )]
+    sample_rate: f64,
+
+    /// When a sampled hit differs from the cache, replace the cached entry
+    /// with the fresh result
+    #[arg(long)]
+    sample_refresh: bool,
+
+    /// How long --single-flight waits before running the command itself
+    /// (e.g. 30s, 10m; default 5m)
+    #[arg(
+        long,
+        value_name = "DURATION",
+        requires = "single_flight",
+        value_parser = duration::parse_duration
+    )]
+    single_flight_timeout: Option<Duration>,
+
+    /// Kill the command if it runs longer than this (e.g. 30s, 10m) and exit
+    /// 124 without caching
+    #[arg(
+        long,
+        value_name = "DURATION",
+        conflicts_with = "passthrough_tty",
+        value_parser = duration::parse_duration
+    )]
+    timeout: Option<Duration>,
+
+    /// Ask for confirmation on stderr before running a command that is not
+    /// cached (only when stdin and stderr are terminals)
+    #[arg(long)]
+    confirm_miss: bool,
+
+    /// Run the command words joined by spaces through `/bin/sh -c`, so pipes
+    /// and redirects work; the shell invocation is what gets hashed
+    #[arg(short = 's', long, conflicts_with = "batch")]
+    exec_shell: bool,
+
+    /// Read the command from a file, one argument per line
+    #[arg(long, value_name = "PATH", conflicts_with = "command")]
+    command_file: Option<PathBuf>,
+
     /// Command to execute/memoize
-    #[arg(trailing_var_arg = true, required = true, allow_hyphen_values = true)]
+    ///
+    /// Everything from the first positional token on (or after a leading
+    /// `--`) is passed through verbatim, including flags meant for a nested
+    /// `memo` and any later `--`.
+    #[arg(
+        trailing_var_arg = true,
+        required_unless_present_any = [
+            "list",
+            "rank",
+            "command_file",
+            "purge_orphans",
+            "doctor",
+            "dedupe",
+            "prune",
+            "clear",
+            "batch",
+            "drop_digest",
+            "self_bench",
+            "report_saved",
+            "repair",
+            "bundle",
+            "unbundle"
+        ],
+        allow_hyphen_values = true
+    )]
     command: Vec<String>,
 }
 
+/// Metric to sort `--rank` output by
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum RankBy {
+    /// Number of cache hits
+    Hits,
+    /// Run time of the original execution, in milliseconds
+    Duration,
+    /// Size on disk, in bytes
+    Size,
+}
+
 fn main() {
     match run() {
         Ok(exit_code) => process::exit(exit_code),
         Err(e) => {
-            eprintln!(":: memo :: ERROR: {}", e);
+            // Even with --quiet, since exit 1 alone could be the command's
+            diag::set_quiet(false);
+            diag!("ERROR: {}", e);
             process::exit(1);
         }
     }
 }
 
+/// Read a command from a file containing one argument per line
+///
+/// A trailing newline does not add an empty argument, but blank lines in the
+/// middle are kept as empty arguments.
+fn read_command_file(path: &Path) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| MemoError::InvalidCommand(format!("cannot read {}: {}", path.display(), e)))?;
+    let command: Vec<String> = text.lines().map(str::to_string).collect();
+
+    if command.is_empty() {
+        return Err(MemoError::InvalidCommand(format!(
+            "{} is empty",
+            path.display()
+        )));
+    }
+
+    Ok(command)
+}
+
+/// Build the command string shown in diagnostics, truncated to --display-width
+fn display_command(args: &Cli) -> String {
+    truncate_display(&build_command_string(&args.command), args.display_width)
+}
+
+/// Fill in options not given on the command line from a config file
+fn apply_config(args: &mut Cli, config: &Config) {
+    args.verbose |= !args.quiet && config.verbose.unwrap_or(false);
+    args.auto_invalidate_binary |= config.auto_invalidate_binary.unwrap_or(false);
+    args.shard = args.shard.or(config.shard);
+    args.digest = args.digest.or(config.digest);
+    args.buffer_size = args
+        .buffer_size
+        .or(config.buffer_size.map(|size| size as u64));
+}
+
+/// Select the portion of cached stdout to replay from --head/--tail
+fn replay_range(args: &Cli) -> Option<StreamRange> {
+    match (args.head, args.tail, args.bytes) {
+        (Some(n), _, true) => Some(StreamRange::HeadBytes(n)),
+        (Some(n), _, false) => Some(StreamRange::HeadLines(n)),
+        (None, Some(n), true) => Some(StreamRange::TailBytes(n)),
+        (None, Some(n), false) => Some(StreamRange::TailLines(n)),
+        (None, None, _) => None,
+    }
+}
+
+/// Check whether the program that would be executed is newer than a memo
+///
+/// Returns `false` if the program cannot be resolved, since there is nothing
+/// to compare against. An unparsable memo timestamp counts as stale.
+fn binary_is_newer(program: &str, memo: &Memo) -> bool {
+    let Some(path) = resolve_program(program) else {
+        return false;
+    };
+    let Ok(modified) = fs::metadata(&path).and_then(|m| m.modified()) else {
+        return false;
+    };
+
+    match memo.executed_at() {
+        Some(executed_at) => DateTime::<Utc>::from(modified) > executed_at,
+        None => true,
+    }
+}
+
+/// The argument vector to execute, with `--argv0` substituted for the program
+fn exec_args(args: &Cli) -> Vec<&str> {
+    let mut cmd_args: Vec<&str> = args.command.iter().map(|s| s.as_str()).collect();
+    if let (Some(program), Some(first)) = (&args.argv0, cmd_args.first_mut()) {
+        *first = program;
+    }
+    cmd_args
+}
+
+/// Wrap a command in `/bin/sh -c "<command>"`
+///
+/// The user's `$SHELL` is not used: it may not be POSIX, and the same
+/// pipeline would get a different key for every user.
+fn shell_command(command: &[String]) -> Vec<String> {
+    vec![
+        DEFAULT_SHELL.to_string(),
+        "-c".to_string(),
+        command.join(" "),
+    ]
+}
+
+/// Build the execution options selected on the command line
+fn exec_options(args: &Cli) -> ExecOptions {
+    ExecOptions {
+        pty: args.pty,
+        stdin_path: args.stdin_file.clone(),
+        timeout: args.timeout,
+        ..ExecOptions::default()
+    }
+}
+
+/// Collect the key-influencing options selected on the command line
+fn digest_options(args: &Cli, cwd: &str) -> Result<DigestOptions> {
+    let algorithm = args.digest.unwrap_or_default();
+    let mut extras = Vec::new();
+    if args.depends_on_mtime {
+        let hash = deps::hash_dependency_stats(&args.depends_on, algorithm)?;
+        extras.push(("depends-on-mtime".to_string(), hash));
+    } else if !args.depends_on.is_empty() {
+        let hash = deps::hash_dependencies(&args.depends_on, algorithm)?;
+        extras.push(("depends-on".to_string(), hash));
+    }
+    if let Some(probe) = &args.key_from {
+        let hash = deps::hash_probe_output(probe, algorithm)?;
+        extras.push(("key-from".to_string(), hash));
+    }
+    if let Some(path) = &args.stdin_file {
+        let hash = deps::hash_stdin_file(path, algorithm)?;
+        extras.push(("stdin-file".to_string(), hash));
+    }
+    if let Some(path) = &args.cache_key_file {
+        let hash = deps::hash_key_file(path, algorithm)?;
+        extras.push(("cache-key-file".to_string(), hash));
+    }
+    if args.git_aware {
+        match deps::git_state(Path::new(cwd)) {
+            Some(state) => {
+                extras.push(("git-head".to_string(), state.head));
+                extras.push(("git-dirty".to_string(), state.dirty.to_string()));
+            }
+            None => diag!("WARNING: {cwd} is not in a git repository, ignoring --git-aware"),
+        }
+    }
+    if args.pty {
+        extras.push(("pty".to_string(), "true".to_string()));
+    }
+    if args.encrypt {
+        extras.push(("encrypt".to_string(), "true".to_string()));
+    }
+
+    let mut names = args.env.clone();
+    names.sort();
+    names.dedup();
+    let env = names
+        .into_iter()
+        .map(|name| {
+            let value = std::env::var_os(&name).map(|v| v.to_string_lossy().into_owned());
+            (name, value)
+        })
+        .collect();
+
+    Ok(DigestOptions {
+        env,
+        extras,
+        algorithm,
+    })
+}
+
+/// Compute the cache key for the command, folding in key-influencing options
+fn cache_key(args: &Cli, cwd: &str) -> Result<String> {
+    let mut command = args.command.clone();
+    if args.resolve_argv0 {
+        let path_var = std::env::var_os("PATH");
+        command = digest::resolve_argv0(&command, cwd, path_var.as_deref()).ok_or_else(|| {
+            MemoError::InvalidCommand(format!("cannot resolve `{}` in PATH", command[0]))
+        })?;
+    }
+    if args.canonicalize_paths_in_args {
+        command = digest::canonicalize_path_args(&command, cwd);
+    }
+    if args.order_insensitive {
+        command = digest::sort_flags(&command);
+    }
+    let key_cwd = if args.cwd_basename {
+        digest::cwd_basename(cwd)
+    } else {
+        cwd
+    };
+    digest::cache_key(&command, key_cwd, &digest_options(args, cwd)?)
+}
+
+/// Collect the cache entries selected by --offset/--limit, oldest first
+///
+/// Entries with unreadable metadata have no timestamp and sort last.
+fn list_entries(args: &Cli, cache_dir: &Path) -> Result<Vec<(String, PathBuf, io::Result<Memo>)>> {
+    let mut entries: Vec<_> = iter_memos(cache_dir)?.collect();
+    entries.sort_by_key(|(_, _, memo)| {
+        let executed_at = memo.as_ref().ok().and_then(Memo::executed_at);
+        (executed_at.is_none(), executed_at)
+    });
+
+    let limit = args.limit.unwrap_or(usize::MAX);
+    Ok(entries.into_iter().skip(args.offset).take(limit).collect())
+}
+
+/// Print one record per cache entry: a [`LIST_FORMAT`] table row, or the
+/// `--format` template
+///
+/// With `--json`, prints a JSON array of the full metadata instead. Entries
+/// with unreadable metadata are marked as corrupt rather than skipped.
+fn run_list(args: &Cli) -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    let mut stdout = io::stdout().lock();
+
+    if args.json {
+        let mut entries = Vec::new();
+        for (digest, dir, memo) in list_entries(args, &cache_dir)? {
+            entries.push(match memo {
+                Ok(memo) => {
+                    let mut value = serde_json::to_value(memo)?;
+                    value["pinned"] = is_pinned_in_dir(&dir).into();
+                    value
+                }
+                Err(_) => serde_json::json!({ "digest": digest, "corrupt": true }),
+            });
+        }
+        serde_json::to_writer_pretty(&mut stdout, &entries)?;
+        writeln!(stdout)?;
+        return Ok(0);
+    }
+
+    let terminator = if args.print0 { '\0' } else { '\n' };
+    let template = match &args.format {
+        Some(template) => template.clone(),
+        None => Template::parse(LIST_FORMAT).expect("default template is valid"),
+    };
+    // NUL-terminated output is for machines, so keep commands whole
+    let cmd_width = if args.print0 { 0 } else { args.display_width };
+    for (digest, dir, memo) in list_entries(args, &cache_dir)? {
+        let hits = hit_count_in_dir(&dir);
+        let record = template.render(&digest, memo.as_ref().ok(), hits, cmd_width);
+        // The default format marks pinned entries in a last column
+        let pin_mark = if args.format.is_none() && is_pinned_in_dir(&dir) {
+            "\tpinned"
+        } else {
+            ""
+        };
+        write!(stdout, "{record}{pin_mark}{terminator}")?;
+    }
+
+    Ok(0)
+}
+
+/// Print the entries with the highest --by metric, highest first
+///
+/// Each line is the metric value, the digest, and the command, tab-separated.
+/// Entries with unreadable metadata are skipped.
+fn run_rank(args: &Cli) -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    let mut ranked = Vec::new();
+    for (digest, dir, memo) in iter_memos(&cache_dir)? {
+        let Ok(memo) = memo else { continue };
+        let value = match args.by {
+            RankBy::Hits => hit_count_in_dir(&dir),
+            RankBy::Duration => memo.duration_ms,
+            RankBy::Size => entry_size_in_dir(&dir)?,
+        };
+        ranked.push((value, digest, dir, memo));
+    }
+    ranked.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+    let template = Template::parse("{digest}\t{cmd}").expect("default template is valid");
+    let mut stdout = io::stdout().lock();
+    for (value, digest, dir, memo) in ranked.iter().take(args.limit.unwrap_or(RANK_LIMIT)) {
+        let hits = hit_count_in_dir(dir);
+        let record = template.render(digest, Some(memo), hits, args.display_width);
+        writeln!(stdout, "{value}\t{record}")?;
+    }
+
+    Ok(0)
+}
+
+/// Print the current `MEMO_SESSION`'s run count, hits, and time saved
+fn run_report_saved() -> Result<i32> {
+    let name = session::current()
+        .ok_or_else(|| MemoError::Session(format!("{} is not set", session::SESSION_VAR)))?;
+    let tally = session::tally(&get_cache_dir()?, &name)?;
+
+    println!("session: {name}");
+    println!("commands: {}", tally.commands);
+    println!("hits: {}", tally.hits);
+    println!("saved: {}ms", tally.saved_ms);
+    Ok(0)
+}
+
+/// Add a run to the `MEMO_SESSION` tally, if a session is set
+///
+/// A failure to record only warns, since the run itself succeeded.
+fn record_session(cache_dir: &Path, digest: &str, hit: bool, duration_ms: Option<u64>) {
+    let Some(name) = session::current() else {
+        return;
+    };
+    let result = match duration_ms {
+        Some(ms) => Ok(ms),
+        None => read_memo_metadata(cache_dir, digest)
+            .map(|memo| memo.duration_ms)
+            .map_err(MemoError::from),
+    }
+    .and_then(|ms| session::record(cache_dir, &name, hit, ms));
+    if let Err(e) = result {
+        diag!("WARNING: could not record the run in session {name}: {e}");
+    }
+}
+
+/// Rewrite each entry's recorded digest to match its directory and report the fixes
+fn run_repair() -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    let repairs = cache::repair_digests(&cache_dir)?;
+
+    for repair in &repairs {
+        println!(
+            "repaired {}: digest was {}",
+            repair.path.display(),
+            repair.recorded
+        );
+    }
+    println!("repaired {} digest(s)", repairs.len());
+    Ok(0)
+}
+
+/// Print every problem found in the cache
+fn run_doctor() -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    let issues = cache::validate(&cache_dir)?;
+
+    for issue in &issues {
+        println!("{issue}");
+    }
+
+    // Mixed versions help explain entries written by older formats
+    let mut versions = std::collections::BTreeMap::new();
+    for (_, _, memo) in iter_memos(&cache_dir)? {
+        if let Ok(memo) = memo {
+            *versions.entry(memo.memo_version).or_insert(0) += 1;
+        }
+    }
+    if !versions.is_empty() {
+        let counts: Vec<String> = versions
+            .iter()
+            .map(|(version, count)| format!("{version} ({count})"))
+            .collect();
+        println!("entries by memo version: {}", counts.join(", "));
+    }
+
+    if issues.is_empty() {
+        println!("no problems found in {}", cache_dir.display());
+        return Ok(0);
+    }
+    Ok(1)
+}
+
+/// Remove temp directories left behind by interrupted runs
+fn run_purge_orphans(args: &Cli) -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    let max_age = args.temp_max_age.unwrap_or(Duration::ZERO);
+    let report = purge_temp_dirs(&cache_dir, max_age)?;
+
+    println!(
+        "purged {} temp dir(s), reclaimed {} bytes",
+        report.removed, report.bytes
+    );
+    Ok(0)
+}
+
+/// Pin or unpin the command's cached entry
+fn run_pin(args: &Cli) -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    let cwd = std::env::current_dir()?.to_string_lossy().to_string();
+    let digest = cache_key(args, &cwd)?;
+    if !memo_complete(&cache_dir, &digest) {
+        return Err(MemoError::NotCached(display_command(args)));
+    }
+
+    set_pinned(&cache_dir, &digest, args.pin)?;
+    let action = if args.pin { "pinned" } else { "unpinned" };
+    println!("{action} {digest}");
+    Ok(0)
+}
+
+/// Remove a single cache entry, named by its command or a digest prefix
+fn run_drop(args: &Cli) -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    let (digest, dir) = match &args.drop_digest {
+        Some(prefix) => resolve_digest_prefix(&cache_dir, prefix)?,
+        None => {
+            let cwd = std::env::current_dir()?.to_string_lossy().to_string();
+            let digest = cache_key(args, &cwd)?;
+            let dir = digest_dir(&cache_dir, &digest);
+            (digest, dir)
+        }
+    };
+
+    if !remove_cache_entry_in_dir(&dir)? {
+        // A resolved digest can vanish if another process removes it first
+        return Err(match args.drop_digest {
+            Some(_) => MemoError::DigestNotFound(digest),
+            None => MemoError::NotCached(display_command(args)),
+        });
+    }
+    println!("dropped {digest}");
+    Ok(0)
+}
+
+/// Print the command's cached metadata as pretty-printed JSON
+fn run_json_meta(args: &Cli) -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    let cwd = std::env::current_dir()?.to_string_lossy().to_string();
+    let digest = cache_key(args, &cwd)?;
+    if !memo_complete(&cache_dir, &digest) {
+        return Err(MemoError::NotCached(display_command(args)));
+    }
+
+    let memo = read_memo_metadata(&cache_dir, &digest)?;
+    println!("{}", serde_json::to_string_pretty(&memo)?);
+    Ok(0)
+}
+
+/// Remove old entries, keeping pinned ones, and report what was removed
+fn run_prune(args: &Cli) -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    let older_than = args.older_than.unwrap_or(Duration::ZERO);
+    let report = prune_entries(&cache_dir, older_than)?;
+
+    println!(
+        "pruned {} entries, reclaimed {} bytes, kept {} pinned",
+        report.removed, report.bytes, report.pinned
+    );
+    Ok(0)
+}
+
+/// Remove every entry and report the space freed
+fn run_clear(args: &Cli) -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    let report = cache::clear_entries(&cache_dir, args.dry_run)?;
+
+    if args.dry_run {
+        for digest in &report.removed {
+            println!("would remove {digest}");
+        }
+        println!(
+            "would clear {} entries, {} bytes",
+            report.removed.len(),
+            report.bytes
+        );
+    } else {
+        println!(
+            "cleared {} entries, freed {} bytes",
+            report.removed.len(),
+            report.bytes
+        );
+    }
+    Ok(0)
+}
+
+/// Hard-link identical output files across entries and report the savings
+fn run_dedupe() -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    let report = dedupe::dedupe(&cache_dir)?;
+
+    println!(
+        "deduplicated {} file(s), reclaimed {} bytes",
+        report.linked, report.bytes
+    );
+    Ok(0)
+}
+
+/// Archive the whole cache into one file
+fn run_bundle(out: &Path) -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    let count = bundle::bundle(&cache_dir, out)?;
+
+    println!("bundled {count} entries into {}", out.display());
+    Ok(0)
+}
+
+/// Merge the entries of a bundle into the cache
+fn run_unbundle(input: &Path) -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    ensure_cache_dir(&cache_dir)?;
+    let report = bundle::unbundle(&cache_dir, input)?;
+
+    println!(
+        "restored {} entries, skipped {} already cached",
+        report.restored, report.skipped
+    );
+    Ok(0)
+}
+
+/// Run the command a second time and compare it with the first run
+///
+/// The second run's output goes to a scratch temp dir and is discarded.
+/// Returns a description of the first difference, or `None` if stdout,
+/// stderr, and the exit code all match.
+fn check_deterministic(
+    cmd_args: &[&str],
+    cache_dir: &Path,
+    digest: &str,
+    options: &ExecOptions,
+    first: &ExecutionResult,
+    first_out: &Path,
+    first_err: &Path,
+) -> Result<Option<String>> {
+    let temp_dir = create_temp_cache_dir(cache_dir, digest)?;
+    let (_, second_out, second_err) = temp_dir.get_paths();
+    let second = execute_to_files(cmd_args, &second_out, &second_err, options)?;
+
+    describe_difference(
+        (first.exit_code, first_out, first_err),
+        (second.exit_code, &second_out, &second_err),
+    )
+}
+
+/// Describe the first difference between two runs' exit codes and outputs
+///
+/// Each run is its exit code, stdout path, and stderr path. Returns `None`
+/// if they all match.
+fn describe_difference(
+    (first_exit, first_out, first_err): (i32, &Path, &Path),
+    (second_exit, second_out, second_err): (i32, &Path, &Path),
+) -> Result<Option<String>> {
+    for (name, first, second) in [
+        ("stdout", first_out, second_out),
+        ("stderr", first_err, second_err),
+    ] {
+        if let Some(offset) = first_difference(first, second)? {
+            return Ok(Some(format!("{name} differs at byte {offset}")));
+        }
+    }
+    if first_exit != second_exit {
+        return Ok(Some(format!(
+            "exit code differs ({first_exit} then {second_exit})"
+        )));
+    }
+    Ok(None)
+}
+
+/// Run the command fresh and compare it against the cached entry
+///
+/// Prints a one-line summary each for stdout, stderr, and the exit code. The
+/// fresh output goes to a temp dir that is discarded, so the cache is left
+/// untouched. Returns 0 if everything matches and 1 otherwise.
+fn run_diff(args: &Cli, cache_dir: &Path, digest: &str) -> Result<i32> {
+    if !memo_complete(cache_dir, digest) {
+        return Err(MemoError::NotCached(display_command(args)));
+    }
+
+    let memo = read_memo_metadata(cache_dir, digest)?;
+
+    let temp_dir = create_temp_cache_dir(cache_dir, digest)?;
+    let (_, fresh_out, fresh_err) = temp_dir.get_paths();
+    let (_, cached_out, cached_err) = get_cache_paths_in_dir(&digest_dir(cache_dir, digest));
+
+    let cmd_args = exec_args(args);
+    let result = execute_to_files(&cmd_args, &fresh_out, &fresh_err, &exec_options(args))?;
+
+    let mut identical = true;
+    let mut stdout = io::stdout().lock();
+
+    for (name, cached, fresh) in [
+        ("stdout", &cached_out, &fresh_out),
+        ("stderr", &cached_err, &fresh_err),
+    ] {
+        match first_difference(cached, fresh)? {
+            None => writeln!(stdout, "{name}: identical")?,
+            Some(offset) => {
+                identical = false;
+                writeln!(
+                    stdout,
+                    "{name}: differs at byte {offset} (cached {} bytes, fresh {} bytes)",
+                    fs::metadata(cached)?.len(),
+                    fs::metadata(fresh)?.len()
+                )?;
+            }
+        }
+    }
+
+    if memo.exit_code == result.exit_code {
+        writeln!(stdout, "exit code: identical ({})", memo.exit_code)?;
+    } else {
+        identical = false;
+        writeln!(
+            stdout,
+            "exit code: differs (cached {}, fresh {})",
+            memo.exit_code, result.exit_code
+        )?;
+    }
+
+    Ok(if identical { 0 } else { 1 })
+}
+
+/// An option's default from an environment variable, if it is set and
+/// non-empty, parsed with the option's own value parser
+fn env_default<T>(
+    var: &str,
+    parse: fn(&str) -> std::result::Result<T, String>,
+) -> Result<Option<T>> {
+    match std::env::var(var) {
+        Ok(value) if !value.is_empty() => parse(&value)
+            .map(Some)
+            .map_err(|e| MemoError::Config(format!("{var}: {e}"))),
+        _ => Ok(None),
+    }
+}
+
 fn run() -> Result<i32> {
-    let args = Cli::parse();
+    let mut args = Cli::parse();
+    diag::set_color(args.color);
+    diag::set_log_format(args.log_format);
+    // A JSON status replaces the diagnostics it summarizes
+    let json_status = args.json && !args.list;
+    diag::set_quiet(args.quiet || json_status);
+
+    if let Some(path) = &args.config {
+        let config = Config::load(path)?;
+        apply_config(&mut args, &config);
+    }
+
+    if args.ttl.is_none() {
+        args.ttl = env_default(TTL_VAR, duration::parse_duration)?;
+    }
+    if args.max_size.is_none() {
+        args.max_size = env_default(MAX_SIZE_VAR, size::parse_size)?;
+    }
+    if args.env.is_empty() {
+        args.env = env_default(ENV_VAR, |names| {
+            Ok(names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect())
+        })?
+        .unwrap_or_default();
+    }
+
+    set_shard_depth(args.shard.unwrap_or(0).into());
+    set_buffer_size(
+        args.buffer_size
+            .map_or(DEFAULT_BUFFER_SIZE, |size| size as usize),
+    );
+    cache::set_cache_dir(args.cache_dir.clone());
+    set_cache_prefix(args.prefix.clone());
+    cache::set_chmod(!args.no_chmod);
+    cache::set_allow_missing_home(args.allow_missing_home || cache::is_temp_fallback_enabled());
+    if args.verbose && cache::uses_temp_cache() {
+        diag!(
+            "WARNING: no home directory, using a temporary cache in {}",
+            std::env::temp_dir().join("memo").display()
+        );
+    }
+    trace::set_enabled(args.trace);
+
+    if args.list {
+        return run_list(&args);
+    }
+
+    if args.rank {
+        return run_rank(&args);
+    }
+
+    if args.purge_orphans {
+        return run_purge_orphans(&args);
+    }
+
+    if args.doctor {
+        return run_doctor();
+    }
+
+    if args.report_saved {
+        return run_report_saved();
+    }
+
+    if args.dedupe {
+        return run_dedupe();
+    }
 
-    // Check if memoization is disabled
-    if is_memo_disabled() {
+    if args.repair {
+        return run_repair();
+    }
+
+    if let Some(out) = &args.bundle {
+        return run_bundle(out);
+    }
+
+    if let Some(input) = &args.unbundle {
+        return run_unbundle(input);
+    }
+
+    if args.self_bench {
+        bench::run(args.quick)?;
+        return Ok(0);
+    }
+
+    if args.prune {
+        return run_prune(&args);
+    }
+
+    if args.clear {
+        return run_clear(&args);
+    }
+
+    if args.batch {
+        return run_batch(&args);
+    }
+
+    if let Some(path) = &args.command_file {
+        args.command = read_command_file(path)?;
+    }
+
+    if args.exec_shell {
+        args.command = shell_command(&args.command);
+    }
+
+    if args.pin || args.unpin {
+        return run_pin(&args);
+    }
+
+    if args.json_meta {
+        return run_json_meta(&args);
+    }
+
+    if args.drop || args.drop_digest.is_some() {
+        return run_drop(&args);
+    }
+
+    // Check if memoization is disabled, globally or by a .memoignore file
+    let command_string = build_command_string(&args.command);
+    let ignored_by = ignore::find_ignoring_file(&std::env::current_dir()?, &command_string)?;
+
+    if is_memo_disabled() || ignored_by.is_some() {
         if args.verbose {
-            eprintln!(":: memo :: disabled");
+            match &ignored_by {
+                Some(path) => diag!("ignored by {}", path.display()),
+                None => diag!("disabled"),
+            }
         }
 
-        // Convert Vec<String> to Vec<&str>
-        let cmd_args: Vec<&str> = args.command.iter().map(|s| s.as_str()).collect();
+        let cmd_args = exec_args(&args);
 
         // Execute directly without caching
-        let result = execute_direct(&cmd_args)?;
-        return Ok(result.exit_code);
+        let result = execute_direct(&cmd_args, args.stdin_file.as_deref())?;
+        if json_status {
+            status::record("ignored", None);
+            // The run already happened, so a missing cache location can't fail it
+            let cache_dir = get_cache_dir().ok();
+            status::print(&args.command, result.exit_status(), cache_dir.as_deref());
+        }
+        return Ok(result.exit_status());
     }
 
     // Get cache directory
@@ -129,59 +1551,729 @@ fn run() -> Result<i32> {
     // Get current working directory
     let cwd = std::env::current_dir()?.to_string_lossy().to_string();
 
+    // Piped stdin is part of the command's input, so read it all (even if this
+    // turns out to be a hit), key on it, and give it to the command
+    let _stdin_spool = if args.stdin_file.is_none()
+        && !args.ignore_stdin
+        && !args.passthrough_tty
+        && !io::stdin().is_terminal()
+    {
+        let spool = cache::spool_stdin(&cache_dir, &mut io::stdin().lock())?;
+        if args.verbose && spool.bytes > 0 {
+            diag!("keying on {} bytes of stdin", spool.bytes);
+        }
+        // Empty stdin keys like no stdin, so existing entries stay valid
+        (spool.bytes > 0).then(|| {
+            args.stdin_file = Some(spool.path.clone());
+            spool
+        })
+    } else {
+        None
+    };
+
+    if args.diff {
+        let digest = cache_key(&args, &cwd)?;
+        return run_diff(&args, &cache_dir, &digest);
+    }
+
+    if args.watch {
+        return run_watch(&args, &cache_dir, &cwd);
+    }
+
+    let exit_code = memoize(&args, &cache_dir, &cwd)?;
+    if json_status {
+        status::print(&args.command, exit_code, Some(&cache_dir));
+    }
+    Ok(exit_code)
+}
+
+/// Memoize each command read from stdin, printing `<status>\t<line>` per line
+///
+/// The status is `hit` if the command was already cached, `miss` if it was run
+/// and cached, `ignored` if caching is disabled for it, or `error` if the line
+/// could not be parsed. Blank lines are skipped. Commands' output is only
+/// cached, not printed. Returns 1 if any line was an error or any command
+/// failed, or the failing command's exit code with `--fail-fast`.
+fn run_batch(args: &Cli) -> Result<i32> {
+    let cache_dir = get_cache_dir()?;
+    ensure_cache_dir(&cache_dir)?;
+    cleanup_temp_dirs(&cache_dir, args.verbose)?;
+
+    let cwd_path = std::env::current_dir()?;
+    let cwd = cwd_path.to_string_lossy().to_string();
+    let mut failed = false;
+    let mut failures = Vec::new();
+
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let command = match shell_words::split(&line) {
+            Ok(command) if !command.is_empty() => command,
+            _ => {
+                println!("error\t{line}");
+                failed = true;
+                continue;
+            }
+        };
+
+        let ignored = is_memo_disabled()
+            || ignore::find_ignoring_file(&cwd_path, &build_command_string(&command))?.is_some();
+        let (status, exit_code) = if ignored {
+            ("ignored", 0)
+        } else {
+            let args = Cli {
+                command,
+                ..args.clone()
+            };
+            let digest = cache_key(&args, &cwd)?;
+            if memo_complete(&cache_dir, &digest) {
+                (
+                    "hit",
+                    read_memo_metadata(&cache_dir, &digest)?.exit_status(),
+                )
+            } else {
+                ("miss", memoize(&args, &cache_dir, &cwd)?)
+            }
+        };
+        println!("{status}\t{line}");
+
+        if exit_code != 0 {
+            if args.fail_fast {
+                diag!("stopping batch: `{line}` exited with {exit_code}");
+                return Ok(exit_code);
+            }
+            failures.push((exit_code, line));
+        }
+    }
+
+    if !failures.is_empty() {
+        diag!("{} command(s) failed:", failures.len());
+        for (exit_code, line) in &failures {
+            diag!("  exit {exit_code}: {line}");
+        }
+        failed = true;
+    }
+
+    Ok(if failed { 1 } else { 0 })
+}
+
+/// Memoize the command, then re-run it whenever a dependency changes
+///
+/// Dependency paths are polled for changes in size or modification time.
+/// Changes are debounced so a burst of writes (e.g. a checkout) causes a
+/// single re-execution. Since dependencies are part of the cache key, each
+/// re-execution refreshes the cache. Runs until interrupted.
+fn run_watch(args: &Cli, cache_dir: &Path, cwd: &str) -> Result<i32> {
+    loop {
+        let before = deps::snapshot(&args.depends_on);
+        memoize(args, cache_dir, cwd)?;
+
+        if args.verbose {
+            diag!("watching {} path(s) for changes", args.depends_on.len());
+        }
+
+        wait_for_change(&args.depends_on, before);
+
+        if args.verbose {
+            diag!("change detected");
+        }
+    }
+}
+
+/// Block until the dependency paths differ from a snapshot and then settle
+fn wait_for_change(paths: &[PathBuf], mut last: deps::Snapshot) {
+    loop {
+        thread::sleep(WATCH_POLL_INTERVAL);
+        let current = deps::snapshot(paths);
+        if current != last {
+            last = current;
+            break;
+        }
+    }
+
+    loop {
+        thread::sleep(WATCH_DEBOUNCE);
+        let current = deps::snapshot(paths);
+        if current == last {
+            return;
+        }
+        last = current;
+    }
+}
+
+/// Replay a cached entry to stdout/stderr and return its exit code
+fn replay(args: &Cli, cache_dir: &Path, digest: &str) -> Result<i32> {
+    // Read metadata
+    let memo = read_memo_metadata(cache_dir, digest)?;
+
+    // Hit counts only feed --rank, so a failure to record one is not an error
+    let _ = record_hit(cache_dir, digest);
+
+    // Only the exit code is wanted, so leave the output files (and any
+    // decryption) alone
+    if args.replay_exit_only {
+        return Ok(replay_exit(args, &memo));
+    }
+
+    let keys = match &memo.encryption {
+        Some(encryption) => Some(EntryKeys::for_entry(&crypto::passphrase()?, encryption)?),
+        None => None,
+    };
+
+    // Streams with a requested output file are written there instead
+    let (_, out_path, err_path) = get_cache_paths_in_dir(&digest_dir(cache_dir, digest));
+    write_output_files(args, &out_path, &err_path, keys.as_ref())?;
+    let (stdout_file, stderr_file) = output_files(args);
+
+    // Replay both streams in the order they were captured (an encrypted entry
+    // can't have been captured with --interleave)
+    if args.interleave && keys.is_none() {
+        let stdout = ExtraCopy::new(io::stdout(), args.tee_to.as_deref());
+        let stderr: Box<dyn Write> = if args.merge_streams {
+            Box::new(io::stdout())
+        } else {
+            Box::new(io::stderr())
+        };
+        stream_interleaved(cache_dir, digest, stdout, stderr)?;
+        return Ok(replay_exit(args, &memo));
+    }
+
+    // Stream output to stdout/stderr (partial and timed replay conflict with
+    // --encrypt, so encrypted entries are always replayed whole)
+    if stdout_file.is_none() {
+        let stdout: Box<dyn Write> = if args.replay_stdout_to_stderr {
+            Box::new(io::stderr())
+        } else {
+            Box::new(io::stdout())
+        };
+        let console = ExtraCopy::new(stdout, args.tee_to.as_deref());
+        let mut stdout = ReplacingWriter::new(&args.replace, "stdout", console);
+        match (&keys, replay_range(args)) {
+            (Some(keys), _) => stream_stdout_decrypted(cache_dir, digest, keys, &mut stdout)?,
+            (None, Some(range)) => stream_range(cache_dir, digest, range, &mut stdout)?,
+            (None, None) if args.replay_timing => {
+                stream_stdout_timed(cache_dir, digest, &mut stdout)?
+            }
+            (None, None) => stream_stdout(cache_dir, digest, &mut stdout)?,
+        }
+        stdout.finish()?;
+    }
+    if stderr_file.is_none() {
+        let console: Box<dyn Write> = if args.merge_streams {
+            Box::new(io::stdout())
+        } else {
+            Box::new(io::stderr())
+        };
+        let mut console = ReplacingWriter::new(&args.replace, "stderr", console);
+        match &keys {
+            Some(keys) => stream_stderr_decrypted(cache_dir, digest, keys, &mut console)?,
+            None => stream_stderr(cache_dir, digest, &mut console)?,
+        }
+        console.finish()?;
+    }
+
+    Ok(replay_exit(args, &memo))
+}
+
+/// Print the exit trailer if requested and pick the exit code of a hit
+fn replay_exit(args: &Cli, memo: &Memo) -> i32 {
+    if args.exit_trailer {
+        println!("::memo-exit={}::", memo.exit_status());
+    }
+
+    // Exit with stored exit code, unless the caller only wants the output
+    if args.ignore_exit {
+        return 0;
+    }
+    memo.exit_status()
+}
+
+/// Print the part of fresh stdout that extends the previous run's stdout
+///
+/// If the fresh output doesn't start with the previous output (or the
+/// previous output is gone), all of it is printed.
+fn print_appended(args: &Cli, previous: &Path, fresh: &Path) -> Result<()> {
+    let start = match compare::appended_offset(previous, fresh) {
+        Ok(Some(offset)) => offset,
+        _ => {
+            if args.verbose {
+                diag!("output doesn't extend the previous run, printing all of it");
+            }
+            0
+        }
+    };
+
+    let mut file = fs::File::open(fresh)?;
+    file.seek(SeekFrom::Start(start))?;
+    cache::copy(&mut file, &mut io::stdout())?;
+    Ok(())
+}
+
+/// Destinations requested with --output-dir, --stdout-file, and --stderr-file
+fn output_files(args: &Cli) -> (Option<PathBuf>, Option<PathBuf>) {
+    match &args.output_dir {
+        Some(dir) => (Some(dir.join("stdout")), Some(dir.join("stderr"))),
+        None => (args.stdout_file.clone(), args.stderr_file.clone()),
+    }
+}
+
+/// Copy output files (decrypting them if needed) to the requested destinations
+fn write_output_files(
+    args: &Cli,
+    out_path: &Path,
+    err_path: &Path,
+    keys: Option<&EntryKeys>,
+) -> Result<()> {
+    let (stdout_file, stderr_file) = output_files(args);
+    if let Some(dir) = &args.output_dir {
+        fs::create_dir_all(dir)?;
+    }
+
+    if let Some(dest) = stdout_file {
+        let (mut src, mut dest) = (fs::File::open(out_path)?, fs::File::create(dest)?);
+        match keys {
+            Some(keys) => keys.decrypt_stdout(src, dest)?,
+            None => {
+                io::copy(&mut src, &mut dest)?;
+            }
+        }
+    }
+    if let Some(dest) = stderr_file {
+        let (mut src, mut dest) = (fs::File::open(err_path)?, fs::File::create(dest)?);
+        match keys {
+            Some(keys) => keys.decrypt_stderr(src, dest)?,
+            None => {
+                io::copy(&mut src, &mut dest)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Outcome of waiting for the single-flight lock on a missed entry
+enum Flight {
+    /// We hold the lock and should run the command
+    Lead(EntryLock),
+    /// Another process committed the entry while we waited
+    Cached,
+    /// The lock was held for too long; run the command without it
+    TimedOut,
+}
+
+/// Take the single-flight lock for a digest, or wait for its holder
+///
+/// If the holder finishes without committing (e.g. `--no-cache-exit`), the
+/// lock is released and the next waiter takes over.
+fn wait_for_flight(args: &Cli, cache_dir: &Path, digest: &str) -> Result<Flight> {
+    let timeout = args.single_flight_timeout.unwrap_or(SINGLE_FLIGHT_TIMEOUT);
+    let start = Instant::now();
+
+    loop {
+        if let Some(lock) = try_lock_entry(cache_dir, digest)? {
+            // The previous holder may have committed just before releasing
+            if memo_complete(cache_dir, digest) {
+                return Ok(Flight::Cached);
+            }
+            return Ok(Flight::Lead(lock));
+        }
+        if memo_complete(cache_dir, digest) {
+            return Ok(Flight::Cached);
+        }
+        if start.elapsed() >= timeout {
+            return Ok(Flight::TimedOut);
+        }
+        thread::sleep(SINGLE_FLIGHT_POLL_INTERVAL);
+    }
+}
+
+/// Wait until one of the `--max-concurrency` slots is free and take it
+fn wait_for_slot(args: &Cli, cache_dir: &Path, limit: usize) -> Result<ConcurrencySlot> {
+    let mut waiting = false;
+    loop {
+        if let Some(slot) = try_acquire_slot(cache_dir, limit)? {
+            return Ok(slot);
+        }
+        if args.verbose && !waiting {
+            diag!("waiting for one of {limit} concurrency slot(s)");
+        }
+        waiting = true;
+        thread::sleep(CONCURRENCY_POLL_INTERVAL);
+    }
+}
+
+/// Replay the command from cache, or execute and cache it on a miss
+fn memoize(args: &Cli, cache_dir: &Path, cwd: &str) -> Result<i32> {
     // Build command string for display and compute digest from argv.
-    let command_string = build_command_string(&args.command);
-    let digest = compute_digest_for_args(&args.command, &cwd)?;
+    let command_string = display_command(args);
+    let digest = cache_key(args, cwd)?;
 
     // Check if memo exists
-    if memo_complete(&cache_dir, &digest) {
-        // Cache hit - replay
+    let mut hit = memo_complete(cache_dir, &digest);
+
+    // Fall back to the shared remote cache, if one is configured (a cold
+    // start would only replace what it fetched)
+    let remote = HttpBackend::from_env();
+    if let Some(remote) = remote.as_ref().filter(|_| !hit && !args.cold_start) {
+        match remote.fetch(cache_dir, &digest) {
+            Ok(fetched) => {
+                if fetched && args.verbose {
+                    diag!(
+                        event = "fetched", command = &command_string, digest = &digest;
+                        "fetched `{command_string}` from remote"
+                    );
+                }
+                hit = fetched;
+            }
+            Err(e) => diag!("WARNING: remote cache unavailable: {e}"),
+        }
+    }
+
+    // A corrupt meta.json can't be replayed, so run the command again instead
+    if hit {
+        if let Err(e) = read_memo_metadata(cache_dir, &digest) {
+            if matches!(
+                e.kind(),
+                io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+            ) {
+                if args.verbose {
+                    diag!("WARNING: corrupt metadata for `{command_string}` ({e}), re-running");
+                }
+                remove_cache_entry(cache_dir, &digest)?;
+                hit = false;
+            }
+        }
+    }
+
+    // Invalidate the entry if the command's binary changed since it was cached
+    if hit && args.auto_invalidate_binary {
+        let memo = read_memo_metadata(cache_dir, &digest)?;
+        if binary_is_newer(exec_args(args)[0], &memo) {
+            if args.verbose {
+                diag!(
+                    event = "stale", command = &command_string, digest = &digest;
+                    "stale `{command_string}` (binary modified)"
+                );
+            }
+            remove_cache_entry(cache_dir, &digest)?;
+            hit = false;
+        }
+    }
+
+    // Expire entries by age, with separate limits for successes and failures
+    if hit && (args.ttl.is_some() || args.ttl_success.is_some() || args.ttl_failure.is_some()) {
+        let memo = read_memo_metadata(cache_dir, &digest)?;
+        let ttl = if memo.exit_code == 0 {
+            args.ttl_success
+        } else {
+            args.ttl_failure
+        }
+        .or(args.ttl);
+        if ttl.is_some_and(|ttl| memo.is_expired(ttl, Utc::now())) {
+            if args.verbose {
+                diag!(
+                    event = "stale", command = &command_string, digest = &digest;
+                    "stale `{command_string}` (expired)"
+                );
+            }
+            remove_cache_entry(cache_dir, &digest)?;
+            hit = false;
+        }
+    }
+
+    // An unknown exit code would replay as a misleading 255, so run it again
+    // (unless the signal that killed the command was recorded)
+    if hit && args.strict_exit {
+        let memo = read_memo_metadata(cache_dir, &digest)?;
+        if memo.exit_code == UNKNOWN_EXIT_CODE && memo.signal.is_none() {
+            if args.verbose {
+                diag!(
+                    event = "stale", command = &command_string, digest = &digest;
+                    "stale `{command_string}` (unknown exit code)"
+                );
+            }
+            remove_cache_entry(cache_dir, &digest)?;
+            hit = false;
+        }
+    }
+
+    // An incremental run always executes, against the previous entry's output
+    let previous = if args.incremental && hit {
+        hit = false;
+        Some(digest_dir(cache_dir, &digest).join("stdout"))
+    } else {
+        None
+    };
+
+    // A cold start always executes, replacing the entry it would have hit
+    let cold = args.cold_start && hit;
+    if cold {
+        hit = false;
         if args.verbose {
-            eprintln!(":: memo :: hit `{command_string}` => {digest}");
+            diag!(
+                event = "cold-start", command = &command_string, digest = &digest;
+                "cold start `{command_string}` => {digest} (cached, running anyway)"
+            );
         }
+    }
 
-        // Read metadata
-        let memo = read_memo_metadata(&cache_dir, &digest)?;
+    // A sampled hit executes to check that the cached result still holds
+    let sampled = hit && args.sample_rate > 0.0 && sample::should_sample(args.sample_rate);
+    let sample_of = if sampled {
+        hit = false;
+        if args.verbose {
+            diag!(
+                event = "sample", command = &command_string, digest = &digest;
+                "sample `{command_string}` => {digest} (cached, re-running to check)"
+            );
+        }
+        Some(read_memo_metadata(cache_dir, &digest)?.exit_code)
+    } else {
+        None
+    };
 
-        // Stream output to stdout/stderr
-        stream_stdout(&cache_dir, &digest, io::stdout())?;
-        stream_stderr(&cache_dir, &digest, io::stderr())?;
+    if hit {
+        // Cache hit - replay
+        status::record("hit", Some(&digest));
+        if args.verbose {
+            // Entries from before run times were recorded have nothing to report
+            let saved = match read_memo_metadata(cache_dir, &digest) {
+                Ok(memo) if memo.duration_ms > 0 => format!(" (saved ~{}ms)", memo.duration_ms),
+                _ => String::new(),
+            };
+            diag!(
+                event = "hit", command = &command_string, digest = &digest;
+                "hit `{command_string}` => {digest}{saved}"
+            );
+        }
 
-        // Exit with stored exit code
-        Ok(memo.exit_code)
+        record_session(cache_dir, &digest, true, None);
+        replay(args, cache_dir, &digest)
     } else {
         // Cache miss - execute and memoize
+        status::record("miss", Some(&digest));
         if args.verbose {
-            eprintln!(":: memo :: miss `{command_string}` => {digest}");
+            diag!(
+                event = "miss", command = &command_string, digest = &digest;
+                "miss `{command_string}` => {digest}"
+            );
+        }
+
+        // Give the user a chance to back out of an accidental expensive run
+        if args.confirm_miss
+            && io::stdin().is_terminal()
+            && io::stderr().is_terminal()
+            && !prompt::confirm(
+                "Command not cached, run it?",
+                io::stdin().lock(),
+                io::stderr(),
+            )?
+        {
+            return Ok(CONFIRM_ABORT_EXIT_CODE);
+        }
+
+        // Interactive commands (sudo, ssh) need the real terminal, so don't tee
+        if args.passthrough_tty {
+            let cmd_args = exec_args(args);
+            let result = execute_direct(&cmd_args, args.stdin_file.as_deref())?;
+            diag!("WARNING: `{command_string}` ran with the terminal attached and was not cached");
+            return Ok(result.exit_status());
         }
 
+        // Let one process run a slow miss while concurrent ones wait for it
+        let _lock = if args.single_flight {
+            match wait_for_flight(args, cache_dir, &digest)? {
+                Flight::Lead(lock) => Some(lock),
+                Flight::Cached => {
+                    status::record("hit", Some(&digest));
+                    if args.verbose {
+                        diag!(
+                            event = "hit", command = &command_string, digest = &digest;
+                            "hit `{command_string}` => {digest} (after waiting)"
+                        );
+                    }
+                    record_session(cache_dir, &digest, true, None);
+                    return replay(args, cache_dir, &digest);
+                }
+                Flight::TimedOut => {
+                    if args.verbose {
+                        diag!("timed out waiting for `{command_string}`");
+                    }
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // Queue behind other misses if too many are already running
+        let _slot = match args.max_concurrency {
+            Some(limit) => Some(wait_for_slot(args, cache_dir, limit as usize)?),
+            None => None,
+        };
+
+        // Fail before running anything if there is no passphrase
+        let keys = if args.encrypt {
+            Some(EntryKeys::generate(&crypto::passphrase()?)?)
+        } else {
+            None
+        };
+
         let timestamp = Utc::now().to_rfc3339();
 
+        // A directory left incomplete by a crash would block the commit below
+        if remove_incomplete_entry(cache_dir, &digest)? && args.verbose {
+            diag!("removed incomplete entry {digest}");
+        }
+
         // Create a temp directory for this process to write cache files
-        let mut temp_dir = create_temp_cache_dir(&cache_dir, &digest)?;
+        let mut temp_dir = create_temp_cache_dir(cache_dir, &digest)?;
         let (json_path, out_path, err_path) = temp_dir.get_paths();
 
-        // Convert Vec<String> to Vec<&str>
-        let cmd_args: Vec<&str> = args.command.iter().map(|s| s.as_str()).collect();
+        let cmd_args = exec_args(args);
+
+        let options = ExecOptions {
+            timing_path: args.replay_timing.then(|| temp_dir.path.join(TIMING_FILE)),
+            order_path: args.interleave.then(|| temp_dir.path.join(ORDER_FILE)),
+            encryption: keys.clone(),
+            // In batch mode stdin holds the remaining commands
+            null_stdin: args.batch,
+            ..exec_options(args)
+        };
 
-        // Execute command and stream to files AND console simultaneously
-        let result = execute_and_stream(&cmd_args, &out_path, &err_path)?;
+        // Execute command and stream to files AND console simultaneously (files
+        // only with --no-tee, or in batch mode, where stdout carries the
+        // status lines)
+        let quiet = args.batch || args.no_tee;
+        let stdout_console: Box<dyn Write> = if quiet || previous.is_some() {
+            // With --incremental, stdout is held back until it can be
+            // compared with the previous run
+            Box::new(io::sink())
+        } else {
+            Box::new(io::stdout())
+        };
+        let stderr_console: Box<dyn Write + Send> = if quiet {
+            Box::new(io::sink())
+        } else {
+            Box::new(io::stderr())
+        };
+        let started = Instant::now();
+        let result = execute_and_tee(
+            &cmd_args,
+            &out_path,
+            &err_path,
+            &options,
+            ExtraCopy::new(stdout_console, args.tee_to.as_deref()),
+            stderr_console,
+        )?;
+        let duration_ms = started.elapsed().as_millis() as u64;
+        let finished_at = Utc::now().to_rfc3339();
 
         // Report any file write errors
         if let Some(path) = &result.stdout_error {
-            eprintln!(":: memo :: ERROR: could not write {}", path.display());
+            diag!("ERROR: could not write {}", path.display());
         }
         if let Some(path) = &result.stderr_error {
-            eprintln!(":: memo :: ERROR: could not write {}", path.display());
+            diag!("ERROR: could not write {}", path.display());
+        }
+
+        record_session(cache_dir, &digest, false, Some(duration_ms));
+
+        if let Some(previous) = &previous {
+            print_appended(args, previous, &out_path)?;
+        }
+
+        // A killed run's output is partial, so it must never be replayed
+        if result.timed_out {
+            diag!(
+                "WARNING: `{command_string}` timed out after {:?} and was not cached",
+                args.timeout.unwrap_or_default()
+            );
+            return Ok(result.exit_status());
+        }
+
+        // Report a sampled hit whose cached result no longer holds
+        let mut drifted = false;
+        if let Some(cached_exit) = sample_of {
+            let cached = digest_dir(cache_dir, &digest);
+            if let Some(difference) = describe_difference(
+                (cached_exit, &cached.join("stdout"), &cached.join("stderr")),
+                (result.exit_code, &out_path, &err_path),
+            )? {
+                drifted = true;
+                diag!(
+                    "WARNING: `{command_string}` no longer matches its cached result ({difference})"
+                );
+            }
+        }
+
+        // Keep requested output files even if the result isn't cached below
+        write_output_files(args, &out_path, &err_path, keys.as_ref())?;
+
+        // Skip caching failures, which are often transient, unless asked to
+        // keep them, and any exit code the command uses for a transient result
+        if (result.exit_code != 0 && !args.cache_failures)
+            || args.no_cache_exit.contains(&result.exit_code)
+        {
+            if args.verbose {
+                diag!("not caching (exit {})", result.exit_status());
+            }
+            return Ok(result.exit_status());
+        }
+
+        // Skip caching trivial commands that are cheaper to re-run
+        if args.no_commit_empty
+            && result.exit_code == 0
+            && result.stdout_bytes == 0
+            && result.stderr_bytes == 0
+        {
+            if args.verbose {
+                diag!("not caching (no output)");
+            }
+            return Ok(result.exit_status());
+        }
+
+        // Skip caching commands that are fast enough to just re-run
+        if args.min_duration.is_some_and(|min| duration_ms < min) {
+            if args.verbose {
+                diag!("not caching (ran in {duration_ms}ms)");
+            }
+            return Ok(result.exit_status());
+        }
+
+        // Refuse to cache output that a second run doesn't reproduce
+        if args.deterministic_check {
+            // Our stdin was consumed by the first run
+            let rerun = ExecOptions {
+                null_stdin: true,
+                ..exec_options(args)
+            };
+            if let Some(difference) = check_deterministic(
+                &cmd_args, cache_dir, &digest, &rerun, &result, &out_path, &err_path,
+            )? {
+                return Err(MemoError::Nondeterministic(command_string, difference));
+            }
         }
 
         // Create memo metadata
         let memo = Memo {
             cmd: args.command.clone(),
-            cwd: cwd.clone(),
+            cwd: cwd.to_string(),
             exit_code: result.exit_code,
-            timestamp,
+            signal: result.signal,
+            timestamp: timestamp.clone(),
             digest: digest.clone(),
+            rusage: result.rusage,
+            stdout_was_piped: !args.pty,
+            stderr_was_piped: true,
+            encryption: keys.map(|keys| keys.encryption),
+            duration_ms,
+            started_at: Some(timestamp),
+            finished_at: Some(finished_at),
+            memo_version: MEMO_VERSION.to_string(),
         };
 
         // Write metadata to JSON
@@ -191,19 +2283,57 @@ fn run() -> Result<i32> {
             f.write_all(json.as_bytes())?;
         }
 
+        // The new output replaces the previous run's
+        if previous.is_some() || args.cold_start || (drifted && args.sample_refresh) {
+            remove_cache_entry(cache_dir, &digest)?;
+        }
+
         // Atomically commit the temp directory to the final location
         // If another process already committed, that's fine - we just clean up
-        let committed = commit_cache_dir(&mut temp_dir, &cache_dir, &digest)?;
+        let committed = commit_cache_dir(&mut temp_dir, cache_dir, &digest)?;
+
+        if let Some(remote) = remote.as_ref().filter(|_| committed) {
+            if let Err(e) = remote.store(cache_dir, &digest) {
+                diag!("WARNING: could not upload to remote cache: {e}");
+            }
+        }
 
         if args.verbose {
             if committed {
-                eprintln!(":: memo :: committed temp dir {}", temp_dir.path.display());
+                diag!("committed temp dir {}", temp_dir.path.display());
             } else {
-                eprintln!(":: memo :: dropping temp dir {}", temp_dir.path.display());
+                diag!("dropping temp dir {}", temp_dir.path.display());
+            }
+        }
+
+        // Make room for the new entry, keeping it even if it alone is too big.
+        // The entry is already committed, so a failure here doesn't fail the run.
+        if let Some(max_size) = args.max_size.filter(|_| committed) {
+            match evict_to_size_limit(cache_dir, max_size, &digest) {
+                Ok(report) if args.verbose && report.removed > 0 => diag!(
+                    "evicted {} entries ({} bytes) to stay under {max_size} bytes",
+                    report.removed,
+                    report.bytes
+                ),
+                Ok(_) => {}
+                Err(e) => {
+                    diag!("WARNING: could not evict entries to stay under {max_size} bytes: {e}")
+                }
             }
         }
 
+        // Flag outputs that will bloat the cache, but keep them (unless another
+        // process committed first, so ours weren't cached)
+        let output_size = result.stdout_bytes + result.stderr_bytes;
+        if args
+            .warn_output_size
+            .filter(|_| committed)
+            .is_some_and(|limit| output_size > limit)
+        {
+            diag!("WARNING: cached {output_size} bytes of output for {digest}");
+        }
+
         // Exit with command's exit code (output already streamed to console)
-        Ok(result.exit_code)
+        Ok(result.exit_status())
     }
 }
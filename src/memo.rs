@@ -3,7 +3,10 @@
 //! This module defines the metadata structure that is serialized to JSON
 //! and stored in the cache directory.
 
+use crate::constants::SIGNAL_EXIT_BASE;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 /// Metadata for a memoized command execution
 ///
@@ -18,10 +21,111 @@ pub struct Memo {
     pub cwd: String,
     /// The exit code returned by the command
     pub exit_code: i32,
-    /// ISO 8601 timestamp of when the command was executed
+    /// The signal that killed the command, if any (Unix only; `exit_code` is
+    /// then unknown)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+    /// ISO 8601 timestamp of when the command was executed (same as
+    /// `started_at`, kept for compatibility)
     pub timestamp: String,
     /// SHA-256 digest used as the cache key
     pub digest: String,
+    /// Resource usage of the command (Unix only; absent in older entries)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rusage: Option<ResourceUsage>,
+    /// Whether stdout was a pipe rather than a terminal (older entries: pipe)
+    #[serde(default = "default_true")]
+    pub stdout_was_piped: bool,
+    /// Whether stderr was a pipe rather than a terminal (older entries: pipe)
+    #[serde(default = "default_true")]
+    pub stderr_was_piped: bool,
+    /// Key derivation salt and nonces if the output files are encrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    /// Wall-clock run time of the command in milliseconds (0 in older entries)
+    #[serde(default)]
+    pub duration_ms: u64,
+    /// RFC 3339 timestamp of when the command was started (absent in older
+    /// entries)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<String>,
+    /// RFC 3339 timestamp of when the command exited (absent in older entries)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    /// Version of memo that created the entry (`unknown` in older entries)
+    #[serde(default = "unknown_version")]
+    pub memo_version: String,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn unknown_version() -> String {
+    "unknown".to_string()
+}
+
+/// Resource usage of a finished command, as reported by `wait4`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// Peak resident set size in kilobytes
+    pub max_rss_kb: u64,
+    /// User CPU time in milliseconds
+    pub user_ms: u64,
+    /// System CPU time in milliseconds
+    pub sys_ms: u64,
+}
+
+/// Parameters needed to decrypt an entry's output (see `--encrypt`)
+///
+/// All fields are hex-encoded. The passphrase itself is never stored.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Encryption {
+    /// Argon2id salt for deriving the key from the passphrase
+    pub salt: String,
+    /// STREAM nonce prefix for the stdout file
+    pub stdout_nonce: String,
+    /// STREAM nonce prefix for the stderr file
+    pub stderr_nonce: String,
+}
+
+impl Memo {
+    /// Parse the execution timestamp
+    ///
+    /// Returns `None` if the stored timestamp is not valid RFC 3339.
+    pub fn executed_at(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.timestamp)
+            .ok()
+            .map(|t| t.with_timezone(&Utc))
+    }
+
+    /// The status memo exits with when replaying the entry
+    ///
+    /// This is the exit code, or `128 + signal` for a command killed by a
+    /// signal, as a shell reports it.
+    pub fn exit_status(&self) -> i32 {
+        exit_status(self.exit_code, self.signal)
+    }
+
+    /// Whether the entry was cached more than `ttl` before `now`
+    ///
+    /// An unparsable timestamp counts as expired, and so does any entry when
+    /// `ttl` is zero.
+    pub fn is_expired(&self, ttl: Duration, now: DateTime<Utc>) -> bool {
+        if ttl.is_zero() {
+            return true;
+        }
+        match (self.executed_at(), chrono::Duration::from_std(ttl)) {
+            (Some(executed_at), Ok(ttl)) => now - executed_at > ttl,
+            (Some(_), Err(_)) => false,
+            (None, _) => true,
+        }
+    }
+}
+
+/// Combine an exit code and terminating signal into a shell-style status
+pub fn exit_status(exit_code: i32, signal: Option<i32>) -> i32 {
+    signal.map_or(exit_code, |signal| SIGNAL_EXIT_BASE + signal)
 }
 
 #[cfg(test)]
@@ -39,8 +143,17 @@ mod tests {
             cmd: vec!["echo".to_string(), "hello".to_string()],
             cwd: "/test/path".to_string(),
             exit_code: 0,
+            signal: None,
             timestamp: ts(),
             digest: "abc123".to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
         };
 
         let json = serde_json::to_string(&memo).unwrap();
@@ -75,8 +188,17 @@ mod tests {
             cmd: vec!["ls".to_string(), "-la".to_string()],
             cwd: "/home/user".to_string(),
             exit_code: 1,
+            signal: None,
             timestamp: ts(),
             digest: "xyz789".to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
         };
 
         let json = serde_json::to_string(&original).unwrap();
@@ -91,8 +213,17 @@ mod tests {
             cmd: vec!["echo".to_string(), "\"hello\" 'world' $USER".to_string()],
             cwd: "/tmp".to_string(),
             exit_code: 0,
+            signal: None,
             timestamp: ts(),
             digest: "special123".to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
         };
 
         let json = serde_json::to_string(&memo).unwrap();
@@ -107,8 +238,17 @@ mod tests {
             cmd: vec!["test".to_string()],
             cwd: "/".to_string(),
             exit_code: -1,
+            signal: None,
             timestamp: ts(),
             digest: "neg123".to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
         };
 
         let json = serde_json::to_string(&memo).unwrap();
@@ -117,6 +257,144 @@ mod tests {
         assert_eq!(memo.exit_code, deserialized.exit_code);
     }
 
+    #[test]
+    fn test_memo_signal_round_trip() {
+        let json = r#"{
+            "cmd": ["sh"],
+            "cwd": "/",
+            "exit_code": -1,
+            "signal": 9,
+            "timestamp": "2025-12-22T01:51:52.369Z",
+            "digest": "sig"
+        }"#;
+        let memo: Memo = serde_json::from_str(json).unwrap();
+        assert_eq!(memo.signal, Some(9));
+        assert_eq!(memo.exit_status(), 137);
+
+        let value = serde_json::to_value(&memo).unwrap();
+        assert_eq!(value["signal"], json!(9));
+        let plain = Memo {
+            signal: None,
+            exit_code: 3,
+            ..memo
+        };
+        assert_eq!(plain.exit_status(), 3);
+        assert!(serde_json::to_value(&plain)
+            .unwrap()
+            .get("signal")
+            .is_none());
+    }
+
+    #[test]
+    fn test_memo_deserialization_without_rusage() {
+        let json = r#"{
+            "cmd": ["true"],
+            "cwd": "/",
+            "exit_code": 0,
+            "timestamp": "2025-12-22T01:51:52.369Z",
+            "digest": "old123"
+        }"#;
+
+        let memo: Memo = serde_json::from_str(json).unwrap();
+        assert_eq!(memo.rusage, None);
+        assert_eq!(memo.started_at, None);
+        assert_eq!(memo.finished_at, None);
+        assert_eq!(memo.memo_version, "unknown");
+        assert!(memo.stdout_was_piped);
+        assert!(memo.stderr_was_piped);
+
+        let value = serde_json::to_value(&memo).unwrap();
+        assert!(value.get("rusage").is_none());
+        assert!(value.get("started_at").is_none());
+    }
+
+    #[test]
+    fn test_memo_rusage_serialization() {
+        let memo = Memo {
+            cmd: vec!["true".to_string()],
+            cwd: "/".to_string(),
+            exit_code: 0,
+            signal: None,
+            timestamp: ts(),
+            digest: "rusage123".to_string(),
+            rusage: Some(ResourceUsage {
+                max_rss_kb: 2048,
+                user_ms: 15,
+                sys_ms: 3,
+            }),
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+
+        let value = serde_json::to_value(&memo).unwrap();
+        assert_eq!(
+            value["rusage"],
+            json!({"max_rss_kb": 2048, "user_ms": 15, "sys_ms": 3})
+        );
+    }
+
+    #[test]
+    fn test_memo_executed_at() {
+        let mut memo = Memo {
+            cmd: vec!["test".to_string()],
+            cwd: "/".to_string(),
+            exit_code: 0,
+            signal: None,
+            timestamp: ts(),
+            digest: "ts123".to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+
+        let executed_at = memo.executed_at().unwrap();
+        assert_eq!(executed_at.to_rfc3339(), "2025-12-22T01:51:52.369+00:00");
+
+        memo.timestamp = "not a timestamp".to_string();
+        assert!(memo.executed_at().is_none());
+    }
+
+    #[test]
+    fn test_memo_is_expired() {
+        let mut memo = Memo {
+            cmd: vec!["test".to_string()],
+            cwd: "/".to_string(),
+            exit_code: 0,
+            signal: None,
+            timestamp: ts(),
+            digest: "ttl123".to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
+        };
+        let cached_at = memo.executed_at().unwrap();
+        let minute = Duration::from_secs(60);
+
+        let later = cached_at + chrono::Duration::seconds(90);
+        assert!(memo.is_expired(minute, later));
+        assert!(!memo.is_expired(2 * minute, later));
+        assert!(!memo.is_expired(minute, cached_at));
+        assert!(memo.is_expired(Duration::ZERO, cached_at));
+
+        memo.timestamp = "not a timestamp".to_string();
+        assert!(memo.is_expired(minute, later));
+    }
+
     #[test]
     fn test_memo_multiline_command() {
         let memo = Memo {
@@ -127,8 +405,17 @@ mod tests {
             ],
             cwd: "/var".to_string(),
             exit_code: 0,
+            signal: None,
             timestamp: ts(),
             digest: "multi123".to_string(),
+            rusage: None,
+            stdout_was_piped: true,
+            stderr_was_piped: true,
+            encryption: None,
+            duration_ms: 0,
+            started_at: None,
+            finished_at: None,
+            memo_version: "unknown".to_string(),
         };
 
         let json = serde_json::to_string(&memo).unwrap();
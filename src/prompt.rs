@@ -0,0 +1,55 @@
+//! Interactive confirmation prompts
+
+use std::io::{self, BufRead, Write};
+
+/// Ask a yes/no question, defaulting to "no"
+///
+/// Writes `question` followed by ` [y/N] ` to `output` and reads one line
+/// from `input`. Only `y` or `yes` (any case) confirm; anything else,
+/// including end of input, declines.
+pub fn confirm<R: BufRead, W: Write>(
+    question: &str,
+    mut input: R,
+    mut output: W,
+) -> io::Result<bool> {
+    write!(output, "{question} [y/N] ")?;
+    output.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+
+    let answer = answer.trim().to_ascii_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn ask(answer: &str) -> (bool, String) {
+        let mut output = Vec::new();
+        let confirmed = confirm("Run it?", Cursor::new(answer), &mut output).unwrap();
+        (confirmed, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_confirm_no() {
+        let (confirmed, output) = ask("n\n");
+        assert!(!confirmed);
+        assert_eq!(output, "Run it? [y/N] ");
+    }
+
+    #[test]
+    fn test_confirm_yes() {
+        assert!(ask("y\n").0);
+        assert!(ask("Yes\n").0);
+    }
+
+    #[test]
+    fn test_confirm_defaults_to_no() {
+        assert!(!ask("\n").0);
+        assert!(!ask("").0);
+        assert!(!ask("maybe\n").0);
+    }
+}
@@ -0,0 +1,106 @@
+//! Shared remote cache over plain HTTP (`MEMO_REMOTE_URL`)
+//!
+//! An entry is stored as three objects named after the files of a local
+//! entry: `<base>/<digest>/stdout`, `<base>/<digest>/stderr`, and
+//! `<base>/<digest>/meta.json`, the JSON metadata that acts as the entry's
+//! envelope. Any server that answers `PUT` and `GET` for arbitrary paths
+//! (and 404 for missing ones) will do.
+//!
+//! The local cache stays the first level: a local miss is fetched from the
+//! remote into the local cache, and every local commit is uploaded. The
+//! metadata is uploaded last, so a reader that finds it also finds the output.
+
+use crate::cache::{commit_cache_dir, create_temp_cache_dir, digest_dir};
+use crate::constants::REMOTE_TIMEOUT;
+use crate::error::{MemoError, Result};
+use crate::memo::Memo;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+/// Environment variable holding the remote cache's base URL
+pub const REMOTE_URL_VAR: &str = "MEMO_REMOTE_URL";
+
+/// Objects fetched for an entry, metadata first so a missing entry is found
+/// with a single request
+const FETCH_ORDER: [&str; 3] = ["meta.json", "stdout", "stderr"];
+
+/// Objects uploaded for an entry, metadata last (see the module docs)
+const STORE_ORDER: [&str; 3] = ["stdout", "stderr", "meta.json"];
+
+/// A remote cache reached with `GET`/`PUT` requests below a base URL
+pub struct HttpBackend {
+    base_url: String,
+    agent: ureq::Agent,
+}
+
+impl HttpBackend {
+    /// Create a backend for `MEMO_REMOTE_URL`, if it is set
+    pub fn from_env() -> Option<Self> {
+        match std::env::var(REMOTE_URL_VAR) {
+            Ok(url) if !url.is_empty() => Some(Self::new(&url)),
+            _ => None,
+        }
+    }
+
+    /// Create a backend for a base URL
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            agent: ureq::AgentBuilder::new().timeout(REMOTE_TIMEOUT).build(),
+        }
+    }
+
+    fn url(&self, digest: &str, name: &str) -> String {
+        format!("{}/{digest}/{name}", self.base_url)
+    }
+
+    /// Download an entry into the local cache
+    ///
+    /// Returns `Ok(false)` if the remote doesn't have the entry. The entry is
+    /// committed like a locally executed one, so a failed download leaves
+    /// nothing behind.
+    pub fn fetch(&self, cache_dir: &Path, digest: &str) -> Result<bool> {
+        let mut temp_dir = create_temp_cache_dir(cache_dir, digest)?;
+
+        for name in FETCH_ORDER {
+            let response = match self.agent.get(&self.url(digest, name)).call() {
+                Ok(response) => response,
+                Err(ureq::Error::Status(404, _)) => return Ok(false),
+                Err(e) => return Err(MemoError::Remote(e.to_string())),
+            };
+            let mut file = File::create(temp_dir.path.join(name))?;
+            io::copy(&mut response.into_reader(), &mut file)?;
+        }
+
+        // Don't let a confused server plant a different command's output
+        let (json_path, _, _) = temp_dir.get_paths();
+        let memo: Memo = serde_json::from_slice(&fs::read(json_path)?)?;
+        if memo.digest != digest {
+            return Err(MemoError::Remote(format!(
+                "entry {digest} has metadata for {}",
+                memo.digest
+            )));
+        }
+
+        commit_cache_dir(&mut temp_dir, cache_dir, digest)?;
+        Ok(true)
+    }
+
+    /// Upload a committed local entry
+    pub fn store(&self, cache_dir: &Path, digest: &str) -> Result<()> {
+        let dir = digest_dir(cache_dir, digest);
+
+        for name in STORE_ORDER {
+            let file = File::open(dir.join(name))?;
+            let len = file.metadata()?.len();
+            self.agent
+                .put(&self.url(digest, name))
+                .set("Content-Length", &len.to_string())
+                .send(file)
+                .map_err(|e| MemoError::Remote(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+}
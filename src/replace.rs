@@ -0,0 +1,152 @@
+//! Substitutions applied to replayed output (`--replace FROM=TO`)
+//!
+//! Replacements only change what a hit prints; the cached files are never
+//! modified. Output is buffered so a match can't be split across writes, and
+//! output that looks binary is printed unchanged with a warning.
+
+use crate::diag::diag;
+use std::io::{self, Write};
+
+/// One `FROM=TO` substitution
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replacement {
+    from: String,
+    to: String,
+}
+
+impl Replacement {
+    /// Parse `FROM=TO`, splitting at the first `=`
+    ///
+    /// Used as a clap `value_parser`, so errors are plain strings.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text.split_once('=') {
+            Some((from, to)) if !from.is_empty() => Ok(Self {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            Some(_) => Err("FROM must not be empty".to_string()),
+            None => Err("expected FROM=TO".to_string()),
+        }
+    }
+}
+
+/// Apply replacements in order to text output
+///
+/// Returns `None` if the output is binary (contains NUL or is not UTF-8).
+pub fn apply(output: &[u8], replacements: &[Replacement]) -> Option<Vec<u8>> {
+    if output.contains(&0) {
+        return None;
+    }
+    let mut text = std::str::from_utf8(output).ok()?.to_string();
+    for replacement in replacements {
+        text = text.replace(&replacement.from, &replacement.to);
+    }
+    Some(text.into_bytes())
+}
+
+/// A writer that applies replacements to everything written through it
+///
+/// Without replacements, writes pass straight through. Otherwise they are
+/// buffered until [`ReplacingWriter::finish`].
+pub struct ReplacingWriter<'a, W: Write> {
+    replacements: &'a [Replacement],
+    stream: &'static str,
+    buffer: Vec<u8>,
+    inner: W,
+}
+
+impl<'a, W: Write> ReplacingWriter<'a, W> {
+    /// Wrap `inner`; `stream` names the output in the binary-output warning
+    pub fn new(replacements: &'a [Replacement], stream: &'static str, inner: W) -> Self {
+        Self {
+            replacements,
+            stream,
+            buffer: Vec::new(),
+            inner,
+        }
+    }
+
+    /// Write the buffered output with the replacements applied
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.replacements.is_empty() || self.buffer.is_empty() {
+            return self.inner.flush();
+        }
+
+        match apply(&self.buffer, self.replacements) {
+            Some(replaced) => self.inner.write_all(&replaced)?,
+            None => {
+                diag!(
+                    "WARNING: cached {} is binary, replaying it without --replace",
+                    self.stream
+                );
+                self.inner.write_all(&self.buffer)?;
+            }
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for ReplacingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.replacements.is_empty() {
+            return self.inner.write(buf);
+        }
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.replacements.is_empty() {
+            return self.inner.flush();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replacements(specs: &[&str]) -> Vec<Replacement> {
+        specs
+            .iter()
+            .map(|s| Replacement::parse(s).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_splits_at_first_equals() {
+        let replacement = Replacement::parse("a=b=c").unwrap();
+        assert_eq!(replacement.from, "a");
+        assert_eq!(replacement.to, "b=c");
+        assert_eq!(Replacement::parse("gone=").unwrap().to, "");
+
+        assert!(Replacement::parse("=x").is_err());
+        assert!(Replacement::parse("no-equals").is_err());
+    }
+
+    #[test]
+    fn test_apply_in_order() {
+        let output = b"/old/dir/file and /old/dir\n";
+        let replaced = apply(output, &replacements(&["/old/dir=/new", "/new/file=F"]));
+        assert_eq!(replaced.unwrap(), b"F and /new\n");
+    }
+
+    #[test]
+    fn test_apply_skips_binary() {
+        let replacements = replacements(&["a=b"]);
+        assert_eq!(apply(b"a\0a", &replacements), None);
+        assert_eq!(apply(&[b'a', 0xff], &replacements), None);
+    }
+
+    #[test]
+    fn test_writer_joins_split_matches() {
+        let replacements = replacements(&["hello=bye"]);
+        let mut out = Vec::new();
+        let mut writer = ReplacingWriter::new(&replacements, "stdout", &mut out);
+        writer.write_all(b"hel").unwrap();
+        writer.write_all(b"lo world").unwrap();
+        writer.finish().unwrap();
+        assert_eq!(out, b"bye world");
+    }
+}
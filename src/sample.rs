@@ -0,0 +1,70 @@
+//! Re-checking a fraction of cache hits (`--sample-rate`)
+//!
+//! Sampling doesn't need good randomness, only a different roll per run, so
+//! the roll is mixed from the clock and the process id rather than pulling in
+//! a random number crate.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Parse a sampling rate between 0.0 and 1.0
+///
+/// Used as a clap `value_parser`, so errors are plain strings.
+pub fn parse_rate(text: &str) -> Result<f64, String> {
+    let rate: f64 = text
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid rate `{text}`"))?;
+    if (0.0..=1.0).contains(&rate) {
+        Ok(rate)
+    } else {
+        Err(format!("rate `{text}` is not between 0.0 and 1.0"))
+    }
+}
+
+/// Decide whether to sample this hit, with probability `rate`
+pub fn should_sample(rate: f64) -> bool {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    roll(nanos ^ (u64::from(std::process::id()) << 32)) < rate
+}
+
+/// Map a seed to a number in `[0.0, 1.0)` (splitmix64)
+fn roll(seed: u64) -> f64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    // The top 53 bits fill an f64 mantissa exactly
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("0"), Ok(0.0));
+        assert_eq!(parse_rate("0.25"), Ok(0.25));
+        assert_eq!(parse_rate("1.0"), Ok(1.0));
+        assert!(parse_rate("1.5").is_err());
+        assert!(parse_rate("-0.1").is_err());
+        assert!(parse_rate("often").is_err());
+    }
+
+    #[test]
+    fn test_rolls_stay_below_one() {
+        for seed in [0, 1, u64::MAX, 0x1234_5678_9abc_def0] {
+            let r = roll(seed);
+            assert!((0.0..1.0).contains(&r), "{r}");
+        }
+    }
+
+    #[test]
+    fn test_extreme_rates() {
+        assert!(should_sample(1.0));
+        assert!(!should_sample(0.0));
+    }
+}
@@ -0,0 +1,140 @@
+//! Per-session tallies of hits and time saved (`MEMO_SESSION`)
+//!
+//! When `MEMO_SESSION` is set, every memoized run appends one line to
+//! `<cache>/.session-<name>`:
+//!
+//! ```text
+//! hit 1520
+//! miss 340
+//! ```
+//!
+//! The word is the outcome, and the number is the command's run time in
+//! milliseconds (for a hit, the time recorded when it was cached, which is the
+//! time saved). Each line is appended with a single write to a file opened
+//! for appending, so concurrent memo processes never interleave partial lines.
+
+use crate::error::{MemoError, Result};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Environment variable naming the current session
+pub const SESSION_VAR: &str = "MEMO_SESSION";
+
+/// The session named by `MEMO_SESSION`, if it is set and non-empty
+pub fn current() -> Option<String> {
+    std::env::var(SESSION_VAR)
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+/// Totals for one session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tally {
+    /// Memoized runs, hits and misses
+    pub commands: u64,
+    /// Runs replayed from the cache
+    pub hits: u64,
+    /// Sum of the recorded run times of the hits, in milliseconds
+    pub saved_ms: u64,
+}
+
+/// Path of a session's file, rejecting names that aren't safe file names
+fn session_path(cache_dir: &Path, name: &str) -> Result<PathBuf> {
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(MemoError::Session(format!(
+            "`{name}` may only contain letters, digits, `-`, `_`, and `.`"
+        )));
+    }
+    Ok(cache_dir.join(format!(".session-{name}")))
+}
+
+/// Append one run to a session
+pub fn record(cache_dir: &Path, name: &str, hit: bool, duration_ms: u64) -> Result<()> {
+    let line = format!("{} {duration_ms}\n", if hit { "hit" } else { "miss" });
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(session_path(cache_dir, name)?)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Add up a session's runs; a session with no runs yet has an empty tally
+///
+/// Lines that can't be parsed are skipped.
+pub fn tally(cache_dir: &Path, name: &str) -> Result<Tally> {
+    let text = match fs::read_to_string(session_path(cache_dir, name)?) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Tally::default()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut tally = Tally::default();
+    for line in text.lines() {
+        let Some((outcome, ms)) = line.split_once(' ') else {
+            continue;
+        };
+        let Ok(ms) = ms.parse::<u64>() else {
+            continue;
+        };
+        match outcome {
+            "hit" => {
+                tally.hits += 1;
+                tally.saved_ms += ms;
+            }
+            "miss" => {}
+            _ => continue,
+        }
+        tally.commands += 1;
+    }
+    Ok(tally)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_tally_sums_hits() {
+        let temp = TempDir::new().unwrap();
+        record(temp.path(), "ci-1", false, 900).unwrap();
+        record(temp.path(), "ci-1", true, 900).unwrap();
+        record(temp.path(), "ci-1", true, 250).unwrap();
+        record(temp.path(), "other", true, 5000).unwrap();
+
+        assert_eq!(
+            tally(temp.path(), "ci-1").unwrap(),
+            Tally {
+                commands: 3,
+                hits: 2,
+                saved_ms: 1150,
+            }
+        );
+        assert_eq!(tally(temp.path(), "new").unwrap(), Tally::default());
+    }
+
+    #[test]
+    fn test_tally_skips_malformed_lines() {
+        let temp = TempDir::new().unwrap();
+        fs::write(
+            temp.path().join(".session-s"),
+            "hit 10\ngarbage\nhit x\nmiss 5\nhit 7",
+        )
+        .unwrap();
+
+        let tally = tally(temp.path(), "s").unwrap();
+        assert_eq!((tally.commands, tally.hits, tally.saved_ms), (3, 2, 17));
+    }
+
+    #[test]
+    fn test_session_name_must_be_a_file_name() {
+        let temp = TempDir::new().unwrap();
+        assert!(record(temp.path(), "../escape", true, 1).is_err());
+        assert!(tally(temp.path(), "a/b").is_err());
+    }
+}
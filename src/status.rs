@@ -0,0 +1,84 @@
+//! A run's outcome as one JSON object on stderr (`--json` with a command)
+//!
+//! The lookup result is decided deep inside memoization, so, like tracing, it
+//! is recorded process-wide and printed once the command's exit code is
+//! known:
+//!
+//! ```text
+//! {"status":"hit","digest":"3f2a…","command":["make"],"exit_code":0,"cache_dir":"/home/me/.cache/memo"}
+//! ```
+//!
+//! `status` is `hit`, `miss` (also for `--cold-start`), or `ignored` (caching
+//! disabled, so there is no `digest`, and no `cache_dir` if none could be
+//! resolved).
+
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The recorded status and digest
+static LOOKUP: Mutex<Option<(&'static str, Option<String>)>> = Mutex::new(None);
+
+/// The object printed for a run, named like [`Memo`](crate::memo::Memo)'s fields
+#[derive(Serialize)]
+struct Status<'a> {
+    status: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    digest: Option<&'a str>,
+    command: &'a [String],
+    exit_code: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_dir: Option<&'a Path>,
+}
+
+/// Record how the cache lookup went, replacing any earlier record
+pub fn record(status: &'static str, digest: Option<&str>) {
+    let mut lookup = LOOKUP.lock().unwrap_or_else(|e| e.into_inner());
+    *lookup = Some((status, digest.map(str::to_string)));
+}
+
+/// Render the status object, or `None` if nothing was recorded
+fn render(command: &[String], exit_code: i32, cache_dir: Option<&Path>) -> Option<String> {
+    let lookup = LOOKUP.lock().unwrap_or_else(|e| e.into_inner());
+    let (status, digest) = lookup.as_ref()?;
+    serde_json::to_string(&Status {
+        status,
+        digest: digest.as_deref(),
+        command,
+        exit_code,
+        cache_dir,
+    })
+    .ok()
+}
+
+/// Write the status object for the recorded lookup to stderr
+pub fn print(command: &[String], exit_code: i32, cache_dir: Option<&Path>) {
+    if let Some(line) = render(command, exit_code, cache_dir) {
+        eprintln!("{line}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_records_lookup() {
+        let command = vec!["echo".to_string(), "hi".to_string()];
+        record("ignored", None);
+        record("hit", Some("abc"));
+
+        let line = render(&command, 3, Some(Path::new("/cache"))).unwrap();
+        assert_eq!(
+            line,
+            r#"{"status":"hit","digest":"abc","command":["echo","hi"],"exit_code":3,"cache_dir":"/cache"}"#
+        );
+
+        record("ignored", None);
+        let line = render(&command, 0, None).unwrap();
+        assert_eq!(
+            line,
+            r#"{"status":"ignored","command":["echo","hi"],"exit_code":0}"#
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! Opt-in tracing of cache operations (`--trace`)
+//!
+//! Tracing is a process-wide switch so deeply nested cache functions can log
+//! without threading a flag through every call. Messages go to stderr with a
+//! timestamp, after the usual `:: memo ::` prefix.
+
+use chrono::{SecondsFormat, Utc};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turn tracing on or off for the rest of the process
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether `--trace` is active
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Write one trace line; use the [`trace!`] macro instead
+pub fn emit(message: fmt::Arguments<'_>) {
+    let now = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    if crate::diag::is_json() {
+        let message = format!("{now} {message}");
+        crate::diag::emit_json("trace", "trace", None, None, &message);
+    } else {
+        eprintln!("{} trace {now} {message}", crate::diag::prefix());
+    }
+}
+
+/// Log a formatted message if tracing is enabled
+///
+/// The arguments are not evaluated when tracing is off.
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::trace::enabled() {
+            $crate::trace::emit(format_args!($($arg)*));
+        }
+    };
+}
+
+pub(crate) use trace;
@@ -1,9 +1,13 @@
 use assert_cmd::Command;
-use predicates::prelude::predicate;
+use predicates::prelude::{predicate, PredicateBooleanExt};
 use regex::Regex;
+use std::collections::HashMap;
 use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::path::PathBuf;
 use std::process::Stdio;
+use std::sync::{Arc, Mutex};
 use tempfile::TempDir;
 
 /// Test environment for integration tests
@@ -31,7 +35,8 @@ impl TestEnv {
     /// The command is pre-configured with the test cache directory.
     fn cmd(&self) -> Command {
         let mut cmd = assert_cmd::cargo::cargo_bin_cmd!("memo");
-        cmd.env("XDG_CACHE_HOME", self.cache_dir.path());
+        cmd.env("XDG_CACHE_HOME", self.cache_dir.path())
+            .env_remove("MEMO_CACHE_DIR");
         cmd
     }
 
@@ -47,10 +52,10 @@ impl TestEnv {
             .filter_map(|e| {
                 let entry = e.unwrap();
                 let path = entry.path();
-                // Only include directories (not temp dirs)
+                // Only include directories (not temp dirs or memo's hidden ones)
                 if path.is_dir() {
                     let name = entry.file_name().to_string_lossy().to_string();
-                    if !name.contains(".tmp.") {
+                    if !name.contains(".tmp.") && !name.starts_with('.') {
                         Some(name)
                     } else {
                         None
@@ -88,12 +93,13 @@ impl TestEnv {
         let bin = assert_cmd::cargo::cargo_bin!("memo");
         let mut children = vec![];
         for _ in 0..n {
-            let child = std::process::Command::new(&bin)
+            let child = std::process::Command::new(bin)
                 .env("XDG_CACHE_HOME", self.cache_path())
                 .arg("-v")
                 .arg("bash")
                 .arg("-c")
                 .arg("sleep 1; echo hello")
+                .stdin(Stdio::null())
                 .stderr(Stdio::piped())
                 .spawn()
                 .unwrap();
@@ -717,3 +723,4034 @@ fn test_argv_collision_avoidance() {
     // Verify we have two distinct cache entries
     env.assert_cache_entry_count(2);
 }
+
+// Test Case: Partial Replay with --head/--tail
+#[test]
+fn test_partial_replay_head_and_tail() {
+    let env = TestEnv::new();
+
+    // First run - execute and cache the full output
+    env.cmd()
+        .arg("printf")
+        .arg("one\ntwo\nthree\nfour\n")
+        .assert()
+        .success()
+        .stdout("one\ntwo\nthree\nfour\n");
+
+    // Hit - replay only the last two lines
+    env.cmd()
+        .arg("--tail")
+        .arg("2")
+        .arg("printf")
+        .arg("one\ntwo\nthree\nfour\n")
+        .assert()
+        .success()
+        .stdout("three\nfour\n");
+
+    // Hit - replay only the first ten bytes
+    env.cmd()
+        .arg("--head")
+        .arg("10")
+        .arg("--bytes")
+        .arg("printf")
+        .arg("one\ntwo\nthree\nfour\n")
+        .assert()
+        .success()
+        .stdout("one\ntwo\nth");
+
+    // The range does not affect the cache key
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: Binary Modification Invalidates Entry
+#[cfg(unix)]
+#[test]
+fn test_auto_invalidate_binary() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, SystemTime};
+
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let script = work.path().join("script.sh");
+    let counter = work.path().join("counter");
+
+    fs::write(
+        &script,
+        format!("#!/bin/sh\necho run >> {}\necho hi\n", counter.display()),
+    )
+    .unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let run = || {
+        env.cmd()
+            .arg("--auto-invalidate-binary")
+            .arg(&script)
+            .assert()
+            .success()
+            .stdout("hi\n");
+    };
+    let runs = || fs::read_to_string(&counter).unwrap().lines().count();
+
+    // First run executes, second run is a hit
+    run();
+    run();
+    assert_eq!(runs(), 1);
+
+    // Touch the script so it is newer than the cache entry
+    let future = SystemTime::now() + Duration::from_secs(60);
+    fs::File::options()
+        .write(true)
+        .open(&script)
+        .unwrap()
+        .set_modified(future)
+        .unwrap();
+
+    // Next run re-executes and refreshes the entry
+    run();
+    assert_eq!(runs(), 2);
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: Listing Entries
+#[test]
+fn test_list_entries() {
+    let env = TestEnv::new();
+
+    env.cmd().arg("echo").arg("one").assert().success();
+    env.cmd().arg("echo").arg("two").assert().success();
+
+    let output = env
+        .cmd()
+        .arg("--list")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+
+    assert_eq!(output.lines().count(), 2);
+    assert!(output.contains("\techo one\n"));
+    assert!(output.contains("\techo two\n"));
+}
+
+// Test Case: Custom --list Format
+#[test]
+fn test_list_format_template() {
+    let env = TestEnv::new();
+
+    env.cmd().arg("echo").arg("one").assert().success();
+    env.cmd()
+        .args(["--cache-failures", "sh", "-c", "exit 3"])
+        .assert()
+        .code(3);
+
+    env.cmd()
+        .arg("--list")
+        .arg("--format")
+        .arg("[{exit}] {cmd}")
+        .assert()
+        .success()
+        .stdout("[0] echo one\n[3] sh -c exit 3\n");
+
+    env.cmd().arg("echo").arg("one").assert().success();
+    env.cmd()
+        .arg("--list")
+        .arg("--format")
+        .arg("{hits} {cmd}")
+        .assert()
+        .success()
+        .stdout("1 echo one\n0 sh -c exit 3\n");
+
+    env.cmd()
+        .arg("--list")
+        .arg("--format")
+        .arg("{exit} {bogus}")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("unknown placeholder `{bogus}`"));
+}
+
+// Test Case: Paginated Listing
+#[test]
+fn test_list_limit_offset() {
+    let env = TestEnv::new();
+
+    for word in ["one", "two", "three", "four", "five"] {
+        env.cmd().arg("echo").arg(word).assert().success();
+    }
+
+    let list = |extra: &[&str]| {
+        let output = env
+            .cmd()
+            .arg("--list")
+            .args(extra)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        String::from_utf8(output).unwrap()
+    };
+
+    let limited = list(&["--limit", "2"]);
+    assert_eq!(limited.lines().count(), 2);
+    assert!(limited.contains("\techo one\n"));
+    assert!(limited.contains("\techo two\n"));
+
+    let paged = list(&["--offset", "3", "--limit", "10"]);
+    assert_eq!(paged.lines().count(), 2);
+    assert!(paged.contains("\techo four\n"));
+    assert!(paged.contains("\techo five\n"));
+}
+
+// Test Case: Sharded Cache Layout
+#[test]
+fn test_shard_commit_and_replay() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .arg("--shard")
+        .arg("2")
+        .arg("echo")
+        .arg("sharded")
+        .assert()
+        .success()
+        .stdout("sharded\n");
+
+    // Nothing is stored at the top level apart from the two-character shards
+    let memo_dir = env.cache_path().join("memo");
+    let top: Vec<String> = fs::read_dir(&memo_dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(top.len(), 1);
+    assert_eq!(top[0].len(), 2);
+
+    let outer = memo_dir.join(&top[0]);
+    let inner = fs::read_dir(&outer)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let entry = fs::read_dir(&inner).unwrap().next().unwrap().unwrap();
+    let digest = entry.file_name().to_string_lossy().to_string();
+    assert!(digest.starts_with(&top[0]));
+    assert_eq!(fs::read(entry.path().join("stdout")).unwrap(), b"sharded\n");
+
+    // Replay is a hit, and --list finds the nested entry
+    env.cmd()
+        .arg("-v")
+        .arg("--shard")
+        .arg("2")
+        .arg("echo")
+        .arg("sharded")
+        .assert()
+        .success()
+        .stdout("sharded\n")
+        .stderr(predicate::str::contains("hit"));
+
+    env.cmd()
+        .arg("--list")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(format!(
+            "{}\t0\t",
+            &digest[..12]
+        )))
+        .stdout(predicate::str::ends_with("\techo sharded\n"));
+}
+
+// Test Case: NUL-delimited Listing
+#[test]
+fn test_list_print0() {
+    let env = TestEnv::new();
+
+    env.cmd().arg("echo").arg("one").assert().success();
+    env.cmd().arg("echo").arg("two").assert().success();
+
+    let output = env
+        .cmd()
+        .arg("--list")
+        .arg("--print0")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    assert_eq!(output.iter().filter(|b| **b == b'\0').count(), 2);
+    assert!(!output.contains(&b'\n'));
+    assert_eq!(output.last(), Some(&b'\0'));
+
+    let records: Vec<&[u8]> = output[..output.len() - 1].split(|b| *b == b'\0').collect();
+    assert_eq!(records.len(), 2);
+    assert!(records
+        .iter()
+        .all(|r| r.ends_with(b"echo one") || r.ends_with(b"echo two")));
+}
+
+// Test Case: Diff Against Cached Result
+#[test]
+fn test_diff_mode() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let input = work.path().join("input");
+    let script = format!("cat {}", input.display());
+
+    fs::write(&input, "original\n").unwrap();
+    env.cmd()
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .assert()
+        .success()
+        .stdout("original\n");
+
+    // Identical fresh run exits 0
+    env.cmd()
+        .arg("--diff")
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .assert()
+        .code(0)
+        .stdout(predicate::str::contains("stdout: identical"))
+        .stdout(predicate::str::contains("exit code: identical (0)"));
+
+    // Drifted fresh run exits non-zero and reports the difference
+    fs::write(&input, "changed\n").unwrap();
+    env.cmd()
+        .arg("--diff")
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains(
+            "stdout: differs at byte 0 (cached 9 bytes, fresh 8 bytes)",
+        ))
+        .stdout(predicate::str::contains("stderr: identical"));
+
+    // The cache was not overwritten
+    env.cmd()
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .assert()
+        .success()
+        .stdout("original\n");
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: Diff Without Cached Entry
+#[test]
+fn test_diff_mode_not_cached() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .arg("--diff")
+        .arg("echo")
+        .arg("hello")
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(predicate::str::contains("No cached entry for `echo hello`"));
+}
+
+// Test Case: Explicit Config File
+#[test]
+fn test_config_file() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let config = work.path().join("memo.toml");
+    fs::write(&config, "verbose = true\n").unwrap();
+
+    env.cmd()
+        .arg("--config")
+        .arg(&config)
+        .arg("echo")
+        .arg("test")
+        .assert()
+        .success()
+        .stdout("test\n")
+        .stderr(predicate::str::contains("miss `echo test`"));
+}
+
+// Test Case: Missing Config File
+#[test]
+fn test_config_file_missing() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let config = work.path().join("missing.toml");
+
+    env.cmd()
+        .arg("--config")
+        .arg(&config)
+        .arg("echo")
+        .arg("test")
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(predicate::str::contains("Config error: cannot read"))
+        .stderr(predicate::str::contains("missing.toml"));
+}
+
+// Test Case: Resource Usage Recorded in Metadata
+#[cfg(unix)]
+#[test]
+fn test_rusage_recorded() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .arg("sh")
+        .arg("-c")
+        .arg("i=0; while [ $i -lt 200000 ]; do i=$((i+1)); done")
+        .assert()
+        .success();
+
+    let output = env
+        .cmd()
+        .arg("--list")
+        .arg("--json")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let entries: serde_json::Value = serde_json::from_slice(&output).unwrap();
+
+    let rusage = &entries[0]["rusage"];
+    assert!(rusage["user_ms"].as_u64().unwrap() > 0, "got {}", rusage);
+    assert!(rusage["max_rss_kb"].as_u64().unwrap() > 0, "got {}", rusage);
+    assert!(rusage["sys_ms"].is_u64());
+}
+
+// Test Case: Sentinel Exit Code Skips Caching
+#[test]
+fn test_no_cache_exit() {
+    let env = TestEnv::new();
+
+    // Sentinel exit code: output streams live but nothing is committed
+    env.cmd()
+        .arg("-v")
+        .arg("--cache-failures")
+        .arg("--no-cache-exit")
+        .arg("75")
+        .arg("sh")
+        .arg("-c")
+        .arg("echo transient; exit 75")
+        .assert()
+        .code(75)
+        .stdout("transient\n")
+        .stderr(predicate::str::contains("not caching (exit 75)"));
+    env.assert_cache_entry_count(0);
+
+    // With --cache-failures, any other exit code is cached
+    env.cmd()
+        .arg("--cache-failures")
+        .arg("--no-cache-exit")
+        .arg("75")
+        .arg("--no-cache-exit")
+        .arg("76")
+        .arg("sh")
+        .arg("-c")
+        .arg("echo stable; exit 3")
+        .assert()
+        .code(3)
+        .stdout("stable\n");
+    env.assert_cache_entry_count(1);
+    env.assert_valid_cache_structure();
+}
+
+// Test Case: Dependency Contents in Cache Key
+#[test]
+fn test_depends_on_invalidates_cache() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let input = work.path().join("schema.sql");
+    fs::write(&input, "v1").unwrap();
+
+    let run = || {
+        env.cmd()
+            .arg("-v")
+            .arg("--depends-on")
+            .arg(&input)
+            .arg("echo")
+            .arg("codegen")
+            .assert()
+            .success()
+            .stdout("codegen\n")
+    };
+
+    run().stderr(predicate::str::contains("miss"));
+    run().stderr(predicate::str::contains("hit"));
+
+    fs::write(&input, "v2").unwrap();
+    run().stderr(predicate::str::contains("miss"));
+    env.assert_cache_entry_count(2);
+}
+
+// Test Case: Missing Dependency Is an Error
+#[test]
+fn test_depends_on_missing_path() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+
+    env.cmd()
+        .arg("--depends-on")
+        .arg(work.path().join("missing"))
+        .arg("echo")
+        .arg("hello")
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(predicate::str::contains("Dependency not found"));
+}
+
+// Test Case: Watch Mode Re-executes on Dependency Change
+#[test]
+fn test_watch_reexecutes_on_change() {
+    use std::time::{Duration, Instant};
+
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let input = work.path().join("input");
+    let counter = work.path().join("counter");
+    fs::write(&input, "one").unwrap();
+
+    let bin = assert_cmd::cargo::cargo_bin!("memo");
+    let mut child = std::process::Command::new(bin)
+        .env("XDG_CACHE_HOME", env.cache_path())
+        .arg("--watch")
+        .arg("--depends-on")
+        .arg(&input)
+        .arg("sh")
+        .arg("-c")
+        .arg(format!("echo run >> {}", counter.display()))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let wait_for_runs = |n: usize| {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            let runs = fs::read_to_string(&counter)
+                .map(|s| s.lines().count())
+                .unwrap_or(0);
+            if runs >= n {
+                return runs;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        0
+    };
+
+    assert_eq!(wait_for_runs(1), 1);
+
+    fs::write(&input, "three").unwrap();
+    let runs = wait_for_runs(2);
+
+    // Wait for the refreshed entry to be committed before stopping the watcher
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while env.list_cache_entries().len() < 2 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert_eq!(runs, 2);
+    env.assert_cache_entry_count(2);
+}
+
+// Test Case: .memoignore Disables Caching
+#[test]
+fn test_memoignore_disables_caching() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let nested = work.path().join("scripts");
+    fs::create_dir(&nested).unwrap();
+    fs::write(work.path().join(".memoignore"), "").unwrap();
+
+    for _ in 0..2 {
+        env.cmd()
+            .current_dir(&nested)
+            .arg("-v")
+            .arg("echo")
+            .arg("hello")
+            .assert()
+            .success()
+            .stdout("hello\n")
+            .stderr(predicate::str::contains("ignored by"))
+            .stderr(predicate::str::contains(".memoignore"));
+    }
+
+    env.assert_cache_entry_count(0);
+}
+
+// Test Case: .memoignore Patterns Select Commands
+#[test]
+fn test_memoignore_patterns() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    fs::write(work.path().join(".memoignore"), "# secrets\n*token*\n").unwrap();
+
+    env.cmd()
+        .current_dir(work.path())
+        .arg("echo")
+        .arg("print-token")
+        .assert()
+        .success()
+        .stdout("print-token\n");
+    env.assert_cache_entry_count(0);
+
+    env.cmd()
+        .current_dir(work.path())
+        .arg("echo")
+        .arg("hello")
+        .assert()
+        .success()
+        .stdout("hello\n");
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: --pty Runs the Command on a Terminal
+#[cfg(unix)]
+#[test]
+fn test_pty_records_terminal_output() {
+    let env = TestEnv::new();
+    let script = "[ -t 1 ] && printf 'tty' || printf 'pipe'";
+
+    env.cmd()
+        .arg("--pty")
+        .arg("sh")
+        .arg("-c")
+        .arg(script)
+        .assert()
+        .success()
+        .stdout("tty");
+
+    env.cmd()
+        .arg("sh")
+        .arg("-c")
+        .arg(script)
+        .assert()
+        .success()
+        .stdout("pipe");
+
+    // Terminal and piped runs are cached separately
+    let entries = env.list_cache_entries();
+    assert_eq!(entries.len(), 2);
+
+    let mut piped: Vec<bool> = entries
+        .iter()
+        .map(|digest| {
+            let meta: serde_json::Value =
+                serde_json::from_slice(&env.read_cache_file(digest, "meta.json")).unwrap();
+            assert_eq!(meta["stderr_was_piped"], true);
+            meta["stdout_was_piped"].as_bool().unwrap()
+        })
+        .collect();
+    piped.sort();
+    assert_eq!(piped, vec![false, true]);
+
+    // Replay keeps the terminal output
+    env.cmd()
+        .arg("--pty")
+        .arg("sh")
+        .arg("-c")
+        .arg(script)
+        .assert()
+        .success()
+        .stdout("tty");
+}
+
+// Test Case: Command Read From a File
+#[test]
+fn test_command_file() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let command_file = work.path().join("cmd.txt");
+    fs::write(&command_file, "printf\n%s|%s\nhello world\nit's quoted\n").unwrap();
+
+    for _ in 0..2 {
+        env.cmd()
+            .arg("--command-file")
+            .arg(&command_file)
+            .assert()
+            .success()
+            .stdout("hello world|it's quoted");
+    }
+    env.assert_cache_entry_count(1);
+
+    // Same argv on the command line shares the cache entry
+    env.cmd()
+        .arg("printf")
+        .arg("%s|%s")
+        .arg("hello world")
+        .arg("it's quoted")
+        .assert()
+        .success()
+        .stdout("hello world|it's quoted");
+    env.assert_cache_entry_count(1);
+
+    // Trailing args and --command-file together are rejected
+    env.cmd()
+        .arg("--command-file")
+        .arg(&command_file)
+        .arg("echo")
+        .assert()
+        .failure();
+}
+
+// Test Case: --confirm-miss Does Not Prompt Without a Terminal
+#[test]
+fn test_confirm_miss_non_interactive() {
+    let env = TestEnv::new();
+
+    // stdin is not a terminal, so the answer is never read and the miss runs
+    env.cmd()
+        .arg("--confirm-miss")
+        .arg("echo")
+        .arg("hello")
+        .write_stdin("n\n")
+        .assert()
+        .success()
+        .stdout("hello\n")
+        .stderr("");
+    env.assert_cache_entry_count(1);
+
+    // Hits never prompt
+    env.cmd()
+        .arg("--confirm-miss")
+        .arg("echo")
+        .arg("hello")
+        .assert()
+        .success()
+        .stdout("hello\n")
+        .stderr("");
+}
+
+// Test Case: --strict-exit Re-executes Entries With an Unknown Exit Code
+#[test]
+fn test_strict_exit_reexecutes_unknown_exit() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let counter = work.path().join("counter");
+    let script = format!("echo run >> {}; echo out", counter.display());
+
+    env.cmd()
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .assert()
+        .success();
+
+    // Simulate an entry recorded after the command was killed by a signal
+    let entries = env.list_cache_entries();
+    assert_eq!(entries.len(), 1);
+    let meta_path = env
+        .cache_path()
+        .join("memo")
+        .join(&entries[0])
+        .join("meta.json");
+    let mut meta: serde_json::Value =
+        serde_json::from_slice(&fs::read(&meta_path).unwrap()).unwrap();
+    meta["exit_code"] = serde_json::json!(-1);
+    fs::write(&meta_path, serde_json::to_vec(&meta).unwrap()).unwrap();
+
+    // Without the flag the sentinel is replayed
+    env.cmd()
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .assert()
+        .code(255)
+        .stdout("out\n");
+    assert_eq!(fs::read_to_string(&counter).unwrap(), "run\n");
+
+    // With the flag the command runs again and the entry is refreshed
+    env.cmd()
+        .arg("--strict-exit")
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .assert()
+        .success()
+        .stdout("out\n");
+    assert_eq!(fs::read_to_string(&counter).unwrap(), "run\nrun\n");
+    env.assert_cache_entry_count(1);
+
+    let meta: serde_json::Value =
+        serde_json::from_slice(&env.read_cache_file(&entries[0], "meta.json")).unwrap();
+    assert_eq!(meta["exit_code"], 0);
+}
+
+// Test Case: --purge-orphans Removes Old Temp Directories
+#[test]
+fn test_purge_orphans() {
+    let env = TestEnv::new();
+    env.cmd().arg("echo").arg("hello").assert().success();
+
+    let memo_dir = env.cache_path().join("memo");
+    let old = memo_dir.join("old.tmp.1.2");
+    let fresh = memo_dir.join("fresh.tmp.3.4");
+    fs::create_dir(&old).unwrap();
+    fs::create_dir(&fresh).unwrap();
+    fs::write(old.join("stdout"), "12345").unwrap();
+    fs::write(fresh.join("stdout"), "123").unwrap();
+
+    let two_hours_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(7200);
+    fs::File::open(&old)
+        .unwrap()
+        .set_modified(two_hours_ago)
+        .unwrap();
+
+    env.cmd()
+        .arg("--purge-orphans")
+        .arg("--temp-max-age")
+        .arg("1h")
+        .assert()
+        .success()
+        .stdout("purged 1 temp dir(s), reclaimed 5 bytes\n");
+    assert!(!old.exists());
+    assert!(fresh.exists());
+
+    env.cmd()
+        .arg("--purge-orphans")
+        .assert()
+        .success()
+        .stdout("purged 1 temp dir(s), reclaimed 3 bytes\n");
+    assert!(!fresh.exists());
+
+    // Committed entries are untouched
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: --key-from Reruns When the Probe Output Changes
+#[test]
+fn test_key_from_probe() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let version = work.path().join("version");
+    let counter = work.path().join("counter");
+    let probe = format!("cat {}", version.display());
+    let script = format!("echo run >> {}; echo generated", counter.display());
+
+    let run = || {
+        env.cmd()
+            .arg("--key-from")
+            .arg(&probe)
+            .arg("sh")
+            .arg("-c")
+            .arg(&script)
+            .assert()
+            .success()
+            .stdout("generated\n");
+    };
+    let runs = || fs::read_to_string(&counter).unwrap().lines().count();
+
+    fs::write(&version, "tool 1.0\n").unwrap();
+    run();
+    run();
+    assert_eq!(runs(), 1);
+
+    fs::write(&version, "tool 1.1\n").unwrap();
+    run();
+    assert_eq!(runs(), 2);
+    env.assert_cache_entry_count(2);
+
+    // A failing probe is an error rather than an empty key
+    fs::remove_file(&version).unwrap();
+    env.cmd()
+        .arg("--key-from")
+        .arg(&probe)
+        .arg("sh")
+        .arg("-c")
+        .arg(&script)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Key probe failed"));
+    assert_eq!(runs(), 2);
+}
+
+// Test Case: --replay-timing Records and Replays Output Timing
+#[test]
+fn test_replay_timing() {
+    let env = TestEnv::new();
+    let script = "printf one; sleep 0.3; printf two";
+
+    env.cmd()
+        .arg("--replay-timing")
+        .arg("sh")
+        .arg("-c")
+        .arg(script)
+        .assert()
+        .success()
+        .stdout("onetwo");
+
+    let entries = env.list_cache_entries();
+    assert_eq!(entries.len(), 1);
+    let timing = String::from_utf8(env.read_cache_file(&entries[0], "timing")).unwrap();
+    assert_eq!(timing.lines().count(), 2);
+
+    let start = std::time::Instant::now();
+    env.cmd()
+        .arg("--replay-timing")
+        .arg("sh")
+        .arg("-c")
+        .arg(script)
+        .assert()
+        .success()
+        .stdout("onetwo");
+    assert!(start.elapsed() >= std::time::Duration::from_millis(300));
+
+    // Timing is opt-in: plain runs don't record it
+    env.cmd().arg("echo").arg("plain").assert().success();
+    let plain = env
+        .list_cache_entries()
+        .into_iter()
+        .find(|e| *e != entries[0])
+        .unwrap();
+    assert!(!env
+        .cache_path()
+        .join("memo")
+        .join(plain)
+        .join("timing")
+        .exists());
+}
+
+// Test Case: --merge-streams Replays Both Streams to Stdout
+#[test]
+fn test_merge_streams_on_replay() {
+    let env = TestEnv::new();
+    let script = "echo out; echo err >&2";
+
+    env.cmd()
+        .arg("sh")
+        .arg("-c")
+        .arg(script)
+        .assert()
+        .success()
+        .stdout("out\n")
+        .stderr("err\n");
+
+    env.cmd()
+        .arg("--merge-streams")
+        .arg("sh")
+        .arg("-c")
+        .arg(script)
+        .assert()
+        .success()
+        .stdout("out\nerr\n")
+        .stderr("");
+
+    // The stored streams stay separate
+    env.cmd()
+        .arg("sh")
+        .arg("-c")
+        .arg(script)
+        .assert()
+        .success()
+        .stdout("out\n")
+        .stderr("err\n");
+}
+
+// Test Case: --single-flight Runs a Concurrent Miss Only Once
+#[test]
+fn test_single_flight_runs_once() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let counter = work.path().join("counter");
+    let script = format!("echo run >> {}; sleep 1; echo hello", counter.display());
+
+    let bin = assert_cmd::cargo::cargo_bin!("memo");
+    let children: Vec<_> = (0..4)
+        .map(|_| {
+            std::process::Command::new(bin)
+                .env("XDG_CACHE_HOME", env.cache_path())
+                .arg("--single-flight")
+                .arg("sh")
+                .arg("-c")
+                .arg(&script)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+
+    for child in children {
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"hello\n");
+    }
+
+    assert_eq!(fs::read_to_string(&counter).unwrap(), "run\n");
+    env.assert_cache_entry_count(1);
+    env.assert_valid_cache_structure();
+}
+
+// Test Case: Long Commands Are Truncated for Display Only
+#[test]
+fn test_long_command_display_truncation() {
+    let env = TestEnv::new();
+    let words: Vec<String> = (0..100).map(|i| format!("word{i}")).collect();
+    let full = format!("echo {}", words.join(" "));
+
+    env.cmd()
+        .arg("-v")
+        .arg("--display-width")
+        .arg("20")
+        .arg("echo")
+        .args(&words)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("miss `echo word0 word1 wo…`"));
+
+    // The stored argv is complete
+    let entries = env.list_cache_entries();
+    let meta: serde_json::Value =
+        serde_json::from_slice(&env.read_cache_file(&entries[0], "meta.json")).unwrap();
+    assert_eq!(meta["cmd"].as_array().unwrap().len(), 101);
+
+    env.cmd()
+        .arg("--list")
+        .arg("--format")
+        .arg("{cmd}")
+        .arg("--display-width")
+        .arg("20")
+        .assert()
+        .success()
+        .stdout("echo word0 word1 wo…\n");
+
+    env.cmd()
+        .arg("--list")
+        .arg("--format")
+        .arg("{cmd}")
+        .arg("--display-width")
+        .arg("0")
+        .assert()
+        .success()
+        .stdout(format!("{full}\n"));
+}
+
+// Test Case: --exit-trailer Reports the Exit Code After Replay
+#[test]
+fn test_exit_trailer_on_hit() {
+    let env = TestEnv::new();
+    let script = "echo out; echo err >&2; exit 5";
+
+    // Misses stream output unchanged
+    env.cmd()
+        .arg("--exit-trailer")
+        .arg("--cache-failures")
+        .arg("sh")
+        .arg("-c")
+        .arg(script)
+        .assert()
+        .code(5)
+        .stdout("out\n");
+
+    env.cmd()
+        .arg("--exit-trailer")
+        .arg("--merge-streams")
+        .arg("sh")
+        .arg("-c")
+        .arg(script)
+        .assert()
+        .code(5)
+        .stdout("out\nerr\n::memo-exit=5::\n")
+        .stderr("");
+}
+
+// Test Case: --doctor Reports Cache Problems
+#[test]
+fn test_doctor() {
+    let env = TestEnv::new();
+    env.cmd().arg("echo").arg("hello").assert().success();
+
+    env.cmd()
+        .arg("--doctor")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("no problems found"));
+
+    let entries = env.list_cache_entries();
+    let entry = env.cache_path().join("memo").join(&entries[0]);
+    fs::remove_file(entry.join("stdout")).unwrap();
+
+    let version = memo_version(&env);
+    env.cmd().arg("--doctor").assert().code(1).stdout(format!(
+        "incomplete entry: {} (missing stdout)\nentries by memo version: {version} (1)\n",
+        entry.display()
+    ));
+}
+
+// Test Case: --no-commit-empty Skips Silent Successes
+#[test]
+fn test_no_commit_empty() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .arg("--no-commit-empty")
+        .arg("true")
+        .assert()
+        .success()
+        .stdout("");
+    env.assert_cache_entry_count(0);
+
+    // Failures and commands with output are still cached
+    env.cmd()
+        .arg("--no-commit-empty")
+        .arg("--cache-failures")
+        .arg("false")
+        .assert()
+        .code(1);
+    env.cmd()
+        .arg("--no-commit-empty")
+        .arg("echo")
+        .arg("hello")
+        .assert()
+        .success()
+        .stdout("hello\n");
+    env.assert_cache_entry_count(2);
+}
+
+/// Build a memo command whose PATH also finds memo, for nested invocations
+fn nested_cmd(env: &TestEnv) -> Command {
+    let bin = assert_cmd::cargo::cargo_bin!("memo");
+    let mut paths = vec![bin.parent().unwrap().to_path_buf()];
+    paths.extend(std::env::split_paths(
+        &std::env::var_os("PATH").unwrap_or_default(),
+    ));
+
+    let mut cmd = env.cmd();
+    cmd.env("PATH", std::env::join_paths(paths).unwrap());
+    cmd
+}
+
+/// Find the cache entries whose command starts with `memo`
+fn outer_entries(env: &TestEnv) -> Vec<Vec<String>> {
+    env.list_cache_entries()
+        .iter()
+        .map(|digest| {
+            let meta: serde_json::Value =
+                serde_json::from_slice(&env.read_cache_file(digest, "meta.json")).unwrap();
+            serde_json::from_value::<Vec<String>>(meta["cmd"].clone()).unwrap()
+        })
+        .filter(|cmd| cmd[0] == "memo")
+        .collect()
+}
+
+// Test Case: Nested memo After `--` Keeps the Inner Flags
+#[test]
+fn test_nested_memo_after_separator() {
+    let env = TestEnv::new();
+
+    nested_cmd(&env)
+        .arg("--")
+        .arg("memo")
+        .arg("-v")
+        .arg("echo")
+        .arg("hi")
+        .assert()
+        .success()
+        .stdout("hi\n")
+        .stderr(predicate::str::contains("miss `echo hi`"));
+
+    // The outer memo was not verbose, and cached the inner call verbatim
+    assert_eq!(outer_entries(&env), vec![vec!["memo", "-v", "echo", "hi"]]);
+}
+
+// Test Case: Nested memo Without a Separator
+#[test]
+fn test_nested_memo_without_separator() {
+    let env = TestEnv::new();
+
+    for _ in 0..2 {
+        nested_cmd(&env)
+            .arg("memo")
+            .arg("echo")
+            .arg("hi")
+            .assert()
+            .success()
+            .stdout("hi\n")
+            .stderr("");
+    }
+
+    assert_eq!(outer_entries(&env), vec![vec!["memo", "echo", "hi"]]);
+
+    // A `--` inside the command is passed through
+    nested_cmd(&env)
+        .arg("echo")
+        .arg("--")
+        .arg("--verbose")
+        .assert()
+        .success()
+        .stdout("-- --verbose\n");
+}
+
+// Test Case: --encrypt Stores Ciphertext and Replays Plaintext
+#[test]
+fn test_encrypt_roundtrip() {
+    let env = TestEnv::new();
+    let script = "echo secret-out; echo secret-err >&2; exit 3";
+
+    env.cmd()
+        .env("MEMO_PASSPHRASE", "hunter2")
+        .args(["--encrypt", "--cache-failures", "sh", "-c", script])
+        .assert()
+        .code(3)
+        .stdout("secret-out\n")
+        .stderr("secret-err\n");
+
+    env.assert_cache_entry_count(1);
+    let digest = &env.list_cache_entries()[0];
+    let stored = env.read_cache_file(digest, "stdout");
+    assert!(!String::from_utf8_lossy(&stored).contains("secret-out"));
+    let stored = env.read_cache_file(digest, "stderr");
+    assert!(!String::from_utf8_lossy(&stored).contains("secret-err"));
+
+    env.cmd()
+        .env("MEMO_PASSPHRASE", "hunter2")
+        .args(["--encrypt", "--cache-failures", "sh", "-c", script])
+        .assert()
+        .code(3)
+        .stdout("secret-out\n")
+        .stderr("secret-err\n");
+}
+
+// Test Case: --encrypt Fails Clearly Without the Right Passphrase
+#[test]
+fn test_encrypt_wrong_passphrase() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .env("MEMO_PASSPHRASE", "hunter2")
+        .args(["--encrypt", "echo", "classified"])
+        .assert()
+        .success();
+
+    env.cmd()
+        .env("MEMO_PASSPHRASE", "letmein")
+        .args(["--encrypt", "echo", "classified"])
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(predicate::str::contains("wrong passphrase"));
+
+    // A missing passphrase is an error before anything runs
+    env.cmd()
+        .env_remove("MEMO_PASSPHRASE")
+        .args(["--encrypt", "echo", "other"])
+        .assert()
+        .failure()
+        .stdout("")
+        .stderr(predicate::str::contains("MEMO_PASSPHRASE is not set"));
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: --ignore-exit Replays the Output but Exits 0
+#[test]
+fn test_ignore_exit_on_hit() {
+    let env = TestEnv::new();
+    let script = "echo report; echo warning >&2; exit 5";
+
+    // The miss reports and caches the real exit code
+    env.cmd()
+        .args(["--ignore-exit", "--cache-failures", "sh", "-c", script])
+        .assert()
+        .code(5)
+        .stdout("report\n")
+        .stderr("warning\n");
+
+    env.cmd()
+        .args(["--ignore-exit", "sh", "-c", script])
+        .assert()
+        .code(0)
+        .stdout("report\n")
+        .stderr("warning\n");
+
+    // The cached entry keeps the real code
+    env.cmd().args(["sh", "-c", script]).assert().code(5);
+}
+
+// Test Case: --warn-output-size Warns About Large Outputs but Still Caches
+#[test]
+fn test_warn_output_size() {
+    let env = TestEnv::new();
+
+    let assert = env
+        .cmd()
+        .args(["--warn-output-size", "1000", "seq", "1", "1000"])
+        .assert()
+        .success();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+
+    env.assert_cache_entry_count(1);
+    let digest = &env.list_cache_entries()[0];
+    assert!(
+        stderr.contains(&format!(
+            "WARNING: cached 3893 bytes of output for {digest}"
+        )),
+        "unexpected stderr: {stderr}"
+    );
+
+    // Small outputs don't warn
+    env.cmd()
+        .args(["--warn-output-size", "1000", "echo", "small"])
+        .assert()
+        .success()
+        .stderr("");
+}
+
+// Test Case: --rank Orders Entries by Hits, Duration, and Size
+#[test]
+fn test_rank_by_metric() {
+    let env = TestEnv::new();
+
+    // "sleep" is slow with no output, "seq" is large, "echo" is hit most
+    env.cmd().args(["sleep", "0.3"]).assert().success();
+    env.cmd().args(["seq", "1", "2000"]).assert().success();
+    for _ in 0..3 {
+        env.cmd().args(["echo", "hi"]).assert().success();
+    }
+    env.cmd().args(["seq", "1", "2000"]).assert().success();
+
+    let rank = |by: &str| -> Vec<String> {
+        let output = env.cmd().args(["--rank", "--by", by]).output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| line.rsplit('\t').next().unwrap().to_string())
+            .collect()
+    };
+
+    assert_eq!(rank("hits"), ["echo hi", "seq 1 2000", "sleep 0.3"]);
+    assert_eq!(rank("duration")[0], "sleep 0.3");
+    assert_eq!(rank("size")[0], "seq 1 2000");
+
+    // The hit count is the first column, and --limit caps the output
+    env.cmd()
+        .args(["--rank", "--limit", "1"])
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match("^2\t[0-9a-f]{64}\techo hi\n$").unwrap());
+}
+
+// Test Case: --batch Memoizes Each Command From Stdin
+#[test]
+fn test_batch_from_stdin() {
+    let env = TestEnv::new();
+
+    env.cmd().args(["echo", "one"]).assert().success();
+
+    env.cmd()
+        .arg("--batch")
+        .write_stdin("echo one\necho 'two words'\n\nsh -c 'cat; echo three >&2'\n")
+        .assert()
+        .success()
+        .stdout("hit\techo one\nmiss\techo 'two words'\nmiss\tsh -c 'cat; echo three >&2'\n")
+        .stderr("");
+
+    env.assert_cache_entry_count(3);
+    env.cmd()
+        .args(["echo", "two words"])
+        .assert()
+        .success()
+        .stdout("two words\n");
+
+    // Unparsable lines are reported and make the batch fail
+    env.cmd()
+        .arg("--batch")
+        .write_stdin("echo 'unterminated\n")
+        .assert()
+        .code(1)
+        .stdout("error\techo 'unterminated\n");
+}
+
+// Test Case: --passthrough-tty Runs With Inherited Stdio and Doesn't Cache
+#[test]
+fn test_passthrough_tty() {
+    let env = TestEnv::new();
+
+    for _ in 0..2 {
+        env.cmd()
+            .args([
+                "--passthrough-tty",
+                "sh",
+                "-c",
+                "read answer; echo got $answer",
+            ])
+            .write_stdin("yes\n")
+            .assert()
+            .success()
+            .stdout("got yes\n")
+            .stderr(predicate::str::contains("was not cached"));
+    }
+
+    env.assert_cache_entry_count(0);
+}
+
+// Test Case: --output-dir Writes the Output to Files on Miss and Hit
+#[test]
+fn test_output_dir() {
+    let env = TestEnv::new();
+    let out = TempDir::new().unwrap();
+    let miss_dir = out.path().join("miss");
+    let hit_dir = out.path().join("hit");
+    let script = "echo artifact; echo log >&2";
+
+    // A miss streams as usual and also writes the files
+    env.cmd()
+        .arg("--output-dir")
+        .arg(&miss_dir)
+        .args(["sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("artifact\n")
+        .stderr("log\n");
+    assert_eq!(fs::read(miss_dir.join("stdout")).unwrap(), b"artifact\n");
+    assert_eq!(fs::read(miss_dir.join("stderr")).unwrap(), b"log\n");
+
+    // A hit writes the files instead of the console
+    env.cmd()
+        .arg("--output-dir")
+        .arg(&hit_dir)
+        .args(["sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("");
+    assert_eq!(fs::read(hit_dir.join("stdout")).unwrap(), b"artifact\n");
+    assert_eq!(fs::read(hit_dir.join("stderr")).unwrap(), b"log\n");
+
+    // --stdout-file redirects only stdout
+    let stdout_file = out.path().join("report.txt");
+    env.cmd()
+        .arg("--stdout-file")
+        .arg(&stdout_file)
+        .args(["sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("log\n");
+    assert_eq!(fs::read(&stdout_file).unwrap(), b"artifact\n");
+}
+
+// Test Case: --dedupe Hard-Links Identical Outputs
+#[test]
+fn test_dedupe_identical_outputs() {
+    let env = TestEnv::new();
+    let commands = [
+        ["sh", "-c", "seq 1 500; echo warn >&2"],
+        ["sh", "-c", "seq 1 500"],
+        ["sh", "-c", "seq 1 501; echo warn >&2"],
+    ];
+    for command in &commands {
+        env.cmd().args(command).assert().success();
+    }
+
+    // The second and third copies of stdout and stderr respectively
+    env.cmd()
+        .arg("--dedupe")
+        .assert()
+        .success()
+        .stdout("deduplicated 2 file(s), reclaimed 1897 bytes\n");
+
+    for command in &commands {
+        let fresh = std::process::Command::new(command[0])
+            .args(&command[1..])
+            .output()
+            .unwrap();
+        env.cmd()
+            .args(command)
+            .assert()
+            .success()
+            .stdout(fresh.stdout)
+            .stderr(fresh.stderr);
+    }
+}
+
+/// Rewrite an entry's timestamp as if it had been cached `secs` seconds ago
+fn backdate_entry(env: &TestEnv, digest: &str, secs: i64) {
+    let path = env.cache_path().join("memo").join(digest).join("meta.json");
+    let mut meta: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    let then = chrono::Utc::now() - chrono::Duration::seconds(secs);
+    meta["timestamp"] = serde_json::Value::String(then.to_rfc3339());
+    fs::write(&path, serde_json::to_vec(&meta).unwrap()).unwrap();
+}
+
+// Test Case: --ttl-failure Expires Failures Before --ttl-success Expires Successes
+#[test]
+fn test_ttl_by_exit_code() {
+    let env = TestEnv::new();
+    let counter = env.cache_path().join("runs");
+    let success = format!("echo ok >> {}", counter.display());
+    let failure = format!("echo fail >> {}; exit 1", counter.display());
+    let ttls = [
+        "--ttl-success",
+        "1h",
+        "--ttl-failure",
+        "60s",
+        "--cache-failures",
+    ];
+
+    env.cmd()
+        .args(ttls)
+        .args(["sh", "-c", &success])
+        .assert()
+        .success();
+    env.cmd()
+        .args(ttls)
+        .args(["sh", "-c", &failure])
+        .assert()
+        .code(1);
+    for digest in env.list_cache_entries() {
+        backdate_entry(&env, &digest, 120);
+    }
+
+    // Two minutes later, only the failure is re-run
+    env.cmd()
+        .args(ttls)
+        .args(["sh", "-c", &success])
+        .assert()
+        .success();
+    env.cmd()
+        .args(ttls)
+        .args(["sh", "-c", &failure])
+        .assert()
+        .code(1);
+    assert_eq!(fs::read_to_string(&counter).unwrap(), "ok\nfail\nfail\n");
+    env.assert_cache_entry_count(2);
+
+    // Two hours later, the success has expired too
+    for digest in env.list_cache_entries() {
+        backdate_entry(&env, &digest, 7200);
+    }
+    env.cmd()
+        .args(ttls)
+        .args(["sh", "-c", &success])
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(&counter).unwrap(),
+        "ok\nfail\nfail\nok\n"
+    );
+}
+
+// Test Case: Pinned Entries Survive --prune and Are Marked in --list
+#[test]
+fn test_pin_survives_prune() {
+    let env = TestEnv::new();
+    env.cmd().args(["echo", "keep"]).assert().success();
+    env.cmd().args(["echo", "drop"]).assert().success();
+
+    env.cmd()
+        .args(["--pin", "echo", "keep"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("pinned "));
+    env.cmd()
+        .arg("--list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\techo keep\tpinned\n"))
+        .stdout(predicate::str::contains("\techo drop\n"));
+
+    env.cmd()
+        .args(["--prune", "--older-than", "0s"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("pruned 1 entries"))
+        .stdout(predicate::str::ends_with("kept 1 pinned\n"));
+    env.assert_cache_entry_count(1);
+    env.cmd()
+        .args(["-v", "echo", "keep"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("hit"));
+
+    // Once unpinned, the entry can be pruned
+    env.cmd()
+        .args(["--unpin", "echo", "keep"])
+        .assert()
+        .success();
+    env.cmd()
+        .args(["--prune", "--older-than", "0s"])
+        .assert()
+        .success();
+    env.assert_cache_entry_count(0);
+
+    // Pinning requires a cached entry
+    env.cmd()
+        .args(["--pin", "echo", "keep"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("No cached entry"));
+
+    // The command can come from --command-file instead
+    let work = TempDir::new().unwrap();
+    let command_file = work.path().join("cmd.txt");
+    fs::write(&command_file, "echo\nkeep\n").unwrap();
+    env.cmd().args(["echo", "keep"]).assert().success();
+    for flag in ["--pin", "--unpin"] {
+        env.cmd()
+            .arg(flag)
+            .arg("--command-file")
+            .arg(&command_file)
+            .assert()
+            .success();
+    }
+    env.cmd()
+        .arg("--list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("pinned").not());
+    env.cmd()
+        .arg("--pin")
+        .arg("--command-file")
+        .arg(&command_file)
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("pinned "));
+    env.cmd()
+        .arg("--list")
+        .assert()
+        .success()
+        .stdout(predicate::str::ends_with("\techo keep\tpinned\n"));
+}
+
+// Test Case: --trace Logs the Paths Checked for a Cache Entry
+#[test]
+fn test_trace_logs_checked_paths() {
+    let env = TestEnv::new();
+
+    env.cmd().args(["echo", "traced"]).assert().success();
+    let digest = env.list_cache_entries()[0].clone();
+    let meta_path = env
+        .cache_path()
+        .join("memo")
+        .join(&digest)
+        .join("meta.json");
+
+    let assert = env
+        .cmd()
+        .args(["--trace", "echo", "traced"])
+        .assert()
+        .success()
+        .stdout("traced\n");
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+
+    assert!(
+        stderr.contains(&format!("checked {}: found", meta_path.display())),
+        "unexpected stderr: {stderr}"
+    );
+    assert!(stderr.contains(&format!("digest {digest} from argv [\"echo\",\"traced\"]")));
+    let timestamped = Regex::new(r"(?m)^:: memo :: trace \d{4}-\d{2}-\d{2}T\S+Z ").unwrap();
+    assert!(timestamped.is_match(&stderr));
+}
+
+/// A minimal in-memory HTTP object store standing in for a remote cache
+struct MockRemote {
+    url: String,
+    objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl MockRemote {
+    fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/cache", listener.local_addr().unwrap());
+        let objects: Arc<Mutex<HashMap<String, Vec<u8>>>> = Arc::default();
+
+        let store = Arc::clone(&objects);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let store = Arc::clone(&store);
+                std::thread::spawn(move || serve_mock_remote(stream.unwrap(), &store));
+            }
+        });
+
+        Self { url, objects }
+    }
+
+    fn paths(&self) -> Vec<String> {
+        let mut paths: Vec<_> = self.objects.lock().unwrap().keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+}
+
+/// Answer GET and PUT requests on one (possibly kept-alive) connection
+fn serve_mock_remote(stream: TcpStream, objects: &Mutex<HashMap<String, Vec<u8>>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap().to_string();
+        let path = parts.next().unwrap().to_string();
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+
+        let (status, body) = if method == "PUT" {
+            objects.lock().unwrap().insert(path, body);
+            ("200 OK", Vec::new())
+        } else {
+            match objects.lock().unwrap().get(&path) {
+                Some(object) => ("200 OK", object.clone()),
+                None => ("404 Not Found", Vec::new()),
+            }
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        writer.write_all(&body).unwrap();
+    }
+}
+
+// Test Case: A Remote Cache Is Filled on Commit and Used on a Local Miss
+#[test]
+fn test_remote_cache_put_and_get() {
+    let remote = MockRemote::start();
+    let counter = TempDir::new().unwrap();
+    let counter = counter.path().join("runs");
+    let script = format!(
+        "echo run >> {}; echo shared; echo note >&2",
+        counter.display()
+    );
+
+    // A miss everywhere runs the command and uploads the entry
+    let first = TestEnv::new();
+    first
+        .cmd()
+        .env("MEMO_REMOTE_URL", &remote.url)
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("shared\n")
+        .stderr("note\n");
+    let digest = first.list_cache_entries()[0].clone();
+    assert_eq!(
+        remote.paths(),
+        ["meta.json", "stderr", "stdout"].map(|name| format!("/cache/{digest}/{name}"))
+    );
+
+    // Another machine (an empty local cache) replays it from the remote
+    let second = TestEnv::new();
+    second
+        .cmd()
+        .env("MEMO_REMOTE_URL", &remote.url)
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("shared\n")
+        .stderr("note\n");
+    assert_eq!(fs::read_to_string(&counter).unwrap(), "run\n");
+    second.assert_cache_entry_count(1);
+}
+
+// Test Case: An Unreachable Remote Cache Falls Back to Local Caching
+#[test]
+fn test_remote_cache_unreachable() {
+    let env = TestEnv::new();
+    // Nothing listens on a port once its listener is dropped
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    env.cmd()
+        .env("MEMO_REMOTE_URL", format!("http://{addr}"))
+        .args(["echo", "local"])
+        .assert()
+        .success()
+        .stdout("local\n")
+        .stderr(predicate::str::contains("remote cache unavailable"));
+    env.assert_cache_entry_count(1);
+
+    // Local hits don't touch the remote at all
+    env.cmd()
+        .env("MEMO_REMOTE_URL", format!("http://{addr}"))
+        .args(["echo", "local"])
+        .assert()
+        .success()
+        .stdout("local\n")
+        .stderr("");
+}
+
+// Test Case: --json-meta Prints One Entry's Metadata
+#[test]
+fn test_json_meta() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .args(["--json-meta", "sh", "-c", "exit 4"])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr(predicate::str::contains("No cached entry"));
+
+    env.cmd()
+        .args(["--cache-failures", "sh", "-c", "exit 4"])
+        .assert()
+        .code(4);
+
+    let output = env
+        .cmd()
+        .args(["--json-meta", "sh", "-c", "exit 4"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let meta: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(meta["exit_code"], 4);
+    assert_eq!(meta["cmd"], serde_json::json!(["sh", "-c", "exit 4"]));
+    assert_eq!(meta["digest"], env.list_cache_entries()[0].as_str());
+
+    // The command can come from --command-file instead
+    let work = TempDir::new().unwrap();
+    let command_file = work.path().join("cmd.txt");
+    fs::write(&command_file, "sh\n-c\nexit 4\n").unwrap();
+    let output = env
+        .cmd()
+        .arg("--json-meta")
+        .arg("--command-file")
+        .arg(&command_file)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let from_file: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(from_file, meta);
+
+    // Some command is still required
+    env.cmd().arg("--json-meta").assert().failure();
+}
+
+// Test Case: --argv0 Executes the Override but Keys on the Logical Command
+#[test]
+fn test_argv0_override() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .args(["--argv0", "echo", "logical-tool", "hello"])
+        .assert()
+        .success()
+        .stdout("hello\n");
+    env.assert_cache_entry_count(1);
+
+    // The entry belongs to the command as written, not to `echo hello`
+    let output = env
+        .cmd()
+        .args(["--json-meta", "logical-tool", "hello"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let meta: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(meta["cmd"], serde_json::json!(["logical-tool", "hello"]));
+    env.cmd()
+        .args(["--json-meta", "echo", "hello"])
+        .assert()
+        .code(1);
+
+    // A different override still hits the same entry
+    env.cmd()
+        .args(["--argv0", "false", "logical-tool", "hello"])
+        .assert()
+        .success()
+        .stdout("hello\n");
+}
+
+// Test Case: --batch Keeps Going Past Failures Unless --fail-fast
+#[test]
+fn test_batch_keep_going_and_fail_fast() {
+    let env = TestEnv::new();
+    let batch = "echo one\nsh -c 'exit 3'\necho two\n";
+
+    env.cmd()
+        .args(["--batch", "--keep-going", "--cache-failures"])
+        .write_stdin(batch)
+        .assert()
+        .code(1)
+        .stdout("miss\techo one\nmiss\tsh -c 'exit 3'\nmiss\techo two\n")
+        .stderr(predicate::str::contains("1 command(s) failed"))
+        .stderr(predicate::str::contains("exit 3: sh -c 'exit 3'"));
+    env.assert_cache_entry_count(3);
+
+    // Cached failures count too
+    env.cmd()
+        .args(["--batch", "--cache-failures"])
+        .write_stdin(batch)
+        .assert()
+        .code(1)
+        .stdout("hit\techo one\nhit\tsh -c 'exit 3'\nhit\techo two\n");
+
+    let env = TestEnv::new();
+    env.cmd()
+        .args(["--batch", "--fail-fast", "--cache-failures"])
+        .write_stdin(batch)
+        .assert()
+        .code(3)
+        .stdout("miss\techo one\nmiss\tsh -c 'exit 3'\n")
+        .stderr(predicate::str::contains("stopping batch"));
+    env.assert_cache_entry_count(2);
+}
+
+// Test Case: --resolve-argv0 Keys on the Program Found in PATH
+#[cfg(unix)]
+#[test]
+fn test_resolve_argv0() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let env = TestEnv::new();
+    let dir = TempDir::new().unwrap();
+    let bins: Vec<PathBuf> = ["a", "b"]
+        .iter()
+        .map(|name| {
+            let bin = dir.path().join(name);
+            fs::create_dir(&bin).unwrap();
+            let tool = bin.join("tool");
+            fs::write(&tool, format!("#!/bin/sh\necho {name}\n")).unwrap();
+            fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+            bin
+        })
+        .collect();
+    let path_with = |bin: &PathBuf| format!("{}:/usr/bin:/bin", bin.display());
+
+    for (bin, expected) in bins.iter().zip(["a\n", "b\n"]) {
+        env.cmd()
+            .env("PATH", path_with(bin))
+            .args(["--resolve-argv0", "tool"])
+            .assert()
+            .success()
+            .stdout(expected);
+    }
+    env.assert_cache_entry_count(2);
+
+    env.cmd()
+        .env("PATH", "/usr/bin:/bin")
+        .args(["--resolve-argv0", "tool"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot resolve `tool`"));
+}
+
+// Test Case: --require-clean-exit-for-replay Only Replays Normal Exits
+#[test]
+fn test_require_clean_exit_for_replay() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let counter = work.path().join("counter");
+    let killed = format!("echo killed >> {}", counter.display());
+    let clean = format!("echo clean >> {}; exit 2", counter.display());
+
+    env.cmd().args(["sh", "-c", &killed]).assert().success();
+    env.cmd()
+        .args(["--cache-failures", "sh", "-c", &clean])
+        .assert()
+        .code(2);
+
+    // Mark the first entry as interrupted by a signal
+    let output = env
+        .cmd()
+        .args(["--json-meta", "sh", "-c", &killed])
+        .output()
+        .unwrap();
+    let meta: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let digest = meta["digest"].as_str().unwrap();
+    let meta_path = env.cache_path().join("memo").join(digest).join("meta.json");
+    let mut meta: serde_json::Value =
+        serde_json::from_slice(&fs::read(&meta_path).unwrap()).unwrap();
+    meta["exit_code"] = serde_json::json!(-1);
+    fs::write(&meta_path, serde_json::to_vec(&meta).unwrap()).unwrap();
+
+    for script in [&killed, &clean] {
+        env.cmd()
+            .args(["--require-clean-exit-for-replay", "sh", "-c", script])
+            .assert();
+    }
+    assert_eq!(
+        fs::read_to_string(&counter).unwrap(),
+        "killed\nclean\nkilled\n"
+    );
+}
+
+// Test Case: --git-aware Keys on HEAD and the Dirty State
+#[test]
+fn test_git_aware() {
+    let env = TestEnv::new();
+    let repo = TempDir::new().unwrap();
+    let work = TempDir::new().unwrap();
+    let counter = work.path().join("counter");
+    let script = format!("echo run >> {}", counter.display());
+
+    let git = |args: &[&str]| {
+        let status = std::process::Command::new("git")
+            .current_dir(repo.path())
+            .args(["-c", "user.name=memo", "-c", "user.email=memo@example.com"])
+            .args(args)
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+    let run = || {
+        env.cmd()
+            .current_dir(repo.path())
+            .args(["--git-aware", "sh", "-c", &script])
+            .assert()
+            .success()
+            .stderr("");
+    };
+    let runs = || fs::read_to_string(&counter).unwrap().lines().count();
+
+    git(&["init", "-q"]);
+    fs::write(repo.path().join("file"), "one").unwrap();
+    git(&["add", "file"]);
+    git(&["commit", "-q", "-m", "one"]);
+
+    run();
+    run();
+    assert_eq!(runs(), 1);
+
+    // Editing the tree makes it dirty
+    fs::write(repo.path().join("file"), "two").unwrap();
+    run();
+    assert_eq!(runs(), 2);
+
+    // Committing the edit moves HEAD
+    git(&["commit", "-q", "-am", "two"]);
+    run();
+    run();
+    assert_eq!(runs(), 3);
+    env.assert_cache_entry_count(3);
+
+    // Outside a repository the flag is ignored with a warning
+    env.cmd()
+        .current_dir(work.path())
+        .args(["--git-aware", "echo", "hi"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("not in a git repository"));
+}
+
+// Test Case: Metadata Records Start and Finish Times
+#[test]
+fn test_started_and_finished_at() {
+    let env = TestEnv::new();
+    env.cmd().args(["sh", "-c", "sleep 0.1"]).assert().success();
+
+    let output = env.cmd().args(["--list", "--json"]).output().unwrap();
+    let entries: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let entry = &entries[0];
+
+    let parse = |field: &str| {
+        chrono::DateTime::parse_from_rfc3339(entry[field].as_str().unwrap())
+            .unwrap_or_else(|e| panic!("{field} is not RFC 3339: {e}"))
+    };
+    let started_at = parse("started_at");
+    let finished_at = parse("finished_at");
+    assert!(finished_at >= started_at);
+    assert!(finished_at - started_at >= chrono::Duration::milliseconds(100));
+    assert_eq!(entry["timestamp"], entry["started_at"]);
+}
+
+// Test Case: --prefix Isolates a Cache Within the Cache Directory
+#[test]
+fn test_prefix_isolates_cache() {
+    let env = TestEnv::new();
+
+    env.cmd().args(["echo", "plain"]).assert().success();
+    env.cmd()
+        .args(["--prefix", "ab", "echo", "scoped"])
+        .assert()
+        .success();
+    assert!(env.cache_path().join("memo/ab/.memo-prefix").exists());
+
+    // Each cache only lists its own entries
+    env.cmd()
+        .args(["--list", "--format", "{cmd}"])
+        .assert()
+        .success()
+        .stdout("echo plain\n");
+    env.cmd()
+        .args(["--prefix", "ab", "--list", "--format", "{cmd}"])
+        .assert()
+        .success()
+        .stdout("echo scoped\n");
+    env.cmd()
+        .args(["--prefix", "ab", "--json-meta", "echo", "plain"])
+        .assert()
+        .code(1);
+
+    // Pruning the prefixed cache leaves the outer one alone, and vice versa
+    env.cmd()
+        .args(["--prefix", "ab", "--prune", "--older-than", "0s"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("pruned 1 entries"));
+    env.cmd()
+        .args(["--json-meta", "echo", "plain"])
+        .assert()
+        .success();
+
+    env.cmd()
+        .args(["--prefix", "ab", "echo", "scoped"])
+        .assert()
+        .success();
+    env.cmd()
+        .args(["--prune", "--older-than", "0s"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("pruned 1 entries"));
+    env.cmd()
+        .args(["--prefix", "ab", "--json-meta", "echo", "scoped"])
+        .assert()
+        .success();
+
+    env.cmd()
+        .args(["--prefix", "../escape", "echo", "hi"])
+        .assert()
+        .failure();
+}
+
+// Test Case: --replace Rewrites Replayed Output but Not the Cache
+#[test]
+fn test_replace_on_replay() {
+    let env = TestEnv::new();
+    let script = "echo built /work/a/out; echo warn /work/a >&2";
+
+    env.cmd()
+        .args(["sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("built /work/a/out\n");
+
+    env.cmd()
+        .args(["--replace", "/work/a=/src/b", "--replace", "built=made"])
+        .args(["sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("made /src/b/out\n")
+        .stderr("warn /src/b\n");
+
+    let digest = &env.list_cache_entries()[0];
+    assert_eq!(
+        env.read_cache_file(digest, "stdout"),
+        b"built /work/a/out\n"
+    );
+
+    // Binary output is replayed as is
+    env.cmd()
+        .args(["sh", "-c", "printf 'a\\000a'"])
+        .assert()
+        .success();
+    env.cmd()
+        .args(["--replace", "a=b", "sh", "-c", "printf 'a\\000a'"])
+        .assert()
+        .success()
+        .stdout(&b"a\0a"[..])
+        .stderr(predicate::str::contains("binary"));
+}
+
+// Test Case: --exec-shell Runs and Caches a Pipeline
+#[test]
+fn test_exec_shell_pipeline() {
+    let env = TestEnv::new();
+
+    for _ in 0..2 {
+        env.cmd()
+            .env("SHELL", "/bin/false")
+            .args(["-s", "printf 'a\\nb\\nc\\n' | wc -l | tr -d ' '"])
+            .assert()
+            .success()
+            .stdout("3\n");
+    }
+    env.assert_cache_entry_count(1);
+
+    let digest = &env.list_cache_entries()[0];
+    let meta: serde_json::Value =
+        serde_json::from_slice(&env.read_cache_file(digest, "meta.json")).unwrap();
+    assert_eq!(
+        meta["cmd"],
+        serde_json::json!(["/bin/sh", "-c", "printf 'a\\nb\\nc\\n' | wc -l | tr -d ' '"])
+    );
+
+    // A different pipeline is a different entry
+    env.cmd()
+        .args([
+            "--exec-shell",
+            "printf 'a\\nb\\n'",
+            "|",
+            "wc -l | tr -d ' '",
+        ])
+        .assert()
+        .success()
+        .stdout("2\n");
+    env.assert_cache_entry_count(2);
+
+    // $SHELL doesn't change the shell or the key
+    env.cmd()
+        .env("SHELL", "/usr/bin/fish")
+        .args(["-s", "printf 'a\\nb\\nc\\n' | wc -l | tr -d ' '"])
+        .assert()
+        .success()
+        .stdout("3\n");
+    env.assert_cache_entry_count(2);
+}
+
+/// The short version memo reports with `-V`
+fn memo_version(env: &TestEnv) -> String {
+    let output = env.cmd().arg("-V").output().unwrap();
+    let version = String::from_utf8(output.stdout).unwrap();
+    version.trim().strip_prefix("memo ").unwrap().to_string()
+}
+
+// Test Case: New Entries Record the memo Version
+#[test]
+fn test_memo_version_recorded() {
+    let env = TestEnv::new();
+    env.cmd().args(["echo", "hello"]).assert().success();
+
+    let version = memo_version(&env);
+    assert!(!version.is_empty());
+
+    let digest = &env.list_cache_entries()[0];
+    let meta: serde_json::Value =
+        serde_json::from_slice(&env.read_cache_file(digest, "meta.json")).unwrap();
+    assert_eq!(meta["memo_version"], version.as_str());
+
+    // Entries from before the field existed report "unknown"
+    let meta_path = env.cache_path().join("memo").join(digest).join("meta.json");
+    let mut meta = meta;
+    meta.as_object_mut().unwrap().remove("memo_version");
+    fs::write(&meta_path, serde_json::to_vec(&meta).unwrap()).unwrap();
+    env.cmd()
+        .arg("--doctor")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "entries by memo version: unknown (1)",
+        ));
+}
+
+// Test Case: --drop and --drop-digest Remove a Single Entry
+#[test]
+fn test_drop_single_entry() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let counter = work.path().join("counter");
+    let script = |name: &str| format!("echo {name} >> {}; echo {name}", counter.display());
+    let (one, two) = (script("one"), script("two"));
+
+    env.cmd().args(["sh", "-c", &one]).assert().success();
+    env.cmd().args(["sh", "-c", &two]).assert().success();
+    env.assert_cache_entry_count(2);
+
+    env.cmd()
+        .args(["--drop", "sh", "-c", &one])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("dropped "));
+    env.cmd()
+        .args(["--drop", "sh", "-c", &one])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("No cached entry"));
+
+    // The other entry still replays while the dropped one runs again
+    env.cmd()
+        .args(["sh", "-c", &two])
+        .assert()
+        .success()
+        .stdout("two\n");
+    env.cmd().args(["sh", "-c", &one]).assert().success();
+    assert_eq!(fs::read_to_string(&counter).unwrap(), "one\ntwo\none\n");
+
+    // Drop by digest prefix
+    let entries = env.list_cache_entries();
+    let prefix = &entries[0][..12];
+    env.cmd()
+        .args(["--drop-digest", prefix])
+        .assert()
+        .success()
+        .stdout(format!("dropped {}\n", entries[0]));
+    assert_eq!(env.list_cache_entries(), [entries[1].clone()]);
+
+    env.cmd()
+        .args(["--drop-digest", prefix])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("No cached entry matches"));
+
+    // An empty prefix would match the one remaining entry
+    env.cmd()
+        .args(["--drop-digest", ""])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("too short"));
+    assert_eq!(env.list_cache_entries(), [entries[1].clone()]);
+
+    // --drop can take its command from --command-file instead
+    env.cmd().args(["sh", "-c", &one]).assert().success();
+    env.cmd().args(["sh", "-c", &two]).assert().success();
+    env.assert_cache_entry_count(2);
+    let command_file = work.path().join("cmd.txt");
+    fs::write(&command_file, format!("sh\n-c\n{two}\n")).unwrap();
+    env.cmd()
+        .arg("--drop")
+        .arg("--command-file")
+        .arg(&command_file)
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("dropped "));
+    env.assert_cache_entry_count(1);
+    env.cmd()
+        .args(["--drop", "sh", "-c", &two])
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("No cached entry"));
+}
+
+// Test Case: --self-bench --quick Reports Timings
+#[test]
+fn test_self_bench_quick() {
+    let env = TestEnv::new();
+    let output = env
+        .cmd()
+        .args(["--self-bench", "--quick"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let timing = Regex::new(r"^(small|large) +(miss|commit|hit|replay) +\d+ +\d+\.\d").unwrap();
+    let lines: Vec<&str> = stdout.lines().skip(1).collect();
+    assert_eq!(lines.len(), 8, "{stdout}");
+    for line in lines {
+        assert!(timing.is_match(line), "{line:?}");
+    }
+
+    // The real cache is untouched
+    env.assert_cache_entry_count(0);
+}
+
+// Test Case: --replay-exit-only Doesn't Open the Output Files
+#[test]
+fn test_replay_exit_only() {
+    let env = TestEnv::new();
+    env.cmd()
+        .args([
+            "--cache-failures",
+            "sh",
+            "-c",
+            "echo out; echo err >&2; exit 3",
+        ])
+        .assert()
+        .code(3);
+
+    // Replace the output files with directories, which can't be read
+    let digest = &env.list_cache_entries()[0];
+    let entry = env.cache_path().join("memo").join(digest);
+    for name in ["stdout", "stderr"] {
+        fs::remove_file(entry.join(name)).unwrap();
+        fs::create_dir(entry.join(name)).unwrap();
+    }
+
+    env.cmd()
+        .args([
+            "--replay-exit-only",
+            "sh",
+            "-c",
+            "echo out; echo err >&2; exit 3",
+        ])
+        .assert()
+        .code(3)
+        .stdout("")
+        .stderr("");
+    env.cmd()
+        .args(["--replay-exit-only", "--exit-trailer"])
+        .args(["sh", "-c", "echo out; echo err >&2; exit 3"])
+        .assert()
+        .code(3)
+        .stdout("::memo-exit=3::\n");
+
+    // A normal hit has to read them
+    env.cmd()
+        .args(["sh", "-c", "echo out; echo err >&2; exit 3"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("ERROR"));
+}
+
+// Test Case: --buffer-size Doesn't Change the Cached or Replayed Bytes
+#[test]
+fn test_buffer_size_roundtrip() {
+    let env = TestEnv::new();
+    let script = "seq 1 2000; seq 1 500 >&2";
+    let expected: String = (1..=2000).map(|i| format!("{i}\n")).collect();
+
+    for size in ["7", "65536"] {
+        env.cmd()
+            .args(["--buffer-size", size, "sh", "-c", script])
+            .assert()
+            .success()
+            .stdout(expected.clone());
+    }
+    let digest = &env.list_cache_entries()[0];
+    assert_eq!(env.read_cache_file(digest, "stdout"), expected.as_bytes());
+
+    env.cmd()
+        .args(["--buffer-size", "0", "echo", "hi"])
+        .assert()
+        .failure();
+}
+
+// Test Case: --incremental Prints Only the Appended Output
+#[test]
+fn test_incremental_prints_delta() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let log = work.path().join("log");
+    let script = format!("cat {}", log.display());
+
+    fs::write(&log, "line 1\nline 2\n").unwrap();
+    env.cmd()
+        .args(["--incremental", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("line 1\nline 2\n");
+
+    fs::write(&log, "line 1\nline 2\nline 3\n").unwrap();
+    env.cmd()
+        .args(["--incremental", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("line 3\n");
+
+    // The full output is cached and replays normally
+    env.assert_cache_entry_count(1);
+    let digest = &env.list_cache_entries()[0];
+    assert_eq!(
+        env.read_cache_file(digest, "stdout"),
+        b"line 1\nline 2\nline 3\n"
+    );
+    env.cmd()
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("line 1\nline 2\nline 3\n");
+
+    // Nothing new prints nothing; a rewritten log prints in full
+    env.cmd()
+        .args(["--incremental", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("");
+    fs::write(&log, "rotated\n").unwrap();
+    env.cmd()
+        .args(["--incremental", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("rotated\n");
+}
+
+// Test Case: --cache-key-file Contents Are Part of the Cache Key
+#[test]
+fn test_cache_key_file() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let key_file = work.path().join("lock.hash");
+    let key_arg = key_file.to_str().unwrap();
+
+    fs::write(&key_file, "abc123\n").unwrap();
+    env.cmd()
+        .args(["--cache-key-file", key_arg, "date", "+%N"])
+        .assert()
+        .success();
+    env.cmd()
+        .args(["--cache-key-file", key_arg, "date", "+%N"])
+        .assert()
+        .success();
+    env.assert_cache_entry_count(1);
+
+    fs::write(&key_file, "def456\n").unwrap();
+    env.cmd()
+        .args(["--cache-key-file", key_arg, "date", "+%N"])
+        .assert()
+        .success();
+    env.assert_cache_entry_count(2);
+
+    fs::remove_file(&key_file).unwrap();
+    env.cmd()
+        .args(["--cache-key-file", key_arg, "date", "+%N"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Cannot read cache key file"))
+        .stderr(predicate::str::contains("lock.hash"));
+    env.assert_cache_entry_count(2);
+}
+
+// Test Case: --no-tee Caches a Miss Without Console Output
+#[test]
+fn test_no_tee_miss_is_silent() {
+    let env = TestEnv::new();
+    let script = "echo out; echo err >&2; exit 3";
+
+    env.cmd()
+        .args(["--no-tee", "--cache-failures", "sh", "-c", script])
+        .assert()
+        .code(3)
+        .stdout("")
+        .stderr("");
+
+    env.assert_cache_entry_count(1);
+    let digest = &env.list_cache_entries()[0];
+    assert_eq!(env.read_cache_file(digest, "stdout"), b"out\n");
+    assert_eq!(env.read_cache_file(digest, "stderr"), b"err\n");
+
+    env.cmd()
+        .args(["sh", "-c", script])
+        .assert()
+        .code(3)
+        .stdout("out\n")
+        .stderr("err\n");
+}
+
+// Test Case: An Incomplete Entry Directory Is Replaced on the Next Miss
+#[test]
+fn test_incomplete_entry_self_heals() {
+    use std::time::{Duration, SystemTime};
+    let env = TestEnv::new();
+
+    env.cmd().args(["echo", "healed"]).assert().success();
+    let digest = env.list_cache_entries()[0].clone();
+
+    // Leave only meta.json behind, as an interrupted commit might, a while ago
+    let entry = env.cache_path().join("memo").join(&digest);
+    fs::remove_file(entry.join("stdout")).unwrap();
+    fs::remove_file(entry.join("stderr")).unwrap();
+    let stale = SystemTime::now() - Duration::from_secs(600);
+    for path in [entry.clone(), entry.join("meta.json")] {
+        fs::File::open(path).unwrap().set_modified(stale).unwrap();
+    }
+
+    env.cmd()
+        .args(["--verbose", "echo", "healed"])
+        .assert()
+        .success()
+        .stdout("healed\n")
+        .stderr(predicate::str::contains("miss `echo healed`"))
+        .stderr(predicate::str::contains(format!(
+            "removed incomplete entry {digest}"
+        )));
+
+    assert_eq!(env.read_cache_file(&digest, "stdout"), b"healed\n");
+    env.cmd()
+        .args(["--verbose", "echo", "healed"])
+        .assert()
+        .success()
+        .stdout("healed\n")
+        .stderr(predicate::str::contains("hit `echo healed`"));
+}
+
+// Test Case: --color=always Colors Diagnostics on a Pipe
+#[test]
+fn test_color_always_on_pipe() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .env_remove("NO_COLOR")
+        .args(["--verbose", "echo", "plain"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(":: memo :: miss"))
+        .stderr(predicate::str::contains("\x1b[").not());
+
+    env.cmd()
+        .env("NO_COLOR", "1")
+        .args(["--color=always", "--verbose", "echo", "plain"])
+        .assert()
+        .success()
+        .stdout("plain\n")
+        .stderr(predicate::str::contains("\x1b[1;34m:: memo ::\x1b[0m hit"));
+}
+
+// Test Case: --color=never Leaves Diagnostics Plain on a Terminal
+#[cfg(unix)]
+#[test]
+fn test_color_never_on_terminal() {
+    let env = TestEnv::new();
+    let memo = env!("CARGO_BIN_EXE_memo");
+    // The outer memo gives the inner one a terminal for stdout and stderr
+    let inner = |color: &str| format!("{memo} --color={color} --verbose echo colored 2>&1");
+
+    let assert = env
+        .cmd()
+        .env_remove("NO_COLOR")
+        .args(["--pty", "sh", "-c", &inner("never")])
+        .assert()
+        .success();
+    let output = String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+    assert!(output.contains(":: memo :: miss"), "{output}");
+    assert!(!output.contains('\x1b'), "{output}");
+
+    let assert = env
+        .cmd()
+        .env_remove("NO_COLOR")
+        .args(["--pty", "sh", "-c", &inner("auto")])
+        .assert()
+        .success();
+    let output = String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+    assert!(
+        output.contains("\x1b[1;34m:: memo ::\x1b[0m hit"),
+        "{output}"
+    );
+}
+
+// Test Case: --stdin-file Feeds the Command and Keys on the File's Contents
+#[test]
+fn test_stdin_file() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let input = work.path().join("input.txt");
+    let input_arg = input.to_str().unwrap();
+
+    fs::write(&input, "pear\napple\n").unwrap();
+    env.cmd()
+        .args(["--verbose", "--stdin-file", input_arg, "sort"])
+        .assert()
+        .success()
+        .stdout("apple\npear\n")
+        .stderr(predicate::str::contains("miss `sort`"));
+    env.cmd()
+        .args(["--verbose", "--stdin-file", input_arg, "sort"])
+        .assert()
+        .success()
+        .stdout("apple\npear\n")
+        .stderr(predicate::str::contains("hit `sort`"));
+    env.assert_cache_entry_count(1);
+
+    fs::write(&input, "pear\nfig\n").unwrap();
+    env.cmd()
+        .args(["--verbose", "--stdin-file", input_arg, "sort"])
+        .assert()
+        .success()
+        .stdout("fig\npear\n")
+        .stderr(predicate::str::contains("miss `sort`"));
+    env.assert_cache_entry_count(2);
+
+    // A disabled run still reads the file, not memo's own stdin
+    env.cmd()
+        .env("MEMO_DISABLE", "1")
+        .args(["--stdin-file", input_arg, "sort"])
+        .write_stdin("ignored\n")
+        .assert()
+        .success()
+        .stdout("fig\npear\n");
+
+    fs::remove_file(&input).unwrap();
+    env.cmd()
+        .args(["--stdin-file", input_arg, "sort"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Cannot read stdin file"));
+}
+
+// Test Case: --no-chmod Leaves New Cache Directories at the Default Mode
+#[cfg(unix)]
+#[test]
+fn test_no_chmod() {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = |path: PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+    let env = TestEnv::new();
+    env.cmd().args(["echo", "secure"]).assert().success();
+    let digest = env.list_cache_entries()[0].clone();
+    assert_eq!(mode(env.cache_path().join("memo")), 0o700);
+    assert_eq!(mode(env.cache_path().join("memo").join(&digest)), 0o700);
+
+    let env = TestEnv::new();
+    env.cmd()
+        .args(["--no-chmod", "echo", "open"])
+        .assert()
+        .success()
+        .stdout("open\n");
+    let digest = env.list_cache_entries()[0].clone();
+    assert_ne!(mode(env.cache_path().join("memo")), 0o700);
+    assert_ne!(mode(env.cache_path().join("memo").join(&digest)), 0o700);
+}
+
+// Test Case: --report-saved Sums the Durations of a Session's Hits
+#[test]
+fn test_report_saved() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .env("MEMO_SESSION", "build-1")
+        .args(["sleep", "0.2"])
+        .assert()
+        .success();
+    for _ in 0..2 {
+        env.cmd()
+            .env("MEMO_SESSION", "build-1")
+            .args(["sleep", "0.2"])
+            .assert()
+            .success();
+    }
+    // Runs outside the session aren't counted
+    env.cmd().args(["sleep", "0.2"]).assert().success();
+
+    let digest = &env.list_cache_entries()[0];
+    let meta: serde_json::Value =
+        serde_json::from_slice(&env.read_cache_file(digest, "meta.json")).unwrap();
+    let duration_ms = meta["duration_ms"].as_u64().unwrap();
+    assert!(duration_ms >= 200);
+
+    env.cmd()
+        .env("MEMO_SESSION", "build-1")
+        .arg("--report-saved")
+        .assert()
+        .success()
+        .stdout(format!(
+            "session: build-1\ncommands: 3\nhits: 2\nsaved: {}ms\n",
+            2 * duration_ms
+        ));
+
+    env.cmd()
+        .env_remove("MEMO_SESSION")
+        .arg("--report-saved")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("MEMO_SESSION is not set"));
+}
+
+// Test Case: --deterministic-check Caches Only Reproducible Output
+#[test]
+fn test_deterministic_check() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .args(["--deterministic-check", "echo", "stable"])
+        .assert()
+        .success()
+        .stdout("stable\n");
+    env.assert_cache_entry_count(1);
+
+    env.cmd()
+        .args(["--deterministic-check", "date", "+%N"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "`date +%N` is not deterministic (stdout differs at byte",
+        ))
+        .stderr(predicate::str::contains("not caching"));
+    env.assert_cache_entry_count(1);
+
+    env.cmd()
+        .args([
+            "--deterministic-check",
+            "sh",
+            "-c",
+            "test -e flag && exit 1; touch flag",
+        ])
+        .current_dir(env.cache_path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("exit code differs (0 then 1)"));
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: --tee-to Copies stdout to a File on a Miss and a Hit
+#[test]
+fn test_tee_to() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let log = work.path().join("build.log");
+    let log_arg = log.to_str().unwrap();
+    let script = "printf 'line 1\\nline 2'; echo oops >&2";
+
+    env.cmd()
+        .args(["--tee-to", log_arg, "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("line 1\nline 2")
+        .stderr("oops\n");
+    assert_eq!(fs::read(&log).unwrap(), b"line 1\nline 2");
+
+    fs::remove_file(&log).unwrap();
+    env.cmd()
+        .args(["--tee-to", log_arg, "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("line 1\nline 2")
+        .stderr("oops\n");
+    assert_eq!(fs::read(&log).unwrap(), b"line 1\nline 2");
+
+    // An unwritable destination only warns
+    let bad = work.path().join("missing").join("build.log");
+    env.cmd()
+        .args(["--tee-to", bad.to_str().unwrap(), "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("line 1\nline 2")
+        .stderr(predicate::str::contains("WARNING: could not create"));
+}
+
+// Test Case: --log-format json Writes Diagnostics as JSON Lines
+#[test]
+fn test_log_format_json() {
+    let env = TestEnv::new();
+    let events = |stderr: &[u8]| -> Vec<serde_json::Value> {
+        String::from_utf8_lossy(stderr)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    };
+
+    let assert = env
+        .cmd()
+        .args(["--log-format", "json", "--verbose", "echo", "structured"])
+        .assert()
+        .success()
+        .stdout("structured\n");
+    let digest = env.list_cache_entries()[0].clone();
+    let miss = events(&assert.get_output().stderr)
+        .into_iter()
+        .find(|event| event["event"] == "miss")
+        .expect("no miss event");
+    assert_eq!(miss["level"], "info");
+    assert_eq!(miss["command"], "echo structured");
+    assert_eq!(miss["digest"], digest.as_str());
+
+    let assert = env
+        .cmd()
+        .args(["--log-format=json", "--verbose", "echo", "structured"])
+        .assert()
+        .success();
+    let stderr = events(&assert.get_output().stderr);
+    assert!(stderr.iter().any(|event| event["event"] == "hit"));
+
+    // Errors are JSON too
+    let assert = env
+        .cmd()
+        .args([
+            "--log-format=json",
+            "--cache-key-file",
+            "/nonexistent/key",
+            "true",
+        ])
+        .assert()
+        .failure();
+    let stderr = events(&assert.get_output().stderr);
+    assert_eq!(stderr.len(), 1);
+    assert_eq!(stderr[0]["level"], "error");
+    assert_eq!(stderr[0]["event"], "message");
+}
+
+// Test Case: --cold-start Always Runs and Replaces the Cached Entry
+#[test]
+fn test_cold_start() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let counter = work.path().join("runs");
+    let script = format!("echo x >> {0}; wc -l < {0}", counter.display());
+
+    env.cmd()
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("1\n");
+    env.cmd()
+        .args(["--cold-start", "--verbose", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("2\n")
+        .stderr(predicate::str::contains("cached, running anyway"));
+    env.cmd()
+        .args(["--cold-start", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("3\n");
+
+    // The latest run replaced the entry
+    env.assert_cache_entry_count(1);
+    env.cmd()
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("3\n");
+    assert_eq!(fs::read_to_string(&counter).unwrap().lines().count(), 3);
+}
+
+// Test Case: --order-insensitive Shares Entries Across Flag Orders
+#[test]
+fn test_order_insensitive() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .args(["--order-insensitive", "ls", "-a", "-d", "/"])
+        .assert()
+        .success()
+        .stdout("/\n");
+    env.cmd()
+        .args(["--order-insensitive", "--verbose", "ls", "-d", "-a", "/"])
+        .assert()
+        .success()
+        .stdout("/\n")
+        .stderr(predicate::str::contains("hit `ls -d -a /`"));
+    env.assert_cache_entry_count(1);
+
+    env.cmd().args(["ls", "-d", "-a", "/"]).assert().success();
+    env.assert_cache_entry_count(2);
+}
+
+// Test Case: --max-concurrency Limits How Many Misses Run at Once
+#[test]
+fn test_max_concurrency() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let running = work.path().join("running");
+    let log = work.path().join("counts");
+    fs::create_dir(&running).unwrap();
+
+    // Each command notes how many commands are running while it runs
+    let script = format!(
+        "touch {running}/$1; ls {running} | wc -l >> {log}; sleep 0.3; \
+         ls {running} | wc -l >> {log}; rm {running}/$1",
+        running = running.display(),
+        log = log.display()
+    );
+
+    let children: Vec<_> = (0..6)
+        .map(|i| {
+            std::process::Command::new(env!("CARGO_BIN_EXE_memo"))
+                .env("XDG_CACHE_HOME", env.cache_path())
+                .args(["--max-concurrency", "2", "sh", "-c", &script, "sh"])
+                .arg(i.to_string())
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+
+    let counts: Vec<usize> = fs::read_to_string(&log)
+        .unwrap()
+        .split_whitespace()
+        .map(|n| n.parse().unwrap())
+        .collect();
+    assert_eq!(counts.len(), 12);
+    assert!(counts.iter().all(|&n| n <= 2), "{counts:?}");
+    env.assert_cache_entry_count(6);
+}
+
+// Test Case: --repair Rewrites Mismatched Digests in meta.json
+#[test]
+fn test_repair_mismatched_digest() {
+    let env = TestEnv::new();
+    env.cmd().args(["echo", "fixme"]).assert().success();
+    let digest = env.list_cache_entries()[0].clone();
+
+    // Simulate a manual edit that left the recorded digest stale
+    let meta_path = env
+        .cache_path()
+        .join("memo")
+        .join(&digest)
+        .join("meta.json");
+    let mut meta: serde_json::Value =
+        serde_json::from_slice(&fs::read(&meta_path).unwrap()).unwrap();
+    meta["digest"] = serde_json::json!("0000");
+    fs::write(&meta_path, serde_json::to_vec(&meta).unwrap()).unwrap();
+
+    env.cmd()
+        .arg("--doctor")
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("digest mismatch"));
+
+    env.cmd()
+        .arg("--repair")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "{digest}: digest was 0000"
+        )))
+        .stdout(predicate::str::contains("repaired 1 digest(s)"));
+
+    let meta: serde_json::Value =
+        serde_json::from_slice(&env.read_cache_file(&digest, "meta.json")).unwrap();
+    assert_eq!(meta["digest"], digest.as_str());
+    env.cmd().arg("--doctor").assert().success();
+    env.cmd()
+        .args(["echo", "fixme"])
+        .assert()
+        .success()
+        .stdout("fixme\n");
+}
+
+// Test Case: --version Includes Build Information
+#[test]
+fn test_long_version_build_info() {
+    let env = TestEnv::new();
+    let output = env.cmd().arg("--version").output().unwrap();
+    assert!(output.status.success());
+    let long_version = String::from_utf8(output.stdout).unwrap();
+
+    // The first line is the git describe output, e.g. `v1.2.3-4-gabcdef0`
+    // or an abbreviated commit
+    let first_line = long_version.lines().next().unwrap();
+    let describe = Regex::new(r"^memo (v\d+\.\d+\.\d+|[0-9a-f]{7,})").unwrap();
+    assert!(describe.is_match(first_line), "{long_version}");
+
+    let date = Regex::new(r"(?m)^build-date +\d{4}-\d{2}-\d{2}$").unwrap();
+    assert!(date.is_match(&long_version), "{long_version}");
+    let sha = Regex::new(r"(?m)^commit-id +[0-9a-f]{40}$").unwrap();
+    assert!(sha.is_match(&long_version), "{long_version}");
+    assert!(long_version.contains("rustc-version "), "{long_version}");
+}
+
+// Test Case: A Corrupt meta.json Is Replaced by Re-running the Command
+#[test]
+fn test_corrupt_meta_self_heals() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let counter = work.path().join("runs");
+    let script = format!("echo x >> {0}; wc -l < {0}", counter.display());
+
+    env.cmd()
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("1\n");
+    let digest = env.list_cache_entries()[0].clone();
+
+    let meta_path = env
+        .cache_path()
+        .join("memo")
+        .join(&digest)
+        .join("meta.json");
+    let meta = fs::read(&meta_path).unwrap();
+    fs::write(&meta_path, &meta[..meta.len() / 2]).unwrap();
+
+    env.cmd()
+        .args(["--verbose", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("2\n")
+        .stderr(predicate::str::contains("WARNING: corrupt metadata"))
+        .stderr(predicate::str::contains("miss `sh -c"));
+
+    let meta: serde_json::Value =
+        serde_json::from_slice(&env.read_cache_file(&digest, "meta.json")).unwrap();
+    assert_eq!(meta["digest"], digest.as_str());
+    env.cmd()
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("2\n");
+
+    // Garbage (rather than truncated) metadata heals the same way
+    fs::write(&meta_path, "not json").unwrap();
+    env.cmd()
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("3\n");
+    env.cmd().arg("--doctor").assert().success();
+}
+
+// Test Case: --replay-stdout-to-stderr Sends a Hit's stdout to stderr
+#[test]
+fn test_replay_stdout_to_stderr() {
+    let env = TestEnv::new();
+    let script = "printf out; printf err >&2";
+
+    env.cmd()
+        .args(["--replay-stdout-to-stderr", "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("out")
+        .stderr("err");
+
+    env.cmd()
+        .args(["--replay-stdout-to-stderr", "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("outerr");
+}
+
+// Test Case: --sample-rate 1.0 Re-runs Every Hit and Reports Drift
+#[test]
+fn test_sample_rate_reports_drift() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let counter = work.path().join("runs");
+    let script = format!("echo x >> {0}; wc -l < {0}", counter.display());
+
+    env.cmd()
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    // The entry is now stale, and the sampled run notices
+    env.cmd()
+        .args(["--sample-rate", "1.0", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("2\n")
+        .stderr(predicate::str::contains(
+            "no longer matches its cached result (stdout differs at byte 0)",
+        ));
+    env.cmd()
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    // With --sample-refresh the fresh result replaces the entry
+    env.cmd()
+        .args([
+            "--sample-rate",
+            "1",
+            "--sample-refresh",
+            "sh",
+            "-c",
+            &script,
+        ])
+        .assert()
+        .success()
+        .stdout("3\n");
+    env.cmd()
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("3\n");
+    assert_eq!(fs::read_to_string(&counter).unwrap().lines().count(), 3);
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: --sample-rate Rejects Rates Outside 0.0 to 1.0
+#[test]
+fn test_sample_rate_range() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .args(["--sample-rate", "1.5", "echo", "hi"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("not between 0.0 and 1.0"));
+}
+
+// Test Case: --bundle and --unbundle Round-Trip the Whole Cache
+#[test]
+fn test_bundle_roundtrip() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let bundle = work.path().join("cache.tar.zst");
+
+    env.cmd().args(["echo", "one"]).assert().success();
+    env.cmd()
+        .args([
+            "--cache-failures",
+            "sh",
+            "-c",
+            "echo two; echo err >&2; exit 3",
+        ])
+        .assert()
+        .code(3);
+
+    env.cmd()
+        .arg("--bundle")
+        .arg(&bundle)
+        .assert()
+        .success()
+        .stdout(format!("bundled 2 entries into {}\n", bundle.display()));
+
+    fs::remove_dir_all(env.cache_path().join("memo")).unwrap();
+    env.assert_cache_entry_count(0);
+
+    env.cmd()
+        .arg("--unbundle")
+        .arg(&bundle)
+        .assert()
+        .success()
+        .stdout("restored 2 entries, skipped 0 already cached\n");
+    env.assert_cache_entry_count(2);
+
+    env.cmd()
+        .args(["--verbose", "echo", "one"])
+        .assert()
+        .success()
+        .stdout("one\n")
+        .stderr(predicate::str::contains("hit `echo one`"));
+    env.cmd()
+        .args(["--verbose", "sh", "-c", "echo two; echo err >&2; exit 3"])
+        .assert()
+        .code(3)
+        .stdout("two\n")
+        .stderr(predicate::str::contains("err\n").and(predicate::str::contains("hit `sh")));
+
+    // Restoring again skips everything
+    env.cmd()
+        .arg("--unbundle")
+        .arg(&bundle)
+        .assert()
+        .success()
+        .stdout("restored 0 entries, skipped 2 already cached\n");
+}
+
+// Test Case: --min-duration Only Caches Slow Runs
+#[test]
+fn test_min_duration() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .args(["--min-duration", "10000", "--verbose", "echo", "fast"])
+        .assert()
+        .success()
+        .stdout("fast\n")
+        .stderr(predicate::str::contains("not caching (ran in"));
+    env.assert_cache_entry_count(0);
+
+    env.cmd()
+        .args(["--min-duration", "500", "sh", "-c", "sleep 1; echo slow"])
+        .assert()
+        .success()
+        .stdout("slow\n");
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: --ttl and MEMO_TTL Expire Old Entries, and --ttl 0 Always Re-runs
+#[test]
+fn test_ttl() {
+    let env = TestEnv::new();
+    let counter = env.cache_path().join("runs");
+    let script = format!("echo x >> {0}; wc -l < {0}", counter.display());
+
+    env.cmd()
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("1\n");
+    env.cmd()
+        .args(["--ttl", "10m", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    // Twenty minutes later the entry has expired, by flag or by MEMO_TTL
+    let digest = env.list_cache_entries().remove(0);
+    backdate_entry(&env, &digest, 1200);
+    env.cmd()
+        .env("MEMO_TTL", "10m")
+        .args(["--verbose", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("2\n")
+        .stderr(predicate::str::contains("(expired)"));
+    env.cmd()
+        .env("MEMO_TTL", "10m")
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("2\n");
+
+    // The flag overrides the environment, and zero always re-runs
+    env.cmd()
+        .env("MEMO_TTL", "1h")
+        .args(["--ttl", "0", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("3\n");
+
+    // An entry whose timestamp can't be parsed counts as expired
+    let path = env
+        .cache_path()
+        .join("memo")
+        .join(&digest)
+        .join("meta.json");
+    let mut meta: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    meta["timestamp"] = serde_json::Value::String("yesterday".to_string());
+    fs::write(&path, serde_json::to_vec(&meta).unwrap()).unwrap();
+    env.cmd()
+        .args(["--ttl", "1h", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("4\n");
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: An Invalid MEMO_TTL Is an Error
+#[test]
+fn test_invalid_memo_ttl() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .env("MEMO_TTL", "soon")
+        .args(["echo", "hi"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("MEMO_TTL"));
+}
+
+// Test Case: --force and -f Are Aliases of --cold-start
+#[test]
+fn test_force() {
+    let env = TestEnv::new();
+    let counter = env.cache_path().join("runs");
+    let script = format!("echo x >> {0}; wc -l < {0}", counter.display());
+
+    env.cmd()
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("1\n");
+    env.cmd()
+        .args(["-f", "--verbose", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("2\n")
+        .stderr(
+            predicate::str::contains(":: memo :: cold start `sh -c")
+                .and(predicate::str::contains(":: memo :: hit").not()),
+        );
+    env.cmd()
+        .args(["--force", "sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("3\n");
+
+    // The forced result replaced the cached one
+    env.assert_cache_entry_count(1);
+    env.cmd()
+        .args(["sh", "-c", &script])
+        .assert()
+        .success()
+        .stdout("3\n");
+}
+
+// Test Case: --clear Removes Every Entry, and --dry-run Only Lists Them
+#[test]
+fn test_clear() {
+    let env = TestEnv::new();
+    env.cmd().args(["echo", "one"]).assert().success();
+    env.cmd().args(["echo", "two"]).assert().success();
+    env.cmd().args(["--pin", "echo", "two"]).assert().success();
+    let entries = env.list_cache_entries();
+
+    env.cmd()
+        .args(["--clear", "--dry-run"])
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains(format!("would remove {}\n", entries[0]))
+                .and(predicate::str::contains(format!(
+                    "would remove {}\n",
+                    entries[1]
+                )))
+                .and(predicate::str::contains("would clear 2 entries")),
+        );
+    env.assert_cache_entry_count(2);
+
+    env.cmd()
+        .arg("--clear")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("cleared 2 entries, freed "));
+    env.assert_cache_entry_count(0);
+    assert!(env.cache_path().join("memo").is_dir());
+}
+
+// Test Case: --max-size Evicts the Least Recently Used Unpinned Entries
+#[test]
+fn test_max_size_evicts_unpinned_entries() {
+    let env = TestEnv::new();
+    let big = |n: &str| {
+        [
+            "sh".to_string(),
+            "-c".to_string(),
+            format!("head -c 2000 /dev/zero; echo {n}"),
+        ]
+    };
+
+    env.cmd().args(big("1")).assert().success();
+    env.cmd().args(["--pin"]).args(big("1")).assert().success();
+    env.cmd().args(big("2")).assert().success();
+    env.assert_cache_entry_count(2);
+
+    // The third entry pushes the cache over 5K, so the oldest unpinned one goes
+    env.cmd()
+        .env("MEMO_MAX_SIZE", "5K")
+        .arg("--verbose")
+        .args(big("3"))
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("evicted 1 entries"));
+    env.assert_cache_entry_count(2);
+
+    let replayed = |n: &str| {
+        let output = env.cmd().arg("--verbose").args(big(n)).output().unwrap();
+        String::from_utf8_lossy(&output.stderr).contains(":: memo :: hit")
+    };
+    assert!(replayed("1"));
+    assert!(replayed("3"));
+}
+
+// Test Case: --max-size Keeps the Entry It Just Cached
+#[test]
+fn test_max_size_keeps_new_entry() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .args(["--max-size", "1", "echo", "hi"])
+        .assert()
+        .success();
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: Failures Aren't Cached Unless --cache-failures Is Given
+#[test]
+fn test_failures_not_cached_by_default() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .args(["--verbose", "sh", "-c", "echo flaky; exit 7"])
+        .assert()
+        .code(7)
+        .stdout("flaky\n")
+        .stderr(predicate::str::contains(":: memo :: not caching (exit 7)"));
+    env.assert_cache_entry_count(0);
+
+    env.cmd()
+        .args(["--cache-failures", "sh", "-c", "echo flaky; exit 7"])
+        .assert()
+        .code(7);
+    env.assert_cache_entry_count(1);
+
+    // A cached failure replays even without the flag
+    env.cmd()
+        .args(["--verbose", "sh", "-c", "echo flaky; exit 7"])
+        .assert()
+        .code(7)
+        .stdout("flaky\n")
+        .stderr(predicate::str::contains("hit `sh"));
+}
+
+// Test Case: --env and MEMO_ENV Key Entries on Environment Variables
+#[test]
+fn test_env_in_cache_key() {
+    let env = TestEnv::new();
+    let script = "echo \"${LANG-unset}\"";
+
+    env.cmd()
+        .env("LANG", "C")
+        .args(["--env", "LANG", "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("C\n");
+    env.cmd()
+        .env("LANG", "en_US.UTF-8")
+        .args(["--env", "LANG", "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("en_US.UTF-8\n");
+    env.cmd()
+        .env("LANG", "")
+        .args(["--env", "LANG", "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("\n");
+    env.cmd()
+        .env_remove("LANG")
+        .args(["--env", "LANG", "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("unset\n");
+    env.assert_cache_entry_count(4);
+
+    // MEMO_ENV gives the same keys
+    env.cmd()
+        .env("LANG", "C")
+        .env("MEMO_ENV", "TZ, LANG")
+        .args(["--verbose", "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("C\n")
+        .stderr(predicate::str::contains("miss"));
+    env.cmd()
+        .env("LANG", "C")
+        .env("MEMO_ENV", "LANG")
+        .args(["--verbose", "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("C\n")
+        .stderr(predicate::str::contains("hit"));
+}
+
+// Test Case: Piped Stdin Is Part of the Cache Key and Reaches the Command
+#[test]
+fn test_piped_stdin_in_cache_key() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .arg("sort")
+        .write_stdin("b\na\n")
+        .assert()
+        .success()
+        .stdout("a\nb\n");
+    env.cmd()
+        .arg("sort")
+        .write_stdin("d\nc\n")
+        .assert()
+        .success()
+        .stdout("c\nd\n");
+    env.assert_cache_entry_count(2);
+
+    env.cmd()
+        .args(["--verbose", "sort"])
+        .write_stdin("b\na\n")
+        .assert()
+        .success()
+        .stdout("a\nb\n")
+        .stderr(predicate::str::contains("hit `sort`"));
+
+    // Empty stdin keys like no stdin
+    env.cmd().arg("sort").write_stdin("").assert().success();
+    env.cmd()
+        .args(["--verbose", "sort"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("hit `sort`"));
+    env.assert_cache_entry_count(3);
+
+    // No spooled stdin is left behind
+    let leftovers: Vec<_> = fs::read_dir(env.cache_path().join("memo"))
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(".stdin"))
+        .collect();
+    assert!(leftovers.is_empty());
+}
+
+// Test Case: A Hit Drains Large Piped Stdin
+#[test]
+fn test_hit_drains_piped_stdin() {
+    let env = TestEnv::new();
+    let input = "x".repeat(1 << 20);
+
+    env.cmd()
+        .args(["echo", "done"])
+        .write_stdin(input.clone())
+        .assert()
+        .success();
+    env.cmd()
+        .args(["--verbose", "echo", "done"])
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout("done\n")
+        .stderr(predicate::str::contains("keying on 1048576 bytes of stdin"));
+}
+
+// Test Case: --ignore-stdin Leaves Stdin Out of the Key
+#[test]
+fn test_ignore_stdin() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .args(["--ignore-stdin", "cat"])
+        .write_stdin("first\n")
+        .assert()
+        .success()
+        .stdout("first\n");
+    env.cmd()
+        .args(["--ignore-stdin", "cat"])
+        .write_stdin("second\n")
+        .assert()
+        .success()
+        .stdout("first\n");
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: A Verbose Hit Reports the Time Saved
+#[test]
+fn test_verbose_hit_reports_time_saved() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .args(["sh", "-c", "sleep 0.2; echo done"])
+        .assert()
+        .success();
+    let output = env
+        .cmd()
+        .args(["--verbose", "sh", "-c", "sleep 0.2; echo done"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let saved =
+        regex::Regex::new(r"hit `sh -c sleep 0.2; echo done` => [0-9a-f]+ \(saved ~(\d+)ms\)")
+            .unwrap()
+            .captures(&stderr)
+            .unwrap_or_else(|| panic!("no saved time in {stderr}"));
+    assert!(saved[1].parse::<u64>().unwrap() >= 200);
+}
+
+// Test Case: A Timed-Out Run Exits 124 and Is Not Cached
+#[test]
+fn test_timeout_is_not_cached() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .args(["--timeout", "1", "sh", "-c", "echo partial; exec sleep 10"])
+        .assert()
+        .code(124)
+        .stdout("partial\n")
+        .stderr(predicate::str::contains("timed out after 1s"));
+    env.assert_cache_entry_count(0);
+
+    // A command that finishes in time is cached as usual
+    env.cmd()
+        .args(["--timeout", "10s", "echo", "quick"])
+        .assert()
+        .success();
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: Entries Made with One Digest Algorithm Don't Hit for the Other
+#[test]
+fn test_digest_algorithms_keep_separate_entries() {
+    let env = TestEnv::new();
+
+    env.cmd()
+        .args(["--digest", "blake3", "sh", "-c", "echo run; date +%N"])
+        .assert()
+        .success();
+    env.cmd()
+        .args(["sh", "-c", "echo run; date +%N"])
+        .assert()
+        .success();
+    env.assert_cache_entry_count(2);
+
+    let entries = env.list_cache_entries();
+    assert!(entries.iter().any(|digest| digest.starts_with("blake3")));
+
+    // Each algorithm still hits its own entry
+    let output = env
+        .cmd()
+        .args([
+            "--verbose",
+            "--digest",
+            "blake3",
+            "sh",
+            "-c",
+            "echo run; date +%N",
+        ])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("hit "));
+    env.assert_cache_entry_count(2);
+}
+
+// Test Case: --input Is an Alias for --depends-on
+#[test]
+fn test_input_alias_keys_on_file_contents() {
+    let env = TestEnv::new();
+    let schema = env.cache_path().join("schema.sql");
+
+    fs::write(&schema, "create table a;").unwrap();
+    let run = || {
+        env.cmd()
+            .args(["--input", schema.to_str().unwrap(), "--", "echo", "codegen"])
+            .assert()
+            .success();
+    };
+    run();
+    run();
+    env.assert_cache_entry_count(1);
+
+    fs::write(&schema, "create table b;").unwrap();
+    run();
+    env.assert_cache_entry_count(2);
+}
+
+// Test Case: --depends-on-mtime Keys on Size and Modification Time
+#[test]
+fn test_depends_on_mtime() {
+    let env = TestEnv::new();
+    let input = env.cache_path().join("input");
+
+    fs::write(&input, "v1").unwrap();
+    let run = || {
+        env.cmd()
+            .args([
+                "--depends-on-mtime",
+                "--input",
+                input.to_str().unwrap(),
+                "echo",
+                "hi",
+            ])
+            .assert()
+            .success();
+    };
+    run();
+    run();
+    env.assert_cache_entry_count(1);
+
+    fs::write(&input, "v2 is longer").unwrap();
+    run();
+    env.assert_cache_entry_count(2);
+
+    env.cmd()
+        .args([
+            "--depends-on-mtime",
+            "--input",
+            "/nonexistent/input",
+            "echo",
+            "hi",
+        ])
+        .assert()
+        .failure();
+}
+
+// Test Case: --interleave Replays stdout and stderr in Their Original Order
+#[test]
+fn test_interleave_replays_in_order() {
+    let env = TestEnv::new();
+    let script = "echo a; sleep 0.1; echo b >&2; sleep 0.1; echo c";
+
+    env.cmd()
+        .args(["--interleave", "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("a\nc\n")
+        .stderr("b\n");
+
+    // Separate streams are unchanged on a hit
+    env.cmd()
+        .args(["--interleave", "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("a\nc\n")
+        .stderr("b\n");
+
+    // Merged onto one stream, the original order shows
+    env.cmd()
+        .args(["--interleave", "--merge-streams", "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("a\nb\nc\n");
+    env.cmd()
+        .args(["--merge-streams", "sh", "-c", script])
+        .assert()
+        .success()
+        .stdout("a\nc\nb\n");
+    env.assert_cache_entry_count(1);
+}
+
+// Test Case: --cache-dir and MEMO_CACHE_DIR Override the XDG Location
+#[test]
+fn test_cache_dir_override() {
+    let env = TestEnv::new();
+    let project = TempDir::new().unwrap();
+    let flag_dir = project.path().join(".memo-cache");
+    let env_dir = project.path().join("from-env");
+
+    env.cmd()
+        .args(["--cache-dir", flag_dir.to_str().unwrap(), "echo", "hi"])
+        .assert()
+        .success();
+    assert_eq!(fs::read_dir(&flag_dir).unwrap().count(), 1);
+    env.assert_cache_entry_count(0);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&flag_dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    // The flag takes precedence over the environment variable
+    env.cmd()
+        .env("MEMO_CACHE_DIR", &env_dir)
+        .args(["--cache-dir", flag_dir.to_str().unwrap(), "echo", "hi"])
+        .assert()
+        .success();
+    assert!(!env_dir.exists());
+
+    env.cmd()
+        .env("MEMO_CACHE_DIR", &env_dir)
+        .args(["echo", "hi"])
+        .assert()
+        .success();
+    assert_eq!(fs::read_dir(&env_dir).unwrap().count(), 1);
+    env.assert_cache_entry_count(0);
+}
+
+// Test Case: --namespace Isolates Entries and Scopes --clear
+#[test]
+fn test_namespace_isolates_and_clears() {
+    let env = TestEnv::new();
+
+    for namespace in ["nightly", "release"] {
+        env.cmd()
+            .args(["--namespace", namespace, "echo", "build"])
+            .assert()
+            .success();
+    }
+    env.cmd().args(["echo", "build"]).assert().success();
+
+    env.cmd()
+        .args(["--namespace", "nightly", "--clear"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("cleared 1 entries"));
+
+    // Namespaced caches live inside the default one, so count with --list
+    let count = |namespace: &[&str]| {
+        let output = env
+            .cmd()
+            .args(namespace)
+            .args(["--list", "--format", "{cmd}"])
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).lines().count()
+    };
+    assert_eq!(count(&["--namespace", "nightly"]), 0);
+    assert_eq!(count(&["--namespace", "release"]), 1);
+    assert_eq!(count(&[]), 1);
+}
+
+// Test Case: A Command Killed by a Signal Replays as 128 + Signal
+#[test]
+fn test_signal_exit_replays_consistently() {
+    let env = TestEnv::new();
+    let args = ["--cache-failures", "sh", "-c", "echo dying; kill -9 $$"];
+
+    env.cmd().args(args).assert().code(137).stdout("dying\n");
+    env.cmd().args(args).assert().code(137).stdout("dying\n");
+    env.assert_cache_entry_count(1);
+
+    let entries = env.list_cache_entries();
+    let meta: serde_json::Value =
+        serde_json::from_slice(&env.read_cache_file(&entries[0], "meta.json")).unwrap();
+    assert_eq!(meta["exit_code"], -1);
+    assert_eq!(meta["signal"], 9);
+
+    // The signal is known, so --strict-exit replays it too
+    env.cmd()
+        .args(["--strict-exit", "--verbose"])
+        .args(args)
+        .assert()
+        .code(137)
+        .stdout("dying\n")
+        .stderr(predicate::str::contains("hit "));
+}
+
+// Test Case: --quiet Leaves Stderr to the Command
+#[test]
+fn test_quiet_suppresses_diagnostics() {
+    let env = TestEnv::new();
+    let work = TempDir::new().unwrap();
+    let args = ["--git-aware", "sh", "-c", "echo out; echo err >&2; exit 3"];
+
+    // Without --quiet, memo warns that there's no repository
+    env.cmd()
+        .current_dir(work.path())
+        .args(args)
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains(":: memo ::"));
+
+    env.cmd()
+        .current_dir(work.path())
+        .args(["--quiet", "--cache-failures"])
+        .args(args)
+        .assert()
+        .code(3)
+        .stdout("out\n")
+        .stderr("err\n");
+
+    env.cmd()
+        .args(["-q", "--verbose", "true"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+}
+
+// Test Case: --json Reports the Outcome as One Object on Stderr
+#[test]
+fn test_json_status() {
+    let env = TestEnv::new();
+    let args = ["--json", "sh", "-c", "echo out; printf err >&2; exit 3"];
+
+    let status = |output: std::process::Output| -> serde_json::Value {
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out\n");
+        // The command's stderr comes first, untouched, then one JSON line
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let line = stderr.strip_prefix("err").unwrap();
+        assert_eq!(line.lines().count(), 1);
+        serde_json::from_str(line).unwrap()
+    };
+
+    let miss = status(
+        env.cmd()
+            .arg("--cache-failures")
+            .args(args)
+            .output()
+            .unwrap(),
+    );
+    assert_eq!(miss["status"], "miss");
+    assert_eq!(
+        miss["command"],
+        serde_json::json!(["sh", "-c", "echo out; printf err >&2; exit 3"])
+    );
+    assert_eq!(miss["exit_code"], 3);
+
+    // Verbose diagnostics are replaced by the status object
+    let hit = status(
+        env.cmd()
+            .args(["--cache-failures", "--verbose"])
+            .args(args)
+            .output()
+            .unwrap(),
+    );
+    assert_eq!(hit["status"], "hit");
+    assert_eq!(hit["digest"], miss["digest"]);
+    assert_eq!(hit["digest"], env.list_cache_entries()[0].as_str());
+    assert!(hit["cache_dir"].as_str().unwrap().ends_with("memo"));
+
+    let ignored = status(
+        env.cmd()
+            .env("MEMO_DISABLE", "1")
+            .args(args)
+            .output()
+            .unwrap(),
+    );
+    assert_eq!(ignored["status"], "ignored");
+    assert!(ignored.get("digest").is_none());
+}
+
+// Test Case: Entries Cached Under --shard Are Managed Without It
+#[test]
+fn test_sharded_entries_are_managed_at_any_depth() {
+    let env = TestEnv::new();
+    for word in ["keep", "drop"] {
+        env.cmd()
+            .args(["--shard", "2", "echo", word])
+            .assert()
+            .success();
+    }
+    env.cmd()
+        .args(["--shard", "2", "echo", "keep"])
+        .assert()
+        .success();
+    env.cmd()
+        .args(["--shard", "2", "--pin", "echo", "keep"])
+        .assert()
+        .success();
+
+    // Hit counts and pins are read from where the entries actually are
+    env.cmd()
+        .args(["--list", "--format", "{hits} {cmd}"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1 echo keep\n"))
+        .stdout(predicate::str::contains("0 echo drop\n"));
+    let listed: serde_json::Value = serde_json::from_slice(
+        &env.cmd()
+            .args(["--list", "--json"])
+            .output()
+            .unwrap()
+            .stdout,
+    )
+    .unwrap();
+    let pinned: Vec<bool> = listed
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["pinned"].as_bool().unwrap())
+        .collect();
+    assert_eq!(pinned.iter().filter(|&&p| p).count(), 1);
+
+    env.cmd()
+        .args(["--prune", "--older-than", "0s"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("pruned 1 entries"));
+    env.cmd()
+        .arg("--clear")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("cleared 1 entries"));
+    env.cmd().arg("--list").assert().success().stdout("");
+}
+
+// Test Case: The Default --list Is a Table with a 12-character Digest
+#[test]
+fn test_list_default_table() {
+    let env = TestEnv::new();
+    env.cmd()
+        .args(["--cache-failures", "sh", "-c", "exit 3"])
+        .assert()
+        .code(3);
+    env.cmd()
+        .args(["--digest", "blake3", "echo", "fast"])
+        .assert()
+        .success();
+
+    let output = env.cmd().arg("--list").output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let rows: Vec<Vec<&str>> = stdout.lines().map(|l| l.split('\t').collect()).collect();
+    assert_eq!(rows.len(), 2);
+    for row in &rows {
+        assert_eq!(row.len(), 4);
+        assert_eq!(row[0].len(), 12);
+        assert!(row[0].chars().all(|c| c.is_ascii_hexdigit()));
+        assert!(chrono::DateTime::parse_from_rfc3339(row[2]).is_ok());
+    }
+    let exit = rows.iter().find(|row| row[3] == "sh -c exit 3").unwrap();
+    assert_eq!(exit[1], "3");
+
+    // The BLAKE3 entry's short digest names it for --drop-digest
+    let fast = rows.iter().find(|row| row[3] == "echo fast").unwrap();
+    assert_eq!(fast[1], "0");
+    env.cmd()
+        .args(["--drop-digest", fast[0]])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("dropped blake3"));
+}